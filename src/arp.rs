@@ -0,0 +1,284 @@
+//! IP address conflict detection and gratuitous ARP.
+//!
+//! [`ConflictDetector`] probes a candidate address as described in RFC 5227: it broadcasts
+//! ARP requests for the candidate with an unspecified sender address over the MACRAW socket,
+//! so neighbours do not learn the address before it has been claimed. If another host answers
+//! or probes for the same address, it is in use. Otherwise the candidate is applied to the chip
+//! once the last probe went unanswered. Afterwards [`ConflictDetector::poll`] watches the
+//! [`CommonInterrupt::Conflict`] bit, which the chip sets when another host sends an ARP
+//! request with its address as the source.
+//!
+//! A probe takes about five seconds: three ARP requests one second apart, followed by two
+//! seconds of waiting for a late answer. The caller passes a monotonic millisecond timestamp to
+//! [`ConflictDetector::probe`] and [`ConflictDetector::poll`].
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::arp::{ConflictDetector, Event};
+//! use w5500::{IntoMacRawSocket, Ipv4Addr, MacAddress, MacFilter, Socket};
+//!
+//! let mac = MacAddress::new(0x02, 0x01, 0x02, 0x03, 0x04, 0x05);
+//! let socket = w5500.take_socket(Socket::Socket0).unwrap();
+//! let socket = (&mut w5500, socket)
+//!     .try_into_macraw_socket(MacFilter::Enabled)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"));
+//! let mut arp = ConflictDetector::new(&mut w5500, socket, mac)
+//!     .unwrap_or_else(|_| panic!("failed to clear the conflict interrupt"));
+//!
+//! nb::block!(arp.probe(&mut w5500, Ipv4Addr::new(192, 168, 0, 42), now_ms()))
+//!     .unwrap_or_else(|_| panic!());
+//! loop {
+//!     match arp.poll(&mut w5500, now_ms()) {
+//!         Ok(Some(Event::Available(ip))) => {
+//!             // the address has been applied, tell the neighbours about it
+//!             let _ = nb::block!(arp.announce(&mut w5500));
+//!         }
+//!         Ok(Some(Event::InUse(ip))) => { /* pick another address */ }
+//!         Ok(Some(Event::Conflict(ip))) => { /* another host claims the address */ }
+//!         _ => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{
+    ActiveW5500, CommonInterrupt, Error, Ipv4Addr, MacAddress, MacRaw, MacRawSocket, Register,
+};
+
+/// Ethernet header followed by an ARP packet for IPv4, padded to the minimal frame size
+pub(crate) const FRAME_SIZE: usize = 60;
+pub(crate) const ARP_SIZE: usize = 42;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const HTYPE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+
+const PROBE_NUM: u8 = 3;
+/// Time between probes, the lower bound of RFC 5227
+const PROBE_INTERVAL_MS: u64 = 1_000;
+/// Delay after the last probe before the address is taken
+const ANNOUNCE_WAIT_MS: u64 = 2_000;
+
+/// Outcome of a probe or conflict reported by [`ConflictDetector::poll`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// No host answered the probe, the address has been applied to the chip
+    Available(Ipv4Addr),
+    /// Another host answered the probe or probed for the address as well, the address of the
+    /// chip has not been changed
+    InUse(Ipv4Addr),
+    /// Another host sent an ARP request with the address of the chip as its source
+    Conflict(Ipv4Addr),
+}
+
+/// Probe waiting for an answer
+#[derive(Copy, Clone)]
+struct Probe {
+    candidate: Ipv4Addr,
+    /// number of ARP requests sent so far
+    probes: u8,
+    /// timestamp the next probe or the outcome is due at
+    next_ms: u64,
+}
+
+/// Probes addresses and watches for conflicts, see the [module documentation](self)
+pub struct ConflictDetector {
+    socket: MacRawSocket,
+    mac: MacAddress,
+    probe: Option<Probe>,
+    buffer: [u8; FRAME_SIZE],
+}
+
+impl ConflictDetector {
+    /// Creates a detector that probes with the given MAC address, which should be the one the
+    /// chip is configured with, and clears a pending conflict. The MACRAW socket should have the
+    /// MAC filter enabled.
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: MacRawSocket,
+        mac: MacAddress,
+    ) -> Result<Self, Error<Spi::Error, ChipSelect::Error>> {
+        w5500.reset_common_interrupt(CommonInterrupt::Conflict)?;
+        Ok(ConflictDetector {
+            socket,
+            mac,
+            probe: None,
+            buffer: [0u8; FRAME_SIZE],
+        })
+    }
+
+    /// Whether a probe is waiting for its outcome
+    pub fn is_probing(&self) -> bool {
+        self.probe.is_some()
+    }
+
+    /// Returns the socket
+    pub fn release(self) -> MacRawSocket {
+        self.socket
+    }
+
+    /// Sends the first ARP probe for the candidate, [`ConflictDetector::poll`] sends the others
+    /// and reports the outcome. Returns [`nb::Error::WouldBlock`] while a previous probe is
+    /// still in progress or the previous frame is still being sent.
+    pub fn probe<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        candidate: Ipv4Addr,
+        now_ms: u64,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if self.probe.is_some() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.send(w5500, Ipv4Addr::UNSPECIFIED, candidate)?;
+        debug!("ARP probe for {:?}", candidate);
+        self.probe = Some(Probe {
+            candidate,
+            probes: 1,
+            next_ms: now_ms + PROBE_INTERVAL_MS,
+        });
+        Ok(())
+    }
+
+    /// Sends a gratuitous ARP request for the address of the chip, so neighbours update their
+    /// caches. Returns [`nb::Error::WouldBlock`] while a probe is in progress or the previous
+    /// frame is still being sent.
+    pub fn announce<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if self.probe.is_some() {
+            return Err(nb::Error::WouldBlock);
+        }
+        let ip = w5500.read_ip(Register::CommonRegister(0x00_0F_u16))?;
+        if ip != Ipv4Addr::UNSPECIFIED {
+            self.send(w5500, ip, ip)?;
+            debug!("gratuitous ARP for {:?}", ip);
+        }
+        Ok(())
+    }
+
+    /// Processes received ARP packets, sends the remaining probes and reports their outcome
+    /// and conflicts with the address of the chip. Should be called at least every 100 ms while
+    /// probing.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        while let Some(length) = (&mut *w5500, &self.socket).receive_frame(&mut self.buffer)? {
+            let probe = match self.probe {
+                Some(probe) if length >= ARP_SIZE => probe,
+                _ => continue,
+            };
+            if let Some((sender_mac, sender_ip, target_ip)) = parse(&self.buffer) {
+                // an answer, or another host probing for the same address
+                let answered = sender_ip == probe.candidate;
+                let probed = sender_ip == Ipv4Addr::UNSPECIFIED && target_ip == probe.candidate;
+                if sender_mac != self.mac && (answered || probed) {
+                    self.probe = None;
+                    debug!("{:?} is in use", probe.candidate);
+                    return Ok(Some(Event::InUse(probe.candidate)));
+                }
+            }
+        }
+
+        if let Some(mut probe) = self.probe {
+            if now_ms < probe.next_ms {
+                return Ok(None);
+            }
+            if probe.probes < PROBE_NUM {
+                match self.send(w5500, Ipv4Addr::UNSPECIFIED, probe.candidate) {
+                    Ok(()) => {}
+                    Err(nb::Error::WouldBlock) => return Ok(None),
+                    Err(nb::Error::Other(error)) => return Err(error),
+                }
+                probe.probes += 1;
+                probe.next_ms = if probe.probes < PROBE_NUM {
+                    now_ms + PROBE_INTERVAL_MS
+                } else {
+                    now_ms + ANNOUNCE_WAIT_MS
+                };
+                self.probe = Some(probe);
+                return Ok(None);
+            }
+            self.probe = None;
+            w5500.set_ip(probe.candidate)?;
+            // the address is new to the chip, a conflict with the previous one is stale
+            w5500.reset_common_interrupt(CommonInterrupt::Conflict)?;
+            return Ok(Some(Event::Available(probe.candidate)));
+        }
+
+        if w5500.is_common_interrupt_set(CommonInterrupt::Conflict)? {
+            w5500.reset_common_interrupt(CommonInterrupt::Conflict)?;
+            let ip = w5500.read_ip(Register::CommonRegister(0x00_0F_u16))?;
+            return Ok(Some(Event::Conflict(ip)));
+        }
+        Ok(None)
+    }
+
+    /// Broadcasts an ARP request for the target, a probe with an unspecified sender address or
+    /// an announcement with the target itself
+    fn send<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        sender_ip: Ipv4Addr,
+        target_ip: Ipv4Addr,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        write_request(&mut self.buffer, &self.mac, sender_ip, target_ip);
+        (&mut *w5500, &self.socket).send_frame(&self.buffer)
+    }
+}
+
+/// Writes a broadcast ARP request for `target_ip` into the frame
+pub(crate) fn write_request(
+    frame: &mut [u8; FRAME_SIZE],
+    mac: &MacAddress,
+    sender_ip: Ipv4Addr,
+    target_ip: Ipv4Addr,
+) {
+    frame.iter_mut().for_each(|byte| *byte = 0);
+    frame[0..6].copy_from_slice(&[0xFF; 6]);
+    frame[6..12].copy_from_slice(&mac.octets);
+    BigEndian::write_u16(&mut frame[12..14], ETHERTYPE_ARP);
+
+    let arp = &mut frame[14..ARP_SIZE];
+    BigEndian::write_u16(&mut arp[0..2], HTYPE_ETHERNET);
+    BigEndian::write_u16(&mut arp[2..4], ETHERTYPE_IPV4);
+    arp[4] = 6;
+    arp[5] = 4;
+    BigEndian::write_u16(&mut arp[6..8], OPERATION_REQUEST);
+    arp[8..14].copy_from_slice(&mac.octets);
+    arp[14..18].copy_from_slice(&sender_ip.octets);
+    arp[24..28].copy_from_slice(&target_ip.octets);
+    trace!("ARP for {:?} from {:?}", target_ip, sender_ip);
+}
+
+/// Returns the sender MAC, sender IP and target IP of an Ethernet frame holding an ARP packet
+/// for IPv4
+pub(crate) fn parse(frame: &[u8]) -> Option<(MacAddress, Ipv4Addr, Ipv4Addr)> {
+    if frame.len() < ARP_SIZE {
+        return None;
+    }
+    let arp = &frame[14..ARP_SIZE];
+    if BigEndian::read_u16(&frame[12..14]) != ETHERTYPE_ARP
+        || BigEndian::read_u16(&arp[0..2]) != HTYPE_ETHERNET
+        || BigEndian::read_u16(&arp[2..4]) != ETHERTYPE_IPV4
+    {
+        return None;
+    }
+    let mut sender_mac = MacAddress::default();
+    sender_mac.octets.copy_from_slice(&arp[8..14]);
+    let mut sender_ip = Ipv4Addr::default();
+    sender_ip.octets.copy_from_slice(&arp[14..18]);
+    let mut target_ip = Ipv4Addr::default();
+    target_ip.octets.copy_from_slice(&arp[24..28]);
+    Some((sender_mac, sender_ip, target_ip))
+}
@@ -0,0 +1,308 @@
+//! Link-local IPv4 address auto-configuration (RFC 3927).
+//!
+//! Without a DHCP server, for example on a direct cable to a laptop, [`AutoIp`] picks an
+//! address from 169.254.1.0 to 169.254.254.255, probes it with ARP over the MACRAW socket,
+//! announces it and applies it through [`ActiveW5500::set_ip`] and
+//! [`ActiveW5500::set_subnet`]. The candidates are derived from the MAC address, so a device
+//! gets the same address again after a restart unless another host took it in the meantime.
+//! Afterwards the address is defended against other hosts claiming it, and given up for a new
+//! one if they insist. The caller passes a monotonic millisecond timestamp to every call of
+//! [`AutoIp::poll`].
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::autoip::{AutoIp, Event};
+//! use w5500::{IntoMacRawSocket, MacAddress, MacFilter, Socket};
+//!
+//! let mac = MacAddress::new(0x02, 0x01, 0x02, 0x03, 0x04, 0x05);
+//! let socket = w5500.take_socket(Socket::Socket0).unwrap();
+//! let socket = (&mut w5500, socket)
+//!     .try_into_macraw_socket(MacFilter::Enabled)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"));
+//! let mut autoip = AutoIp::new(socket, mac);
+//!
+//! loop {
+//!     match autoip.poll(&mut w5500, now_ms()) {
+//!         Ok(Some(Event::Configured(ip))) => { /* the address has been applied */ }
+//!         Ok(Some(Event::Deconfigured)) => { /* lost the address to another host */ }
+//!         _ => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::arp::{self, ARP_SIZE, FRAME_SIZE};
+use crate::rand::Xorshift32;
+use crate::{ActiveW5500, Error, Ipv4Addr, MacAddress, MacRaw, MacRawSocket};
+
+/// Subnet mask of the link-local network 169.254.0.0/16
+pub const SUBNET: Ipv4Addr = Ipv4Addr::new(255, 255, 0, 0);
+
+/// Upper bound for the random delay before the first probe
+const PROBE_WAIT_MS: u64 = 1_000;
+const PROBE_NUM: u8 = 3;
+/// Bounds for the random delay between probes
+const PROBE_MIN_MS: u64 = 1_000;
+const PROBE_MAX_MS: u64 = 2_000;
+/// Delay after the last probe before the address is taken
+const ANNOUNCE_WAIT_MS: u64 = 2_000;
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL_MS: u64 = 2_000;
+/// Number of conflicts after which new candidates are only probed once a minute
+const MAX_CONFLICTS: u8 = 10;
+const RATE_LIMIT_INTERVAL_MS: u64 = 60_000;
+/// Minimal time between two defences of the address, a second conflict within it gives up
+const DEFEND_INTERVAL_MS: u64 = 10_000;
+
+/// First and number of the usable link-local addresses, 169.254.1.0 to 169.254.254.255
+const FIRST_ADDRESS: u32 = 0xA9FE_0100;
+const ADDRESS_COUNT: u32 = 0xFE00;
+
+/// Change of the network configuration reported by [`AutoIp::poll`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// The address has been probed successfully and applied to the chip
+    Configured(Ipv4Addr),
+    /// Another host kept claiming the address, the IP address of the chip has been cleared
+    /// and a new address is being probed
+    Deconfigured,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum State {
+    /// Nothing sent yet
+    Init,
+    /// Probing the candidate, `probes` have been sent so far
+    Probing { probes: u8 },
+    /// Address applied, `announcements` have been sent so far
+    Announcing { announcements: u8 },
+    /// Address applied and announced
+    Bound,
+}
+
+/// Link-local address configuration state machine, see the [module documentation](self)
+pub struct AutoIp {
+    socket: MacRawSocket,
+    mac: MacAddress,
+    /// the address being probed or applied
+    ip: Ipv4Addr,
+    state: State,
+    /// timestamp the next probe or announcement is due at
+    next_ms: u64,
+    conflicts: u8,
+    /// timestamp the address has last been defended at
+    defended_ms: Option<u64>,
+    random: Xorshift32,
+    buffer: [u8; FRAME_SIZE],
+}
+
+impl AutoIp {
+    /// Creates a state machine that probes with the given MAC address, which should be the one
+    /// the chip is configured with. The MACRAW socket should have the MAC filter enabled.
+    pub fn new(socket: MacRawSocket, mac: MacAddress) -> Self {
+        let seed = BigEndian::read_u32(&mac.octets[2..]) ^ (u32::from(mac.octets[1]) << 24);
+        let mut autoip = AutoIp {
+            socket,
+            mac,
+            ip: Ipv4Addr::UNSPECIFIED,
+            state: State::Init,
+            next_ms: 0,
+            conflicts: 0,
+            defended_ms: None,
+            random: Xorshift32::new(seed),
+            buffer: [0u8; FRAME_SIZE],
+        };
+        autoip.ip = autoip.next_candidate();
+        autoip
+    }
+
+    /// The address that is currently applied, if any
+    pub fn address(&self) -> Option<Ipv4Addr> {
+        match self.state {
+            State::Announcing { .. } | State::Bound => Some(self.ip),
+            State::Init | State::Probing { .. } => None,
+        }
+    }
+
+    /// Returns the socket. An applied address stays configured on the chip, so a DHCP lease
+    /// acquired later can replace it.
+    pub fn release(self) -> MacRawSocket {
+        self.socket
+    }
+
+    /// Processes received ARP packets and sends probes and announcements as needed. Should be
+    /// called at least every 100 ms while probing.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        if self.state == State::Init {
+            self.restart(now_ms);
+        }
+        while let Some(length) = (&mut *w5500, &self.socket).receive_frame(&mut self.buffer)? {
+            if length >= ARP_SIZE && self.is_conflict() {
+                if let Some(event) = self.handle_conflict(w5500, now_ms)? {
+                    return Ok(Some(event));
+                }
+            }
+        }
+        self.handle_timeouts(w5500, now_ms)
+    }
+
+    fn handle_timeouts<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        if now_ms < self.next_ms {
+            return Ok(None);
+        }
+        match self.state {
+            State::Probing { probes } if probes < PROBE_NUM => {
+                match self.send(w5500, Ipv4Addr::UNSPECIFIED) {
+                    Ok(()) => {}
+                    Err(nb::Error::WouldBlock) => return Ok(None),
+                    Err(nb::Error::Other(error)) => return Err(error),
+                }
+                self.state = State::Probing { probes: probes + 1 };
+                self.next_ms = if probes + 1 < PROBE_NUM {
+                    now_ms + self.random_delay(PROBE_MIN_MS, PROBE_MAX_MS)
+                } else {
+                    now_ms + ANNOUNCE_WAIT_MS
+                };
+                Ok(None)
+            }
+            State::Probing { .. } => {
+                w5500.set_ip(self.ip)?;
+                w5500.set_subnet(SUBNET)?;
+                match self.send(w5500, self.ip) {
+                    Ok(()) => {}
+                    Err(nb::Error::WouldBlock) => return Ok(None),
+                    Err(nb::Error::Other(error)) => return Err(error),
+                }
+                self.state = State::Announcing { announcements: 1 };
+                self.next_ms = now_ms + ANNOUNCE_INTERVAL_MS;
+                self.conflicts = 0;
+                self.defended_ms = None;
+                debug!("link-local address {:?} configured", self.ip);
+                Ok(Some(Event::Configured(self.ip)))
+            }
+            State::Announcing { announcements } => {
+                match self.send(w5500, self.ip) {
+                    Ok(()) => {}
+                    Err(nb::Error::WouldBlock) => return Ok(None),
+                    Err(nb::Error::Other(error)) => return Err(error),
+                }
+                self.state = if announcements + 1 < ANNOUNCE_NUM {
+                    State::Announcing {
+                        announcements: announcements + 1,
+                    }
+                } else {
+                    State::Bound
+                };
+                self.next_ms = now_ms + ANNOUNCE_INTERVAL_MS;
+                Ok(None)
+            }
+            State::Init | State::Bound => Ok(None),
+        }
+    }
+
+    fn handle_conflict<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        debug!(
+            "link-local address {:?} in use, state {:?}",
+            self.ip, self.state
+        );
+        match self.state {
+            State::Init | State::Probing { .. } => {
+                self.conflicts = self.conflicts.saturating_add(1);
+                self.ip = self.next_candidate();
+                self.restart(now_ms);
+                Ok(None)
+            }
+            State::Announcing { .. } | State::Bound => {
+                let recently_defended = self.defended_ms.is_some_and(|defended_ms| {
+                    now_ms.saturating_sub(defended_ms) < DEFEND_INTERVAL_MS
+                });
+                if !recently_defended {
+                    // not counted as a defence if the previous frame is still being sent
+                    match self.send(w5500, self.ip) {
+                        Ok(()) => self.defended_ms = Some(now_ms),
+                        Err(nb::Error::WouldBlock) => {}
+                        Err(nb::Error::Other(error)) => return Err(error),
+                    }
+                    return Ok(None);
+                }
+                w5500.set_ip(Ipv4Addr::UNSPECIFIED)?;
+                self.ip = self.next_candidate();
+                self.restart(now_ms);
+                Ok(Some(Event::Deconfigured))
+            }
+        }
+    }
+
+    /// Starts probing the candidate after a random delay, or after a minute once too many
+    /// candidates have been taken
+    fn restart(&mut self, now_ms: u64) {
+        let delay_ms = if self.conflicts >= MAX_CONFLICTS {
+            RATE_LIMIT_INTERVAL_MS
+        } else {
+            self.random_delay(0, PROBE_WAIT_MS)
+        };
+        self.state = State::Probing { probes: 0 };
+        self.next_ms = now_ms + delay_ms;
+    }
+
+    /// Whether the ARP packet in the buffer comes from another host that uses the address, or
+    /// probes for it while this one is probing too
+    fn is_conflict(&self) -> bool {
+        let (sender_mac, sender_ip, target_ip) = match arp::parse(&self.buffer) {
+            Some(packet) => packet,
+            None => return false,
+        };
+        if sender_mac == self.mac {
+            return false;
+        }
+        let probing = matches!(self.state, State::Init | State::Probing { .. });
+        sender_ip == self.ip
+            || (probing && sender_ip == Ipv4Addr::UNSPECIFIED && target_ip == self.ip)
+    }
+
+    /// Broadcasts an ARP request for the address, a probe with an unspecified sender address
+    /// or an announcement with the address itself. Returns [`nb::Error::WouldBlock`] while the
+    /// previous frame is still being sent, the caller retries on the next poll.
+    fn send<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        sender_ip: Ipv4Addr,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        arp::write_request(&mut self.buffer, &self.mac, sender_ip, self.ip);
+        (&mut *w5500, &self.socket).send_frame(&self.buffer)
+    }
+
+    /// Picks the next address to probe
+    fn next_candidate(&mut self) -> Ipv4Addr {
+        let address = FIRST_ADDRESS + self.random.next_u32() % ADDRESS_COUNT;
+        let mut ip = Ipv4Addr::default();
+        BigEndian::write_u32(&mut ip.octets, address);
+        ip
+    }
+
+    /// Random delay in milliseconds from `min_ms` to `max_ms`
+    fn random_delay(&mut self, min_ms: u64, max_ms: u64) -> u64 {
+        min_ms + u64::from(self.random.next_u32()) % (max_ms - min_ms + 1)
+    }
+}
@@ -0,0 +1,86 @@
+//! Deadlines for blocking operations.
+//!
+//! The driver does not keep time itself. The blocking operations that can wait for the
+//! network take a [`Clock`] and a deadline on that clock in milliseconds, and give up with
+//! [`Error::Timeout`] once it has passed. Any `Fn() -> u64` returning a monotonic millisecond
+//! timestamp is a clock.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::clock::{self, Error};
+//! use w5500::{ErrorSource, IntoTcpSocket, Ipv4Addr, Socket, Tcp};
+//!
+//! w5500
+//!     .wait_for_link(&now_ms, now_ms() + 5_000)
+//!     .unwrap_or_else(|_| panic!("no link"));
+//!
+//! let socket = w5500.take_socket(Socket::Socket1).unwrap();
+//! let socket = (&mut w5500, socket)
+//!     .try_into_tcp_socket(49152)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"));
+//! let server = Ipv4Addr::new(192, 168, 0, 1);
+//! match (&mut w5500, &socket).connect_until(&server, 8080, &now_ms, now_ms() + 3_000) {
+//!     Ok(()) => {
+//!         let deadline_ms = now_ms() + 1_000;
+//!         let sent = clock::block_until(&now_ms, deadline_ms, || {
+//!             (&mut w5500, &socket).send(b"hello")
+//!         });
+//!     }
+//!     Err(Error::Timeout) => { /* the server did not answer in time */ }
+//!     Err(Error::Other(error)) => match error.source() {
+//!         ErrorSource::Bus => { /* failed to talk to the chip */ }
+//!         _ => { /* refused or timed out by the chip */ }
+//!     },
+//! }
+//! # }
+//! ```
+
+/// Monotonic millisecond clock of the application
+pub trait Clock {
+    /// Milliseconds since an arbitrary point in time, must never go backwards
+    fn now_ms(&self) -> u64;
+}
+
+impl<F: Fn() -> u64> Clock for F {
+    fn now_ms(&self) -> u64 {
+        self()
+    }
+}
+
+/// Error returned by blocking operations with a deadline
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The operation itself failed, for example because the chip gave up on its own
+    /// retransmission timeout
+    Other(E),
+    /// The deadline has passed
+    Timeout,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::Other(error)
+    }
+}
+
+/// Calls the non-blocking operation until it completes or the deadline passes
+pub fn block_until<C: Clock, T, E>(
+    clock: &C,
+    deadline_ms: u64,
+    mut operation: impl FnMut() -> nb::Result<T, E>,
+) -> Result<T, Error<E>> {
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(nb::Error::Other(error)) => return Err(Error::Other(error)),
+            Err(nb::Error::WouldBlock) if clock.now_ms() >= deadline_ms => {
+                return Err(Error::Timeout)
+            }
+            Err(nb::Error::WouldBlock) => {}
+        }
+    }
+}
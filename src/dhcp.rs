@@ -0,0 +1,930 @@
+//! DHCPv4 client.
+//!
+//! The [`DhcpClient`] acquires a lease over a UDP socket bound to port 68, applies it to the
+//! common registers through [`ActiveW5500::set_ip`], [`ActiveW5500::set_subnet`] and
+//! [`ActiveW5500::set_gateway`], renews it at T1, rebinds at T2 and releases it on
+//! [`DhcpClient::release`]. The client does not keep time itself, the caller passes a
+//! monotonic millisecond timestamp to every call of [`DhcpClient::poll`].
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! # let seed = 0x2f6b_91c4; // from a hardware RNG
+//! use w5500::dhcp::{DhcpClient, Event};
+//! use w5500::{MacAddress, Socket};
+//!
+//! let mac = MacAddress::new(0x02, 0x01, 0x02, 0x03, 0x04, 0x05);
+//! let socket = w5500.take_socket(Socket::Socket7).unwrap();
+//! let mut dhcp = DhcpClient::new(&mut w5500, socket, mac, seed)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"))
+//!     .with_hostname("sensor-42");
+//!
+//! loop {
+//!     match dhcp.poll(&mut w5500, now_ms()) {
+//!         Ok(Some(Event::Configured(lease))) => { /* the lease has been applied */ }
+//!         Ok(Some(Event::Deconfigured)) => { /* the lease has expired */ }
+//!         _ => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::clock::{self, Clock};
+use crate::rand::Xorshift32;
+use crate::{ActiveW5500, Error, Ipv4Addr, MacAddress, Udp, UdpSocket, UninitializedSocket};
+
+/// UDP port the client listens on
+pub const CLIENT_PORT: u16 = 68;
+/// UDP port the server listens on
+pub const SERVER_PORT: u16 = 67;
+
+/// Largest DHCP message the client sends or receives
+const MESSAGE_SIZE: usize = 576;
+/// Size of the fixed BOOTP header preceding the magic cookie
+const BOOTP_SIZE: usize = 236;
+/// The `sname` and `file` fields, which carry options if the overload option says so
+const SNAME: core::ops::Range<usize> = 44..108;
+const FILE: core::ops::Range<usize> = 108..236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_OVERLOAD: u8 = 52;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_CLIENT_ID: u8 = 61;
+const OPTION_END: u8 = 255;
+
+/// Initial retransmission timeout, doubled on every retransmission (RFC 2131 section 4.1)
+const RETRANSMIT_INITIAL_MS: u64 = 4_000;
+/// Upper bound for the retransmission timeout
+const RETRANSMIT_MAX_MS: u64 = 64_000;
+/// Number of REQUESTs sent for an offer before starting over with a DISCOVER
+const REQUEST_ATTEMPTS: u8 = 4;
+/// Lower bound for the retransmission timeout while renewing or rebinding
+const RENEW_RETRANSMIT_MIN_MS: u64 = 60_000;
+
+/// DHCP message types (option 53)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<MessageType> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            _ => return None,
+        })
+    }
+}
+
+/// Network configuration handed out by a DHCP server
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Lease {
+    /// The leased IP address
+    pub ip: Ipv4Addr,
+    /// The subnet mask of the network
+    pub subnet: Ipv4Addr,
+    /// The first router of the network, if the server announced one
+    pub gateway: Option<Ipv4Addr>,
+    /// The first DNS server of the network, if the server announced one
+    pub dns_server: Option<Ipv4Addr>,
+    /// The server that granted the lease
+    pub server: Ipv4Addr,
+    /// Duration of the lease in seconds
+    pub lease_time: u32,
+    /// Seconds after which the lease is renewed with the granting server (T1)
+    pub renewal_time: u32,
+    /// Seconds after which the lease is renewed with any server (T2)
+    pub rebinding_time: u32,
+}
+
+/// Change of the network configuration reported by [`DhcpClient::poll`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A lease has been acquired or renewed and applied to the chip
+    Configured(Lease),
+    /// The lease has expired or was revoked, the IP address of the chip has been cleared
+    Deconfigured,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum State {
+    /// Nothing sent yet, or starting over
+    Init,
+    /// DISCOVER sent, waiting for an OFFER
+    Selecting,
+    /// REQUEST for an offer sent, waiting for an ACK
+    Requesting {
+        offer: Ipv4Addr,
+        server: Ipv4Addr,
+        attempts: u8,
+    },
+    /// Lease applied
+    Bound,
+    /// Past T1, renewing with the granting server
+    Renewing,
+    /// Past T2, renewing with any server
+    Rebinding,
+}
+
+/// Fields of a received DHCP message that the client is interested in
+struct Reply {
+    message_type: MessageType,
+    your_ip: Ipv4Addr,
+    server: Option<Ipv4Addr>,
+    subnet: Option<Ipv4Addr>,
+    gateway: Option<Ipv4Addr>,
+    dns_server: Option<Ipv4Addr>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+/// DHCPv4 client state machine, see the [module documentation](self)
+pub struct DhcpClient<'a> {
+    socket: UdpSocket,
+    mac: MacAddress,
+    hostname: Option<&'a str>,
+    client_id: Option<&'a [u8]>,
+    state: State,
+    lease: Option<Lease>,
+    /// timestamp the current lease has been acknowledged at
+    lease_start_ms: u64,
+    xid: u32,
+    random: Xorshift32,
+    /// timestamp the last message has been sent at
+    sent_ms: u64,
+    retransmit_ms: u64,
+    buffer: [u8; MESSAGE_SIZE],
+}
+
+impl<'a> DhcpClient<'a> {
+    /// Opens the socket on [`CLIENT_PORT`] and creates a client that identifies itself with
+    /// the given MAC address, which should be the one the chip is configured with. The
+    /// transaction IDs are derived from `seed`, which should come from a random source such
+    /// as a hardware RNG so that they cannot be guessed by other hosts on the network.
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: UninitializedSocket,
+        mac: MacAddress,
+        seed: u32,
+    ) -> Result<Self, Error<Spi::Error, ChipSelect::Error>> {
+        let socket = socket.0;
+        w5500.open_udp(socket, CLIENT_PORT)?;
+        Ok(DhcpClient {
+            socket: UdpSocket(socket),
+            mac,
+            hostname: None,
+            client_id: None,
+            state: State::Init,
+            lease: None,
+            lease_start_ms: 0,
+            xid: 0,
+            random: Xorshift32::new(seed),
+            sent_ms: 0,
+            retransmit_ms: RETRANSMIT_INITIAL_MS,
+            buffer: [0u8; MESSAGE_SIZE],
+        })
+    }
+
+    /// Sends the given hostname (option 12) to the server
+    pub fn with_hostname(mut self, hostname: &'a str) -> Self {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    /// Identifies the client with the given client identifier (option 61) instead of the
+    /// default, which is the hardware type followed by the MAC address
+    pub fn with_client_id(mut self, client_id: &'a [u8]) -> Self {
+        self.client_id = Some(client_id);
+        self
+    }
+
+    /// The lease that is currently applied, if any
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// Processes received messages and timeouts and sends messages as needed. Should be
+    /// called regularly, at least once per second while no lease is bound.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        while let Some((_, _, length, _)) = (&mut *w5500, &self.socket).receive(&mut self.buffer)? {
+            // options beyond the buffer are lost, the rest of the reply is still usable
+            let length = length.min(MESSAGE_SIZE);
+            if let Some(event) = self.handle_reply(w5500, length, now_ms)? {
+                return Ok(Some(event));
+            }
+        }
+        self.handle_timeouts(w5500, now_ms)
+    }
+
+    /// Releases the lease, clears the IP address of the chip and returns the socket. Waits
+    /// until the RELEASE has been sent or the deadline passes, the server lets the lease
+    /// expire if it never receives it.
+    pub fn release<ChipSelect: OutputPin, Spi: FullDuplex<u8>, C: Clock>(
+        mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<UdpSocket, Error<Spi::Error, ChipSelect::Error>> {
+        if let Some(lease) = self.lease.take() {
+            self.xid = self.random.next_u32();
+            let length = self.build(
+                MessageType::Release,
+                Some(lease.ip),
+                None,
+                Some(lease.server),
+            );
+            match (&mut *w5500, &self.socket).send_until(
+                &lease.server,
+                SERVER_PORT,
+                &self.buffer[..length],
+                clock,
+                deadline_ms,
+            ) {
+                Ok(()) => {}
+                Err(clock::Error::Timeout) | Err(clock::Error::Other(Error::ArpTimeout)) => {
+                    debug!("DHCP RELEASE to {} not sent", lease.server);
+                }
+                Err(clock::Error::Other(error)) => return Err(error),
+            }
+            w5500.set_ip(Ipv4Addr::UNSPECIFIED)?;
+        }
+        Ok(self.socket)
+    }
+
+    fn handle_reply<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        length: usize,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        let reply = match self.parse(length) {
+            Some(reply) => reply,
+            None => return Ok(None),
+        };
+        debug!("DHCP {:?} in state {:?}", reply.message_type, self.state);
+
+        match (self.state, reply.message_type) {
+            (State::Selecting, MessageType::Offer) => {
+                if let Some(server) = reply.server {
+                    self.state = State::Requesting {
+                        offer: reply.your_ip,
+                        server,
+                        attempts: 0,
+                    };
+                    self.retransmit_ms = RETRANSMIT_INITIAL_MS;
+                    self.send_request(w5500, now_ms)?;
+                }
+                Ok(None)
+            }
+            (State::Requesting { .. }, MessageType::Ack)
+            | (State::Renewing, MessageType::Ack)
+            | (State::Rebinding, MessageType::Ack) => self.bind(w5500, &reply, now_ms),
+            (State::Requesting { .. }, MessageType::Nak) => {
+                self.state = State::Init;
+                Ok(None)
+            }
+            (State::Renewing, MessageType::Nak) | (State::Rebinding, MessageType::Nak) => {
+                self.deconfigure(w5500)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn handle_timeouts<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        let retransmit_due = now_ms.saturating_sub(self.sent_ms) >= self.retransmit_ms;
+        match self.state {
+            State::Init => {
+                self.state = State::Selecting;
+                self.retransmit_ms = RETRANSMIT_INITIAL_MS;
+                self.send_discover(w5500, now_ms)?;
+            }
+            State::Selecting if retransmit_due => {
+                self.retransmit_ms = (self.retransmit_ms * 2).min(RETRANSMIT_MAX_MS);
+                self.send_discover(w5500, now_ms)?;
+            }
+            State::Requesting {
+                offer,
+                server,
+                attempts,
+            } if retransmit_due => {
+                if attempts + 1 >= REQUEST_ATTEMPTS {
+                    self.state = State::Init;
+                } else {
+                    self.state = State::Requesting {
+                        offer,
+                        server,
+                        attempts: attempts + 1,
+                    };
+                    self.retransmit_ms = (self.retransmit_ms * 2).min(RETRANSMIT_MAX_MS);
+                    self.send_request(w5500, now_ms)?;
+                }
+            }
+            State::Bound | State::Renewing | State::Rebinding => {
+                let lease = match self.lease {
+                    Some(lease) => lease,
+                    None => {
+                        self.state = State::Init;
+                        return Ok(None);
+                    }
+                };
+                let elapsed_ms = now_ms.saturating_sub(self.lease_start_ms);
+                let renewal_ms = u64::from(lease.renewal_time) * 1000;
+                let rebinding_ms = u64::from(lease.rebinding_time) * 1000;
+                let expiry_ms = u64::from(lease.lease_time) * 1000;
+
+                if elapsed_ms >= expiry_ms {
+                    return self.deconfigure(w5500);
+                }
+                let next_state = if elapsed_ms >= rebinding_ms {
+                    State::Rebinding
+                } else if elapsed_ms >= renewal_ms {
+                    State::Renewing
+                } else {
+                    State::Bound
+                };
+                // RFC 2131 section 4.4.5: wait half of the remaining time until T2
+                // (or the end of the lease), but at least a minute
+                let deadline_ms = if next_state == State::Rebinding {
+                    expiry_ms
+                } else {
+                    rebinding_ms
+                };
+                let retransmit_ms = ((deadline_ms - elapsed_ms) / 2).max(RENEW_RETRANSMIT_MIN_MS);
+
+                if next_state != State::Bound
+                    && (next_state != self.state
+                        || now_ms.saturating_sub(self.sent_ms) >= retransmit_ms)
+                {
+                    self.state = next_state;
+                    self.send_request(w5500, now_ms)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn bind<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        reply: &Reply,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        let lease = match self.lease_from(reply) {
+            Some(lease) => lease,
+            None => return Ok(None),
+        };
+
+        w5500.set_ip(lease.ip)?;
+        w5500.set_subnet(lease.subnet)?;
+        w5500.set_gateway(lease.gateway.unwrap_or(Ipv4Addr::UNSPECIFIED))?;
+
+        self.state = State::Bound;
+        self.lease = Some(lease);
+        self.lease_start_ms = self.sent_ms.min(now_ms);
+        debug!("DHCP bound to {:?} for {} s", lease.ip, lease.lease_time);
+        Ok(Some(Event::Configured(lease)))
+    }
+
+    /// The lease an ACK grants, with T1 and T2 derived from the lease time unless the server
+    /// sent them
+    fn lease_from(&self, reply: &Reply) -> Option<Lease> {
+        let server = match (reply.server, self.state, self.lease) {
+            (Some(server), _, _) => server,
+            (None, State::Requesting { server, .. }, _) => server,
+            (None, _, Some(lease)) => lease.server,
+            (None, _, None) => return None,
+        };
+        let lease_time = reply.lease_time.unwrap_or(u32::MAX);
+        Some(Lease {
+            ip: reply.your_ip,
+            subnet: reply.subnet.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            gateway: reply.gateway,
+            dns_server: reply.dns_server,
+            server,
+            lease_time,
+            renewal_time: reply.renewal_time.unwrap_or(lease_time / 2),
+            rebinding_time: reply
+                .rebinding_time
+                .unwrap_or((u64::from(lease_time) * 7 / 8) as u32),
+        })
+    }
+
+    fn deconfigure<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        w5500.set_ip(Ipv4Addr::UNSPECIFIED)?;
+        self.lease = None;
+        self.state = State::Init;
+        Ok(Some(Event::Deconfigured))
+    }
+
+    fn send_discover<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        self.xid = self.random.next_u32();
+        let length = self.build(MessageType::Discover, None, None, None);
+        self.send(w5500, Ipv4Addr::BROADCAST, length, now_ms)
+    }
+
+    fn send_request<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let (length, destination) = match (self.state, self.lease) {
+            (State::Requesting { offer, server, .. }, _) => (
+                self.build(MessageType::Request, None, Some(offer), Some(server)),
+                Ipv4Addr::BROADCAST,
+            ),
+            (State::Renewing, Some(lease)) => {
+                self.xid = self.random.next_u32();
+                (
+                    self.build(MessageType::Request, Some(lease.ip), None, None),
+                    lease.server,
+                )
+            }
+            (State::Rebinding, Some(lease)) => {
+                self.xid = self.random.next_u32();
+                (
+                    self.build(MessageType::Request, Some(lease.ip), None, None),
+                    Ipv4Addr::BROADCAST,
+                )
+            }
+            _ => return Ok(()),
+        };
+        self.send(w5500, destination, length, now_ms)
+    }
+
+    fn send<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        destination: Ipv4Addr,
+        length: usize,
+        now_ms: u64,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        self.sent_ms = now_ms;
+        // a message the chip cannot take or deliver counts as lost and is retransmitted
+        match (&mut *w5500, &self.socket).send(&destination, SERVER_PORT, &self.buffer[..length]) {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(Error::ArpTimeout)) => {
+                debug!("DHCP message lost to an ARP timeout");
+                Ok(())
+            }
+            Err(nb::Error::Other(error)) => Err(error),
+        }
+    }
+
+    /// Writes a message into the buffer and returns its length
+    fn build(
+        &mut self,
+        message_type: MessageType,
+        client_ip: Option<Ipv4Addr>,
+        requested_ip: Option<Ipv4Addr>,
+        server: Option<Ipv4Addr>,
+    ) -> usize {
+        let buffer = &mut self.buffer;
+        buffer.iter_mut().for_each(|byte| *byte = 0);
+
+        buffer[0] = OP_REQUEST;
+        buffer[1] = HTYPE_ETHERNET;
+        buffer[2] = self.mac.octets.len() as u8;
+        BigEndian::write_u32(&mut buffer[4..8], self.xid);
+        if client_ip.is_none() {
+            // without an address, the reply has to be broadcast to reach the client
+            BigEndian::write_u16(&mut buffer[10..12], FLAG_BROADCAST);
+        }
+        if let Some(client_ip) = client_ip {
+            buffer[12..16].copy_from_slice(&client_ip.octets);
+        }
+        buffer[28..34].copy_from_slice(&self.mac.octets);
+        buffer[BOOTP_SIZE..BOOTP_SIZE + 4].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut options = OptionWriter {
+            buffer: &mut buffer[..MESSAGE_SIZE - 1],
+            position: BOOTP_SIZE + 4,
+        };
+        options.write(OPTION_MESSAGE_TYPE, &[message_type as u8]);
+        match self.client_id {
+            Some(client_id) => options.write(OPTION_CLIENT_ID, client_id),
+            None => {
+                let mut client_id = [HTYPE_ETHERNET; 7];
+                client_id[1..].copy_from_slice(&self.mac.octets);
+                options.write(OPTION_CLIENT_ID, &client_id);
+            }
+        }
+        if let Some(requested_ip) = requested_ip {
+            options.write(OPTION_REQUESTED_IP, &requested_ip.octets);
+        }
+        if let Some(server) = server {
+            options.write(OPTION_SERVER_ID, &server.octets);
+        }
+        if message_type != MessageType::Release {
+            if let Some(hostname) = self.hostname {
+                options.write(OPTION_HOSTNAME, hostname.as_bytes());
+            }
+            options.write(
+                OPTION_PARAMETER_REQUEST_LIST,
+                &[
+                    OPTION_SUBNET_MASK,
+                    OPTION_ROUTER,
+                    OPTION_DNS_SERVER,
+                    OPTION_LEASE_TIME,
+                    OPTION_RENEWAL_TIME,
+                    OPTION_REBINDING_TIME,
+                ],
+            );
+        }
+        let position = options.position;
+        buffer[position] = OPTION_END;
+
+        // pad to the minimal BOOTP message size, some servers drop shorter ones
+        (position + 1).max(300)
+    }
+
+    /// Parses the message in the buffer, ignoring messages that are not for this client
+    fn parse(&self, length: usize) -> Option<Reply> {
+        let message = self.buffer.get(..length)?;
+        if length < BOOTP_SIZE + 4
+            || message[0] != OP_REPLY
+            || BigEndian::read_u32(&message[4..8]) != self.xid
+            || message[28..34] != self.mac.octets
+            || message[BOOTP_SIZE..BOOTP_SIZE + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut your_ip = Ipv4Addr::default();
+        your_ip.octets.copy_from_slice(&message[16..20]);
+        let mut reply = Reply {
+            message_type: MessageType::Discover,
+            your_ip,
+            server: None,
+            subnet: None,
+            gateway: None,
+            dns_server: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+        };
+        let mut message_type = None;
+        let mut overload = 0;
+        read_options(
+            &message[BOOTP_SIZE + 4..],
+            &mut reply,
+            &mut message_type,
+            &mut overload,
+        )?;
+        if overload & 0x01 != 0 {
+            read_options(&message[FILE], &mut reply, &mut message_type, &mut 0)?;
+        }
+        if overload & 0x02 != 0 {
+            read_options(&message[SNAME], &mut reply, &mut message_type, &mut 0)?;
+        }
+
+        reply.message_type = MessageType::from_u8(message_type?)?;
+        Some(reply)
+    }
+}
+
+/// Reads the options up to the end option into the reply, returns `None` if one is cut off
+fn read_options(
+    mut options: &[u8],
+    reply: &mut Reply,
+    message_type: &mut Option<u8>,
+    overload: &mut u8,
+) -> Option<()> {
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let (&length, rest) = rest.split_first()?;
+        let value = rest.get(..usize::from(length))?;
+        options = &rest[usize::from(length)..];
+
+        let ip = || {
+            value.get(..4).map(|octets| {
+                let mut ip = Ipv4Addr::default();
+                ip.octets.copy_from_slice(octets);
+                ip
+            })
+        };
+        let seconds = || value.get(..4).map(BigEndian::read_u32);
+        match code {
+            OPTION_MESSAGE_TYPE => *message_type = value.first().copied(),
+            OPTION_OVERLOAD => *overload = value.first().copied().unwrap_or(0),
+            OPTION_SERVER_ID => reply.server = ip(),
+            OPTION_SUBNET_MASK => reply.subnet = ip(),
+            OPTION_ROUTER => reply.gateway = ip(),
+            OPTION_DNS_SERVER => reply.dns_server = ip(),
+            OPTION_LEASE_TIME => reply.lease_time = seconds(),
+            OPTION_RENEWAL_TIME => reply.renewal_time = seconds(),
+            OPTION_REBINDING_TIME => reply.rebinding_time = seconds(),
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+/// Appends options to a DHCP message, dropping options that do not fit
+struct OptionWriter<'b> {
+    buffer: &'b mut [u8],
+    position: usize,
+}
+
+impl OptionWriter<'_> {
+    fn write(&mut self, code: u8, value: &[u8]) {
+        let length = value.len().min(usize::from(u8::MAX));
+        let end = self.position + 2 + length;
+        if end <= self.buffer.len() {
+            self.buffer[self.position] = code;
+            self.buffer[self.position + 1] = length as u8;
+            self.buffer[self.position + 2..end].copy_from_slice(&value[..length]);
+            self.position = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Socket;
+
+    const MAC: MacAddress = MacAddress {
+        octets: [0x02, 0x00, 0x00, 0x12, 0x34, 0x56],
+    };
+    const XID: u32 = 0x3903_f326;
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const OFFER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 100);
+
+    fn client() -> DhcpClient<'static> {
+        DhcpClient {
+            socket: UdpSocket(Socket::Socket0),
+            mac: MAC,
+            hostname: None,
+            client_id: None,
+            state: State::Selecting,
+            lease: None,
+            lease_start_ms: 0,
+            xid: XID,
+            random: Xorshift32::new(1),
+            sent_ms: 0,
+            retransmit_ms: RETRANSMIT_INITIAL_MS,
+            buffer: [0u8; MESSAGE_SIZE],
+        }
+    }
+
+    /// Writes a server reply with the given options into the buffer of the client and returns
+    /// its length
+    fn reply(client: &mut DhcpClient, xid: u32, options: &[u8]) -> usize {
+        let buffer = &mut client.buffer;
+        buffer.iter_mut().for_each(|byte| *byte = 0);
+        buffer[..4].copy_from_slice(&[OP_REPLY, HTYPE_ETHERNET, 6, 0]);
+        BigEndian::write_u32(&mut buffer[4..8], xid);
+        buffer[16..20].copy_from_slice(&OFFER.octets);
+        buffer[28..34].copy_from_slice(&MAC.octets);
+        buffer[BOOTP_SIZE..BOOTP_SIZE + 4].copy_from_slice(&MAGIC_COOKIE);
+        let end = BOOTP_SIZE + 4 + options.len();
+        buffer[BOOTP_SIZE + 4..end].copy_from_slice(options);
+        buffer[end] = OPTION_END;
+        300
+    }
+
+    /// The value of the option in a message the client built
+    fn option(message: &[u8], code: u8) -> Option<&[u8]> {
+        let mut options = &message[BOOTP_SIZE + 4..];
+        while let [found, length, rest @ ..] = options {
+            if *found == OPTION_END {
+                break;
+            }
+            let (value, rest) = rest.split_at(usize::from(*length));
+            if *found == code {
+                return Some(value);
+            }
+            options = rest;
+        }
+        None
+    }
+
+    #[test]
+    fn parses_offer() {
+        let mut client = client();
+        let length = reply(
+            &mut client,
+            XID,
+            &[
+                53, 1, 2, // OFFER
+                54, 4, 192, 168, 1, 1, // server
+                1, 4, 255, 255, 255, 0, // subnet mask
+                3, 8, 192, 168, 1, 254, 192, 168, 1, 253, // routers
+                6, 4, 9, 9, 9, 9, // DNS server
+                15, 3, b'l', b'a', b'n', // domain name
+            ],
+        );
+        let reply = client.parse(length).unwrap();
+        assert_eq!(reply.message_type, MessageType::Offer);
+        assert_eq!(reply.your_ip, OFFER);
+        assert_eq!(reply.server, Some(SERVER));
+        assert_eq!(reply.subnet, Some(Ipv4Addr::new(255, 255, 255, 0)));
+        assert_eq!(reply.gateway, Some(Ipv4Addr::new(192, 168, 1, 254)));
+        assert_eq!(reply.dns_server, Some(Ipv4Addr::new(9, 9, 9, 9)));
+        assert_eq!(reply.lease_time, None);
+    }
+
+    #[test]
+    fn parses_ack_and_nak() {
+        let mut client = client();
+        let length = reply(&mut client, XID, &[53, 1, 5, 51, 4, 0, 0, 0x0e, 0x10]);
+        let reply_ = client.parse(length).unwrap();
+        assert_eq!(reply_.message_type, MessageType::Ack);
+        assert_eq!(reply_.lease_time, Some(3600));
+
+        let length = reply(&mut client, XID, &[53, 1, 6, 54, 4, 192, 168, 1, 1]);
+        assert_eq!(client.parse(length).unwrap().message_type, MessageType::Nak);
+
+        // no or an unknown message type
+        let length = reply(&mut client, XID, &[54, 4, 192, 168, 1, 1]);
+        assert!(client.parse(length).is_none());
+        let length = reply(&mut client, XID, &[53, 1, 9]);
+        assert!(client.parse(length).is_none());
+    }
+
+    #[test]
+    fn derives_renewal_and_rebinding_times() {
+        let mut client = client();
+        client.state = State::Requesting {
+            offer: OFFER,
+            server: SERVER,
+            attempts: 0,
+        };
+        let length = reply(&mut client, XID, &[53, 1, 5, 51, 4, 0, 0, 0x0e, 0x10]);
+        let lease = client.lease_from(&client.parse(length).unwrap()).unwrap();
+        assert_eq!(lease.server, SERVER);
+        assert_eq!(lease.lease_time, 3600);
+        assert_eq!(lease.renewal_time, 1800);
+        assert_eq!(lease.rebinding_time, 3150);
+
+        let length = reply(
+            &mut client,
+            XID,
+            &[
+                53, 1, 5, 51, 4, 0, 0, 0x0e, 0x10, 58, 4, 0, 0, 0x03, 0x84, 59, 4, 0, 0, 0x07, 0x08,
+            ],
+        );
+        let lease = client.lease_from(&client.parse(length).unwrap()).unwrap();
+        assert_eq!(lease.renewal_time, 900);
+        assert_eq!(lease.rebinding_time, 1800);
+
+        // without a lease time, the lease is infinite
+        let length = reply(&mut client, XID, &[53, 1, 5]);
+        let lease = client.lease_from(&client.parse(length).unwrap()).unwrap();
+        assert_eq!(lease.lease_time, u32::MAX);
+        assert_eq!(lease.rebinding_time, (u64::from(u32::MAX) * 7 / 8) as u32);
+    }
+
+    #[test]
+    fn reads_padded_and_overloaded_options() {
+        let mut client = client();
+        let length = reply(&mut client, XID, &[0, 0, 53, 1, 5, 0, 52, 1, 3]);
+        client.buffer[FILE][..13].copy_from_slice(&[54, 4, 192, 168, 1, 1, 0, 51, 4, 0, 0, 1, 0]);
+        client.buffer[FILE][13] = OPTION_END;
+        client.buffer[SNAME][..6].copy_from_slice(&[1, 4, 255, 255, 0, 0]);
+        client.buffer[SNAME][6] = OPTION_END;
+        let reply = client.parse(length).unwrap();
+        assert_eq!(reply.message_type, MessageType::Ack);
+        assert_eq!(reply.server, Some(SERVER));
+        assert_eq!(reply.lease_time, Some(256));
+        assert_eq!(reply.subnet, Some(Ipv4Addr::new(255, 255, 0, 0)));
+
+        // without the overload option, the fields are not read
+        client.buffer[BOOTP_SIZE + 10] = OPTION_END;
+        let reply = client.parse(length).unwrap();
+        assert_eq!(reply.server, None);
+        assert_eq!(reply.subnet, None);
+    }
+
+    #[test]
+    fn rejects_cut_off_option() {
+        let mut client = client();
+        reply(&mut client, XID, &[53, 1, 5, 51, 4, 0, 0]);
+        assert!(client.parse(BOOTP_SIZE + 4 + 7).is_none());
+    }
+
+    #[test]
+    fn ignores_other_transactions() {
+        let mut client = client();
+        let length = reply(&mut client, XID ^ 1, &[53, 1, 2]);
+        assert!(client.parse(length).is_none());
+
+        let length = reply(&mut client, XID, &[53, 1, 2]);
+        client.buffer[33] ^= 1;
+        assert!(client.parse(length).is_none());
+
+        let length = reply(&mut client, XID, &[53, 1, 2]);
+        client.buffer[0] = OP_REQUEST;
+        assert!(client.parse(length).is_none());
+    }
+
+    #[test]
+    fn builds_discover() {
+        let mut client = client().with_hostname("sensor");
+        let length = client.build(MessageType::Discover, None, None, None);
+        let message = &client.buffer[..length];
+        assert_eq!(length, 300);
+        assert_eq!(message[..3], [OP_REQUEST, HTYPE_ETHERNET, 6]);
+        assert_eq!(BigEndian::read_u32(&message[4..8]), XID);
+        assert_eq!(BigEndian::read_u16(&message[10..12]), FLAG_BROADCAST);
+        assert_eq!(message[28..34], MAC.octets);
+        assert_eq!(option(message, OPTION_MESSAGE_TYPE), Some(&[1][..]));
+        assert_eq!(
+            option(message, OPTION_CLIENT_ID),
+            Some(&[1, 0x02, 0x00, 0x00, 0x12, 0x34, 0x56][..])
+        );
+        assert_eq!(option(message, OPTION_HOSTNAME), Some(&b"sensor"[..]));
+        assert!(option(message, OPTION_PARAMETER_REQUEST_LIST).is_some());
+        assert_eq!(option(message, OPTION_REQUESTED_IP), None);
+    }
+
+    #[test]
+    fn builds_request() {
+        let mut client = client()
+            .with_hostname("sensor")
+            .with_client_id(b"\x00sensor-7");
+        let length = client.build(MessageType::Request, None, Some(OFFER), Some(SERVER));
+        let message = &client.buffer[..length];
+        assert_eq!(option(message, OPTION_MESSAGE_TYPE), Some(&[3][..]));
+        assert_eq!(
+            option(message, OPTION_CLIENT_ID),
+            Some(&b"\x00sensor-7"[..])
+        );
+        assert_eq!(option(message, OPTION_HOSTNAME), Some(&b"sensor"[..]));
+        assert_eq!(
+            option(message, OPTION_REQUESTED_IP),
+            Some(&OFFER.octets[..])
+        );
+        assert_eq!(option(message, OPTION_SERVER_ID), Some(&SERVER.octets[..]));
+
+        // renewing from the leased address, the server answers directly
+        let length = client.build(MessageType::Request, Some(OFFER), None, None);
+        let message = &client.buffer[..length];
+        assert_eq!(message[12..16], OFFER.octets);
+        assert_eq!(BigEndian::read_u16(&message[10..12]), 0);
+        assert_eq!(option(message, OPTION_REQUESTED_IP), None);
+    }
+}
@@ -0,0 +1,668 @@
+//! Stub DNS resolver.
+//!
+//! The [`DnsResolver`] sends A queries over a UDP socket on an ephemeral port to the
+//! configured servers in turn, follows CNAME chains in the responses and keeps the resolved
+//! addresses in a small cache until their TTL expires. [`DnsResolver::resolve`] does not
+//! block, it returns [`nb::Error::WouldBlock`] while the query is in flight and has to be
+//! called again with the same name and the current time in milliseconds.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! # let seed = 0x2f6b_91c4; // from a hardware RNG
+//! use w5500::dns::DnsResolver;
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! let servers = [Ipv4Addr::new(192, 168, 0, 1), Ipv4Addr::new(9, 9, 9, 9)];
+//! let socket = w5500.take_socket(Socket::Socket6).unwrap();
+//! let mut resolver = DnsResolver::new(&mut w5500, socket, &servers, seed)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"));
+//!
+//! let telemetry = loop {
+//!     match resolver.resolve(&mut w5500, "telemetry.example.com", now_ms()) {
+//!         Ok(ip) => break ip,
+//!         Err(nb::Error::WouldBlock) => continue,
+//!         Err(nb::Error::Other(_)) => panic!("failed to resolve"),
+//!     }
+//! };
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::rand::Xorshift32;
+use crate::{ActiveW5500, Ipv4Addr, Udp, UdpSocket, UninitializedSocket};
+
+/// UDP port the servers listen on
+pub const SERVER_PORT: u16 = 53;
+/// Number of addresses the resolver caches
+pub const CACHE_SIZE: usize = 4;
+/// Longest name that can be resolved, without the trailing dot
+pub const MAX_NAME_LENGTH: usize = 253;
+
+/// Largest message that is sent over UDP without EDNS
+const MESSAGE_SIZE: usize = 512;
+const HEADER_SIZE: usize = 12;
+const MAX_LABEL_LENGTH: usize = 63;
+/// Number of CNAME records followed before giving up
+const MAX_CNAME_CHAIN: usize = 8;
+/// Number of compression pointers followed in a single name, guards against loops
+const MAX_POINTERS: usize = 16;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u16 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const CLASS_IN: u16 = 1;
+
+/// Default time to wait for a response before asking the next server
+const DEFAULT_TIMEOUT_MS: u64 = 2_000;
+/// Default number of times every server is asked
+const DEFAULT_ATTEMPTS: u8 = 2;
+
+/// Error returned by [`DnsResolver::resolve`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// The name is empty, too long or contains an empty or too long label
+    InvalidName,
+    /// The name does not exist or has no A record
+    NotFound,
+    /// None of the servers answered with a usable response in time
+    Timeout,
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// Domain name in dotted notation, stored inline
+#[derive(Copy, Clone)]
+struct Name {
+    bytes: [u8; MAX_NAME_LENGTH],
+    length: usize,
+}
+
+impl Name {
+    const EMPTY: Name = Name {
+        bytes: [0u8; MAX_NAME_LENGTH],
+        length: 0,
+    };
+
+    /// Validates the name and strips a trailing dot
+    fn parse(name: &str) -> Option<Name> {
+        let name = name.strip_suffix('.').unwrap_or(name).as_bytes();
+        if name.is_empty()
+            || name.len() > MAX_NAME_LENGTH
+            || name
+                .split(|byte| *byte == b'.')
+                .any(|label| label.is_empty() || label.len() > MAX_LABEL_LENGTH)
+        {
+            return None;
+        }
+        let mut parsed = Name::EMPTY;
+        parsed.bytes[..name.len()].copy_from_slice(name);
+        parsed.length = name.len();
+        Some(parsed)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+
+    fn matches(&self, other: &Name) -> bool {
+        self.as_bytes().eq_ignore_ascii_case(other.as_bytes())
+    }
+
+    fn push_label(&mut self, label: &[u8]) -> Option<()> {
+        let separator = usize::from(self.length > 0);
+        let end = self.length + separator + label.len();
+        if end > MAX_NAME_LENGTH {
+            return None;
+        }
+        if separator > 0 {
+            self.bytes[self.length] = b'.';
+        }
+        self.bytes[self.length + separator..end].copy_from_slice(label);
+        self.length = end;
+        Some(())
+    }
+}
+
+#[derive(Copy, Clone)]
+struct CacheEntry {
+    name: Name,
+    ip: Ipv4Addr,
+    expires_ms: u64,
+}
+
+/// Query in flight
+struct Query {
+    name: Name,
+    id: u16,
+    /// number of messages sent so far, selects the server
+    attempt: usize,
+    sent_ms: u64,
+}
+
+/// Result of a response that belongs to the query in flight
+enum Outcome {
+    Address {
+        ip: Ipv4Addr,
+        ttl: u32,
+    },
+    NotFound,
+    /// The server failed, refused or truncated the response, ask the next one
+    Failed,
+}
+
+/// Stub resolver for A records, see the [module documentation](self)
+pub struct DnsResolver<'a> {
+    socket: UdpSocket,
+    servers: &'a [Ipv4Addr],
+    timeout_ms: u64,
+    attempts: u8,
+    random: Xorshift32,
+    query: Option<Query>,
+    cache: [Option<CacheEntry>; CACHE_SIZE],
+    buffer: [u8; MESSAGE_SIZE],
+}
+
+impl<'a> DnsResolver<'a> {
+    /// Opens the socket on an ephemeral port and creates a resolver that asks the given
+    /// servers in order. Every query gets a new ID derived from `seed`, which should come
+    /// from a random source such as a hardware RNG so that off-path attackers cannot guess
+    /// the IDs and spoof responses.
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: UninitializedSocket,
+        servers: &'a [Ipv4Addr],
+        seed: u32,
+    ) -> Result<Self, crate::Error<Spi::Error, ChipSelect::Error>> {
+        let socket = socket.0;
+        let port = w5500.0.next_ephemeral_port();
+        w5500.open_udp(socket, port)?;
+        Ok(DnsResolver {
+            socket: UdpSocket(socket),
+            servers,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            attempts: DEFAULT_ATTEMPTS,
+            random: Xorshift32::new(seed),
+            query: None,
+            cache: [None; CACHE_SIZE],
+            buffer: [0u8; MESSAGE_SIZE],
+        })
+    }
+
+    /// Sets the time to wait for a response before asking the next server
+    pub fn with_timeout(mut self, timeout_ms: u64) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Sets how many times every server is asked before giving up
+    pub fn with_attempts(mut self, attempts: u8) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Forgets all cached addresses
+    pub fn clear_cache(&mut self) {
+        self.cache = [None; CACHE_SIZE];
+    }
+
+    /// Returns the socket, abandoning the query in flight
+    pub fn release(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Resolves the name to an IPv4 address, from the cache if possible. Returns
+    /// [`nb::Error::WouldBlock`] while waiting for a response. Resolving another name
+    /// abandons the query in flight.
+    pub fn resolve<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        name: &str,
+        now_ms: u64,
+    ) -> nb::Result<Ipv4Addr, Error<Spi::Error, ChipSelect::Error>> {
+        let name = Name::parse(name).ok_or(nb::Error::Other(Error::InvalidName))?;
+        if let Some(ip) = self.cached(&name, now_ms) {
+            return Ok(ip);
+        }
+
+        match &self.query {
+            Some(query) if query.name.matches(&name) => {}
+            _ => {
+                self.query = Some(Query {
+                    name,
+                    id: 0,
+                    attempt: 0,
+                    sent_ms: now_ms,
+                });
+                self.send_query(w5500, now_ms)?;
+            }
+        }
+
+        while let Some((ip, port, length, truncated)) = (&mut *w5500, &self.socket)
+            .receive(&mut self.buffer)
+            .map_err(Error::Transfer)?
+        {
+            if port != SERVER_PORT || !self.servers.contains(&ip) || truncated {
+                continue;
+            }
+            let query = match &self.query {
+                Some(query) => query,
+                None => break,
+            };
+            match parse_response(&self.buffer[..length], query.id, &query.name) {
+                Some(Outcome::Address { ip, ttl }) => {
+                    debug!("DNS resolved {:?} with TTL {}", ip, ttl);
+                    self.insert(name, ip, ttl, now_ms);
+                    self.query = None;
+                    return Ok(ip);
+                }
+                Some(Outcome::NotFound) => {
+                    self.query = None;
+                    return Err(nb::Error::Other(Error::NotFound));
+                }
+                Some(Outcome::Failed) => {
+                    debug!("DNS server {:?} failed", ip);
+                    self.send_query(w5500, now_ms)?;
+                }
+                None => {}
+            }
+        }
+
+        if let Some(query) = &self.query {
+            if now_ms.saturating_sub(query.sent_ms) >= self.timeout_ms {
+                self.send_query(w5500, now_ms)?;
+            }
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Sends the query in flight to the next server, or gives up once all attempts are used
+    fn send_query<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let query = match &mut self.query {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        if query.attempt >= self.servers.len() * usize::from(self.attempts) {
+            self.query = None;
+            return Err(Error::Timeout);
+        }
+
+        query.id = (self.random.next_u32() >> 16) as u16;
+        query.sent_ms = now_ms;
+        let server = self.servers[query.attempt % self.servers.len()];
+        query.attempt += 1;
+
+        let length = build_query(&mut self.buffer, query.id, &query.name);
+        // a query the chip cannot take or deliver counts as lost, the timeout asks the next
+        // server
+        match (&mut *w5500, &self.socket).send(&server, SERVER_PORT, &self.buffer[..length]) {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(crate::Error::ArpTimeout)) => {
+                debug!("DNS query lost to an ARP timeout");
+                Ok(())
+            }
+            Err(nb::Error::Other(error)) => Err(Error::Transfer(error)),
+        }
+    }
+
+    fn cached(&self, name: &Name, now_ms: u64) -> Option<Ipv4Addr> {
+        self.cache
+            .iter()
+            .flatten()
+            .find(|entry| entry.expires_ms > now_ms && entry.name.matches(name))
+            .map(|entry| entry.ip)
+    }
+
+    /// Stores the address, replacing an entry for the same name, an expired entry or the
+    /// entry that expires first
+    fn insert(&mut self, name: Name, ip: Ipv4Addr, ttl: u32, now_ms: u64) {
+        if ttl == 0 {
+            return;
+        }
+        let entry = CacheEntry {
+            name,
+            ip,
+            expires_ms: now_ms + u64::from(ttl) * 1000,
+        };
+        let slot = self
+            .cache
+            .iter()
+            .position(|slot| match slot {
+                Some(cached) => cached.name.matches(&name) || cached.expires_ms <= now_ms,
+                None => true,
+            })
+            .unwrap_or_else(|| {
+                (0..CACHE_SIZE)
+                    .min_by_key(|index| self.cache[*index].map_or(0, |cached| cached.expires_ms))
+                    .unwrap_or(0)
+            });
+        self.cache[slot] = Some(entry);
+    }
+}
+
+/// Writes an A query for the name into the buffer and returns its length
+fn build_query(buffer: &mut [u8; MESSAGE_SIZE], id: u16, name: &Name) -> usize {
+    buffer[..HEADER_SIZE].iter_mut().for_each(|byte| *byte = 0);
+    BigEndian::write_u16(&mut buffer[0..2], id);
+    BigEndian::write_u16(&mut buffer[2..4], FLAG_RECURSION_DESIRED);
+    BigEndian::write_u16(&mut buffer[4..6], 1);
+
+    let mut position = HEADER_SIZE;
+    for label in name.as_bytes().split(|byte| *byte == b'.') {
+        buffer[position] = label.len() as u8;
+        buffer[position + 1..position + 1 + label.len()].copy_from_slice(label);
+        position += 1 + label.len();
+    }
+    buffer[position] = 0;
+    BigEndian::write_u16(&mut buffer[position + 1..position + 3], TYPE_A);
+    BigEndian::write_u16(&mut buffer[position + 3..position + 5], CLASS_IN);
+    position + 5
+}
+
+/// Reads the possibly compressed name at the offset and returns the offset behind it
+fn read_name(message: &[u8], offset: usize, name: &mut Name) -> Option<usize> {
+    *name = Name::EMPTY;
+    let mut position = offset;
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *message.get(position)?;
+        match length & 0xc0 {
+            0x00 if length == 0 => return Some(end.unwrap_or(position + 1)),
+            0x00 => {
+                let label = message.get(position + 1..position + 1 + usize::from(length))?;
+                name.push_label(label)?;
+                position += 1 + usize::from(length);
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                let low = *message.get(position + 1)?;
+                end = end.or(Some(position + 2));
+                position = usize::from(u16::from_be_bytes([length & 0x3f, low]));
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Parses a response, returns `None` if it does not belong to the query
+fn parse_response(message: &[u8], id: u16, name: &Name) -> Option<Outcome> {
+    let header = message.get(..HEADER_SIZE)?;
+    let flags = BigEndian::read_u16(&header[2..4]);
+    if BigEndian::read_u16(&header[0..2]) != id || flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let question_count = BigEndian::read_u16(&header[4..6]);
+    let answer_count = BigEndian::read_u16(&header[6..8]);
+
+    let mut owner = Name::EMPTY;
+    let mut position = HEADER_SIZE;
+    for _ in 0..question_count {
+        position = read_name(message, position, &mut owner)?;
+        if !owner.matches(name) {
+            return None;
+        }
+        position += 4;
+    }
+
+    match flags & RCODE_MASK {
+        0 if flags & FLAG_TRUNCATED == 0 => {}
+        RCODE_NAME_ERROR => return Some(Outcome::NotFound),
+        _ => return Some(Outcome::Failed),
+    }
+
+    let answers = position;
+    let mut target = *name;
+    let mut ttl = u32::MAX;
+    for _ in 0..=MAX_CNAME_CHAIN {
+        let mut alias = None;
+        let mut position = answers;
+        for _ in 0..answer_count {
+            position = match read_name(message, position, &mut owner) {
+                Some(position) => position,
+                None => return Some(Outcome::Failed),
+            };
+            let record = match message.get(position..position + 10) {
+                Some(record) => record,
+                None => return Some(Outcome::Failed),
+            };
+            let record_type = BigEndian::read_u16(&record[0..2]);
+            let record_class = BigEndian::read_u16(&record[2..4]);
+            let record_ttl = BigEndian::read_u32(&record[4..8]);
+            let data_length = usize::from(BigEndian::read_u16(&record[8..10]));
+            let data = position + 10;
+            position = data + data_length;
+            if position > message.len() {
+                return Some(Outcome::Failed);
+            }
+
+            if record_class != CLASS_IN || !owner.matches(&target) {
+                continue;
+            }
+            match record_type {
+                TYPE_A if data_length == 4 => {
+                    let mut ip = Ipv4Addr::default();
+                    ip.octets.copy_from_slice(&message[data..data + 4]);
+                    return Some(Outcome::Address {
+                        ip,
+                        ttl: ttl.min(record_ttl),
+                    });
+                }
+                TYPE_CNAME => {
+                    let mut canonical = Name::EMPTY;
+                    if read_name(message, data, &mut canonical).is_some() {
+                        alias = Some((canonical, record_ttl));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match alias {
+            Some((canonical, record_ttl)) => {
+                target = canonical;
+                ttl = ttl.min(record_ttl);
+            }
+            None => return Some(Outcome::NotFound),
+        }
+    }
+    Some(Outcome::Failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response to an A query for example.com with ID 0x1234, the answer points back to the
+    /// question name
+    const RESPONSE: [u8; 45] = [
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+        0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+        0x00, 0x01, 0x00, 0x01, // type A, class IN
+        0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, // answer
+        93, 184, 216, 34,
+    ];
+
+    /// Response to an A query for www.example.com, which is an alias of example.com. The A
+    /// record comes before the CNAME record.
+    const ALIASED_RESPONSE: [u8; 63] = [
+        0x56, 0x78, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, // header
+        0x03, b'w', b'w', b'w', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o',
+        b'm', 0x00, // name
+        0x00, 0x01, 0x00, 0x01, // type A, class IN
+        0xc0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, // answer
+        93, 184, 216, 34, //
+        0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x02, // answer
+        0xc0, 0x10,
+    ];
+
+    fn name(name: &str) -> Name {
+        Name::parse(name).unwrap()
+    }
+
+    fn assert_address(outcome: Option<Outcome>, expected_ip: Ipv4Addr, expected_ttl: u32) {
+        match outcome {
+            Some(Outcome::Address { ip, ttl }) => {
+                assert_eq!(ip, expected_ip);
+                assert_eq!(ttl, expected_ttl);
+            }
+            _ => panic!("no address"),
+        }
+    }
+
+    /// Writes an uncompressed name
+    fn write_name(buffer: &mut [u8], position: &mut usize, name: &str) {
+        for label in name.split('.') {
+            buffer[*position] = label.len() as u8;
+            buffer[*position + 1..*position + 1 + label.len()].copy_from_slice(label.as_bytes());
+            *position += 1 + label.len();
+        }
+        buffer[*position] = 0;
+        *position += 1;
+    }
+
+    /// Response to an A query for a0.test where each name up to the last one is an alias of
+    /// the next, and the last has an address
+    fn alias_chain(buffer: &mut [u8; MESSAGE_SIZE], aliases: usize) -> usize {
+        const NAMES: [&str; 10] = [
+            "a0.test", "a1.test", "a2.test", "a3.test", "a4.test", "a5.test", "a6.test", "a7.test",
+            "a8.test", "a9.test",
+        ];
+        buffer[..HEADER_SIZE].copy_from_slice(&[
+            0x00, 0x01, 0x81, 0x80, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        BigEndian::write_u16(&mut buffer[6..8], aliases as u16 + 1);
+        let mut position = HEADER_SIZE;
+        write_name(buffer, &mut position, NAMES[0]);
+        buffer[position..position + 4].copy_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        position += 4;
+        for (owner, alias) in NAMES.iter().zip(&NAMES[1..]).take(aliases) {
+            write_name(buffer, &mut position, owner);
+            buffer[position..position + 8].copy_from_slice(&[0, 5, 0, 1, 0, 0, 0, 60]);
+            BigEndian::write_u16(&mut buffer[position + 8..], alias.len() as u16 + 2);
+            position += 10;
+            write_name(buffer, &mut position, alias);
+        }
+        write_name(buffer, &mut position, NAMES[aliases]);
+        buffer[position..position + 14]
+            .copy_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+        position + 14
+    }
+
+    #[test]
+    fn follows_compression_pointer() {
+        let mut owner = Name::EMPTY;
+        assert_eq!(read_name(&RESPONSE, 29, &mut owner), Some(31));
+        assert_eq!(owner.as_bytes(), b"example.com");
+        assert_address(
+            parse_response(&RESPONSE, 0x1234, &name("example.com")),
+            Ipv4Addr::new(93, 184, 216, 34),
+            3600,
+        );
+    }
+
+    #[test]
+    fn rejects_pointer_loop() {
+        let mut message = [0u8; HEADER_SIZE + 2];
+        message[HEADER_SIZE..].copy_from_slice(&[0xc0, 0x0c]);
+        let mut owner = Name::EMPTY;
+        assert_eq!(read_name(&message, HEADER_SIZE, &mut owner), None);
+
+        // a chain of exactly MAX_POINTERS pointers still resolves
+        let mut message = [0u8; HEADER_SIZE + 2 * MAX_POINTERS + 1];
+        for pointer in 0..MAX_POINTERS {
+            let position = HEADER_SIZE + 2 * pointer;
+            message[position] = 0xc0;
+            message[position + 1] = (position + 2) as u8;
+        }
+        assert_eq!(
+            read_name(&message, HEADER_SIZE, &mut owner),
+            Some(HEADER_SIZE + 2)
+        );
+        message[HEADER_SIZE + 2 * MAX_POINTERS - 1] = HEADER_SIZE as u8;
+        assert_eq!(read_name(&message, HEADER_SIZE, &mut owner), None);
+    }
+
+    #[test]
+    fn follows_alias() {
+        assert_address(
+            parse_response(&ALIASED_RESPONSE, 0x5678, &name("www.example.com")),
+            Ipv4Addr::new(93, 184, 216, 34),
+            300,
+        );
+    }
+
+    #[test]
+    fn limits_alias_chain() {
+        let mut buffer = [0u8; MESSAGE_SIZE];
+        let length = alias_chain(&mut buffer, MAX_CNAME_CHAIN);
+        assert_address(
+            parse_response(&buffer[..length], 1, &name("a0.test")),
+            Ipv4Addr::new(10, 0, 0, 1),
+            60,
+        );
+        let length = alias_chain(&mut buffer, MAX_CNAME_CHAIN + 1);
+        assert!(matches!(
+            parse_response(&buffer[..length], 1, &name("a0.test")),
+            Some(Outcome::Failed)
+        ));
+    }
+
+    #[test]
+    fn reports_name_error() {
+        let mut message = RESPONSE;
+        message[3] = 0x83;
+        BigEndian::write_u16(&mut message[6..8], 0);
+        assert!(matches!(
+            parse_response(&message[..29], 0x1234, &name("example.com")),
+            Some(Outcome::NotFound)
+        ));
+    }
+
+    #[test]
+    fn fails_truncated_response() {
+        let mut message = RESPONSE;
+        message[2] |= (FLAG_TRUNCATED >> 8) as u8;
+        assert!(matches!(
+            parse_response(&message, 0x1234, &name("example.com")),
+            Some(Outcome::Failed)
+        ));
+    }
+
+    #[test]
+    fn ignores_other_queries() {
+        assert!(parse_response(&RESPONSE, 0x1235, &name("example.com")).is_none());
+        assert!(parse_response(&RESPONSE, 0x1234, &name("example.org")).is_none());
+        assert!(parse_response(&RESPONSE, 0x1234, &name("EXAMPLE.com")).is_some());
+
+        let mut query = [0u8; MESSAGE_SIZE];
+        let length = build_query(&mut query, 0x1234, &name("example.com"));
+        assert!(parse_response(&query[..length], 0x1234, &name("example.com")).is_none());
+    }
+}
@@ -0,0 +1,513 @@
+//! Implementation of the [`embassy-net-driver`] [`Driver`] trait.
+//!
+//! The driver consists of two halves that share a [`State`] with fixed size frame queues:
+//!
+//! * The [`Device`] implements [`Driver`] and is handed to `embassy-net`.
+//! * The [`Runner`] owns the chip and has to be run in a background task. It waits on the
+//!   INTn pin of the chip and moves the frames between the queues and
+//!   [`Socket::Socket0`](crate::Socket::Socket0) in MACRAW mode.
+//!
+//! The link state is taken from [`PhyCfg::link_up`](crate::PhyCfg::link_up). As the chip has
+//! no interrupt for link changes, the runner polls the PHY every [`LINK_POLL_INTERVAL_MS`]
+//! milliseconds while there is nothing else to do.
+//!
+//! [`embassy-net-driver`]: https://docs.rs/embassy-net-driver
+
+use core::cell::RefCell;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+
+use crate::{ActiveW5500, Interrupt, MacAddress, MacRaw, MacRawSocket, Register, SocketRegister};
+
+/// Maximum size of an Ethernet frame without the frame check sequence
+pub const MTU: usize = 1514;
+
+/// Interval in which the [`Runner`] polls the PHY for link changes
+pub const LINK_POLL_INTERVAL_MS: u32 = 500;
+
+/// Frames and wakers shared between the [`Device`] and the [`Runner`]. Holds up to `RX`
+/// received frames that have not been consumed by the stack yet and up to `TX` frames that
+/// have not been sent by the chip yet.
+pub struct State<const RX: usize, const TX: usize> {
+    shared: RefCell<Shared<RX, TX>>,
+}
+
+impl<const RX: usize, const TX: usize> State<RX, TX> {
+    /// Creates a new state with empty queues
+    pub const fn new() -> Self {
+        State {
+            shared: RefCell::new(Shared {
+                rx: Queue::new(),
+                tx: Queue::new(),
+                link_up: false,
+                rx_waker: WakerSlot::new(),
+                tx_waker: WakerSlot::new(),
+                link_waker: WakerSlot::new(),
+                runner_waker: WakerSlot::new(),
+                runner_signaled: false,
+            }),
+        }
+    }
+}
+
+impl<const RX: usize, const TX: usize> Default for State<RX, TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Shared<const RX: usize, const TX: usize> {
+    rx: Queue<RX>,
+    tx: Queue<TX>,
+    link_up: bool,
+    rx_waker: WakerSlot,
+    tx_waker: WakerSlot,
+    link_waker: WakerSlot,
+    runner_waker: WakerSlot,
+    runner_signaled: bool,
+}
+
+impl<const RX: usize, const TX: usize> Shared<RX, TX> {
+    /// Wakes the runner because a frame has been queued for sending or RX space was freed
+    fn signal_runner(&mut self) {
+        self.runner_signaled = true;
+        self.runner_waker.wake();
+    }
+}
+
+/// Ring of frame buffers
+struct Queue<const N: usize> {
+    frames: [[u8; MTU]; N],
+    lengths: [usize; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Queue<N> {
+    const fn new() -> Self {
+        Queue {
+            frames: [[0u8; MTU]; N],
+            lengths: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// The oldest frame in the queue
+    fn front(&mut self) -> Option<&mut [u8]> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(&mut self.frames[self.head][..self.lengths[self.head]])
+        }
+    }
+
+    fn pop(&mut self) {
+        if !self.is_empty() {
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+        }
+    }
+
+    /// The buffer behind the newest frame, which is only added to the queue by [`Queue::push`]
+    fn back(&mut self) -> &mut [u8; MTU] {
+        &mut self.frames[(self.head + self.len) % N]
+    }
+
+    fn push(&mut self, length: usize) {
+        if !self.is_full() {
+            self.lengths[(self.head + self.len) % N] = length;
+            self.len += 1;
+        }
+    }
+}
+
+struct WakerSlot(Option<Waker>);
+
+impl WakerSlot {
+    const fn new() -> Self {
+        WakerSlot(None)
+    }
+
+    fn register(&mut self, waker: &Waker) {
+        match &self.0 {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => self.0 = Some(waker.clone()),
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.0.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Creates the [`Device`] for `embassy-net` and the [`Runner`] that has to be run in a
+/// background task. `mac` has to be the MAC address the chip has been configured with.
+pub fn new<'d, const RX: usize, const TX: usize, ChipSelect, Spi, Int, Delay>(
+    state: &'d State<RX, TX>,
+    mac: MacAddress,
+    w5500: ActiveW5500<'d, 'd, ChipSelect, Spi>,
+    socket: MacRawSocket,
+    int: Int,
+    delay: Delay,
+) -> (
+    Device<'d, RX, TX>,
+    Runner<'d, RX, TX, ChipSelect, Spi, Int, Delay>,
+)
+where
+    ChipSelect: OutputPin,
+    Spi: FullDuplex<u8>,
+    Int: Wait,
+    Delay: DelayNs,
+{
+    (
+        Device { state, mac },
+        Runner {
+            state,
+            w5500,
+            socket,
+            int,
+            delay,
+        },
+    )
+}
+
+/// The [`Driver`] half, see the [module documentation](self)
+pub struct Device<'d, const RX: usize, const TX: usize> {
+    state: &'d State<RX, TX>,
+    mac: MacAddress,
+}
+
+impl<'d, const RX: usize, const TX: usize> Driver for Device<'d, RX, TX> {
+    type RxToken<'a>
+        = RxToken<'a, RX, TX>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a, RX, TX>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let mut shared = self.state.shared.borrow_mut();
+        if shared.rx.is_empty() {
+            shared.rx_waker.register(cx.waker());
+            return None;
+        }
+        if shared.tx.is_full() {
+            shared.tx_waker.register(cx.waker());
+            return None;
+        }
+        Some((RxToken(self.state), TxToken(self.state)))
+    }
+
+    fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        let mut shared = self.state.shared.borrow_mut();
+        if shared.tx.is_full() {
+            shared.tx_waker.register(cx.waker());
+            return None;
+        }
+        Some(TxToken(self.state))
+    }
+
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        let mut shared = self.state.shared.borrow_mut();
+        shared.link_waker.register(cx.waker());
+        if shared.link_up {
+            LinkState::Up
+        } else {
+            LinkState::Down
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::default();
+        capabilities.max_transmission_unit = MTU;
+        capabilities.max_burst_size = Some(1);
+        capabilities
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        HardwareAddress::Ethernet(self.mac.octets)
+    }
+}
+
+/// Received frame handed to `embassy-net`
+pub struct RxToken<'d, const RX: usize, const TX: usize>(&'d State<RX, TX>);
+
+impl<const RX: usize, const TX: usize> embassy_net_driver::RxToken for RxToken<'_, RX, TX> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        // the stack sends replies from within `f`, which needs the shared state as well
+        let mut frame = [0u8; MTU];
+        let length = match self.0.shared.borrow_mut().rx.front() {
+            Some(received) => {
+                frame[..received.len()].copy_from_slice(received);
+                received.len()
+            }
+            None => 0,
+        };
+        let result = f(&mut frame[..length]);
+        let mut shared = self.0.shared.borrow_mut();
+        shared.rx.pop();
+        shared.signal_runner();
+        result
+    }
+}
+
+/// Frame buffer handed to `embassy-net`, queued for the [`Runner`] once it has been filled
+pub struct TxToken<'d, const RX: usize, const TX: usize>(&'d State<RX, TX>);
+
+impl<const RX: usize, const TX: usize> embassy_net_driver::TxToken for TxToken<'_, RX, TX> {
+    fn consume<R, F>(self, length: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut shared = self.0.shared.borrow_mut();
+        let length = length.min(MTU);
+        let result = f(&mut shared.tx.back()[..length]);
+        shared.tx.push(length);
+        shared.signal_runner();
+        result
+    }
+}
+
+/// The half that owns the chip, see the [module documentation](self)
+pub struct Runner<'d, const RX: usize, const TX: usize, ChipSelect, Spi, Int, Delay>
+where
+    ChipSelect: OutputPin,
+    Spi: FullDuplex<u8>,
+{
+    state: &'d State<RX, TX>,
+    w5500: ActiveW5500<'d, 'd, ChipSelect, Spi>,
+    socket: MacRawSocket,
+    int: Int,
+    delay: Delay,
+}
+
+impl<'d, const RX: usize, const TX: usize, ChipSelect, Spi, Int, Delay>
+    Runner<'d, RX, TX, ChipSelect, Spi, Int, Delay>
+where
+    ChipSelect: OutputPin,
+    Spi: FullDuplex<u8>,
+    Int: Wait,
+    Delay: DelayNs,
+{
+    /// Moves frames between the chip and the [`Device`] until the task is dropped
+    pub async fn run(mut self) -> ! {
+        let socket = self.socket.0;
+        let mask = Interrupt::Received as u8 | Interrupt::SendOk as u8;
+        if self
+            .w5500
+            .write_u8(Register::CommonRegister(0x00_18_u16), 1 << socket.number())
+            .and_then(|_| {
+                self.w5500
+                    .write_u8(socket.at(SocketRegister::InterruptMask), mask)
+            })
+            .is_err()
+        {
+            debug!("failed to enable the socket interrupts");
+        }
+
+        loop {
+            // clear the interrupts first, so frames arriving from now on raise INTn again
+            if self
+                .w5500
+                .write_u8(socket.at(SocketRegister::Interrupt), mask)
+                .is_err()
+            {
+                debug!("failed to clear the socket interrupts");
+            }
+            self.update_link_state();
+            self.transmit_frames();
+            self.receive_frames();
+
+            let state = self.state;
+            let mut int = pin!(self.int.wait_for_low());
+            let mut poll_link = pin!(self.delay.delay_ms(LINK_POLL_INTERVAL_MS));
+            poll_fn(|cx| {
+                let mut shared = state.shared.borrow_mut();
+                if core::mem::take(&mut shared.runner_signaled) {
+                    return Poll::Ready(());
+                }
+                shared.runner_waker.register(cx.waker());
+                drop(shared);
+                if int.as_mut().poll(cx).is_ready() || poll_link.as_mut().poll(cx).is_ready() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+        }
+    }
+
+    fn update_link_state(&mut self) {
+        let link_up = match self.w5500.phy_cfg() {
+            Ok(phy_cfg) => phy_cfg.link_up(),
+            Err(_) => {
+                debug!("failed to read the PHY state");
+                return;
+            }
+        };
+        let mut shared = self.state.shared.borrow_mut();
+        if shared.link_up != link_up {
+            debug!("link up: {}", link_up);
+            shared.link_up = link_up;
+            shared.link_waker.wake();
+        }
+    }
+
+    fn transmit_frames(&mut self) {
+        let mut shared = self.state.shared.borrow_mut();
+        while let Some(frame) = shared.tx.front() {
+            match (&mut self.w5500, &self.socket).send_frame(frame) {
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => debug!("failed to send frame"),
+                Ok(()) => {}
+            }
+            shared.tx.pop();
+            shared.tx_waker.wake();
+        }
+    }
+
+    fn receive_frames(&mut self) {
+        let mut shared = self.state.shared.borrow_mut();
+        while !shared.rx.is_full() {
+            match (&mut self.w5500, &self.socket).receive_frame(shared.rx.back()) {
+                Ok(Some(length)) if length <= MTU => {
+                    shared.rx.push(length);
+                    shared.rx_waker.wake();
+                }
+                Ok(Some(length)) => debug!("dropped oversized frame of {} bytes", length),
+                Ok(None) => break,
+                Err(_) => {
+                    debug!("failed to receive frame");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "smoltcp"))]
+mod tests {
+    use super::*;
+    use ::smoltcp::iface::{Config, Interface, SocketSet, SocketStorage};
+    use ::smoltcp::phy::{self, DeviceCapabilities, Medium};
+    use ::smoltcp::time::Instant;
+    use ::smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
+    use embassy_net_driver::{RxToken as _, TxToken as _};
+
+    const MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x02];
+    const PEER_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+    /// Hands the [`Device`] to smoltcp the way `embassy-net` does
+    struct Adapter<'d>(Device<'d, 2, 2>);
+
+    struct AdapterRx<'d>(RxToken<'d, 2, 2>);
+    struct AdapterTx<'d>(TxToken<'d, 2, 2>);
+
+    impl phy::RxToken for AdapterRx<'_> {
+        fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+            self.0.consume(|frame| f(frame))
+        }
+    }
+
+    impl phy::TxToken for AdapterTx<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, length: usize, f: F) -> R {
+            self.0.consume(length, f)
+        }
+    }
+
+    impl<'d> phy::Device for Adapter<'d> {
+        type RxToken<'a>
+            = AdapterRx<'a>
+        where
+            Self: 'a;
+        type TxToken<'a>
+            = AdapterTx<'a>
+        where
+            Self: 'a;
+
+        fn receive(&mut self, _: Instant) -> Option<(AdapterRx<'_>, AdapterTx<'_>)> {
+            let mut cx = Context::from_waker(Waker::noop());
+            Driver::receive(&mut self.0, &mut cx).map(|(rx, tx)| (AdapterRx(rx), AdapterTx(tx)))
+        }
+
+        fn transmit(&mut self, _: Instant) -> Option<AdapterTx<'_>> {
+            let mut cx = Context::from_waker(Waker::noop());
+            Driver::transmit(&mut self.0, &mut cx).map(AdapterTx)
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut capabilities = DeviceCapabilities::default();
+            capabilities.medium = Medium::Ethernet;
+            capabilities.max_transmission_unit = MTU;
+            capabilities
+        }
+    }
+
+    #[test]
+    fn interface_replies_from_within_rx_token() {
+        let state = State::<2, 2>::new();
+        let mut device = Adapter(Device {
+            state: &state,
+            mac: MacAddress { octets: MAC },
+        });
+
+        let mut iface = Interface::new(
+            Config::new(EthernetAddress(MAC).into()),
+            &mut device,
+            Instant::ZERO,
+        );
+        iface.update_ip_addrs(|addresses| {
+            addresses
+                .push(IpCidr::new(IpAddress::v4(192, 168, 0, 2), 24))
+                .unwrap();
+        });
+        let mut storage = [SocketStorage::EMPTY; 1];
+        let mut sockets = SocketSet::new(&mut storage[..]);
+
+        // ARP request from 192.168.0.1 for 192.168.0.2
+        let mut request = [0u8; 42];
+        request[..6].copy_from_slice(&[0xFF; 6]);
+        request[6..12].copy_from_slice(&PEER_MAC);
+        request[12..22].copy_from_slice(&[0x08, 0x06, 0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01]);
+        request[22..28].copy_from_slice(&PEER_MAC);
+        request[28..32].copy_from_slice(&[192, 168, 0, 1]);
+        request[38..42].copy_from_slice(&[192, 168, 0, 2]);
+        {
+            let mut shared = state.shared.borrow_mut();
+            shared.rx.back()[..request.len()].copy_from_slice(&request);
+            shared.rx.push(request.len());
+        }
+
+        iface.poll(Instant::ZERO, &mut device, &mut sockets);
+
+        let mut shared = state.shared.borrow_mut();
+        assert!(shared.rx.is_empty());
+        let reply = shared.tx.front().expect("no ARP reply queued");
+        assert_eq!(&reply[..6], &PEER_MAC);
+        assert_eq!(&reply[20..22], &[0x00, 0x02]);
+        assert_eq!(&reply[28..32], &[192, 168, 0, 2]);
+    }
+}
@@ -0,0 +1,24 @@
+//! Logging macros that forward to `defmt` and `log`, depending on the enabled features, and
+//! compile to nothing otherwise. With both features enabled, every event goes to both.
+
+macro_rules! trace {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        defmt::trace!($s $(, $x)*);
+        #[cfg(feature = "log")]
+        log::trace!($s $(, $x)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$x),*);
+    }};
+}
+
+macro_rules! debug {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "defmt")]
+        defmt::debug!($s $(, $x)*);
+        #[cfg(feature = "log")]
+        log::debug!($s $(, $x)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$x),*);
+    }};
+}
@@ -0,0 +1,288 @@
+//! HTTP/1.1 on top of the hardware TCP sockets.
+//!
+//! The [`server`] module serves requests on one or more listening [`TcpSocket`]s, the
+//! [`client`] module sends requests over a connecting one. Messages are parsed in fixed-size
+//! buffers and written straight into the TX buffer of the socket, nothing is allocated.
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::clock::Clock;
+use crate::{ActiveW5500, SocketStatus, TcpSocket};
+
+pub mod client;
+pub mod server;
+
+/// Error returned while exchanging HTTP messages
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// The TCP connection has been closed before the message was complete
+    ConnectionClosed,
+    /// The TCP connection could not be established
+    ConnectionFailed,
+    /// The message is malformed or its head does not fit into the buffer
+    InvalidMessage,
+    /// The deadline passed before the message was complete
+    Timeout,
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// Request method
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+}
+
+impl Method {
+    /// The method as it appears in the request line
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+        }
+    }
+
+    fn parse(method: &[u8]) -> Option<Method> {
+        Some(match method {
+            b"GET" => Method::Get,
+            b"HEAD" => Method::Head,
+            b"POST" => Method::Post,
+            b"PUT" => Method::Put,
+            b"DELETE" => Method::Delete,
+            b"PATCH" => Method::Patch,
+            b"OPTIONS" => Method::Options,
+            _ => return None,
+        })
+    }
+}
+
+/// Header fields of a message, in the order they were received
+#[derive(Copy, Clone, Debug)]
+pub struct Headers<'h>(&'h str);
+
+impl<'h> Headers<'h> {
+    /// Returns the value of the first field with the given name, ignoring ASCII case
+    pub fn get(&self, name: &str) -> Option<&'h str> {
+        self.iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Iterates over the names and values of all fields
+    pub fn iter(&self) -> impl Iterator<Item = (&'h str, &'h str)> {
+        self.0.split("\r\n").filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim(), value.trim()))
+        })
+    }
+
+    /// Whether the comma-separated field contains the token, ignoring ASCII case
+    fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.iter()
+            .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// The value of the Content-Length field, `Err` if it is malformed
+    fn content_length(&self) -> Result<Option<usize>, ()> {
+        match self.get("Content-Length") {
+            Some(value) => value.parse().map(Some).map_err(|_| ()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Splits a message head into start line and header fields. Returns `None` until the head is
+/// complete, otherwise the length of the head including the empty line.
+fn split_head(buffer: &[u8]) -> Option<(&[u8], &[u8], usize)> {
+    let end = buffer.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = &buffer[..end];
+    let (start_line, fields) = match head.windows(2).position(|window| window == b"\r\n") {
+        Some(line_end) => (&head[..line_end], &head[line_end + 2..]),
+        None => (head, &head[head.len()..]),
+    };
+    Some((start_line, fields, end + 4))
+}
+
+/// The standard reason phrase of a status code
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Streams data into the TX buffer of a TCP socket. Data is only sent once the buffer is full
+/// or on [`TxWriter::flush`].
+struct TxWriter<'w, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> {
+    w5500: &'w mut ActiveW5500<'a, 'b, ChipSelect, Spi>,
+    socket: &'w TcpSocket,
+    /// bytes written behind the TX write pointer, not yet sent
+    queued: u16,
+    /// clock and deadline for sending, without one only the retransmission timeout of the chip
+    /// bounds the wait
+    deadline: Option<(&'w dyn Clock, u64)>,
+}
+
+impl<'w, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> TxWriter<'w, 'a, 'b, ChipSelect, Spi> {
+    fn new(w5500: &'w mut ActiveW5500<'a, 'b, ChipSelect, Spi>, socket: &'w TcpSocket) -> Self {
+        TxWriter {
+            w5500,
+            socket,
+            queued: 0,
+            deadline: None,
+        }
+    }
+
+    /// Fails with [`Error::Timeout`] once the deadline on the clock passes
+    fn with_deadline(mut self, clock: &'w dyn Clock, deadline_ms: u64) -> Self {
+        self.deadline = Some((clock, deadline_ms));
+        self
+    }
+
+    fn write(&mut self, mut data: &[u8]) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let socket = self.socket.0;
+        while !data.is_empty() {
+            let free_size = self
+                .w5500
+                .read_u16_stable(socket.at(crate::SocketRegister::TxFreeSize))?
+                .saturating_sub(self.queued);
+            if free_size == 0 {
+                if self.queued == 0 {
+                    self.check_connected()?;
+                }
+                self.flush()?;
+                continue;
+            }
+            let length = data.len().min(usize::from(free_size));
+            self.w5500
+                .queue_tx_buffer(socket, self.queued, &data[..length])?;
+            self.queued += length as u16;
+            data = &data[length..];
+        }
+        Ok(())
+    }
+
+    /// Sends the queued data and waits until it has been sent
+    fn flush(&mut self) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        self.wait_sent()?;
+        if self.queued > 0 {
+            self.check_connected()?;
+            self.w5500.commit_tx_buffer(self.socket.0, self.queued)?;
+            self.queued = 0;
+            self.wait_sent()?;
+        }
+        Ok(())
+    }
+
+    /// Waits until the chip has sent the committed data or the deadline passes
+    fn wait_sent(&mut self) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        loop {
+            match self.w5500.poll_send_complete(self.socket.0) {
+                Ok(()) => return Ok(()),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(error)) => return Err(error.into()),
+            }
+            if let Some((clock, deadline_ms)) = self.deadline {
+                if clock.now_ms() >= deadline_ms {
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+    }
+
+    fn check_connected(&mut self) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let status: SocketStatus = self
+            .w5500
+            .read_u8(self.socket.0.at(crate::SocketRegister::Status))?
+            .into();
+        match status {
+            SocketStatus::Established | SocketStatus::CloseWait => Ok(()),
+            _ => Err(Error::ConnectionClosed),
+        }
+    }
+}
+
+/// Adapter to format into a [`TxWriter`], keeping the first error
+struct Formatter<'f, 'w, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> {
+    writer: &'f mut TxWriter<'w, 'a, 'b, ChipSelect, Spi>,
+    error: Option<Error<Spi::Error, ChipSelect::Error>>,
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> core::fmt::Write
+    for Formatter<'_, '_, '_, '_, ChipSelect, Spi>
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.writer.write(s.as_bytes()).map_err(|error| {
+            self.error = Some(error);
+            core::fmt::Error
+        })
+    }
+}
+
+/// Writes formatted text into the [`TxWriter`]
+fn write_fmt<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+    writer: &mut TxWriter<'_, '_, '_, ChipSelect, Spi>,
+    arguments: core::fmt::Arguments,
+) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+    let mut formatter = Formatter {
+        writer,
+        error: None,
+    };
+    match core::fmt::write(&mut formatter, arguments) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(formatter.error.unwrap_or(Error::ConnectionClosed)),
+    }
+}
@@ -0,0 +1,675 @@
+//! HTTP/1.1 client.
+//!
+//! The [`HttpClient`] connects its TCP socket for every [`Request`], sends the request line,
+//! the header fields and the body, and parses the status line and header fields of the
+//! response into a buffer of [`RESPONSE_BUFFER_SIZE`] bytes. The body is then streamed into
+//! caller buffers with [`Response::read`], decoding Content-Length and chunked bodies as well
+//! as bodies that end with the connection.
+//!
+//! Both block until the data is there, but give up with [`Error::Timeout`] once the deadline on
+//! the [`Clock`] passes. [`Response::set_deadline`] extends it for long bodies.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::http::client::{HttpClient, Request};
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! let mut client = HttpClient::new(w5500.take_socket(Socket::Socket1).unwrap());
+//! let request = Request::post(Ipv4Addr::new(192, 168, 0, 10), 8080, "/api/readings")
+//!     .with_host("telemetry.example.com")
+//!     .with_headers(&[("Content-Type", "application/json")])
+//!     .with_body(br#"{"temperature":21}"#);
+//!
+//! let mut response = client
+//!     .request(&mut w5500, &request, &now_ms, now_ms() + 5_000)
+//!     .unwrap_or_else(|_| panic!("request failed"));
+//! assert_eq!(response.status(), 201);
+//!
+//! let mut body = [0u8; 256];
+//! let mut length = 0;
+//! loop {
+//!     match response.read(&mut body[length..]) {
+//!         Ok(0) => break,
+//!         Ok(read) => length += read,
+//!         Err(_) => panic!("failed to read the body"),
+//!     }
+//! }
+//! # }
+//! ```
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use super::{split_head, write_fmt, Error, Headers, Method, TxWriter};
+use crate::clock::Clock;
+use crate::{
+    ActiveW5500, Ipv4Addr, SocketCommand, SocketRegister, SocketStatus, Tcp, TcpSocket,
+    UninitializedSocket,
+};
+
+/// Size of the buffer the response head has to fit into. The rest of the buffer holds body
+/// data that has been received but not read yet.
+pub const RESPONSE_BUFFER_SIZE: usize = 1024;
+
+/// Space kept free behind the response head for body data
+const MIN_READ_AHEAD: usize = 64;
+
+/// Request to send with [`HttpClient::request`]
+#[derive(Copy, Clone, Debug)]
+pub struct Request<'r> {
+    method: Method,
+    address: Ipv4Addr,
+    port: u16,
+    host: Option<&'r str>,
+    path: &'r str,
+    headers: &'r [(&'r str, &'r str)],
+    body: &'r [u8],
+}
+
+impl<'r> Request<'r> {
+    /// Creates a request for the path on the server at the given address and port
+    pub fn new(method: Method, address: Ipv4Addr, port: u16, path: &'r str) -> Self {
+        Request {
+            method,
+            address,
+            port,
+            host: None,
+            path,
+            headers: &[],
+            body: &[],
+        }
+    }
+
+    /// Creates a GET request
+    pub fn get(address: Ipv4Addr, port: u16, path: &'r str) -> Self {
+        Request::new(Method::Get, address, port, path)
+    }
+
+    /// Creates a POST request
+    pub fn post(address: Ipv4Addr, port: u16, path: &'r str) -> Self {
+        Request::new(Method::Post, address, port, path)
+    }
+
+    /// Creates a PUT request
+    pub fn put(address: Ipv4Addr, port: u16, path: &'r str) -> Self {
+        Request::new(Method::Put, address, port, path)
+    }
+
+    /// Sets the Host field, which defaults to the address and port of the server
+    pub fn with_host(mut self, host: &'r str) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Adds header fields. Host, Content-Length and Connection are set by the client.
+    pub fn with_headers(mut self, headers: &'r [(&'r str, &'r str)]) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Sets the body, which is sent with a Content-Length field
+    pub fn with_body(mut self, body: &'r [u8]) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+/// HTTP/1.1 client, see the [module documentation](self)
+pub struct HttpClient {
+    socket: TcpSocket,
+    buffer: [u8; RESPONSE_BUFFER_SIZE],
+}
+
+impl HttpClient {
+    /// Creates a client that sends its requests over the socket
+    pub fn new(socket: UninitializedSocket) -> Self {
+        HttpClient {
+            socket: TcpSocket(socket.0),
+            buffer: [0u8; RESPONSE_BUFFER_SIZE],
+        }
+    }
+
+    /// Returns the socket, which may still be connected
+    pub fn release(self) -> TcpSocket {
+        self.socket
+    }
+
+    /// Connects to the server, sends the request and waits for the head of the response.
+    /// A connection that is left over from a previous request is closed first. Returns
+    /// [`Error::Timeout`] if the deadline on the clock passes first, which also applies to
+    /// reading the body.
+    pub fn request<'c, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>, C: Clock>(
+        &'c mut self,
+        w5500: &'c mut ActiveW5500<'a, 'b, ChipSelect, Spi>,
+        request: &Request<'_>,
+        clock: &'c C,
+        deadline_ms: u64,
+    ) -> Result<Response<'c, 'a, 'b, ChipSelect, Spi>, Error<Spi::Error, ChipSelect::Error>> {
+        let socket = self.socket.0;
+        if (&mut *w5500, &self.socket).status()? != SocketStatus::Closed {
+            w5500.write_u8(
+                socket.at(SocketRegister::Command),
+                SocketCommand::Close as u8,
+            )?;
+        }
+        let port = w5500.0.next_ephemeral_port();
+        w5500.open_tcp(socket, port)?;
+        (&mut *w5500, &self.socket).connect(&request.address, request.port)?;
+        loop {
+            match (&mut *w5500, &self.socket).status()? {
+                SocketStatus::Established => break,
+                SocketStatus::Closed => return Err(Error::ConnectionFailed),
+                _ if clock.now_ms() >= deadline_ms => return Err(Error::Timeout),
+                _ => {}
+            }
+        }
+        debug!("HTTP {} {}", request.method.as_str(), request.path);
+
+        let mut writer = TxWriter::new(w5500, &self.socket).with_deadline(clock, deadline_ms);
+        write_fmt(
+            &mut writer,
+            format_args!("{} {} HTTP/1.1\r\n", request.method.as_str(), request.path),
+        )?;
+        match request.host {
+            Some(host) => write_fmt(&mut writer, format_args!("Host: {}\r\n", host))?,
+            None => {
+                let octets = request.address.octets;
+                write_fmt(
+                    &mut writer,
+                    format_args!(
+                        "Host: {}.{}.{}.{}:{}\r\n",
+                        octets[0], octets[1], octets[2], octets[3], request.port
+                    ),
+                )?
+            }
+        }
+        for (name, value) in request.headers {
+            write_fmt(&mut writer, format_args!("{}: {}\r\n", name, value))?;
+        }
+        if !request.body.is_empty() || matches!(request.method, Method::Post | Method::Put) {
+            write_fmt(
+                &mut writer,
+                format_args!("Content-Length: {}\r\n", request.body.len()),
+            )?;
+        }
+        writer.write(b"Connection: close\r\n\r\n")?;
+        writer.write(request.body)?;
+        writer.flush()?;
+
+        let mut response = Response {
+            w5500,
+            socket: &self.socket,
+            buffer: &mut self.buffer,
+            status: 0,
+            head_length: 0,
+            start: 0,
+            end: 0,
+            body: Body::Done,
+            disconnected: false,
+            clock,
+            deadline_ms,
+        };
+        response.read_head(request.method == Method::Head)?;
+        Ok(response)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Body {
+    /// Content-Length with the number of bytes still to read
+    Length(usize),
+    Chunked(Chunk),
+    /// The body ends when the server closes the connection
+    UntilClose,
+    Done,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Chunk {
+    /// Expecting the chunk size line
+    Size,
+    /// Number of bytes left in the current chunk
+    Data(usize),
+    /// Expecting the line break behind the chunk data
+    DataEnd,
+    /// Expecting trailer fields or the final empty line
+    Trailer,
+}
+
+/// Response to a [`Request`], its body is read from the socket with [`Response::read`]
+pub struct Response<'c, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> {
+    w5500: &'c mut ActiveW5500<'a, 'b, ChipSelect, Spi>,
+    socket: &'c TcpSocket,
+    buffer: &'c mut [u8; RESPONSE_BUFFER_SIZE],
+    status: u16,
+    head_length: usize,
+    /// range of received body data in the buffer that has not been read yet
+    start: usize,
+    end: usize,
+    body: Body,
+    disconnected: bool,
+    clock: &'c dyn Clock,
+    deadline_ms: u64,
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> Response<'_, '_, '_, ChipSelect, Spi> {
+    /// The status code
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The header fields
+    pub fn headers(&self) -> Headers<'_> {
+        match split_head(&self.buffer[..self.head_length]) {
+            Some((_, fields, _)) => Headers(core::str::from_utf8(fields).unwrap_or("")),
+            None => Headers(""),
+        }
+    }
+
+    /// Whether the whole body has been read
+    pub fn is_complete(&self) -> bool {
+        self.body == Body::Done
+    }
+
+    /// Sets the deadline on the clock of the request that reading the body fails at
+    pub fn set_deadline(&mut self, deadline_ms: u64) {
+        self.deadline_ms = deadline_ms;
+    }
+
+    /// Reads body data into `destination` and returns the number of bytes read, `0` once the
+    /// body is complete. Blocks until data has been received or the deadline passes. The
+    /// connection is closed when the body is complete.
+    pub fn read(
+        &mut self,
+        destination: &mut [u8],
+    ) -> Result<usize, Error<Spi::Error, ChipSelect::Error>> {
+        if destination.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.body {
+                Body::Done => {
+                    self.disconnect()?;
+                    return Ok(0);
+                }
+                Body::Length(0) => self.body = Body::Done,
+                Body::Length(remaining) => {
+                    let length = destination.len().min(remaining);
+                    let read = self.read_data(&mut destination[..length])?;
+                    if read == 0 {
+                        return Err(Error::ConnectionClosed);
+                    }
+                    self.body = Body::Length(remaining - read);
+                    return Ok(read);
+                }
+                Body::UntilClose => {
+                    let read = self.read_data(destination)?;
+                    if read == 0 {
+                        self.body = Body::Done;
+                    } else {
+                        return Ok(read);
+                    }
+                }
+                Body::Chunked(Chunk::Size) => {
+                    self.body = match self.read_line()? {
+                        Some(0) => Body::Chunked(Chunk::Trailer),
+                        Some(size) => Body::Chunked(Chunk::Data(size)),
+                        None => return Err(Error::InvalidMessage),
+                    };
+                }
+                Body::Chunked(Chunk::Data(remaining)) => {
+                    let length = destination.len().min(remaining);
+                    let read = self.read_data(&mut destination[..length])?;
+                    if read == 0 {
+                        return Err(Error::ConnectionClosed);
+                    }
+                    self.body = Body::Chunked(match remaining - read {
+                        0 => Chunk::DataEnd,
+                        remaining => Chunk::Data(remaining),
+                    });
+                    return Ok(read);
+                }
+                Body::Chunked(Chunk::DataEnd) => {
+                    self.body = match self.read_line()? {
+                        None => Body::Chunked(Chunk::Size),
+                        Some(_) => return Err(Error::InvalidMessage),
+                    };
+                }
+                Body::Chunked(Chunk::Trailer) => {
+                    if self.read_line_end()? {
+                        self.body = Body::Done;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Receives until the head is complete and skips interim responses
+    fn read_head(
+        &mut self,
+        head_request: bool,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        loop {
+            let (start_line, fields, head_length) = loop {
+                if let Some(head) = split_head(&self.buffer[..self.end]) {
+                    break head;
+                }
+                if self.end >= RESPONSE_BUFFER_SIZE - MIN_READ_AHEAD {
+                    return Err(Error::InvalidMessage);
+                }
+                let received = self.receive(self.end)?;
+                if received == 0 {
+                    return Err(Error::ConnectionClosed);
+                }
+                self.end += received;
+            };
+
+            let start_line = core::str::from_utf8(start_line).map_err(|_| Error::InvalidMessage)?;
+            let fields = core::str::from_utf8(fields).map_err(|_| Error::InvalidMessage)?;
+            let mut parts = start_line.splitn(3, ' ');
+            let (version, status) = match (parts.next(), parts.next()) {
+                (Some(version), Some(status)) => (version, status),
+                _ => return Err(Error::InvalidMessage),
+            };
+            if !version.starts_with("HTTP/1.") {
+                return Err(Error::InvalidMessage);
+            }
+            let status: u16 = status.parse().map_err(|_| Error::InvalidMessage)?;
+
+            if (100..200).contains(&status) && status != 101 {
+                // interim response such as 100 Continue, the final one follows
+                self.buffer.copy_within(head_length..self.end, 0);
+                self.end -= head_length;
+                continue;
+            }
+
+            let headers = Headers(fields);
+            let body = if head_request || status == 204 || status == 304 {
+                Body::Done
+            } else if headers.contains_token("Transfer-Encoding", "chunked") {
+                Body::Chunked(Chunk::Size)
+            } else {
+                match headers.content_length() {
+                    Ok(Some(length)) => Body::Length(length),
+                    Ok(None) => Body::UntilClose,
+                    Err(()) => return Err(Error::InvalidMessage),
+                }
+            };
+            debug!("HTTP response {}", status);
+
+            self.status = status;
+            self.head_length = head_length;
+            self.start = head_length;
+            self.body = body;
+            return Ok(());
+        }
+    }
+
+    /// Reads body data from the buffer, refilling it from the socket if it is empty. Returns
+    /// `0` once the connection is closed.
+    fn read_data(
+        &mut self,
+        destination: &mut [u8],
+    ) -> Result<usize, Error<Spi::Error, ChipSelect::Error>> {
+        if self.start == self.end && !self.fill()? {
+            return Ok(0);
+        }
+        let length = destination.len().min(self.end - self.start);
+        destination[..length].copy_from_slice(&self.buffer[self.start..self.start + length]);
+        self.start += length;
+        Ok(length)
+    }
+
+    /// Reads a line of a chunked body and returns the hexadecimal number it starts with, or
+    /// `None` for an empty line
+    fn read_line(&mut self) -> Result<Option<usize>, Error<Spi::Error, ChipSelect::Error>> {
+        let mut value: Option<usize> = None;
+        let mut digits = true;
+        loop {
+            let byte = self.read_byte()?;
+            match byte {
+                b'\n' => return Ok(value),
+                b'\r' => digits = false,
+                _ if digits => match (byte as char).to_digit(16) {
+                    Some(digit) => {
+                        value = Some(
+                            value
+                                .unwrap_or(0)
+                                .checked_mul(16)
+                                .and_then(|value| value.checked_add(digit as usize))
+                                .ok_or(Error::InvalidMessage)?,
+                        );
+                    }
+                    // chunk extensions and trailer fields are ignored
+                    None => {
+                        digits = false;
+                        value = value.or(Some(0));
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Skips a trailer line and returns whether it was the final empty line
+    fn read_line_end(&mut self) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+        let mut empty = true;
+        loop {
+            match self.read_byte()? {
+                b'\n' => return Ok(empty),
+                b'\r' => {}
+                _ => empty = false,
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error<Spi::Error, ChipSelect::Error>> {
+        let mut byte = [0u8];
+        match self.read_data(&mut byte)? {
+            0 => Err(Error::ConnectionClosed),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    /// Refills the buffer behind the head from the socket, returns `false` once the connection
+    /// is closed
+    fn fill(&mut self) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+        self.start = self.head_length;
+        self.end = self.head_length;
+        let received = self.receive(self.head_length)?;
+        self.end += received;
+        Ok(received > 0)
+    }
+
+    /// Receives into the buffer from `offset` on, blocks until data has been received or the
+    /// deadline passes and returns `0` once the connection is closed
+    fn receive(&mut self, offset: usize) -> Result<usize, Error<Spi::Error, ChipSelect::Error>> {
+        loop {
+            match (&mut *self.w5500, self.socket).receive(&mut self.buffer[offset..]) {
+                Ok(length) => return Ok(length),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(error)) => return Err(error.into()),
+            }
+            if (&mut *self.w5500, self.socket).status()? != SocketStatus::Established {
+                // data may have arrived right before the server closed the connection
+                return match (&mut *self.w5500, self.socket).receive(&mut self.buffer[offset..]) {
+                    Ok(length) => Ok(length),
+                    Err(nb::Error::WouldBlock) => Ok(0),
+                    Err(nb::Error::Other(error)) => Err(error.into()),
+                };
+            }
+            if self.clock.now_ms() >= self.deadline_ms {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    fn disconnect(&mut self) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if !self.disconnected {
+            self.disconnected = true;
+            (&mut *self.w5500, self.socket).disconnect()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::{Replay, ReplayChipSelect, ReplaySpi};
+    use crate::{ArpResponses, ConnectionType, OnPingRequest, OnWakeOnLan, Socket, W5500};
+
+    /// Checks the chip version, resets the chip and configures the mode register
+    const INITIALISATION: &str = "R 00 0039 04\nW 04 0000 80\nW 04 0000 00\n";
+    const SOCKET: TcpSocket = TcpSocket(Socket::Socket1);
+
+    type ReplayResponse<'c, 'a, 'b, 'r, 'l> =
+        Response<'c, 'a, 'b, ReplayChipSelect<'r, 'l>, ReplaySpi<'r, 'l>>;
+    type ReplayError = Error<crate::replay::ReplayError, crate::replay::ReplayError>;
+
+    /// Runs the test on a response whose bytes have all been received already, so the socket
+    /// is never read
+    fn with_response(data: &[u8], test: impl FnOnce(&mut ReplayResponse<'_, '_, '_, '_, '_>)) {
+        let replay = Replay::new(INITIALISATION);
+        let mut spi = replay.spi();
+        let mut w5500 = W5500::with_initialisation(
+            replay.chip_select(),
+            &mut spi,
+            OnWakeOnLan::Ignore,
+            OnPingRequest::Respond,
+            ConnectionType::Ethernet,
+            ArpResponses::Cache,
+        )
+        .unwrap();
+        let mut w5500 = w5500.activate(&mut spi).unwrap();
+        let mut buffer = [0u8; RESPONSE_BUFFER_SIZE];
+        buffer[..data.len()].copy_from_slice(data);
+        let clock = || 0;
+        let mut response = Response {
+            w5500: &mut w5500,
+            socket: &SOCKET,
+            buffer: &mut buffer,
+            status: 0,
+            head_length: 0,
+            start: 0,
+            end: data.len(),
+            body: Body::Done,
+            disconnected: true,
+            clock: &clock,
+            deadline_ms: u64::MAX,
+        };
+        test(&mut response);
+        replay.finish().unwrap();
+    }
+
+    /// Reads the body in small pieces and returns its length
+    fn read_body(
+        response: &mut ReplayResponse<'_, '_, '_, '_, '_>,
+        body: &mut [u8],
+    ) -> Result<usize, ReplayError> {
+        let mut length = 0;
+        loop {
+            let end = body.len().min(length + 3);
+            match response.read(&mut body[length..end])? {
+                0 => return Ok(length),
+                read => length += read,
+            }
+        }
+    }
+
+    #[test]
+    fn reads_content_length_body() {
+        with_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world",
+            |response| {
+                response.read_head(false).unwrap();
+                assert_eq!(response.status(), 200);
+                assert_eq!(response.headers().get("content-length"), Some("11"));
+                let mut body = [0u8; 16];
+                assert_eq!(read_body(response, &mut body).ok(), Some(11));
+                assert_eq!(&body[..11], b"hello world");
+                assert!(response.is_complete());
+            },
+        );
+    }
+
+    #[test]
+    fn skips_interim_response() {
+        with_response(
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok",
+            |response| {
+                response.read_head(false).unwrap();
+                assert_eq!(response.status(), 201);
+                let mut body = [0u8; 4];
+                assert_eq!(read_body(response, &mut body).ok(), Some(2));
+                assert_eq!(&body[..2], b"ok");
+            },
+        );
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        with_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4;name=value\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n\
+              0\r\nExpires: never\r\n\r\n",
+            |response| {
+                response.read_head(false).unwrap();
+                let mut body = [0u8; 32];
+                assert_eq!(read_body(response, &mut body).ok(), Some(23));
+                assert_eq!(&body[..23], b"Wikipedia in\r\n\r\nchunks.");
+                assert!(response.is_complete());
+            },
+        );
+    }
+
+    #[test]
+    fn rejects_missing_line_break_after_chunk() {
+        with_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWikipedia\r\n0\r\n\r\n",
+            |response| {
+                response.read_head(false).unwrap();
+                let mut body = [0u8; 32];
+                assert!(matches!(
+                    read_body(response, &mut body),
+                    Err(Error::InvalidMessage)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_chunk() {
+        with_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              10000000000000000000000000000000\r\n",
+            |response| {
+                response.read_head(false).unwrap();
+                let mut body = [0u8; 32];
+                assert!(matches!(
+                    read_body(response, &mut body),
+                    Err(Error::InvalidMessage)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_status_line() {
+        with_response(b"HTTP/2 200 OK\r\n\r\n", |response| {
+            assert!(matches!(
+                response.read_head(false),
+                Err(Error::InvalidMessage)
+            ));
+        });
+        with_response(b"HTTP/1.1 2xx OK\r\n\r\n", |response| {
+            assert!(matches!(
+                response.read_head(false),
+                Err(Error::InvalidMessage)
+            ));
+        });
+    }
+}
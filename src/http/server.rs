@@ -0,0 +1,642 @@
+//! HTTP/1.1 server.
+//!
+//! The [`HttpServer`] listens on one [`Connection`] per hardware socket, so it serves as many
+//! clients at the same time as sockets are handed to it. Every connection parses the request
+//! line, the header fields and a Content-Length body into a buffer of
+//! [`REQUEST_BUFFER_SIZE`] bytes and routes the request by method and path to a [`Handler`].
+//! The handler writes the [`Response`] straight into the TX buffer of the socket. Connections
+//! are kept alive unless the client asks to close them or the response has no known length
+//! and cannot be chunked.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use embedded_hal::digital::v2::OutputPin;
+//! use embedded_hal::spi::FullDuplex;
+//! use w5500::http::server::{Connection, Handler, HttpServer, Request, Response, Route};
+//! use w5500::http::{Error, Method};
+//! use w5500::Socket;
+//!
+//! enum Page {
+//!     Index,
+//!     Status,
+//! }
+//!
+//! struct App {
+//!     temperature: i32,
+//! }
+//!
+//! impl Handler<Page> for App {
+//!     fn handle<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+//!         &mut self,
+//!         page: &Page,
+//!         _request: &Request,
+//!         response: &mut Response<ChipSelect, Spi>,
+//!     ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+//!         match page {
+//!             Page::Index => response.send(200, "text/html", b"<h1>Sensor 42</h1>"),
+//!             Page::Status => {
+//!                 response.start(200, &[("Content-Type", "application/json")], None)?;
+//!                 write!(response, "{{\"temperature\":{}}}", self.temperature)
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let routes = [
+//!     Route { method: Method::Get, path: "/", target: Page::Index },
+//!     Route { method: Method::Get, path: "/status", target: Page::Status },
+//! ];
+//! let mut connections = [
+//!     Connection::new(w5500.take_socket(Socket::Socket2).unwrap()),
+//!     Connection::new(w5500.take_socket(Socket::Socket3).unwrap()),
+//! ];
+//! let mut server = HttpServer::new(80, &routes, &mut connections);
+//! let mut app = App { temperature: 21 };
+//!
+//! loop {
+//!     let _ = server.poll(&mut w5500, &mut app, now_ms());
+//! }
+//! # }
+//! ```
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use super::{reason, split_head, write_fmt, Error, Headers, Method, TxWriter};
+use crate::{ActiveW5500, SocketStatus, Tcp, TcpSocket, UninitializedSocket};
+
+/// Size of the buffer a request head and body have to fit into
+pub const REQUEST_BUFFER_SIZE: usize = 1024;
+
+/// Time after which an idle or incomplete connection is closed
+const IDLE_TIMEOUT_MS: u64 = 10_000;
+
+/// Maps requests with the given method and path to a target the [`Handler`] understands.
+/// `HEAD` requests are served by the `GET` route of the path.
+#[derive(Copy, Clone, Debug)]
+pub struct Route<'r, T> {
+    /// The method of the request
+    pub method: Method,
+    /// The path of the request, without query
+    pub path: &'r str,
+    /// Passed to [`Handler::handle`]
+    pub target: T,
+}
+
+/// Handles routed requests
+pub trait Handler<T> {
+    /// Answers the request for the route target. A response that has not been started when
+    /// this returns is answered with `500 Internal Server Error`.
+    fn handle<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        target: &T,
+        request: &Request<'_>,
+        response: &mut Response<'_, '_, '_, ChipSelect, Spi>,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>>;
+}
+
+/// A received request, borrowed from the buffer of the [`Connection`]
+#[derive(Copy, Clone, Debug)]
+pub struct Request<'r> {
+    /// The request method
+    pub method: Method,
+    /// The path of the request target
+    pub path: &'r str,
+    /// The query of the request target, without `?`
+    pub query: Option<&'r str>,
+    /// The header fields
+    pub headers: Headers<'r>,
+    /// The body, as long as the Content-Length field says
+    pub body: &'r [u8],
+    http10: bool,
+    keep_alive: bool,
+}
+
+enum Framing {
+    NotStarted,
+    /// Content-Length with the number of bytes still to write
+    Length(usize),
+    Chunked,
+    /// The end of the body is signalled by closing the connection
+    Close,
+}
+
+/// Response that is streamed into the TX buffer of the socket
+pub struct Response<'w, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> {
+    writer: TxWriter<'w, 'a, 'b, ChipSelect, Spi>,
+    framing: Framing,
+    keep_alive: bool,
+    head_only: bool,
+    http10: bool,
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> Response<'_, '_, '_, ChipSelect, Spi> {
+    /// Writes the status line and header fields. With a `content_length` the body has to be
+    /// exactly that long, without one the body is chunked or ends with the connection.
+    /// Does nothing if the response has already been started.
+    pub fn start(
+        &mut self,
+        status: u16,
+        headers: &[(&str, &str)],
+        content_length: Option<usize>,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if !matches!(self.framing, Framing::NotStarted) {
+            return Ok(());
+        }
+        self.framing = match content_length {
+            Some(length) => Framing::Length(length),
+            None if self.keep_alive && !self.http10 => Framing::Chunked,
+            None => {
+                self.keep_alive = false;
+                Framing::Close
+            }
+        };
+
+        let writer = &mut self.writer;
+        write_fmt(
+            writer,
+            format_args!("HTTP/1.1 {} {}\r\n", status, reason(status)),
+        )?;
+        for (name, value) in headers {
+            write_fmt(writer, format_args!("{}: {}\r\n", name, value))?;
+        }
+        match content_length {
+            Some(length) => write_fmt(writer, format_args!("Content-Length: {}\r\n", length))?,
+            None if self.keep_alive => writer.write(b"Transfer-Encoding: chunked\r\n")?,
+            None => {}
+        }
+        if self.keep_alive {
+            writer.write(b"Connection: keep-alive\r\n\r\n")
+        } else {
+            writer.write(b"Connection: close\r\n\r\n")
+        }
+    }
+
+    /// Writes a complete response with a Content-Type field and the given body
+    pub fn send(
+        &mut self,
+        status: u16,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        self.start(status, &[("Content-Type", content_type)], Some(body.len()))?;
+        self.write(body)
+    }
+
+    /// Writes part of the body, starting a `200 OK` response without length if needed. Data
+    /// beyond the announced Content-Length is dropped.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if let Framing::NotStarted = self.framing {
+            self.start(200, &[], None)?;
+        }
+        if self.head_only {
+            return Ok(());
+        }
+        match &mut self.framing {
+            Framing::Length(remaining) => {
+                let length = data.len().min(*remaining);
+                *remaining -= length;
+                self.writer.write(&data[..length])
+            }
+            Framing::Chunked if data.is_empty() => Ok(()),
+            Framing::Chunked => {
+                write_fmt(&mut self.writer, format_args!("{:x}\r\n", data.len()))?;
+                self.writer.write(data)?;
+                self.writer.write(b"\r\n")
+            }
+            _ => self.writer.write(data),
+        }
+    }
+
+    /// Writes formatted text into the body, allows to use [`write!`] on the response
+    pub fn write_fmt(
+        &mut self,
+        arguments: core::fmt::Arguments,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let mut formatter = BodyFormatter {
+            response: self,
+            error: None,
+        };
+        match core::fmt::write(&mut formatter, arguments) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(formatter.error.unwrap_or(Error::ConnectionClosed)),
+        }
+    }
+
+    /// Completes the body and sends everything that is still queued
+    fn finish(&mut self) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        match self.framing {
+            Framing::NotStarted => self.start(500, &[], Some(0))?,
+            Framing::Length(remaining) if remaining > 0 => self.keep_alive = false,
+            Framing::Chunked if !self.head_only => self.writer.write(b"0\r\n\r\n")?,
+            _ => {}
+        }
+        self.writer.flush()
+    }
+}
+
+struct BodyFormatter<'r, 'w, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> {
+    response: &'r mut Response<'w, 'a, 'b, ChipSelect, Spi>,
+    error: Option<Error<Spi::Error, ChipSelect::Error>>,
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> core::fmt::Write
+    for BodyFormatter<'_, '_, '_, '_, ChipSelect, Spi>
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.response.write(s.as_bytes()).map_err(|error| {
+            self.error = Some(error);
+            core::fmt::Error
+        })
+    }
+}
+
+/// A socket the server listens on, together with its request buffer
+pub struct Connection {
+    socket: TcpSocket,
+    buffer: [u8; REQUEST_BUFFER_SIZE],
+    filled: usize,
+    connected: bool,
+    continued: bool,
+    last_activity_ms: u64,
+}
+
+impl Connection {
+    /// Creates a connection on the socket, it is opened by [`HttpServer::poll`]
+    pub fn new(socket: UninitializedSocket) -> Self {
+        Connection {
+            socket: TcpSocket(socket.0),
+            buffer: [0u8; REQUEST_BUFFER_SIZE],
+            filled: 0,
+            connected: false,
+            continued: false,
+            last_activity_ms: 0,
+        }
+    }
+
+    /// Returns the socket, which may still be connected
+    pub fn release(self) -> TcpSocket {
+        self.socket
+    }
+}
+
+/// Result of parsing the buffer of a connection
+enum Parsed<'r> {
+    Incomplete { expects_continue: bool },
+    Complete { request: Request<'r>, length: usize },
+    Invalid(u16),
+}
+
+/// HTTP/1.1 server, see the [module documentation](self)
+pub struct HttpServer<'c, 'r, T> {
+    port: u16,
+    routes: &'r [Route<'r, T>],
+    connections: &'c mut [Connection],
+}
+
+impl<'c, 'r, T> HttpServer<'c, 'r, T> {
+    /// Creates a server that listens on the port with all given connections
+    pub fn new(port: u16, routes: &'r [Route<'r, T>], connections: &'c mut [Connection]) -> Self {
+        HttpServer {
+            port,
+            routes,
+            connections,
+        }
+    }
+
+    /// Reopens closed connections, receives requests and passes complete ones to the handler.
+    /// Connections that fail or time out are closed, only failing to communicate with the chip
+    /// is returned as error.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>, H: Handler<T>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        handler: &mut H,
+        now_ms: u64,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        for connection in self.connections.iter_mut() {
+            match poll_connection(w5500, self.port, self.routes, handler, connection, now_ms) {
+                Ok(()) => {}
+                Err(Error::Transfer(error)) => return Err(error),
+                Err(_) => {
+                    debug!("HTTP connection on {:?} closed", connection.socket.0);
+                    connection.filled = 0;
+                    connection.connected = false;
+                    (&mut *w5500, &connection.socket).disconnect()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn poll_connection<ChipSelect: OutputPin, Spi: FullDuplex<u8>, T, H: Handler<T>>(
+    w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    port: u16,
+    routes: &[Route<'_, T>],
+    handler: &mut H,
+    connection: &mut Connection,
+    now_ms: u64,
+) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+    let Connection {
+        socket,
+        buffer,
+        filled,
+        connected,
+        continued,
+        last_activity_ms,
+    } = connection;
+
+    let status = (&mut *w5500, &*socket).status()?;
+    match status {
+        SocketStatus::Closed => {
+            w5500.open_tcp(socket.0, port)?;
+            (&mut *w5500, &*socket).listen()?;
+            *filled = 0;
+            *connected = false;
+            return Ok(());
+        }
+        SocketStatus::Init => {
+            (&mut *w5500, &*socket).listen()?;
+            return Ok(());
+        }
+        SocketStatus::Established | SocketStatus::CloseWait if *connected => {}
+        SocketStatus::Established | SocketStatus::CloseWait => {
+            *connected = true;
+            *continued = false;
+            *last_activity_ms = now_ms;
+        }
+        _ => return Ok(()),
+    }
+
+    if *filled < REQUEST_BUFFER_SIZE {
+        match (&mut *w5500, &*socket).receive(&mut buffer[*filled..]) {
+            Ok(length) => {
+                *filled += length;
+                *last_activity_ms = now_ms;
+            }
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(error)) => return Err(error.into()),
+        }
+    }
+
+    loop {
+        match parse(&buffer[..*filled]) {
+            Parsed::Incomplete { expects_continue } => {
+                if status == SocketStatus::CloseWait
+                    || now_ms.saturating_sub(*last_activity_ms) >= IDLE_TIMEOUT_MS
+                {
+                    return Err(Error::ConnectionClosed);
+                }
+                if expects_continue && !*continued {
+                    *continued = true;
+                    let mut writer = TxWriter::new(w5500, socket);
+                    writer.write(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                    writer.flush()?;
+                }
+                return Ok(());
+            }
+            Parsed::Invalid(status) => {
+                debug!("HTTP invalid request, {}", status);
+                let mut response = Response {
+                    writer: TxWriter::new(w5500, socket),
+                    framing: Framing::NotStarted,
+                    keep_alive: false,
+                    head_only: false,
+                    http10: false,
+                };
+                response.start(status, &[], Some(0))?;
+                response.finish()?;
+                return Err(Error::ConnectionClosed);
+            }
+            Parsed::Complete { request, length } => {
+                let keep_alive = dispatch(w5500, socket, routes, handler, &request)?;
+                buffer.copy_within(length..*filled, 0);
+                *filled -= length;
+                *continued = false;
+                *last_activity_ms = now_ms;
+                if !keep_alive {
+                    return Err(Error::ConnectionClosed);
+                }
+            }
+        }
+    }
+}
+
+/// Routes the request to the handler and returns whether the connection is kept alive
+fn dispatch<ChipSelect: OutputPin, Spi: FullDuplex<u8>, T, H: Handler<T>>(
+    w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    socket: &TcpSocket,
+    routes: &[Route<'_, T>],
+    handler: &mut H,
+    request: &Request<'_>,
+) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+    debug!("HTTP {} {}", request.method.as_str(), request.path);
+    let head_only = request.method == Method::Head;
+    let mut response = Response {
+        writer: TxWriter::new(w5500, socket),
+        framing: Framing::NotStarted,
+        keep_alive: request.keep_alive,
+        head_only,
+        http10: request.http10,
+    };
+
+    let route = routes.iter().find(|route| {
+        route.path == request.path
+            && (route.method == request.method || (head_only && route.method == Method::Get))
+    });
+    match route {
+        Some(route) => handler.handle(&route.target, request, &mut response)?,
+        None if routes.iter().any(|route| route.path == request.path) => {
+            response.start(405, &[], Some(0))?
+        }
+        None => response.start(404, &[], Some(0))?,
+    }
+    response.finish()?;
+    Ok(response.keep_alive)
+}
+
+fn parse(buffer: &[u8]) -> Parsed<'_> {
+    let (start_line, fields, head_length) = match split_head(buffer) {
+        Some(head) => head,
+        None if buffer.len() >= REQUEST_BUFFER_SIZE => return Parsed::Invalid(431),
+        None => {
+            return Parsed::Incomplete {
+                expects_continue: false,
+            }
+        }
+    };
+    let (start_line, fields) = match (
+        core::str::from_utf8(start_line),
+        core::str::from_utf8(fields),
+    ) {
+        (Ok(start_line), Ok(fields)) => (start_line, fields),
+        _ => return Parsed::Invalid(400),
+    };
+
+    let mut parts = start_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Parsed::Invalid(400),
+    };
+    let http10 = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => return Parsed::Invalid(505),
+    };
+    let method = match Method::parse(method.as_bytes()) {
+        Some(method) => method,
+        None => return Parsed::Invalid(501),
+    };
+
+    let headers = Headers(fields);
+    if headers.get("Transfer-Encoding").is_some() {
+        // only Content-Length bodies fit into the fixed-size buffer
+        return Parsed::Invalid(411);
+    }
+    let body_length = match headers.content_length() {
+        Ok(length) => length.unwrap_or(0),
+        Err(()) => return Parsed::Invalid(400),
+    };
+    let length = head_length.saturating_add(body_length);
+    if length > REQUEST_BUFFER_SIZE {
+        return Parsed::Invalid(413);
+    }
+    if buffer.len() < length {
+        return Parsed::Incomplete {
+            expects_continue: headers.contains_token("Expect", "100-continue"),
+        };
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    let keep_alive = if http10 {
+        headers.contains_token("Connection", "keep-alive")
+    } else {
+        !headers.contains_token("Connection", "close")
+    };
+    Parsed::Complete {
+        request: Request {
+            method,
+            path,
+            query,
+            headers,
+            body: &buffer[head_length..length],
+            http10,
+            keep_alive,
+        },
+        length,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(buffer: &[u8]) -> (Request<'_>, usize) {
+        match parse(buffer) {
+            Parsed::Complete { request, length } => (request, length),
+            _ => panic!("request not complete"),
+        }
+    }
+
+    fn invalid(buffer: &[u8]) -> Option<u16> {
+        match parse(buffer) {
+            Parsed::Invalid(status) => Some(status),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_request_with_body() {
+        let message =
+            b"POST /led?state=on HTTP/1.1\r\nHost: sensor\r\nContent-Length: 5\r\n\r\nhello";
+        let (request, length) = complete(message);
+        assert_eq!(length, message.len());
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/led");
+        assert_eq!(request.query, Some("state=on"));
+        assert_eq!(request.headers.get("host"), Some("sensor"));
+        assert_eq!(request.body, b"hello");
+
+        // the body has not been received completely
+        assert!(matches!(
+            parse(&message[..message.len() - 1]),
+            Parsed::Incomplete {
+                expects_continue: false
+            }
+        ));
+        assert!(matches!(
+            parse(&message[..20]),
+            Parsed::Incomplete {
+                expects_continue: false
+            }
+        ));
+    }
+
+    #[test]
+    fn expects_continue() {
+        let message = b"PUT /config HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n";
+        assert!(matches!(
+            parse(message),
+            Parsed::Incomplete {
+                expects_continue: true
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        assert_eq!(
+            invalid(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Some(411)
+        );
+        assert_eq!(
+            invalid(b"POST / HTTP/1.1\r\nContent-Length: 2000\r\n\r\n"),
+            Some(413)
+        );
+        assert_eq!(
+            invalid(b"POST / HTTP/1.1\r\nContent-Length: five\r\n\r\n"),
+            Some(400)
+        );
+        assert_eq!(invalid(&[b'a'; REQUEST_BUFFER_SIZE]), Some(431));
+        assert_eq!(invalid(b"GET / HTTP/2.0\r\n\r\n"), Some(505));
+        assert_eq!(invalid(b"BREW /pot HTTP/1.1\r\n\r\n"), Some(501));
+        assert_eq!(invalid(b"GET /\r\n\r\n"), Some(400));
+    }
+
+    #[test]
+    fn keep_alive_defaults() {
+        let (request, _) = complete(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(!request.http10);
+        assert!(request.keep_alive);
+        let (request, _) = complete(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(!request.keep_alive);
+
+        let (request, _) = complete(b"GET / HTTP/1.0\r\n\r\n");
+        assert!(request.http10);
+        assert!(!request.keep_alive);
+        let (request, _) = complete(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n");
+        assert!(request.keep_alive);
+    }
+
+    #[test]
+    fn parses_pipelined_requests() {
+        let buffer =
+            b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET /b HTTP/1.1\r\n\r\nGET /c";
+        let (request, length) = complete(buffer);
+        assert_eq!(request.path, "/a");
+        assert_eq!(request.body, b"ok");
+        let (request, second_length) = complete(&buffer[length..]);
+        assert_eq!(request.path, "/b");
+        assert_eq!(request.body, b"");
+        assert!(matches!(
+            parse(&buffer[length + second_length..]),
+            Parsed::Incomplete { .. }
+        ));
+    }
+}
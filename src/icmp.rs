@@ -0,0 +1,296 @@
+//! ICMP echo (ping) client.
+//!
+//! [`PingClient`] sends echo requests on an IPRAW socket and matches the replies by identifier
+//! and sequence number. The round-trip time is measured with the `now_ms` clock supplied by the
+//! caller. Destination unreachable and time exceeded messages that quote one of the requests
+//! end the ping early.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::icmp::PingClient;
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! let gateway = Ipv4Addr::new(192, 168, 0, 1);
+//! let socket = w5500.take_socket(Socket::Socket7).unwrap();
+//! let mut ping = PingClient::new(&mut w5500, socket)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"))
+//!     .with_timeout(500);
+//!
+//! let reachable = (0..3).any(|_| nb::block!(ping.ping(&mut w5500, gateway, now_ms())).is_ok());
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{ActiveW5500, IpRaw, IpRawSocket, Ipv4Addr, UninitializedSocket};
+
+/// Payload size of the echo requests unless set with [`PingClient::with_payload_size`]
+pub const DEFAULT_PAYLOAD_SIZE: usize = 32;
+/// Largest payload of an echo request
+pub const MAX_PAYLOAD_SIZE: usize = 256;
+
+/// IP protocol number of ICMP
+const PROTOCOL: u8 = 1;
+/// Type, code, checksum, identifier and sequence number
+const HEADER_SIZE: usize = 8;
+const PACKET_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD_SIZE;
+const DEFAULT_TIMEOUT_MS: u64 = 1_000;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const TYPE_TIME_EXCEEDED: u8 = 11;
+
+/// Echo reply to a [`PingClient::ping`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Reply {
+    /// Host that answered
+    pub from: Ipv4Addr,
+    /// Sequence number of the echo request
+    pub sequence: u16,
+    /// Size of the ICMP message, including the 8 byte header
+    pub size: usize,
+    /// Time between sending the request and receiving the reply
+    pub rtt_ms: u64,
+}
+
+/// Error returned by [`PingClient::ping`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// No reply arrived within the timeout
+    Timeout,
+    /// A host reported the target as unreachable with the given ICMP code, for example 1 for
+    /// host unreachable
+    Unreachable { from: Ipv4Addr, code: u8 },
+    /// A router discarded the request because its TTL expired
+    TimeExceeded { from: Ipv4Addr },
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// Echo request waiting for its reply
+#[derive(Copy, Clone)]
+struct Request {
+    target: Ipv4Addr,
+    sequence: u16,
+    sent_ms: u64,
+}
+
+/// ICMP echo client, see the [module documentation](self)
+pub struct PingClient {
+    socket: IpRawSocket,
+    identifier: u16,
+    /// sequence number of the next request
+    sequence: u16,
+    payload_size: usize,
+    timeout_ms: u64,
+    request: Option<Request>,
+    buffer: [u8; PACKET_SIZE],
+}
+
+impl PingClient {
+    /// Opens the socket for ICMP. The identifier of the requests is taken from the ephemeral
+    /// port range, so clients on different sockets do not see each other's replies.
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: UninitializedSocket,
+    ) -> Result<Self, crate::Error<Spi::Error, ChipSelect::Error>> {
+        let socket = socket.0;
+        let identifier = w5500.0.next_ephemeral_port();
+        w5500.open_ipraw(socket, PROTOCOL)?;
+        Ok(PingClient {
+            socket: IpRawSocket(socket),
+            identifier,
+            sequence: 0,
+            payload_size: DEFAULT_PAYLOAD_SIZE,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            request: None,
+            buffer: [0u8; PACKET_SIZE],
+        })
+    }
+
+    /// Sets the identifier of the echo requests
+    pub fn with_identifier(mut self, identifier: u16) -> Self {
+        self.identifier = identifier;
+        self
+    }
+
+    /// Sets the payload size of the echo requests, at most [`MAX_PAYLOAD_SIZE`]
+    pub fn with_payload_size(mut self, payload_size: usize) -> Self {
+        self.payload_size = payload_size.min(MAX_PAYLOAD_SIZE);
+        self
+    }
+
+    /// Sets the time to wait for the reply, defaults to one second
+    pub fn with_timeout(mut self, timeout_ms: u64) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Whether a request is waiting for its reply
+    pub fn is_busy(&self) -> bool {
+        self.request.is_some()
+    }
+
+    /// Returns the socket, it is still open for ICMP
+    pub fn release(self) -> IpRawSocket {
+        self.socket
+    }
+
+    /// Pings the target and returns its reply. The first call sends an echo request, further
+    /// calls with the same target return [`nb::Error::WouldBlock`] until the reply arrives or
+    /// the timeout expires. The next call after that sends a new request with the following
+    /// sequence number. Calling it with another target abandons the pending request.
+    pub fn ping<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        target: Ipv4Addr,
+        now_ms: u64,
+    ) -> nb::Result<Reply, Error<Spi::Error, ChipSelect::Error>> {
+        let request = match self.request {
+            Some(request) if request.target == target => request,
+            _ => self.send_request(w5500, target, now_ms)?,
+        };
+
+        while let Some((from, size)) = (&mut *w5500, &self.socket)
+            .receive_packet(&mut self.buffer)
+            .map_err(Error::Transfer)?
+        {
+            if let Some(result) = self.check(&request, from, size, now_ms) {
+                self.request = None;
+                return result.map_err(nb::Error::Other);
+            }
+        }
+
+        if now_ms.saturating_sub(request.sent_ms) >= self.timeout_ms {
+            debug!("ping {:?} seq {} timed out", target, request.sequence);
+            self.request = None;
+            return Err(nb::Error::Other(Error::Timeout));
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Sends the next echo request to the target
+    fn send_request<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        target: Ipv4Addr,
+        now_ms: u64,
+    ) -> nb::Result<Request, Error<Spi::Error, ChipSelect::Error>> {
+        self.request = None;
+        let sequence = self.sequence;
+        let length = HEADER_SIZE + self.payload_size;
+        let packet = &mut self.buffer[..length];
+        packet[0] = TYPE_ECHO_REQUEST;
+        packet[1] = 0;
+        BigEndian::write_u16(&mut packet[2..4], 0);
+        BigEndian::write_u16(&mut packet[4..6], self.identifier);
+        BigEndian::write_u16(&mut packet[6..8], sequence);
+        for (i, byte) in packet[HEADER_SIZE..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let checksum = checksum(packet);
+        BigEndian::write_u16(&mut packet[2..4], checksum);
+
+        (&mut *w5500, &self.socket)
+            .send_packet(&target, packet)
+            .map_err(|error| error.map(Error::Transfer))?;
+        trace!("ping {:?} seq {}", target, sequence);
+
+        self.sequence = sequence.wrapping_add(1);
+        let request = Request {
+            target,
+            sequence,
+            sent_ms: now_ms,
+        };
+        self.request = Some(request);
+        Ok(request)
+    }
+
+    /// Matches a received ICMP message of `size` bytes against the pending request
+    fn check<SpiError, ChipSelectError>(
+        &self,
+        request: &Request,
+        from: Ipv4Addr,
+        size: usize,
+        now_ms: u64,
+    ) -> Option<Result<Reply, Error<SpiError, ChipSelectError>>> {
+        let packet = &self.buffer[..size.min(PACKET_SIZE)];
+        // truncated messages can only be quoting errors, their checksum cannot be verified
+        if packet.len() < HEADER_SIZE || (packet.len() == size && checksum(packet) != 0) {
+            return None;
+        }
+        match packet[0] {
+            TYPE_ECHO_REPLY => {
+                let matches = from == request.target
+                    && size == HEADER_SIZE + self.payload_size
+                    && self.is_request(&packet[4..8], request);
+                matches.then(|| {
+                    Ok(Reply {
+                        from,
+                        sequence: request.sequence,
+                        size,
+                        rtt_ms: now_ms.saturating_sub(request.sent_ms),
+                    })
+                })
+            }
+            TYPE_DESTINATION_UNREACHABLE | TYPE_TIME_EXCEEDED => {
+                // the message quotes the IP header and the first 8 bytes of the request
+                let quoted = &packet[HEADER_SIZE..];
+                let header_length = usize::from(quoted.first()? & 0x0F) * 4;
+                if header_length < 20 {
+                    return None;
+                }
+                let original = quoted.get(header_length..header_length + HEADER_SIZE)?;
+                let matches = quoted[9] == PROTOCOL
+                    && quoted[16..20] == request.target.octets
+                    && original[0] == TYPE_ECHO_REQUEST
+                    && self.is_request(&original[4..8], request);
+                matches.then(|| {
+                    if packet[0] == TYPE_TIME_EXCEEDED {
+                        Err(Error::TimeExceeded { from })
+                    } else {
+                        Err(Error::Unreachable {
+                            from,
+                            code: packet[1],
+                        })
+                    }
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the identifier and sequence number belong to the request
+    fn is_request(&self, id_and_sequence: &[u8], request: &Request) -> bool {
+        BigEndian::read_u16(&id_and_sequence[0..2]) == self.identifier
+            && BigEndian::read_u16(&id_and_sequence[2..4]) == request.sequence
+    }
+}
+
+/// Internet checksum of RFC 1071, yields zero over a message that carries a valid checksum
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -1,10 +1,37 @@
 #![no_std]
-#![deny(broken_intra_doc_links)]
+#![deny(rustdoc::broken_intra_doc_links)]
 
 #[macro_use(block)]
 extern crate nb;
 
+#[macro_use]
+mod fmt;
+
+pub mod arp;
+pub mod autoip;
+pub mod clock;
+pub mod dhcp;
+pub mod dns;
+#[cfg(feature = "embassy-net-driver")]
+pub mod embassy;
+pub mod http;
+pub mod icmp;
+pub mod mdns;
+pub mod modbus;
+pub mod mqtt;
+#[cfg(feature = "embedded-nal")]
+pub mod nal;
 pub mod net;
+pub mod power;
+mod rand;
+pub mod replay;
+#[cfg(feature = "smoltcp")]
+pub mod smoltcp;
+pub mod sntp;
+pub mod syslog;
+pub mod telnet;
+pub mod tftp;
+pub mod wol;
 pub use net::{Ipv4Addr, MacAddress};
 
 use byteorder::BigEndian;
@@ -12,9 +39,17 @@ use byteorder::ByteOrder;
 use embedded_hal::digital::v2::OutputPin;
 use embedded_hal::spi::FullDuplex;
 
+use clock::Clock;
+
 const COMMAND_READ: u8 = 0x00 << 2;
 const COMMAND_WRITE: u8 = 0x01 << 2;
 
+/// Length of the source address, port and payload size in front of received UDP datagrams
+const UDP_HEADER_SIZE: u16 = 8;
+
+/// First port of the dynamic range (RFC 6335) that local ports are picked from
+const EPHEMERAL_PORT_START: u16 = 49152;
+
 const VARIABLE_DATA_LENGTH: u8 = 0b_00;
 #[allow(unused)]
 const FIXED_DATA_LENGTH_1_BYTE: u8 = 0b_01;
@@ -23,14 +58,66 @@ const FIXED_DATA_LENGTH_2_BYTES: u8 = 0b_10;
 #[allow(unused)]
 const FIXED_DATA_LENGTH_4_BYTES: u8 = 0b_11;
 
-/// Error enum that represents the union between SPI hardware errors and digital IO pin errors.
-/// Returned as an Error type by many [`ActiveW5500`] operations that talk to the chip
+/// Value of the chip version register (VERSIONR) of a W5500
+const CHIP_VERSION: u8 = 0x04;
+
+/// Error returned by the [`ActiveW5500`] operations and the socket traits. Besides the SPI
+/// hardware and digital IO pin errors of talking to the chip, it reports failures on the
+/// network and misuse of a socket, which [`Error::source`] tells apart.
 #[derive(Copy, Clone, Debug)]
-pub enum TransferError<SpiError, ChipSelectError> {
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The SPI bus failed
     SpiError(SpiError),
+    /// Driving the chip select pin failed
     ChipSelectError(ChipSelectError),
+    /// The chip gave up resolving the MAC address of the destination
+    ArpTimeout,
+    /// The chip gave up retransmitting on a TCP connection and closed it
+    TcpTimeout,
+    /// The TX buffer of the socket is smaller than the data, which needs `required` bytes
+    BufferTooSmall { required: usize },
+    /// The socket is not in a state that allows the operation
+    InvalidState(SocketStatus),
+    /// The socket does not support the requested mode
+    UnsupportedSocket(Socket),
+    /// The chip reported a version other than that of a W5500
+    UnsupportedVersion(u8),
+}
+
+/// Where an [`Error`] comes from, see [`Error::source`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ErrorSource {
+    /// Communicating with the chip over SPI failed
+    Bus,
+    /// The network did not answer in time
+    Network,
+    /// The operation does not fit the socket or its current state
+    Usage,
+    /// The chip is not a W5500
+    Chip,
+}
+
+impl<SpiError, ChipSelectError> Error<SpiError, ChipSelectError> {
+    /// Classifies the error, for example to retry on [`ErrorSource::Network`] but reset the
+    /// chip on [`ErrorSource::Bus`]
+    pub fn source(&self) -> ErrorSource {
+        match self {
+            Error::SpiError(_) | Error::ChipSelectError(_) => ErrorSource::Bus,
+            Error::ArpTimeout | Error::TcpTimeout => ErrorSource::Network,
+            Error::BufferTooSmall { .. } | Error::InvalidState(_) | Error::UnsupportedSocket(_) => {
+                ErrorSource::Usage
+            }
+            Error::UnsupportedVersion(_) => ErrorSource::Chip,
+        }
+    }
 }
 
+/// Former name of [`Error`] from when it only held the SPI and chip select errors
+#[deprecated(note = "use `Error`, which the modules return as well")]
+pub type TransferError<SpiError, ChipSelectError> = Error<SpiError, ChipSelectError>;
+
 /// Settings for wake on LAN.  Allows the W5500 to optionally emit an interrupt upon receiving a
 /// WOL magic packet.
 #[derive(Copy, Clone, PartialOrd, PartialEq)]
@@ -60,8 +147,19 @@ pub enum ArpResponses {
     DropAfterUse,
 }
 
+/// Settings for the MAC filter of a [`MacRawSocket`].
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MacFilter {
+    /// Receive every frame on the wire.
+    Disabled,
+    /// Only receive frames addressed to the own MAC address or to the broadcast address.
+    Enabled,
+}
+
 /// PHY operation mode.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum OperationMode {
     /// 10BT half-duplex. Auto-negotiation disabled.
@@ -77,6 +175,7 @@ pub enum OperationMode {
     /// Power down mode.
     PowerDown = 0b110,
     /// All capable. Auto-negotiation enabled.
+    #[default]
     Auto = 0b111,
 }
 
@@ -86,14 +185,9 @@ impl From<OperationMode> for u8 {
     }
 }
 
-impl Default for OperationMode {
-    fn default() -> OperationMode {
-        OperationMode::Auto
-    }
-}
-
 /// PHY speed status.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum SpeedStatus {
     /// 10Mbps based.
@@ -104,6 +198,7 @@ pub enum SpeedStatus {
 
 /// PHY duplex status.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum DuplexStatus {
     /// Half duplex.
@@ -131,9 +226,9 @@ impl PhyCfg {
     // Operation mode bit position.
     const OPMDC_POS: u8 = 3;
     // Configure PHY opeartion mode bit position.
-    // const OPMD_POS: u8 = 6;
+    const OPMD_POS: u8 = 6;
     // Reset bit position.
-    // const RST_POS: u8 = 7;
+    const RST_POS: u8 = 7;
 
     /// PHY link status.
     ///
@@ -181,12 +276,36 @@ impl core::convert::From<u8> for PhyCfg {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for PhyCfg {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "PhyCfg {{ link_up: {}, speed: {}, duplex: {}, operation_mode: {} }}",
+            self.link_up(),
+            self.speed(),
+            self.duplex(),
+            self.operation_mode(),
+        )
+    }
+}
+
 /// Represents a [`Socket`] that has not yet been initialized for a particular protocol
 pub struct UninitializedSocket(Socket);
 
 /// Represents a [`Socket`] that has been initialized to use the UDP protocol
 pub struct UdpSocket(Socket);
 
+/// Represents a [`Socket`] that has been initialized to use the TCP protocol
+pub struct TcpSocket(Socket);
+
+/// Represents [`Socket::Socket0`] initialized to send and receive raw Ethernet frames
+pub struct MacRawSocket(Socket);
+
+/// Represents a [`Socket`] that has been initialized to send and receive the payload of IP
+/// packets with a single protocol number
+pub struct IpRawSocket(Socket);
+
 /// The first level of instantiating communication with the W5500 device. This type is not used
 /// for communication, but to keep track of the state of the device. Calling [`W5500::activate`]
 /// will return an [`ActiveW5500`] which can be used to communicate with the device. This
@@ -196,6 +315,13 @@ pub struct W5500<ChipSelect: OutputPin> {
     chip_select: ChipSelect,
     /// each bit represents whether the corresponding socket is available for take
     sockets: u8,
+    /// the local port handed out by the last call to [`W5500::next_ephemeral_port`]
+    ephemeral_port: u16,
+    /// each bit represents whether the corresponding TCP socket waits for a send to complete
+    sending: u8,
+    /// each bit represents whether the corresponding socket returns to the pool once its
+    /// graceful disconnect has completed
+    closing: u8,
 }
 
 impl<ChipSelectError, ChipSelect: OutputPin<Error = ChipSelectError>> W5500<ChipSelect> {
@@ -203,11 +329,16 @@ impl<ChipSelectError, ChipSelect: OutputPin<Error = ChipSelectError>> W5500<Chip
         W5500 {
             chip_select,
             sockets: 0xFF,
+            ephemeral_port: EPHEMERAL_PORT_START,
+            sending: 0x00,
+            closing: 0x00,
         }
     }
 
     /// Creates a new instance and initializes the device accordingly to the parameters.
     /// To do so, it briefly activates the [`W5500`], to set it up with the specified configuration.
+    /// Returns [`Error::UnsupportedVersion`] if the chip does not identify as a W5500, for
+    /// example because nothing answers on the SPI bus.
     pub fn with_initialisation<Spi: FullDuplex<u8>>(
         chip_select: ChipSelect,
         spi: &mut Spi,
@@ -215,10 +346,15 @@ impl<ChipSelectError, ChipSelect: OutputPin<Error = ChipSelectError>> W5500<Chip
         ping: OnPingRequest,
         mode: ConnectionType,
         arp: ArpResponses,
-    ) -> Result<Self, TransferError<Spi::Error, ChipSelectError>> {
+    ) -> Result<Self, Error<Spi::Error, ChipSelectError>> {
         let mut w5500 = Self::new(chip_select);
         {
             let mut w5500_active = w5500.activate(spi)?;
+            let version = w5500_active.read_u8(Register::CommonRegister(0x00_39_u16))?;
+            if version != CHIP_VERSION {
+                debug!("unsupported chip version {}", version);
+                return Err(Error::UnsupportedVersion(version));
+            }
             unsafe {
                 // this is safe, since the w5500 instance hast just been created and no sockets
                 // are given away or were initialized
@@ -240,13 +376,22 @@ impl<ChipSelectError, ChipSelect: OutputPin<Error = ChipSelectError>> W5500<Chip
         }
     }
 
+    /// Returns a local port from the dynamic range for sockets that do not need a well-known
+    /// port. Ports are handed out in order and wrap around at the end of the range.
+    pub fn next_ephemeral_port(&mut self) -> u16 {
+        self.ephemeral_port = self
+            .ephemeral_port
+            .checked_add(1)
+            .unwrap_or(EPHEMERAL_PORT_START);
+        self.ephemeral_port
+    }
+
     /// Returns a [`ActiveW5500`] which can be used to modify the device and to communicate
     /// with other ethernet devices within the connected LAN.
     pub fn activate<'a, 'b, Spi: FullDuplex<u8>>(
         &'a mut self,
         spi: &'b mut Spi,
-    ) -> Result<ActiveW5500<'a, 'b, ChipSelect, Spi>, TransferError<Spi::Error, ChipSelectError>>
-    {
+    ) -> Result<ActiveW5500<'a, 'b, ChipSelect, Spi>, Error<Spi::Error, ChipSelectError>> {
         Ok(ActiveW5500(self, spi))
     }
 }
@@ -268,14 +413,62 @@ impl<
         Spi: FullDuplex<u8, Error = SpiError>,
     > ActiveW5500<'_, '_, ChipSelect, Spi>
 {
-    /// Returns the requested socket if it is not already taken. See [`W5500::take_socket`]
+    /// Returns the requested socket if it is not already taken. See [`W5500::take_socket`]. A
+    /// socket that is still disconnecting is returned once the chip reports it as closed.
     pub fn take_socket(&mut self, socket: Socket) -> Option<UninitializedSocket> {
+        if self.0.closing & (0x01 << socket.number()) != 0 {
+            let status = self.read_u8(socket.at(SocketRegister::Status));
+            if let Ok(SocketStatus::Closed) = status.map(SocketStatus::from) {
+                self.close_socket(socket).ok()?;
+            }
+        }
         self.0.take_socket(socket)
     }
 
     /// Read the PHY configuration register (PHYCFGR).
-    pub fn phy_cfg(&mut self) -> Result<PhyCfg, TransferError<SpiError, ChipSelectError>> {
-        Ok(self.read_u8(Register::CommonRegister(0x00_2E_u16))?.into())
+    pub fn phy_cfg(&mut self) -> Result<PhyCfg, Error<SpiError, ChipSelectError>> {
+        let phy_cfg: PhyCfg = self.read_u8(Register::CommonRegister(0x00_2E_u16))?.into();
+        debug!(
+            "PHY link up: {}, speed: {:?}, duplex: {:?}, operation mode: {:?}",
+            phy_cfg.link_up(),
+            phy_cfg.speed(),
+            phy_cfg.duplex(),
+            phy_cfg.operation_mode(),
+        );
+        Ok(phy_cfg)
+    }
+
+    /// Blocks until the PHY reports the link as up or the deadline passes
+    pub fn wait_for_link<C: Clock>(
+        &mut self,
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<PhyCfg, clock::Error<Error<SpiError, ChipSelectError>>> {
+        loop {
+            let phy_cfg: PhyCfg = self.read_u8(Register::CommonRegister(0x00_2E_u16))?.into();
+            if phy_cfg.link_up() {
+                return Ok(phy_cfg);
+            }
+            if clock.now_ms() >= deadline_ms {
+                return Err(clock::Error::Timeout);
+            }
+        }
+    }
+
+    /// Configures the PHY operation mode and resets the PHY, which drops the link until it has
+    /// been negotiated again
+    pub fn set_operation_mode(
+        &mut self,
+        mode: OperationMode,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        let value = (1 << PhyCfg::OPMD_POS) | (u8::from(mode) << PhyCfg::OPMDC_POS);
+        debug!("PHY operation mode {:?}", mode);
+        // the reset bit is active low
+        self.write_u8(Register::CommonRegister(0x00_2E_u16), value)?;
+        self.write_u8(
+            Register::CommonRegister(0x00_2E_u16),
+            value | (1 << PhyCfg::RST_POS),
+        )
     }
 
     /// Set up the basic configuration of the W5500 chip
@@ -285,7 +478,7 @@ impl<
         ping: OnPingRequest,
         mode: ConnectionType,
         arp: ArpResponses,
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
         let mut value = 0x00;
 
         if let OnWakeOnLan::InvokeInterrupt = wol {
@@ -311,15 +504,12 @@ impl<
     pub fn set_gateway(
         &mut self,
         gateway: Ipv4Addr,
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.write_to(Register::CommonRegister(0x00_01_u16), &gateway.octets)
     }
 
     /// Sets the subnet on the network (for example 255.255.255.0 for /24 subnets)
-    pub fn set_subnet(
-        &mut self,
-        subnet: Ipv4Addr,
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    pub fn set_subnet(&mut self, subnet: Ipv4Addr) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.write_to(Register::CommonRegister(0x00_05_u16), &subnet.octets)
     }
 
@@ -337,16 +527,13 @@ impl<
     /// "Universally administered and locally administered addresses are distinguished by setting
     /// the second-least-significant bit of the first octet of the address" [Wikipedia](https://en.wikipedia.org/wiki/MAC_address#Universal_vs._local)
     ///
-    pub fn set_mac(
-        &mut self,
-        mac: MacAddress,
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    pub fn set_mac(&mut self, mac: MacAddress) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.write_to(Register::CommonRegister(0x00_09_u16), &mac.octets)
     }
 
     /// Sets the IP address of the W5500 device.  Must be within the range and permitted by the
     /// gateway or the device will not be accessible.
-    pub fn set_ip(&mut self, ip: Ipv4Addr) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    pub fn set_ip(&mut self, ip: Ipv4Addr) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.write_to(Register::CommonRegister(0x00_0F_u16), &ip.octets)
     }
 
@@ -354,7 +541,7 @@ impl<
     pub fn read_ip(
         &mut self,
         register: Register,
-    ) -> Result<Ipv4Addr, TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<Ipv4Addr, Error<SpiError, ChipSelectError>> {
         let mut ip = Ipv4Addr::default();
         self.read_from(register, &mut ip.octets)?;
         Ok(ip)
@@ -368,7 +555,7 @@ impl<
     /// result in undefined behavior.
     ///
     /// [`Sockets`]: crate::Socket
-    pub unsafe fn reset(&mut self) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    pub unsafe fn reset(&mut self) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.write_to(
             Register::CommonRegister(0x00_00_u16),
             &[
@@ -376,18 +563,315 @@ impl<
             ],
         )?;
         self.0.sockets = 0xFF;
+        self.0.sending = 0x00;
+        self.0.closing = 0x00;
+        Ok(())
+    }
+
+    /// Opens the socket in UDP mode, bound to the given local port
+    fn open_udp(
+        &mut self,
+        socket: Socket,
+        port: u16,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        // the outcome of a datagram sent before is of no interest anymore
+        self.write_u8(
+            socket.at(SocketRegister::Interrupt),
+            Interrupt::SendOk as u8 | Interrupt::Timeout as u8,
+        )?;
+        self.write_u16(socket.at(SocketRegister::LocalPort), port)?;
+        self.write_to(
+            socket.at(SocketRegister::Mode),
+            &[
+                Protocol::UDP as u8,       // Socket Mode Register
+                SocketCommand::Open as u8, // Socket Command Register
+            ],
+        )?;
+        self.0.sending &= !(0x01 << socket.number());
+        Ok(())
+    }
+
+    /// Opens the socket in UDP multicast mode, bound to the given port and joined to the
+    /// given group. Datagrams sent with [`ActiveW5500::send_tx_buffer`] go to the group.
+    fn open_udp_multicast(
+        &mut self,
+        socket: Socket,
+        port: u16,
+        group: Ipv4Addr,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        let port = port.to_be_bytes();
+        // RFC 1112: the lower 23 bits of the group are mapped into 01:00:5e:00:00:00
+        self.write_to(
+            socket.at(SocketRegister::LocalPort),
+            &[
+                port[0],
+                port[1], // local port u16
+                0x01,
+                0x00,
+                0x5e,
+                group.octets[1] & 0x7f,
+                group.octets[2],
+                group.octets[3], // destination mac
+                group.octets[0],
+                group.octets[1],
+                group.octets[2],
+                group.octets[3], // destination IP
+                port[0],
+                port[1], // destination port
+            ],
+        )?;
+        self.write_u8(socket.at(SocketRegister::Interrupt), 0xFF)?;
+        self.write_to(
+            socket.at(SocketRegister::Mode),
+            &[
+                Protocol::UDP as u8 | 1 << 7, // Socket Mode Register, MULTI
+                SocketCommand::Open as u8,    // Socket Command Register
+            ],
+        )?;
+        self.0.sending &= !(0x01 << socket.number());
+        Ok(())
+    }
+
+    /// Closes the socket and returns it to the pool, see [`W5500::take_socket`]
+    fn close_socket(&mut self, socket: Socket) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.write_u8(
+            socket.at(SocketRegister::Command),
+            SocketCommand::Close as u8,
+        )?;
+        self.0.sockets |= 0x01 << socket.number();
+        self.0.sending &= !(0x01 << socket.number());
+        self.0.closing &= !(0x01 << socket.number());
+        Ok(())
+    }
+
+    /// Opens the socket in TCP mode, bound to the given local port
+    fn open_tcp(
+        &mut self,
+        socket: Socket,
+        port: u16,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.write_u8(socket.at(SocketRegister::Interrupt), 0xFF)?;
+        self.write_u16(socket.at(SocketRegister::LocalPort), port)?;
+        self.write_to(
+            socket.at(SocketRegister::Mode),
+            &[
+                Protocol::TCP as u8,       // Socket Mode Register
+                SocketCommand::Open as u8, // Socket Command Register
+            ],
+        )?;
+        self.0.sending &= !(0x01 << socket.number());
+        Ok(())
+    }
+
+    /// Opens the socket in MACRAW mode with the given MAC filter setting
+    fn open_macraw(
+        &mut self,
+        socket: Socket,
+        filter: MacFilter,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        let mut mode = Protocol::MACRAW as u8;
+        if let MacFilter::Enabled = filter {
+            mode |= 1 << 7;
+        }
+        self.write_u8(socket.at(SocketRegister::Interrupt), 0xFF)?;
+        self.write_to(
+            socket.at(SocketRegister::Mode),
+            &[
+                mode,                      // Socket Mode Register
+                SocketCommand::Open as u8, // Socket Command Register
+            ],
+        )?;
+        self.0.sending &= !(0x01 << socket.number());
+        Ok(())
+    }
+
+    /// Opens the socket in IPRAW mode for the given IP protocol number
+    fn open_ipraw(
+        &mut self,
+        socket: Socket,
+        protocol: u8,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.write_u8(socket.at(SocketRegister::IpProtocol), protocol)?;
+        self.write_u8(socket.at(SocketRegister::Interrupt), 0xFF)?;
+        self.write_to(
+            socket.at(SocketRegister::Mode),
+            &[
+                Protocol::IPRAW as u8,     // Socket Mode Register
+                SocketCommand::Open as u8, // Socket Command Register
+            ],
+        )?;
+        self.0.sending &= !(0x01 << socket.number());
+        Ok(())
+    }
+
+    /// Closes the TCP socket without a graceful disconnect and returns it to the pool, so it
+    /// can be taken again. See [`Tcp::disconnect`] to close the connection gracefully first.
+    pub fn close_tcp_socket(
+        &mut self,
+        socket: TcpSocket,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.close_socket(socket.0)
+    }
+
+    /// Closes the MACRAW socket and returns it to the pool, so it can be taken again
+    pub fn close_macraw_socket(
+        &mut self,
+        socket: MacRawSocket,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.close_socket(socket.0)
+    }
+
+    /// Closes the IPRAW socket and returns it to the pool, so it can be taken again
+    pub fn close_ipraw_socket(
+        &mut self,
+        socket: IpRawSocket,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.close_socket(socket.0)
+    }
+
+    /// Checks whether the last SEND command of the socket has completed. Returns
+    /// [`nb::Error::WouldBlock`] while it is still in progress.
+    fn poll_send_complete(
+        &mut self,
+        socket: Socket,
+    ) -> nb::Result<(), Error<SpiError, ChipSelectError>> {
+        let mask = 0x01 << socket.number();
+        if self.0.sending & mask != 0 {
+            let interrupts = self.read_u8(socket.at(SocketRegister::Interrupt))?;
+            if interrupts & Interrupt::SendOk as u8 != 0 {
+                self.reset_interrupt(socket, Interrupt::SendOk)?;
+            } else if interrupts & Interrupt::Timeout as u8 == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.0.sending &= !mask;
+        }
+        Ok(())
+    }
+
+    /// Returns [`Error::BufferTooSmall`] if `length` bytes do not fit into the TX buffer of the
+    /// socket even when it is empty
+    fn check_tx_capacity(
+        &mut self,
+        socket: Socket,
+        length: usize,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        // the buffer size is configured in KiB
+        let capacity = usize::from(self.read_u8(socket.at(SocketRegister::TransmitBuffer))?) * 1024;
+        if length > capacity {
+            return Err(Error::BufferTooSmall { required: length });
+        }
         Ok(())
     }
 
+    /// Writes `data` at the TX write pointer of the socket and issues a SEND command. The
+    /// caller has to ensure that the TX buffer has enough free space.
+    fn send_tx_buffer(
+        &mut self,
+        socket: Socket,
+        data: &[u8],
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.queue_tx_buffer(socket, 0, data)?;
+        self.commit_tx_buffer(socket, data.len() as u16)
+    }
+
+    /// Writes `data` into the TX buffer of the socket, `offset` bytes behind the TX write
+    /// pointer, without sending it. See [`ActiveW5500::commit_tx_buffer`].
+    fn queue_tx_buffer(
+        &mut self,
+        socket: Socket,
+        offset: u16,
+        data: &[u8],
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        let write_pointer = self.read_u16(socket.at(SocketRegister::TxWritePointer))?;
+        self.write_to(
+            socket.tx_register_at(write_pointer.wrapping_add(offset)),
+            data,
+        )
+    }
+
+    /// Moves the TX write pointer of the socket `length` bytes forward and issues a SEND
+    /// command for the queued data
+    fn commit_tx_buffer(
+        &mut self,
+        socket: Socket,
+        length: u16,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        let write_pointer = self.read_u16(socket.at(SocketRegister::TxWritePointer))?;
+        self.write_u16(
+            socket.at(SocketRegister::TxWritePointer),
+            write_pointer.wrapping_add(length),
+        )?;
+        self.write_u8(
+            socket.at(SocketRegister::Command),
+            SocketCommand::Send as u8,
+        )?;
+        self.0.sending |= 0x01 << socket.number();
+        Ok(())
+    }
+
+    /// Moves the RX read pointer of the socket to `read_pointer` and issues a RECV command to
+    /// release the consumed part of the RX buffer
+    fn release_rx_buffer(
+        &mut self,
+        socket: Socket,
+        read_pointer: u16,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.write_u16(socket.at(SocketRegister::RxReadPointer), read_pointer)?;
+        self.write_u8(
+            socket.at(SocketRegister::Command),
+            SocketCommand::Recv as u8,
+        )
+    }
+
+    /// Reads the header the chip stores in front of the next received UDP datagram. Returns
+    /// the position of the header in the RX buffer along with the source address, port and
+    /// payload size.
+    #[allow(clippy::type_complexity)]
+    fn read_udp_header(
+        &mut self,
+        socket: Socket,
+    ) -> Result<Option<(u16, Ipv4Addr, u16, u16)>, Error<SpiError, ChipSelectError>> {
+        let receive_size = self.read_u16_stable(socket.at(SocketRegister::RxReceivedSize))?;
+        if receive_size < UDP_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        // |<-- read_pointer                               read_pointer + 8 + size -->|
+        // | Source IP Address | Source Port | Byte Size of DATA |  Actual DATA ...  |
+        // |  --- 4 Bytes ---  | - 2 Bytes - |  --- 2 Bytes ---  |       ....        |
+
+        let read_pointer = self.read_u16(socket.at(SocketRegister::RxReadPointer))?;
+        let mut header = [0u8; UDP_HEADER_SIZE as usize];
+        self.read_from(socket.rx_register_at(read_pointer), &mut header)?;
+        Ok(Some((
+            read_pointer,
+            Ipv4Addr::new(header[0], header[1], header[2], header[3]),
+            BigEndian::read_u16(&header[4..6]),
+            BigEndian::read_u16(&header[6..8]),
+        )))
+    }
+
+    /// Closes the UDP socket and returns it to the pool, so it can be taken again
+    pub fn close_udp_socket(
+        &mut self,
+        socket: UdpSocket,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.close_socket(socket.0)
+    }
+
     /// TODO document
     fn is_interrupt_set(
         &mut self,
         socket: Socket,
         interrupt: Interrupt,
-    ) -> Result<bool, TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<bool, Error<SpiError, ChipSelectError>> {
         let mut state = [0u8; 1];
         self.read_from(socket.at(SocketRegister::Interrupt), &mut state)?;
-        Ok(state[0] & interrupt as u8 != 0)
+        let is_set = state[0] & interrupt as u8 != 0;
+        if is_set {
+            debug!("{:?} interrupt {:?}", socket, interrupt);
+        }
+        Ok(is_set)
     }
 
     /// TODO document
@@ -395,39 +879,84 @@ impl<
         &mut self,
         socket: Socket,
         interrupt: Interrupt,
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.write_to(socket.at(SocketRegister::Interrupt), &[interrupt as u8])
     }
 
-    /// Reads one byte from the given [`Register`] as a u8
-    fn read_u8(
+    /// Whether the common interrupt is set. It stays set until it is cleared with
+    /// [`ActiveW5500::reset_common_interrupt`].
+    pub fn is_common_interrupt_set(
         &mut self,
-        register: Register,
-    ) -> Result<u8, TransferError<SpiError, ChipSelectError>> {
+        interrupt: CommonInterrupt,
+    ) -> Result<bool, Error<SpiError, ChipSelectError>> {
+        let is_set = self.read_u8(Register::CommonRegister(0x00_15_u16))? & interrupt as u8 != 0;
+        if is_set {
+            debug!("common interrupt {:?}", interrupt);
+        }
+        Ok(is_set)
+    }
+
+    /// Clears the common interrupt
+    pub fn reset_common_interrupt(
+        &mut self,
+        interrupt: CommonInterrupt,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        self.write_u8(Register::CommonRegister(0x00_15_u16), interrupt as u8)
+    }
+
+    /// Enables or disables asserting the INTn pin for the common interrupt
+    pub fn set_common_interrupt_enabled(
+        &mut self,
+        interrupt: CommonInterrupt,
+        enabled: bool,
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
+        let mask = self.read_u8(Register::CommonRegister(0x00_16_u16))?;
+        let mask = if enabled {
+            mask | interrupt as u8
+        } else {
+            mask & !(interrupt as u8)
+        };
+        self.write_u8(Register::CommonRegister(0x00_16_u16), mask)
+    }
+
+    /// Reads one byte from the given [`Register`] as a u8
+    fn read_u8(&mut self, register: Register) -> Result<u8, Error<SpiError, ChipSelectError>> {
         let mut buffer = [0u8; 1];
         self.read_from(register, &mut buffer)?;
         Ok(buffer[0])
     }
 
     /// Reads two bytes from the given [`Register`] as a u16
-    fn read_u16(
-        &mut self,
-        register: Register,
-    ) -> Result<u16, TransferError<SpiError, ChipSelectError>> {
+    fn read_u16(&mut self, register: Register) -> Result<u16, Error<SpiError, ChipSelectError>> {
         let mut buffer = [0u8; 2];
         self.read_from(register, &mut buffer)?;
         Ok(BigEndian::read_u16(&buffer))
     }
 
+    /// Reads two bytes from the given [`Register`] until two consecutive reads agree, as
+    /// required for registers the chip updates while they are read
+    fn read_u16_stable(
+        &mut self,
+        register: Register,
+    ) -> Result<u16, Error<SpiError, ChipSelectError>> {
+        loop {
+            let s0 = self.read_u16(register)?;
+            let s1 = self.read_u16(register)?;
+            if s0 == s1 {
+                return Ok(s0);
+            }
+        }
+    }
+
     /// Reads enough bytes from the given [`Register`] address onward to fill the `target` u8 slice
     fn read_from(
         &mut self,
         register: Register,
         target: &mut [u8],
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.chip_select()
-            .map_err(|error| -> TransferError<SpiError, ChipSelectError> {
-                TransferError::ChipSelectError(error)
+            .map_err(|error| -> Error<SpiError, ChipSelectError> {
+                Error::ChipSelectError(error)
             })?;
         let mut request = [
             0_u8,
@@ -439,10 +968,12 @@ impl<
             .write_bytes(&request)
             .and_then(|_| self.read_bytes(target));
         self.chip_deselect()
-            .map_err(|error| -> TransferError<SpiError, ChipSelectError> {
-                TransferError::ChipSelectError(error)
+            .map_err(|error| -> Error<SpiError, ChipSelectError> {
+                Error::ChipSelectError(error)
             })?;
-        result.map_err(TransferError::SpiError)
+        result.map_err(Error::SpiError)?;
+        trace!("read {:?}: {:?}", RegisterName(register), &*target);
+        Ok(())
     }
 
     /// Reads enough bytes over SPI to fill the `target` u8 slice
@@ -466,7 +997,7 @@ impl<
         &mut self,
         register: Register,
         value: u8,
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.write_to(register, &[value])
     }
 
@@ -475,7 +1006,7 @@ impl<
         &mut self,
         register: Register,
         value: u16,
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
         let mut data = [0u8; 2];
         BigEndian::write_u16(&mut data, value);
         self.write_to(register, &data)
@@ -486,10 +1017,10 @@ impl<
         &mut self,
         register: Register,
         data: &[u8],
-    ) -> Result<(), TransferError<SpiError, ChipSelectError>> {
+    ) -> Result<(), Error<SpiError, ChipSelectError>> {
         self.chip_select()
-            .map_err(|error| -> TransferError<SpiError, ChipSelectError> {
-                TransferError::ChipSelectError(error)
+            .map_err(|error| -> Error<SpiError, ChipSelectError> {
+                Error::ChipSelectError(error)
             })?;
         let mut request = [
             0_u8,
@@ -501,10 +1032,15 @@ impl<
             .write_bytes(&request)
             .and_then(|_| self.write_bytes(data));
         self.chip_deselect()
-            .map_err(|error| -> TransferError<SpiError, ChipSelectError> {
-                TransferError::ChipSelectError(error)
+            .map_err(|error| -> Error<SpiError, ChipSelectError> {
+                Error::ChipSelectError(error)
             })?;
-        result.map_err(TransferError::SpiError)
+        result.map_err(Error::SpiError)?;
+        trace!("write {:?}: {:?}", RegisterName(register), data);
+        if let Some((socket, command)) = register.socket_command_in(data) {
+            debug!("{:?} command {:?}", socket, SocketCommand::from_u8(command));
+        }
+        Ok(())
     }
 
     /// Write a slice of u8 bytes over SPI
@@ -541,30 +1077,24 @@ pub trait IntoUdpSocket<SpiError> {
         Self: Sized;
 }
 
-impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> IntoUdpSocket<UninitializedSocket>
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>>
+    IntoUdpSocket<(UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)>
     for (
         &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
         UninitializedSocket,
     )
 {
-    /// Initialize a socket to operate in UDP mode
-    fn try_into_udp_server_socket(self, port: u16) -> Result<UdpSocket, UninitializedSocket> {
+    /// Initialize a socket to operate in UDP mode. If that fails, the socket is returned along
+    /// with the error.
+    fn try_into_udp_server_socket(
+        self,
+        port: u16,
+    ) -> Result<UdpSocket, (UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)> {
         let socket = (self.1).0;
-        (|| {
-            self.0.reset_interrupt(socket, Interrupt::SendOk)?;
-
-            self.0
-                .write_u16(socket.at(SocketRegister::LocalPort), port)?;
-            self.0.write_to(
-                socket.at(SocketRegister::Mode),
-                &[
-                    Protocol::UDP as u8,       // Socket Mode Register
-                    SocketCommand::Open as u8, // Socket Command Register
-                ],
-            )?;
-            Ok(UdpSocket(socket))
-        })()
-        .map_err(|_: TransferError<Spi::Error, ChipSelect::Error>| UninitializedSocket(socket))
+        self.0
+            .open_udp(socket, port)
+            .map(|_| UdpSocket(socket))
+            .map_err(|error| (UninitializedSocket(socket), error))
     }
 }
 
@@ -572,149 +1102,606 @@ impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> IntoUdpSocket<UninitializedSock
 pub trait Udp {
     type Error;
 
+    #[allow(clippy::type_complexity)]
     fn receive(
         &mut self,
         target_buffer: &mut [u8],
-    ) -> Result<Option<(Ipv4Addr, u16, usize)>, Self::Error>;
+    ) -> Result<Option<(Ipv4Addr, u16, usize, bool)>, Self::Error>;
+
+    fn peek_size(&mut self) -> Result<Option<usize>, Self::Error>;
+
+    fn peek_header(&mut self) -> Result<Option<(Ipv4Addr, u16, usize)>, Self::Error>;
+
+    fn send(&mut self, host: &Ipv4Addr, host_port: u16, data: &[u8])
+        -> nb::Result<(), Self::Error>;
 
+    fn poll_send(&mut self) -> nb::Result<(), Self::Error>;
+
+    #[deprecated(note = "use `send` and `poll_send`, or `send_until` with a clock")]
     fn blocking_send(
         &mut self,
         host: &Ipv4Addr,
         host_port: u16,
         data: &[u8],
     ) -> Result<(), Self::Error>;
+
+    fn send_until<C: Clock>(
+        &mut self,
+        host: &Ipv4Addr,
+        host_port: u16,
+        data: &[u8],
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<(), clock::Error<Self::Error>>;
+
+    fn receive_until<C: Clock>(
+        &mut self,
+        target_buffer: &mut [u8],
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<(Ipv4Addr, u16, usize, bool), clock::Error<Self::Error>>;
 }
 
 impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> Udp
     for (&mut ActiveW5500<'_, '_, ChipSelect, Spi>, &UdpSocket)
 {
-    type Error = TransferError<Spi::Error, ChipSelect::Error>;
+    type Error = Error<Spi::Error, ChipSelect::Error>;
 
-    /// Returns a UDP packet if one is available.  Will return `None` if no UDP packets are in the
-    /// socket's buffer
+    /// Returns the source, port and length of the next UDP datagram if one is available and
+    /// copies as much of its payload as fits into `destination`. The datagram is consumed either
+    /// way, the flag is set if it did not fit and has been truncated. Use [`Udp::peek_size`] to
+    /// size the buffer beforehand.
     fn receive(
         &mut self,
         destination: &mut [u8],
-    ) -> Result<Option<(Ipv4Addr, u16, usize)>, Self::Error> {
+    ) -> Result<Option<(Ipv4Addr, u16, usize, bool)>, Self::Error> {
         let (w5500, UdpSocket(socket)) = self;
 
-        if w5500.read_u8(socket.at(SocketRegister::InterruptMask))? & 0x04 == 0 {
-            return Ok(None);
+        let (read_pointer, ip, port, size) = match w5500.read_udp_header(*socket)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let data_length = destination.len().min(usize::from(size));
+
+        w5500.read_from(
+            socket.rx_register_at(read_pointer.wrapping_add(UDP_HEADER_SIZE)),
+            &mut destination[..data_length],
+        )?;
+        w5500.release_rx_buffer(
+            *socket,
+            read_pointer
+                .wrapping_add(UDP_HEADER_SIZE)
+                .wrapping_add(size),
+        )?;
+
+        let truncated = data_length < usize::from(size);
+        if truncated {
+            debug!("{:?} truncated datagram of {} bytes", socket, size);
         }
+        Ok(Some((ip, port, usize::from(size), truncated)))
+    }
 
-        let receive_size = loop {
-            let s0 = w5500.read_u16(socket.at(SocketRegister::RxReceivedSize))?;
-            let s1 = w5500.read_u16(socket.at(SocketRegister::RxReceivedSize))?;
-            if s0 == s1 {
-                break s0 as usize;
-            }
-        };
-        if receive_size >= 8 {
-            let read_pointer = w5500.read_u16(socket.at(SocketRegister::RxReadPointer))?;
-
-            // |<-- read_pointer                                read_pointer + received_size -->|
-            // |Destination IP Address | Destination Port | Byte Size of DATA | Actual DATA ... |
-            // |   --- 4 Bytes ---     |  --- 2 Bytes --- |  --- 2 Bytes ---  |      ....       |
-
-            let ip = w5500.read_ip(socket.rx_register_at(read_pointer))?;
-            let port = w5500.read_u16(socket.rx_register_at(read_pointer + 4))?;
-            let data_length = destination
-                .len()
-                .min(w5500.read_u16(socket.rx_register_at(read_pointer + 6))? as usize);
-
-            w5500.read_from(
-                socket.rx_register_at(read_pointer + 8),
-                &mut destination[..data_length],
-            )?;
-
-            // reset
-            w5500.write_u16(
-                socket.at(SocketRegister::RxReadPointer),
-                read_pointer + receive_size as u16,
-            )?;
-            w5500.write_u8(
-                socket.at(SocketRegister::Command),
-                SocketCommand::Recv as u8,
-            )?;
-
-            Ok(Some((ip, port, data_length)))
+    /// Returns the payload length of the next UDP datagram without consuming it
+    fn peek_size(&mut self) -> Result<Option<usize>, Self::Error> {
+        Ok(self.peek_header()?.map(|(_, _, size)| size))
+    }
+
+    /// Returns the source, port and payload length of the next UDP datagram without consuming
+    /// it
+    fn peek_header(&mut self) -> Result<Option<(Ipv4Addr, u16, usize)>, Self::Error> {
+        let (w5500, UdpSocket(socket)) = self;
+        Ok(w5500
+            .read_udp_header(*socket)?
+            .map(|(_, ip, port, size)| (ip, port, usize::from(size))))
+    }
+
+    /// Queues a UDP datagram for sending to the specified IP and port. Returns
+    /// [`nb::Error::WouldBlock`] while the previous datagram is still being sent or the TX
+    /// buffer has not enough free space, and [`Error::BufferTooSmall`] if the datagram is
+    /// larger than the whole TX buffer. If the previous datagram failed with
+    /// [`Error::ArpTimeout`] and that has not been picked up with [`Udp::poll_send`], the error
+    /// is returned instead of queuing this one.
+    fn send(
+        &mut self,
+        host: &Ipv4Addr,
+        host_port: u16,
+        data: &[u8],
+    ) -> nb::Result<(), Self::Error> {
+        self.poll_send()?;
+        let (w5500, UdpSocket(socket)) = self;
+
+        let free_size = w5500.read_u16_stable(socket.at(SocketRegister::TxFreeSize))?;
+        if usize::from(free_size) < data.len() {
+            w5500.check_tx_capacity(*socket, data.len())?;
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let host_port = host_port.to_be_bytes();
+        w5500.write_to(
+            socket.at(SocketRegister::DestinationIp),
+            &[
+                host.octets[0],
+                host.octets[1],
+                host.octets[2],
+                host.octets[3], // target IP
+                host_port[0],
+                host_port[1], // destination port
+            ],
+        )?;
+        w5500.send_tx_buffer(*socket, data)?;
+        Ok(())
+    }
+
+    /// Checks whether the datagram queued with [`Udp::send`] has been sent. Returns
+    /// [`nb::Error::WouldBlock`] while it is still in progress and [`Error::ArpTimeout`] if the
+    /// chip gave up resolving the MAC address of the host.
+    fn poll_send(&mut self) -> nb::Result<(), Self::Error> {
+        let (w5500, UdpSocket(socket)) = self;
+        let mask = 0x01 << socket.number();
+        if w5500.0.sending & mask == 0 {
+            return Ok(());
+        }
+
+        let interrupts = w5500.read_u8(socket.at(SocketRegister::Interrupt))?;
+        if interrupts & Interrupt::SendOk as u8 != 0 {
+            w5500.reset_interrupt(*socket, Interrupt::SendOk)?;
+            w5500.0.sending &= !mask;
+            Ok(())
+        } else if interrupts & Interrupt::Timeout as u8 != 0 {
+            debug!("{:?} ARP timeout", socket);
+            w5500.reset_interrupt(*socket, Interrupt::Timeout)?;
+            w5500.0.sending &= !mask;
+            Err(nb::Error::Other(Error::ArpTimeout))
         } else {
-            Ok(None)
+            Err(nb::Error::WouldBlock)
         }
     }
 
-    /// Sends a UDP packet to the specified IP and port, and blocks until it is fully sent
+    /// Sends a UDP packet to the specified IP and port, and blocks until it is fully sent.
+    /// Returns [`Error::ArpTimeout`] if the chip gave up resolving the MAC address of the host.
+    /// Only the retransmission timeout of the chip bounds the wait, prefer [`Udp::send`] and
+    /// [`Udp::poll_send`] or [`Udp::send_until`] with a clock.
     fn blocking_send(
         &mut self,
         host: &Ipv4Addr,
         host_port: u16,
         data: &[u8],
     ) -> Result<(), Self::Error> {
-        let (w5500, UdpSocket(socket)) = self;
+        block!(self.send(host, host_port, data))?;
+        block!(self.poll_send())
+    }
 
-        {
-            let local_port = w5500.read_u16(socket.at(SocketRegister::LocalPort))?;
-            let local_port = local_port.to_be_bytes();
-            let host_port = host_port.to_be_bytes();
-
-            w5500.write_to(
-                socket.at(SocketRegister::LocalPort),
-                &[
-                    local_port[0],
-                    local_port[1], // local port u16
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00, // destination mac
-                    host.octets[0],
-                    host.octets[1],
-                    host.octets[2],
-                    host.octets[3], // target IP
-                    host_port[0],
-                    host_port[1], // destination port (5354)
-                ],
-            )?;
-        }
-
-        let data_length = data.len() as u16;
-        {
-            let data_length = data_length.to_be_bytes();
+    /// Sends a UDP packet to the specified IP and port, and blocks until it is fully sent.
+    /// Returns [`clock::Error::Timeout`] if the deadline passes first and [`Error::ArpTimeout`]
+    /// if the chip gave up resolving the MAC address of the host. Waits for the previous
+    /// datagram and for TX buffer space first, see [`Udp::send`].
+    fn send_until<C: Clock>(
+        &mut self,
+        host: &Ipv4Addr,
+        host_port: u16,
+        data: &[u8],
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<(), clock::Error<Self::Error>> {
+        clock::block_until(clock, deadline_ms, || self.send(host, host_port, data))?;
+        clock::block_until(clock, deadline_ms, || self.poll_send())
+    }
 
-            // TODO why write [0x00, 0x00] at TxReadPointer at all?
-            // TODO Is TxWritePointer not sufficient enough?
-            w5500.write_to(
-                socket.at(SocketRegister::TxReadPointer),
-                &[0x00, 0x00, data_length[0], data_length[1]],
-            )?;
+    /// Blocks until a UDP packet is available or the deadline passes, see [`Udp::receive`]
+    fn receive_until<C: Clock>(
+        &mut self,
+        destination: &mut [u8],
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<(Ipv4Addr, u16, usize, bool), clock::Error<Self::Error>> {
+        loop {
+            if let Some(packet) = self.receive(destination)? {
+                return Ok(packet);
+            }
+            if clock.now_ms() >= deadline_ms {
+                return Err(clock::Error::Timeout);
+            }
         }
+    }
+}
 
-        w5500.write_to(
-            socket.tx_register_at(0x00_00),
-            &data[..data_length as usize],
-        )?;
+pub trait IntoTcpSocket<Error> {
+    fn try_into_tcp_socket(self, port: u16) -> Result<TcpSocket, Error>
+    where
+        Self: Sized;
+}
 
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>>
+    IntoTcpSocket<(UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)>
+    for (
+        &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        UninitializedSocket,
+    )
+{
+    /// Initialize a socket to operate in TCP mode. The socket can then either [`Tcp::listen`]
+    /// for or [`Tcp::connect`] to a remote host. If that fails, the socket is returned along
+    /// with the error.
+    fn try_into_tcp_socket(
+        self,
+        port: u16,
+    ) -> Result<TcpSocket, (UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)> {
+        let socket = (self.1).0;
+        self.0
+            .open_tcp(socket, port)
+            .map(|_| TcpSocket(socket))
+            .map_err(|error| (UninitializedSocket(socket), error))
+    }
+}
+
+/// TCP trait that defines connection handling, send and receive methods for TCP streams
+pub trait Tcp {
+    type Error;
+
+    fn listen(&mut self) -> Result<(), Self::Error>;
+
+    fn connect(&mut self, host: &Ipv4Addr, host_port: u16) -> Result<(), Self::Error>;
+
+    fn connect_until<C: Clock>(
+        &mut self,
+        host: &Ipv4Addr,
+        host_port: u16,
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<(), clock::Error<Self::Error>>;
+
+    fn status(&mut self) -> Result<SocketStatus, Self::Error>;
+
+    fn remote(&mut self) -> Result<(Ipv4Addr, u16), Self::Error>;
+
+    fn send(&mut self, data: &[u8]) -> nb::Result<usize, Self::Error>;
+
+    fn receive(&mut self, target_buffer: &mut [u8]) -> nb::Result<usize, Self::Error>;
+
+    fn disconnect(&mut self) -> Result<(), Self::Error>;
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> Tcp
+    for (&mut ActiveW5500<'_, '_, ChipSelect, Spi>, &TcpSocket)
+{
+    type Error = Error<Spi::Error, ChipSelect::Error>;
+
+    /// Waits for a remote host to connect to the local port
+    fn listen(&mut self) -> Result<(), Self::Error> {
+        let (w5500, TcpSocket(socket)) = self;
+        w5500.write_u8(
+            socket.at(SocketRegister::Command),
+            SocketCommand::Listen as u8,
+        )
+    }
+
+    /// Starts to connect to the specified IP and port. The connection is established once
+    /// [`Tcp::status`] reports [`SocketStatus::Established`].
+    fn connect(&mut self, host: &Ipv4Addr, host_port: u16) -> Result<(), Self::Error> {
+        let (w5500, TcpSocket(socket)) = self;
+        let host_port = host_port.to_be_bytes();
         w5500.write_to(
+            socket.at(SocketRegister::DestinationIp),
+            &[
+                host.octets[0],
+                host.octets[1],
+                host.octets[2],
+                host.octets[3], // target IP
+                host_port[0],
+                host_port[1], // destination port
+            ],
+        )?;
+        w5500.write_u8(
             socket.at(SocketRegister::Command),
-            &[SocketCommand::Send as u8],
+            SocketCommand::Connect as u8,
+        )
+    }
+
+    /// Reads the current state of the connection
+    fn status(&mut self) -> Result<SocketStatus, Self::Error> {
+        let (w5500, TcpSocket(socket)) = self;
+        Ok(w5500.read_u8(socket.at(SocketRegister::Status))?.into())
+    }
+
+    /// Returns the IP and port of the connected remote host
+    fn remote(&mut self) -> Result<(Ipv4Addr, u16), Self::Error> {
+        let (w5500, TcpSocket(socket)) = self;
+        let ip = w5500.read_ip(socket.at(SocketRegister::DestinationIp))?;
+        let port = w5500.read_u16(socket.at(SocketRegister::DestinationPort))?;
+        Ok((ip, port))
+    }
+
+    /// Queues as much of `data` as fits into the socket's TX buffer for sending and returns the
+    /// number of queued bytes. Returns [`nb::Error::WouldBlock`] while the TX buffer is full or
+    /// the previous send has not completed yet, [`Error::TcpTimeout`] if the connection timed
+    /// out and [`Error::InvalidState`] if it is not established.
+    fn send(&mut self, data: &[u8]) -> nb::Result<usize, Self::Error> {
+        let (w5500, TcpSocket(socket)) = self;
+        match SocketStatus::from(w5500.read_u8(socket.at(SocketRegister::Status))?) {
+            SocketStatus::Established | SocketStatus::CloseWait => {}
+            SocketStatus::Closed if w5500.is_interrupt_set(*socket, Interrupt::Timeout)? => {
+                w5500.reset_interrupt(*socket, Interrupt::Timeout)?;
+                return Err(nb::Error::Other(Error::TcpTimeout));
+            }
+            status => return Err(nb::Error::Other(Error::InvalidState(status))),
+        }
+        w5500.poll_send_complete(*socket)?;
+
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let free_size = w5500.read_u16_stable(socket.at(SocketRegister::TxFreeSize))?;
+        if free_size == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        let data_length = data.len().min(usize::from(free_size));
+        w5500.send_tx_buffer(*socket, &data[..data_length])?;
+        Ok(data_length)
+    }
+
+    /// Reads as much received data as fits into `destination` and returns the number of bytes
+    /// read. Returns [`nb::Error::WouldBlock`] if no data has been received.
+    fn receive(&mut self, destination: &mut [u8]) -> nb::Result<usize, Self::Error> {
+        let (w5500, TcpSocket(socket)) = self;
+
+        let receive_size = w5500.read_u16_stable(socket.at(SocketRegister::RxReceivedSize))?;
+        if receive_size == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        if destination.is_empty() {
+            return Ok(0);
+        }
+        let data_length = destination.len().min(usize::from(receive_size));
+
+        let read_pointer = w5500.read_u16(socket.at(SocketRegister::RxReadPointer))?;
+        w5500.read_from(
+            socket.rx_register_at(read_pointer),
+            &mut destination[..data_length],
         )?;
+        w5500.release_rx_buffer(*socket, read_pointer.wrapping_add(data_length as u16))?;
+        Ok(data_length)
+    }
+
+    /// Starts to gracefully close the connection. The socket is closed once [`Tcp::status`]
+    /// reports [`SocketStatus::Closed`].
+    fn disconnect(&mut self) -> Result<(), Self::Error> {
+        let (w5500, TcpSocket(socket)) = self;
+        w5500.write_u8(
+            socket.at(SocketRegister::Command),
+            SocketCommand::Disconnect as u8,
+        )
+    }
 
-        for _ in 0..0xFFFF {
-            // wait until sent
-            if w5500.is_interrupt_set(*socket, Interrupt::SendOk)? {
-                w5500.reset_interrupt(*socket, Interrupt::SendOk)?;
-                break;
+    /// Connects to the host and blocks until the connection is established. Returns
+    /// [`clock::Error::Timeout`] if the deadline passes first, [`Error::TcpTimeout`] if the host
+    /// did not answer and [`Error::InvalidState`] with [`SocketStatus::Closed`] if it refused
+    /// the connection.
+    fn connect_until<C: Clock>(
+        &mut self,
+        host: &Ipv4Addr,
+        host_port: u16,
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<(), clock::Error<Self::Error>> {
+        self.connect(host, host_port)?;
+        loop {
+            match self.status()? {
+                SocketStatus::Established | SocketStatus::CloseWait => return Ok(()),
+                SocketStatus::Closed => {
+                    let (w5500, TcpSocket(socket)) = self;
+                    return if w5500.is_interrupt_set(*socket, Interrupt::Timeout)? {
+                        w5500.reset_interrupt(*socket, Interrupt::Timeout)?;
+                        Err(clock::Error::Other(Error::TcpTimeout))
+                    } else {
+                        Err(clock::Error::Other(Error::InvalidState(
+                            SocketStatus::Closed,
+                        )))
+                    };
+                }
+                _ if clock.now_ms() >= deadline_ms => return Err(clock::Error::Timeout),
+                _ => {}
             }
         }
-        // restore listen state
-        w5500.write_to(
-            socket.at(SocketRegister::Mode),
-            &[
-                Protocol::UDP as u8,       // Socket Mode Register
-                SocketCommand::Open as u8, // Socket Command Register
-            ],
+    }
+}
+
+pub trait IntoMacRawSocket<Error> {
+    fn try_into_macraw_socket(self, filter: MacFilter) -> Result<MacRawSocket, Error>
+    where
+        Self: Sized;
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>>
+    IntoMacRawSocket<(UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)>
+    for (
+        &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        UninitializedSocket,
+    )
+{
+    /// Initialize a socket to send and receive raw Ethernet frames. Only [`Socket::Socket0`]
+    /// supports this mode, any other socket is returned with [`Error::UnsupportedSocket`].
+    fn try_into_macraw_socket(
+        self,
+        filter: MacFilter,
+    ) -> Result<MacRawSocket, (UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)> {
+        let socket = (self.1).0;
+        if socket != Socket::Socket0 {
+            return Err((
+                UninitializedSocket(socket),
+                Error::UnsupportedSocket(socket),
+            ));
+        }
+        self.0
+            .open_macraw(socket, filter)
+            .map(|_| MacRawSocket(socket))
+            .map_err(|error| (UninitializedSocket(socket), error))
+    }
+}
+
+/// MACRAW trait that defines send and receive methods for raw Ethernet frames
+pub trait MacRaw {
+    type Error;
+
+    fn receive_frame(&mut self, target_buffer: &mut [u8]) -> Result<Option<usize>, Self::Error>;
+
+    fn send_frame(&mut self, frame: &[u8]) -> nb::Result<(), Self::Error>;
+
+    fn set_mac_filter(&mut self, filter: MacFilter) -> Result<(), Self::Error>;
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> MacRaw
+    for (&mut ActiveW5500<'_, '_, ChipSelect, Spi>, &MacRawSocket)
+{
+    type Error = Error<Spi::Error, ChipSelect::Error>;
+
+    /// Returns the length of the next Ethernet frame if one is available and copies as much of
+    /// it as fits into `destination`. The frame is consumed either way, a returned length
+    /// larger than `destination` means that the frame has been truncated.
+    fn receive_frame(&mut self, destination: &mut [u8]) -> Result<Option<usize>, Self::Error> {
+        let (w5500, MacRawSocket(socket)) = self;
+
+        let receive_size = w5500.read_u16_stable(socket.at(SocketRegister::RxReceivedSize))?;
+        if receive_size < 2 {
+            return Ok(None);
+        }
+
+        // |<-- read_pointer                    read_pointer + frame_size -->|
+        // | Frame size including this header |      Ethernet frame ...      |
+        // |         --- 2 Bytes ---          |            ....              |
+
+        let read_pointer = w5500.read_u16(socket.at(SocketRegister::RxReadPointer))?;
+        let frame_size = w5500.read_u16(socket.rx_register_at(read_pointer))?;
+        let frame_length = usize::from(frame_size.saturating_sub(2));
+        let data_length = destination.len().min(frame_length);
+
+        w5500.read_from(
+            socket.rx_register_at(read_pointer.wrapping_add(2)),
+            &mut destination[..data_length],
+        )?;
+        w5500.release_rx_buffer(*socket, read_pointer.wrapping_add(frame_size.max(2)))?;
+
+        Ok(Some(frame_length))
+    }
+
+    /// Queues the Ethernet frame for sending. Returns [`nb::Error::WouldBlock`] while the
+    /// previous frame is still being sent or the TX buffer has not enough free space, and
+    /// [`Error::BufferTooSmall`] if the frame is larger than the whole TX buffer.
+    fn send_frame(&mut self, frame: &[u8]) -> nb::Result<(), Self::Error> {
+        let (w5500, MacRawSocket(socket)) = self;
+        w5500.poll_send_complete(*socket)?;
+
+        let free_size = w5500.read_u16_stable(socket.at(SocketRegister::TxFreeSize))?;
+        if usize::from(free_size) < frame.len() {
+            w5500.check_tx_capacity(*socket, frame.len())?;
+            return Err(nb::Error::WouldBlock);
+        }
+        w5500.send_tx_buffer(*socket, frame)?;
+        Ok(())
+    }
+
+    /// Reopens the socket with the given MAC filter setting. Frames that have been received
+    /// but not read yet are dropped.
+    fn set_mac_filter(&mut self, filter: MacFilter) -> Result<(), Self::Error> {
+        let (w5500, MacRawSocket(socket)) = self;
+        w5500.write_u8(
+            socket.at(SocketRegister::Command),
+            SocketCommand::Close as u8,
+        )?;
+        w5500.open_macraw(*socket, filter)
+    }
+}
+
+pub trait IntoIpRawSocket<Error> {
+    fn try_into_ipraw_socket(self, protocol: u8) -> Result<IpRawSocket, Error>
+    where
+        Self: Sized;
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>>
+    IntoIpRawSocket<(UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)>
+    for (
+        &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        UninitializedSocket,
+    )
+{
+    /// Initialize a socket to send and receive IP packets with the given protocol number, for
+    /// example 1 for ICMP. The chip adds and removes the IP header. If that fails, the socket
+    /// is returned along with the error.
+    fn try_into_ipraw_socket(
+        self,
+        protocol: u8,
+    ) -> Result<IpRawSocket, (UninitializedSocket, Error<Spi::Error, ChipSelect::Error>)> {
+        let socket = (self.1).0;
+        self.0
+            .open_ipraw(socket, protocol)
+            .map(|_| IpRawSocket(socket))
+            .map_err(|error| (UninitializedSocket(socket), error))
+    }
+}
+
+/// IPRAW trait that defines send and receive methods for the payload of IP packets
+pub trait IpRaw {
+    type Error;
+
+    fn receive_packet(
+        &mut self,
+        target_buffer: &mut [u8],
+    ) -> Result<Option<(Ipv4Addr, usize)>, Self::Error>;
+
+    fn send_packet(&mut self, host: &Ipv4Addr, data: &[u8]) -> nb::Result<(), Self::Error>;
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> IpRaw
+    for (&mut ActiveW5500<'_, '_, ChipSelect, Spi>, &IpRawSocket)
+{
+    type Error = Error<Spi::Error, ChipSelect::Error>;
+
+    /// Returns the source and the payload length of the next packet if one is available and
+    /// copies as much of the payload as fits into `destination`. The packet is consumed either
+    /// way, a returned length larger than `destination` means that it has been truncated.
+    fn receive_packet(
+        &mut self,
+        destination: &mut [u8],
+    ) -> Result<Option<(Ipv4Addr, usize)>, Self::Error> {
+        let (w5500, IpRawSocket(socket)) = self;
+
+        let receive_size = w5500.read_u16_stable(socket.at(SocketRegister::RxReceivedSize))?;
+        if receive_size < 6 {
+            return Ok(None);
+        }
+
+        // |<-- read_pointer                         read_pointer + 6 + size -->|
+        // | Source IP Address | Byte Size of DATA |       Actual DATA ...       |
+        // |  --- 4 Bytes ---  |  --- 2 Bytes ---  |            ....             |
+
+        let read_pointer = w5500.read_u16(socket.at(SocketRegister::RxReadPointer))?;
+        let ip = w5500.read_ip(socket.rx_register_at(read_pointer))?;
+        let size = w5500.read_u16(socket.rx_register_at(read_pointer.wrapping_add(4)))?;
+        let data_length = destination.len().min(usize::from(size));
+
+        w5500.read_from(
+            socket.rx_register_at(read_pointer.wrapping_add(6)),
+            &mut destination[..data_length],
         )?;
+        w5500.release_rx_buffer(*socket, read_pointer.wrapping_add(6).wrapping_add(size))?;
+
+        Ok(Some((ip, usize::from(size))))
+    }
+
+    /// Queues the payload for sending to the host. Returns [`nb::Error::WouldBlock`] while the
+    /// previous packet is still being sent or the TX buffer has not enough free space, and
+    /// [`Error::BufferTooSmall`] if the payload is larger than the whole TX buffer.
+    fn send_packet(&mut self, host: &Ipv4Addr, data: &[u8]) -> nb::Result<(), Self::Error> {
+        let (w5500, IpRawSocket(socket)) = self;
+        w5500.poll_send_complete(*socket)?;
+
+        let free_size = w5500.read_u16_stable(socket.at(SocketRegister::TxFreeSize))?;
+        if usize::from(free_size) < data.len() {
+            w5500.check_tx_capacity(*socket, data.len())?;
+            return Err(nb::Error::WouldBlock);
+        }
+        w5500.write_to(socket.at(SocketRegister::DestinationIp), &host.octets)?;
+        w5500.send_tx_buffer(*socket, data)?;
         Ok(())
     }
 }
@@ -722,6 +1709,7 @@ impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> Udp
 /// Offset addresses in each socket register
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SocketRegister {
     Mode = 0x0000,
     Command = 0x0001,
@@ -732,7 +1720,7 @@ pub enum SocketRegister {
     DestinationIp = 0x000C,
     DestinationPort = 0x0010,
     MaxSegmentSize = 0x0012,
-    // Reserved 0x0014
+    IpProtocol = 0x0014,
     TypeOfService = 0x0015,
     TimeToLive = 0x0016,
     // Reserved 0x0017 - 0x001D
@@ -750,9 +1738,86 @@ pub enum SocketRegister {
     // Reserved 0x0030 - 0xFFFF
 }
 
+impl SocketRegister {
+    /// Returns the socket register at the given offset, if there is one
+    fn from_address(address: u16) -> Option<SocketRegister> {
+        Some(match address {
+            0x0000 => SocketRegister::Mode,
+            0x0001 => SocketRegister::Command,
+            0x0002 => SocketRegister::Interrupt,
+            0x0003 => SocketRegister::Status,
+            0x0004 => SocketRegister::LocalPort,
+            0x0006 => SocketRegister::DestinationMac,
+            0x000C => SocketRegister::DestinationIp,
+            0x0010 => SocketRegister::DestinationPort,
+            0x0012 => SocketRegister::MaxSegmentSize,
+            0x0014 => SocketRegister::IpProtocol,
+            0x0015 => SocketRegister::TypeOfService,
+            0x0016 => SocketRegister::TimeToLive,
+            0x001E => SocketRegister::ReceiveBuffer,
+            0x001F => SocketRegister::TransmitBuffer,
+            0x0020 => SocketRegister::TxFreeSize,
+            0x0022 => SocketRegister::TxReadPointer,
+            0x0024 => SocketRegister::TxWritePointer,
+            0x0026 => SocketRegister::RxReceivedSize,
+            0x0028 => SocketRegister::RxReadPointer,
+            0x002A => SocketRegister::RxWritePointer,
+            0x002C => SocketRegister::InterruptMask,
+            0x002D => SocketRegister::FragmentOffset,
+            0x002F => SocketRegister::KeepAliveTimer,
+            _ => return None,
+        })
+    }
+}
+
+/// Socket status register values
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SocketStatus {
+    Closed,
+    Init,
+    Listen,
+    SynSent,
+    SynRecv,
+    Established,
+    FinWait,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+    Udp,
+    IpRaw,
+    MacRaw,
+    /// A status value not listed in the datasheet
+    Unknown(u8),
+}
+
+impl From<u8> for SocketStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => SocketStatus::Closed,
+            0x13 => SocketStatus::Init,
+            0x14 => SocketStatus::Listen,
+            0x15 => SocketStatus::SynSent,
+            0x16 => SocketStatus::SynRecv,
+            0x17 => SocketStatus::Established,
+            0x18 => SocketStatus::FinWait,
+            0x1A => SocketStatus::Closing,
+            0x1B => SocketStatus::TimeWait,
+            0x1C => SocketStatus::CloseWait,
+            0x1D => SocketStatus::LastAck,
+            0x22 => SocketStatus::Udp,
+            0x32 => SocketStatus::IpRaw,
+            0x42 => SocketStatus::MacRaw,
+            value => SocketStatus::Unknown(value),
+        }
+    }
+}
+
 /// Interrupt state bits
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Interrupt {
     SendOk = 1 << 4,
     Timeout = 1 << 3,
@@ -761,18 +1826,36 @@ pub enum Interrupt {
     Connected = 1, // 1 << 0
 }
 
+/// Common interrupt state bits
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommonInterrupt {
+    /// Another host sent an ARP request with the IP address of the chip as its source
+    Conflict = 1 << 7,
+    /// An ICMP destination unreachable message has been received
+    Unreachable = 1 << 6,
+    /// The PPPoE connection has been closed
+    PppoeClosed = 1 << 5,
+    /// A Wake-on-LAN magic packet has been received
+    MagicPacket = 1 << 4,
+}
+
 /// Register protocol mode bits
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Protocol {
     TCP = 0b0001,
     UDP = 0b0010,
+    IPRAW = 0b0011,
     MACRAW = 0b0100,
 }
 
 /// Bits for socket commands
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SocketCommand {
     Open = 0x01,
     Listen = 0x02,
@@ -785,8 +1868,27 @@ pub enum SocketCommand {
     Recv = 0x40,
 }
 
+impl SocketCommand {
+    /// Returns the command for the given value of the socket command register
+    fn from_u8(value: u8) -> Option<SocketCommand> {
+        Some(match value {
+            0x01 => SocketCommand::Open,
+            0x02 => SocketCommand::Listen,
+            0x04 => SocketCommand::Connect,
+            0x08 => SocketCommand::Disconnect,
+            0x10 => SocketCommand::Close,
+            0x20 => SocketCommand::Send,
+            0x21 => SocketCommand::SendMac,
+            0x22 => SocketCommand::SendKeep,
+            0x40 => SocketCommand::Recv,
+            _ => return None,
+        })
+    }
+}
+
 /// Identifiers for each socket on the W5500
 #[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Socket {
     Socket0,
     Socket1,
@@ -813,6 +1915,20 @@ impl Socket {
         }
     }
 
+    /// Gets the socket for the given number, which must be in `0..8`
+    fn from_number(number: u8) -> Socket {
+        match number {
+            0 => Socket::Socket0,
+            1 => Socket::Socket1,
+            2 => Socket::Socket2,
+            3 => Socket::Socket3,
+            4 => Socket::Socket4,
+            5 => Socket::Socket5,
+            6 => Socket::Socket6,
+            _ => Socket::Socket7,
+        }
+    }
+
     /// Returns the register address for a socket instance's TX
     fn tx_register_at(self, address: u16) -> Register {
         match self {
@@ -862,6 +1978,7 @@ impl Socket {
 
 /// Chip register names
 #[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Register {
     CommonRegister(u16),
 
@@ -901,7 +2018,7 @@ pub enum Register {
 impl Register {
     /// Gets the control bits to identify any given register
     fn control_byte(self) -> u8 {
-        #[allow(clippy::inconsistent_digit_grouping)]
+        #[allow(clippy::inconsistent_digit_grouping, clippy::unusual_byte_groupings)]
         match self {
             Register::CommonRegister(_) => 0b00000_000,
 
@@ -939,6 +2056,25 @@ impl Register {
         }
     }
 
+    /// Returns the socket whose register block is addressed by this register, if any
+    fn socket_register_block(self) -> Option<Socket> {
+        let control = self.control_byte();
+        if (control >> 3) & 0b11 == 0b01 {
+            Some(Socket::from_number(control >> 5))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the socket and the command byte if `data` written to this register
+    /// covers a socket command register
+    fn socket_command_in(self, data: &[u8]) -> Option<(Socket, u8)> {
+        let socket = self.socket_register_block()?;
+        let offset = (SocketRegister::Command as u16).checked_sub(self.address())?;
+        data.get(usize::from(offset))
+            .map(|command| (socket, *command))
+    }
+
     /// Returns the associated address as a u16
     fn address(self) -> u16 {
         match self {
@@ -978,3 +2114,33 @@ impl Register {
         }
     }
 }
+
+/// Formats a [`Register`] with the name of the [`SocketRegister`] it addresses, if any
+struct RegisterName(Register);
+
+impl core::fmt::Debug for RegisterName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let register = self.0;
+        match register
+            .socket_register_block()
+            .zip(SocketRegister::from_address(register.address()))
+        {
+            Some((socket, name)) => write!(f, "{:?}({:?})", socket, name),
+            None => write!(f, "{:?}", register),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for RegisterName {
+    fn format(&self, fmt: defmt::Formatter) {
+        let register = self.0;
+        match register
+            .socket_register_block()
+            .zip(SocketRegister::from_address(register.address()))
+        {
+            Some((socket, name)) => defmt::write!(fmt, "{}({})", socket, name),
+            None => defmt::write!(fmt, "{}", register),
+        }
+    }
+}
@@ -0,0 +1,1033 @@
+//! mDNS responder with DNS-SD service advertisement (RFC 6762, RFC 6763).
+//!
+//! The [`MdnsResponder`] joins the group 224.0.0.251 on port 5353 with a multicast UDP socket
+//! and publishes `<hostname>.local` together with the configured [`Service`]s. After probing
+//! for conflicts and announcing its records it answers A, PTR, SRV, TXT and ANY questions for
+//! them. All responses are sent to the group, legacy unicast queries from ports other than
+//! 5353 are ignored. A host probing for the same names at the same time is resolved by the
+//! tie-breaking of RFC 6762 section 8.2, the loser probes again after one second. The
+//! responder probes and announces again when the link comes back up,
+//! [`MdnsResponder::restart`] does so after the IP address changed, and
+//! [`MdnsResponder::goodbye`] withdraws all records before shutdown.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::mdns::{Event, MdnsResponder, Service};
+//! use w5500::Socket;
+//!
+//! let services = [Service {
+//!     instance: "Sensor 42",
+//!     service: "_http._tcp",
+//!     port: 80,
+//!     txt: &["path=/status"],
+//! }];
+//! let socket = w5500.take_socket(Socket::Socket4).unwrap();
+//! let mut mdns = MdnsResponder::new(&mut w5500, socket, "sensor-42", &services)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"));
+//!
+//! loop {
+//!     match mdns.poll(&mut w5500, now_ms()) {
+//!         Ok(Some(Event::Conflict)) => { /* pick another hostname */ }
+//!         _ => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use core::cmp::Ordering;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::clock::{self, Clock};
+use crate::{ActiveW5500, Error, Ipv4Addr, Register, Udp, UdpSocket, UninitializedSocket};
+
+/// UDP port of mDNS
+pub const PORT: u16 = 5353;
+/// IPv4 multicast group of mDNS
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// Number of services that can be advertised
+pub const MAX_SERVICES: usize = 15;
+
+/// Largest message the responder sends or receives, records that do not fit are left out
+const MESSAGE_SIZE: usize = 512;
+const HEADER_SIZE: usize = 12;
+/// Largest uncompressed name in wire format
+const MAX_NAME_SIZE: usize = 255;
+/// Number of compression pointers followed in a single name, guards against loops
+const MAX_POINTERS: usize = 16;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const OPCODE_MASK: u16 = 0x7800;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// Cache-flush bit of records, unicast-response bit of questions
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+/// TTL of records that contain the hostname (RFC 6762 section 10)
+const HOST_TTL: u32 = 120;
+/// TTL of all other records
+const OTHER_TTL: u32 = 4500;
+
+const PROBE_COUNT: u8 = 3;
+const PROBE_INTERVAL_MS: u64 = 250;
+const ANNOUNCE_COUNT: u8 = 2;
+const ANNOUNCE_INTERVAL_MS: u64 = 1_000;
+/// Time to wait for an IP address before probing
+const ADDRESS_RETRY_MS: u64 = 1_000;
+/// Time to wait before probing again after losing a tie-break
+const TIE_BREAK_DELAY_MS: u64 = 1_000;
+/// Number of records proposed for a single name by another host that are compared when
+/// breaking a tie, further records are ignored
+const MAX_PROPOSED: usize = 4;
+/// Largest record data compared uncompressed: priority, weight and port of SRV followed by a
+/// name
+const MAX_DATA_SIZE: usize = 6 + MAX_NAME_SIZE;
+
+const LOCAL: &str = "local";
+const SERVICES: &str = "_services._dns-sd._udp";
+
+/// Message sections, in the order of their counts in the header
+const QUESTION: usize = 0;
+const ANSWER: usize = 1;
+const AUTHORITY: usize = 2;
+const ADDITIONAL: usize = 3;
+
+/// DNS-SD service instance to advertise
+#[derive(Copy, Clone, Debug)]
+pub struct Service<'a> {
+    /// User-visible instance name, for example `Sensor 42`
+    pub instance: &'a str,
+    /// Service type and protocol, for example `_http._tcp`
+    pub service: &'a str,
+    /// Port the service listens on
+    pub port: u16,
+    /// `key=value` pairs of the TXT record
+    pub txt: &'a [&'a str],
+}
+
+/// State change reported by [`MdnsResponder::poll`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// Probing succeeded and all records have been announced
+    Announced,
+    /// Another host uses the hostname or a service instance name. The responder stops
+    /// answering, a new one with other names has to be created.
+    Conflict,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum State {
+    Probing { sent: u8, next_ms: u64 },
+    Announcing { sent: u8, next_ms: u64 },
+    Running,
+    Conflict,
+}
+
+/// Records the responder publishes, the index selects the service
+#[derive(Copy, Clone, PartialEq)]
+enum Record {
+    /// A record of the hostname
+    Address,
+    /// PTR record from the service enumeration name to the service type
+    Enumeration(usize),
+    /// PTR record from the service type to the instance
+    Pointer(usize),
+    /// SRV record of the instance
+    Server(usize),
+    /// TXT record of the instance
+    Text(usize),
+}
+
+impl Record {
+    fn bit(self) -> u64 {
+        match self {
+            Record::Address => 1,
+            Record::Enumeration(index) => 1 << (1 + 4 * index),
+            Record::Pointer(index) => 1 << (2 + 4 * index),
+            Record::Server(index) => 1 << (3 + 4 * index),
+            Record::Text(index) => 1 << (4 + 4 * index),
+        }
+    }
+
+    fn record_type(self) -> u16 {
+        match self {
+            Record::Address => TYPE_A,
+            Record::Enumeration(_) | Record::Pointer(_) => TYPE_PTR,
+            Record::Server(_) => TYPE_SRV,
+            Record::Text(_) => TYPE_TXT,
+        }
+    }
+
+    /// Unique records are owned by this host only, shared records by all hosts
+    fn is_unique(self) -> bool {
+        !matches!(self, Record::Enumeration(_) | Record::Pointer(_))
+    }
+
+    fn ttl(self) -> u32 {
+        match self {
+            Record::Address | Record::Server(_) => HOST_TTL,
+            _ => OTHER_TTL,
+        }
+    }
+}
+
+/// Name below `.local`, with an optional instance label that may contain dots
+#[derive(Copy, Clone)]
+struct Name<'r> {
+    instance: Option<&'r str>,
+    dotted: &'r str,
+}
+
+impl Name<'_> {
+    /// Writes the name in uncompressed wire format and returns its length
+    fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut position = 0;
+        let labels = self
+            .instance
+            .into_iter()
+            .chain(self.dotted.split('.'))
+            .chain(Some(LOCAL));
+        for label in labels {
+            let label = label.as_bytes();
+            if label.is_empty() || label.len() > 63 {
+                return None;
+            }
+            let end = position + 1 + label.len();
+            out.get_mut(position..end)?[1..].copy_from_slice(label);
+            out[position] = label.len() as u8;
+            position = end;
+        }
+        *out.get_mut(position)? = 0;
+        Some(position + 1)
+    }
+
+    /// Compares the name to one in uncompressed wire format, ignoring ASCII case
+    fn matches(&self, wire: &[u8]) -> bool {
+        let mut encoded = [0u8; MAX_NAME_SIZE];
+        match self.encode(&mut encoded) {
+            Some(length) => encoded[..length].eq_ignore_ascii_case(wire),
+            None => false,
+        }
+    }
+}
+
+/// Record data to write
+enum Data<'r> {
+    Address(Ipv4Addr),
+    Pointer(Name<'r>),
+    Server { port: u16, target: Name<'r> },
+    Text(&'r [&'r str]),
+}
+
+/// Writes a message section by section into a buffer
+struct Message<'b> {
+    buffer: &'b mut [u8],
+    position: usize,
+    counts: [u16; 4],
+}
+
+impl<'b> Message<'b> {
+    fn new(buffer: &'b mut [u8], flags: u16) -> Self {
+        buffer[..HEADER_SIZE].iter_mut().for_each(|byte| *byte = 0);
+        BigEndian::write_u16(&mut buffer[2..4], flags);
+        Message {
+            buffer,
+            position: HEADER_SIZE,
+            counts: [0; 4],
+        }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.position + bytes.len();
+        self.buffer
+            .get_mut(self.position..end)?
+            .copy_from_slice(bytes);
+        self.position = end;
+        Some(())
+    }
+
+    fn put_name(&mut self, name: Name) -> Option<()> {
+        let length = name.encode(self.buffer.get_mut(self.position..)?)?;
+        self.position += length;
+        Some(())
+    }
+
+    fn question(&mut self, name: Name, question_type: u16, class: u16) {
+        let start = self.position;
+        let written = self.put_name(name).and_then(|_| {
+            self.put(&question_type.to_be_bytes())?;
+            self.put(&class.to_be_bytes())
+        });
+        match written {
+            Some(()) => self.counts[QUESTION] += 1,
+            None => self.position = start,
+        }
+    }
+
+    /// Appends a record, or leaves it out if it does not fit
+    fn record(
+        &mut self,
+        section: usize,
+        name: Name,
+        record_type: u16,
+        class: u16,
+        ttl: u32,
+        data: &Data,
+    ) {
+        let start = self.position;
+        match self.put_record(name, record_type, class, ttl, data) {
+            Some(()) => self.counts[section] += 1,
+            None => self.position = start,
+        }
+    }
+
+    fn put_record(
+        &mut self,
+        name: Name,
+        record_type: u16,
+        class: u16,
+        ttl: u32,
+        data: &Data,
+    ) -> Option<()> {
+        self.put_name(name)?;
+        self.put(&record_type.to_be_bytes())?;
+        self.put(&class.to_be_bytes())?;
+        self.put(&ttl.to_be_bytes())?;
+        let length_position = self.position;
+        self.put(&[0, 0])?;
+        self.put_data(data)?;
+        let length = (self.position - length_position - 2) as u16;
+        BigEndian::write_u16(&mut self.buffer[length_position..], length);
+        Some(())
+    }
+
+    /// Writes record data with uncompressed names
+    fn put_data(&mut self, data: &Data) -> Option<()> {
+        match data {
+            Data::Address(ip) => self.put(&ip.octets)?,
+            Data::Pointer(target) => self.put_name(*target)?,
+            Data::Server { port, target } => {
+                // priority and weight
+                self.put(&[0, 0, 0, 0])?;
+                self.put(&port.to_be_bytes())?;
+                self.put_name(*target)?;
+            }
+            Data::Text([]) => self.put(&[0])?,
+            Data::Text(entries) => {
+                for entry in entries.iter() {
+                    let entry = entry.as_bytes();
+                    self.put(&[entry.len().min(255) as u8])?;
+                    self.put(&entry[..entry.len().min(255)])?;
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Writes the section counts and returns the length of the message
+    fn finish(self) -> usize {
+        for (index, count) in self.counts.iter().enumerate() {
+            BigEndian::write_u16(&mut self.buffer[4 + 2 * index..], *count);
+        }
+        self.position
+    }
+}
+
+/// mDNS responder, see the [module documentation](self)
+pub struct MdnsResponder<'a> {
+    socket: UdpSocket,
+    hostname: &'a str,
+    services: &'a [Service<'a>],
+    ip: Ipv4Addr,
+    state: State,
+    link_up: bool,
+    buffer: [u8; MESSAGE_SIZE],
+}
+
+impl<'a> MdnsResponder<'a> {
+    /// Opens the socket in multicast mode and creates a responder that publishes
+    /// `<hostname>.local` and the given services. At most [`MAX_SERVICES`] services are
+    /// advertised. Probing starts with the first call of [`MdnsResponder::poll`].
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: UninitializedSocket,
+        hostname: &'a str,
+        services: &'a [Service<'a>],
+    ) -> Result<Self, Error<Spi::Error, ChipSelect::Error>> {
+        let socket = socket.0;
+        w5500.open_udp_multicast(socket, PORT, GROUP)?;
+        Ok(MdnsResponder {
+            socket: UdpSocket(socket),
+            hostname,
+            services: &services[..services.len().min(MAX_SERVICES)],
+            ip: Ipv4Addr::UNSPECIFIED,
+            state: State::Probing {
+                sent: 0,
+                next_ms: 0,
+            },
+            link_up: false,
+            buffer: [0u8; MESSAGE_SIZE],
+        })
+    }
+
+    /// Probes and announces the records again, for example after the IP address of the chip
+    /// changed. This happens automatically when the link comes back up.
+    pub fn restart(&mut self) {
+        self.state = State::Probing {
+            sent: 0,
+            next_ms: 0,
+        };
+    }
+
+    /// Processes queries and sends probes and announcements when they are due. Should be
+    /// called at least every 250 milliseconds while probing.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        let link_up = w5500.phy_cfg()?.link_up();
+        if link_up && !self.link_up && self.state != State::Conflict {
+            debug!("mDNS link up, probing again");
+            self.restart();
+        }
+        self.link_up = link_up;
+
+        while let Some((_, port, length, _)) =
+            (&mut *w5500, &self.socket).receive(&mut self.buffer)?
+        {
+            if port != PORT || self.state == State::Conflict {
+                continue;
+            }
+            // the questions come first, so a truncated message can still be answered
+            match self.handle_message(length.min(MESSAGE_SIZE)) {
+                Some(Reply::Conflict) => {
+                    debug!("mDNS conflict in state {:?}", self.state);
+                    self.state = State::Conflict;
+                    return Ok(Some(Event::Conflict));
+                }
+                Some(Reply::Defer) => {
+                    debug!("mDNS lost the tie-break, probing again");
+                    self.state = State::Probing {
+                        sent: 0,
+                        next_ms: now_ms + TIE_BREAK_DELAY_MS,
+                    };
+                }
+                Some(Reply::Answer {
+                    answers,
+                    additionals,
+                }) if self.state == State::Running => {
+                    let sent = self.send_records(w5500, answers, additionals)?;
+                    if !sent {
+                        debug!("mDNS answer dropped, the previous message is still being sent");
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match self.state {
+            State::Probing { sent, next_ms } if now_ms >= next_ms => {
+                if sent == 0 {
+                    self.ip = w5500.read_ip(Register::CommonRegister(0x00_0F_u16))?;
+                    if self.ip == Ipv4Addr::UNSPECIFIED {
+                        self.state = State::Probing {
+                            sent,
+                            next_ms: now_ms + ADDRESS_RETRY_MS,
+                        };
+                        return Ok(None);
+                    }
+                }
+                if sent < PROBE_COUNT {
+                    // retried on the next poll while the previous message is being sent
+                    if self.send_probe(w5500)? {
+                        self.state = State::Probing {
+                            sent: sent + 1,
+                            next_ms: now_ms + PROBE_INTERVAL_MS,
+                        };
+                    }
+                } else {
+                    self.state = State::Announcing {
+                        sent: 0,
+                        next_ms: now_ms,
+                    };
+                }
+            }
+            _ => {}
+        }
+
+        match self.state {
+            State::Announcing { sent, next_ms } if now_ms >= next_ms => {
+                if !self.send_records(w5500, self.all_records(), 0)? {
+                    return Ok(None);
+                }
+                if sent + 1 >= ANNOUNCE_COUNT {
+                    self.state = State::Running;
+                    return Ok(Some(Event::Announced));
+                }
+                self.state = State::Announcing {
+                    sent: sent + 1,
+                    next_ms: now_ms + ANNOUNCE_INTERVAL_MS,
+                };
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Withdraws all records with a goodbye packet and returns the socket. Waits until the
+    /// packet has been sent or the deadline passes, other hosts then keep the records until
+    /// their TTL expires.
+    pub fn goodbye<ChipSelect: OutputPin, Spi: FullDuplex<u8>, C: Clock>(
+        self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        clock: &C,
+        deadline_ms: u64,
+    ) -> Result<UdpSocket, Error<Spi::Error, ChipSelect::Error>> {
+        if matches!(self.state, State::Announcing { .. } | State::Running) {
+            let mut buffer = [0u8; MESSAGE_SIZE];
+            let length = self.encode_records(&mut buffer, self.all_records(), 0, true);
+            match (&mut *w5500, &self.socket).send_until(
+                &GROUP,
+                PORT,
+                &buffer[..length],
+                clock,
+                deadline_ms,
+            ) {
+                Ok(()) => {}
+                Err(clock::Error::Timeout) => debug!("mDNS goodbye not sent before the deadline"),
+                Err(clock::Error::Other(error)) => return Err(error),
+            }
+        }
+        Ok(self.socket)
+    }
+
+    fn records(&self) -> impl Iterator<Item = Record> {
+        core::iter::once(Record::Address).chain((0..self.services.len()).flat_map(|index| {
+            [
+                Record::Enumeration(index),
+                Record::Pointer(index),
+                Record::Server(index),
+                Record::Text(index),
+            ]
+        }))
+    }
+
+    fn all_records(&self) -> u64 {
+        self.records().fold(0, |mask, record| mask | record.bit())
+    }
+
+    fn host(&self) -> Name<'a> {
+        Name {
+            instance: None,
+            dotted: self.hostname,
+        }
+    }
+
+    fn instance(&self, index: usize) -> Name<'a> {
+        Name {
+            instance: Some(self.services[index].instance),
+            dotted: self.services[index].service,
+        }
+    }
+
+    fn record_name(&self, record: Record) -> Name<'a> {
+        match record {
+            Record::Address => self.host(),
+            Record::Enumeration(_) => Name {
+                instance: None,
+                dotted: SERVICES,
+            },
+            Record::Pointer(index) => Name {
+                instance: None,
+                dotted: self.services[index].service,
+            },
+            Record::Server(index) | Record::Text(index) => self.instance(index),
+        }
+    }
+
+    fn record_data(&self, record: Record) -> Data<'a> {
+        match record {
+            Record::Address => Data::Address(self.ip),
+            Record::Enumeration(index) => Data::Pointer(Name {
+                instance: None,
+                dotted: self.services[index].service,
+            }),
+            Record::Pointer(index) => Data::Pointer(self.instance(index)),
+            Record::Server(index) => Data::Server {
+                port: self.services[index].port,
+                target: self.host(),
+            },
+            Record::Text(index) => Data::Text(self.services[index].txt),
+        }
+    }
+
+    /// Records RFC 6763 section 12 recommends to add to an answer
+    fn additional_records(&self, record: Record) -> u64 {
+        match record {
+            Record::Pointer(index) => {
+                Record::Server(index).bit() | Record::Text(index).bit() | Record::Address.bit()
+            }
+            Record::Server(_) => Record::Address.bit(),
+            _ => 0,
+        }
+    }
+
+    fn write_record(&self, message: &mut Message, section: usize, record: Record, goodbye: bool) {
+        let mut class = CLASS_IN;
+        if record.is_unique() && !goodbye {
+            class |= CLASS_TOP_BIT;
+        }
+        let ttl = if goodbye { 0 } else { record.ttl() };
+        message.record(
+            section,
+            self.record_name(record),
+            record.record_type(),
+            class,
+            ttl,
+            &self.record_data(record),
+        );
+    }
+
+    /// Sends a probe, returns `false` if the previous message is still being sent
+    fn send_probe<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+        let mut buffer = [0u8; MESSAGE_SIZE];
+        let mut message = Message::new(&mut buffer, 0);
+        // one question per unique name, the proposed records go into the authority section
+        for record in self.records() {
+            if let Record::Address | Record::Server(_) = record {
+                message.question(self.record_name(record), TYPE_ANY, CLASS_IN | CLASS_TOP_BIT);
+            }
+        }
+        for record in self.records().filter(|record| record.is_unique()) {
+            self.write_record(&mut message, AUTHORITY, record, false);
+        }
+        let length = message.finish();
+        self.send(w5500, &buffer[..length])
+    }
+
+    /// Sends a response, returns `false` if the previous message is still being sent
+    fn send_records<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        answers: u64,
+        additionals: u64,
+    ) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+        let mut buffer = [0u8; MESSAGE_SIZE];
+        let length = self.encode_records(&mut buffer, answers, additionals, false);
+        self.send(w5500, &buffer[..length])
+    }
+
+    /// Writes a response with the records into the buffer and returns its length
+    fn encode_records(
+        &self,
+        buffer: &mut [u8; MESSAGE_SIZE],
+        answers: u64,
+        additionals: u64,
+        goodbye: bool,
+    ) -> usize {
+        let mut message = Message::new(buffer, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+        for record in self.records() {
+            if answers & record.bit() != 0 {
+                self.write_record(&mut message, ANSWER, record, goodbye);
+            }
+        }
+        for record in self.records() {
+            if additionals & !answers & record.bit() != 0 {
+                self.write_record(&mut message, ADDITIONAL, record, goodbye);
+            }
+        }
+        message.finish()
+    }
+
+    /// Queues the message for the group, returns `false` if the previous message is still
+    /// being sent or the TX buffer has no room for it
+    fn send<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        message: &[u8],
+    ) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+        match (&mut *w5500, &self.socket).send(&GROUP, PORT, message) {
+            Ok(()) => Ok(true),
+            Err(nb::Error::WouldBlock) => Ok(false),
+            Err(nb::Error::Other(error)) => Err(error),
+        }
+    }
+
+    /// Compares the records another host proposes in the authority section of its probe to
+    /// ours, name by name, and returns whether they are lexicographically later (RFC 6762
+    /// section 8.2). Identical records are not a conflict.
+    fn loses_tie_break(&self, message: &[u8], offset: usize, authority_count: u16) -> bool {
+        // the unique records of every name, ordered by type
+        let names = core::iter::once((self.host(), [Record::Address; 2], 1)).chain(
+            (0..self.services.len()).map(|index| {
+                (
+                    self.instance(index),
+                    [Record::Text(index), Record::Server(index)],
+                    2,
+                )
+            }),
+        );
+        for (name, ours, ours_count) in names {
+            let mut proposed = [Proposed {
+                class: 0,
+                record_type: 0,
+                offset: 0,
+                length: 0,
+            }; MAX_PROPOSED];
+            let mut proposed_count = 0;
+            let mut position = offset;
+            let mut owner = [0u8; MAX_NAME_SIZE];
+            for _ in 0..authority_count {
+                let (owner_length, end) = match read_name(message, position, &mut owner) {
+                    Some(name) => name,
+                    None => return false,
+                };
+                let record = match message.get(end..end + 10) {
+                    Some(record) => record,
+                    None => return false,
+                };
+                let length = usize::from(BigEndian::read_u16(&record[8..10]));
+                position = end + 10 + length;
+                if proposed_count < MAX_PROPOSED && name.matches(&owner[..owner_length]) {
+                    proposed[proposed_count] = Proposed {
+                        class: BigEndian::read_u16(&record[2..4]),
+                        record_type: BigEndian::read_u16(&record[0..2]),
+                        offset: end + 10,
+                        length,
+                    };
+                    proposed_count += 1;
+                }
+            }
+            if proposed_count == 0 {
+                continue;
+            }
+            let proposed = &mut proposed[..proposed_count];
+            // insertion sort, the lists are tiny
+            for index in 1..proposed.len() {
+                let mut current = index;
+                while current > 0 {
+                    let mut data = [0u8; MAX_DATA_SIZE];
+                    let previous = proposed[current - 1];
+                    let ordering = match previous.data(message, &mut data) {
+                        Some(data) => proposed[current].compare(
+                            message,
+                            previous.class,
+                            previous.record_type,
+                            data,
+                        ),
+                        None => Ordering::Equal,
+                    };
+                    if ordering != Ordering::Less {
+                        break;
+                    }
+                    proposed.swap(current - 1, current);
+                    current -= 1;
+                }
+            }
+
+            let ours = &ours[..ours_count];
+            let mut ordering = Ordering::Equal;
+            for (theirs, ours) in proposed.iter().zip(ours.iter()) {
+                let mut buffer = [0u8; MESSAGE_SIZE];
+                let mut data = Message {
+                    buffer: &mut buffer,
+                    position: 0,
+                    counts: [0; 4],
+                };
+                if data.put_data(&self.record_data(*ours)).is_none() {
+                    break;
+                }
+                let length = data.position;
+                ordering = theirs.compare(message, CLASS_IN, ours.record_type(), &buffer[..length]);
+                if ordering != Ordering::Equal {
+                    break;
+                }
+            }
+            if ordering == Ordering::Equal {
+                ordering = proposed.len().cmp(&ours.len());
+            }
+            if ordering == Ordering::Greater {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Parses a received message and decides how to react to it
+    fn handle_message(&self, length: usize) -> Option<Reply> {
+        let message = self.buffer.get(..length)?;
+        let header = message.get(..HEADER_SIZE)?;
+        let flags = BigEndian::read_u16(&header[2..4]);
+        if flags & OPCODE_MASK != 0 {
+            return None;
+        }
+        let question_count = BigEndian::read_u16(&header[4..6]);
+        let record_count = BigEndian::read_u16(&header[6..8])
+            .saturating_add(BigEndian::read_u16(&header[8..10]))
+            .saturating_add(BigEndian::read_u16(&header[10..12]));
+
+        let mut name = [0u8; MAX_NAME_SIZE];
+        let mut position = HEADER_SIZE;
+        let mut answers = 0;
+        let mut additionals = 0;
+        for _ in 0..question_count {
+            let (name_length, end) = read_name(message, position, &mut name)?;
+            let question = message.get(end..end + 4)?;
+            let question_type = BigEndian::read_u16(&question[0..2]);
+            let class = BigEndian::read_u16(&question[2..4]) & !CLASS_TOP_BIT;
+            position = end + 4;
+            if class != CLASS_IN && class != CLASS_ANY {
+                continue;
+            }
+            for record in self.records() {
+                if (question_type == TYPE_ANY || question_type == record.record_type())
+                    && self.record_name(record).matches(&name[..name_length])
+                {
+                    answers |= record.bit();
+                    additionals |= self.additional_records(record);
+                }
+            }
+        }
+
+        if flags & FLAG_RESPONSE == 0 {
+            if let State::Probing { sent, .. } = self.state {
+                // a probe of another host carries its proposed records in the authority section
+                let answer_count = BigEndian::read_u16(&header[6..8]);
+                let authority_count = BigEndian::read_u16(&header[8..10]);
+                if sent > 0 && authority_count > 0 {
+                    for _ in 0..answer_count {
+                        position = skip_record(message, position)?;
+                    }
+                    if self.loses_tie_break(message, position, authority_count) {
+                        return Some(Reply::Defer);
+                    }
+                }
+            }
+        } else {
+            for _ in 0..record_count {
+                let (name_length, end) = read_name(message, position, &mut name)?;
+                let record = message.get(end..end + 10)?;
+                let record_type = BigEndian::read_u16(&record[0..2]);
+                let data_length = usize::from(BigEndian::read_u16(&record[8..10]));
+                let data = message.get(end + 10..end + 10 + data_length)?;
+                position = end + 10 + data_length;
+
+                let owner = &name[..name_length];
+                let conflict = match self.state {
+                    State::Probing { .. } => self
+                        .records()
+                        .filter(|record| record.is_unique())
+                        .any(|record| self.record_name(record).matches(owner)),
+                    _ => {
+                        record_type == TYPE_A
+                            && data != self.ip.octets
+                            && self.host().matches(owner)
+                    }
+                };
+                if conflict {
+                    return Some(Reply::Conflict);
+                }
+            }
+            return None;
+        }
+
+        if answers == 0 {
+            None
+        } else {
+            Some(Reply::Answer {
+                answers,
+                additionals,
+            })
+        }
+    }
+}
+
+/// Reaction to a received message
+enum Reply {
+    Answer {
+        answers: u64,
+        additionals: u64,
+    },
+    Conflict,
+    /// Another host probes for one of the names with lexicographically later records
+    Defer,
+}
+
+/// Record another host proposes in its probe, the data is read from the message on demand
+#[derive(Copy, Clone)]
+struct Proposed {
+    class: u16,
+    record_type: u16,
+    offset: usize,
+    length: usize,
+}
+
+impl Proposed {
+    /// Record data with names decompressed
+    fn data<'d>(&self, message: &'d [u8], out: &'d mut [u8; MAX_DATA_SIZE]) -> Option<&'d [u8]> {
+        let data = message.get(self.offset..self.offset + self.length)?;
+        let prefix = match self.record_type {
+            TYPE_PTR => 0,
+            TYPE_SRV => 6,
+            _ => return Some(data),
+        };
+        out.get_mut(..prefix)?.copy_from_slice(data.get(..prefix)?);
+        let mut name = [0u8; MAX_NAME_SIZE];
+        let (length, _) = read_name(message, self.offset + prefix, &mut name)?;
+        out.get_mut(prefix..prefix + length)?
+            .copy_from_slice(&name[..length]);
+        Some(&out[..prefix + length])
+    }
+
+    /// Orders records by class without the cache-flush bit, type and data as RFC 6762
+    /// section 8.2 requires
+    fn compare(&self, message: &[u8], class: u16, record_type: u16, data: &[u8]) -> Ordering {
+        let mut out = [0u8; MAX_DATA_SIZE];
+        (self.class & !CLASS_TOP_BIT, self.record_type)
+            .cmp(&(class & !CLASS_TOP_BIT, record_type))
+            .then_with(|| self.data(message, &mut out).unwrap_or(&[]).cmp(data))
+    }
+}
+
+/// Skips the record at the offset and returns the offset behind it
+fn skip_record(message: &[u8], offset: usize) -> Option<usize> {
+    let mut name = [0u8; MAX_NAME_SIZE];
+    let (_, end) = read_name(message, offset, &mut name)?;
+    let data_length = usize::from(BigEndian::read_u16(message.get(end + 8..end + 10)?));
+    Some(end + 10 + data_length)
+}
+
+/// Reads the possibly compressed name at the offset in uncompressed wire format, returns its
+/// length and the offset behind it
+fn read_name(
+    message: &[u8],
+    offset: usize,
+    out: &mut [u8; MAX_NAME_SIZE],
+) -> Option<(usize, usize)> {
+    let mut length = 0;
+    let mut position = offset;
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let label_length = *message.get(position)?;
+        match label_length & 0xc0 {
+            0x00 => {
+                let label = message.get(position..position + 1 + usize::from(label_length))?;
+                out.get_mut(length..length + label.len())?
+                    .copy_from_slice(label);
+                length += label.len();
+                if label_length == 0 {
+                    return Some((length, end.unwrap_or(position + 1)));
+                }
+                position += label.len();
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                let low = *message.get(position + 1)?;
+                end = end.or(Some(position + 2));
+                position = usize::from(u16::from_be_bytes([label_length & 0x3f, low]));
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Socket;
+
+    const SERVICES: [Service; 1] = [Service {
+        instance: "Sensor",
+        service: "_http._tcp",
+        port: 80,
+        txt: &[],
+    }];
+
+    fn responder() -> MdnsResponder<'static> {
+        MdnsResponder {
+            socket: UdpSocket(Socket::Socket0),
+            hostname: "sensor",
+            services: &SERVICES,
+            ip: Ipv4Addr::new(192, 168, 0, 10),
+            state: State::Probing {
+                sent: 1,
+                next_ms: 0,
+            },
+            link_up: true,
+            buffer: [0u8; MESSAGE_SIZE],
+        }
+    }
+
+    /// Receives a probe of another host that proposes the given address for our hostname
+    fn probe(responder: &mut MdnsResponder, ip: Ipv4Addr) -> Option<Reply> {
+        let mut message = Message::new(&mut responder.buffer, 0);
+        let host = Name {
+            instance: None,
+            dotted: "sensor",
+        };
+        message.question(host, TYPE_ANY, CLASS_IN | CLASS_TOP_BIT);
+        message.record(
+            AUTHORITY,
+            host,
+            TYPE_A,
+            CLASS_IN,
+            HOST_TTL,
+            &Data::Address(ip),
+        );
+        let length = message.finish();
+        responder.handle_message(length)
+    }
+
+    #[test]
+    fn later_address_wins_the_tie_break() {
+        let mut responder = responder();
+        assert!(matches!(
+            probe(&mut responder, Ipv4Addr::new(192, 168, 0, 20)),
+            Some(Reply::Defer)
+        ));
+        assert!(!matches!(
+            probe(&mut responder, Ipv4Addr::new(192, 168, 0, 5)),
+            Some(Reply::Defer)
+        ));
+    }
+
+    #[test]
+    fn identical_records_are_no_conflict() {
+        let mut responder = responder();
+        assert!(!matches!(
+            probe(&mut responder, Ipv4Addr::new(192, 168, 0, 10)),
+            Some(Reply::Defer) | Some(Reply::Conflict)
+        ));
+    }
+
+    #[test]
+    fn probes_are_ignored_once_announced() {
+        let mut responder = responder();
+        responder.state = State::Running;
+        assert!(!matches!(
+            probe(&mut responder, Ipv4Addr::new(192, 168, 0, 20)),
+            Some(Reply::Defer) | Some(Reply::Conflict)
+        ));
+    }
+}
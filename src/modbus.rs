@@ -0,0 +1,900 @@
+//! Modbus TCP server.
+//!
+//! The [`ModbusServer`] listens on one [`Connection`] per hardware socket, so it serves as
+//! many masters at the same time as sockets are handed to it. Each connection decodes the
+//! MBAP header of the requests, dispatches the function codes 1 to 6, 15, 16 and 23 to the
+//! [`RegisterMap`] of the application and answers with the data or an [`Exception`].
+//! Pipelined requests are answered in order.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::modbus::{BitsMut, Connection, Exception, ModbusServer, RegisterMap, PORT};
+//! use w5500::Socket;
+//!
+//! struct Plant {
+//!     setpoints: [u16; 16],
+//!     pump_running: bool,
+//! }
+//!
+//! impl RegisterMap for Plant {
+//!     fn read_coils(
+//!         &mut self,
+//!         _unit_id: u8,
+//!         address: u16,
+//!         coils: &mut BitsMut<'_>,
+//!     ) -> Result<(), Exception> {
+//!         if address != 0 || coils.len() != 1 {
+//!             return Err(Exception::IllegalDataAddress);
+//!         }
+//!         coils.set(0, self.pump_running);
+//!         Ok(())
+//!     }
+//!
+//!     fn read_holding_registers(
+//!         &mut self,
+//!         _unit_id: u8,
+//!         address: u16,
+//!         registers: &mut [u16],
+//!     ) -> Result<(), Exception> {
+//!         let start = usize::from(address);
+//!         let values = self
+//!             .setpoints
+//!             .get(start..start + registers.len())
+//!             .ok_or(Exception::IllegalDataAddress)?;
+//!         registers.copy_from_slice(values);
+//!         Ok(())
+//!     }
+//!
+//!     fn write_registers(
+//!         &mut self,
+//!         _unit_id: u8,
+//!         address: u16,
+//!         registers: &[u16],
+//!     ) -> Result<(), Exception> {
+//!         let start = usize::from(address);
+//!         self.setpoints
+//!             .get_mut(start..start + registers.len())
+//!             .ok_or(Exception::IllegalDataAddress)?
+//!             .copy_from_slice(registers);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut connections = [
+//!     Connection::new(w5500.take_socket(Socket::Socket2).unwrap()),
+//!     Connection::new(w5500.take_socket(Socket::Socket3).unwrap()),
+//! ];
+//! let mut server = ModbusServer::new(PORT, &mut connections);
+//! let mut plant = Plant { setpoints: [0; 16], pump_running: false };
+//!
+//! loop {
+//!     let _ = server.poll(&mut w5500, &mut plant, now_ms());
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{
+    ActiveW5500, Error, SocketRegister, SocketStatus, Tcp, TcpSocket, UninitializedSocket,
+};
+
+/// TCP port masters connect to
+pub const PORT: u16 = 502;
+
+/// Longest request or response, MBAP header included
+pub const MAX_ADU_SIZE: usize = 260;
+
+/// Transaction ID, protocol ID, length and unit ID
+const MBAP_HEADER_SIZE: usize = 7;
+/// Longest value of the length field, unit ID and PDU of 253 bytes
+const MAX_LENGTH: usize = 254;
+const PROTOCOL_ID: u16 = 0;
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+const READ_WRITE_MULTIPLE_REGISTERS: u8 = 0x17;
+/// Set in the function code of exception responses
+const EXCEPTION_FLAG: u8 = 0x80;
+
+const COIL_ON: u16 = 0xFF00;
+const COIL_OFF: u16 = 0x0000;
+
+/// Most bits or registers a single request may read or write
+const MAX_READ_BITS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_WRITE_BITS: u16 = 1968;
+const MAX_WRITE_REGISTERS: u16 = 123;
+const MAX_READ_WRITE_REGISTERS: u16 = 121;
+
+/// Default time after which an idle connection is closed
+const DEFAULT_IDLE_TIMEOUT_MS: u64 = 60_000;
+
+/// Exception a [`RegisterMap`] answers a request with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Exception {
+    /// The function is not supported
+    IllegalFunction = 0x01,
+    /// The address range is not mapped
+    IllegalDataAddress = 0x02,
+    /// A value of the request is not allowed
+    IllegalDataValue = 0x03,
+    /// An unrecoverable error occurred while performing the request
+    ServerDeviceFailure = 0x04,
+    /// The request has been accepted but takes a long time to complete
+    Acknowledge = 0x05,
+    /// A long-running request is still in progress, the master should retry later
+    ServerDeviceBusy = 0x06,
+    /// The gateway has no path to the unit
+    GatewayPathUnavailable = 0x0A,
+    /// The unit behind the gateway did not respond
+    GatewayTargetDeviceFailedToRespond = 0x0B,
+}
+
+/// Coils or discrete inputs written by a request, packed eight to a byte
+#[derive(Copy, Clone, Debug)]
+pub struct Bits<'b> {
+    bytes: &'b [u8],
+    len: usize,
+}
+
+impl Bits<'_> {
+    /// Number of bits
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no bits
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The bit at the given index, relative to the start address
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    /// Iterates over all bits
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(move |index| self.get(index))
+    }
+}
+
+/// Coils or discrete inputs to be read by a request, all cleared initially
+#[derive(Debug)]
+pub struct BitsMut<'b> {
+    bytes: &'b mut [u8],
+    len: usize,
+}
+
+impl BitsMut<'_> {
+    /// Number of bits
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no bits
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The bit at the given index, relative to the start address
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    /// Sets the bit at the given index, relative to the start address. Indices beyond
+    /// [`BitsMut::len`] are ignored.
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index < self.len {
+            if value {
+                self.bytes[index / 8] |= 1 << (index % 8);
+            } else {
+                self.bytes[index / 8] &= !(1 << (index % 8));
+            }
+        }
+    }
+}
+
+/// The data model of the application. Every method answers a request for a contiguous range
+/// starting at `address` with as many elements as the slice holds, and is passed the unit ID
+/// of the request to tell apart the units behind a gateway. Functions that are not
+/// implemented answer with [`Exception::IllegalFunction`].
+pub trait RegisterMap {
+    /// Reads coils, function code 1
+    fn read_coils(
+        &mut self,
+        _unit_id: u8,
+        _address: u16,
+        _coils: &mut BitsMut<'_>,
+    ) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Reads discrete inputs, function code 2
+    fn read_discrete_inputs(
+        &mut self,
+        _unit_id: u8,
+        _address: u16,
+        _inputs: &mut BitsMut<'_>,
+    ) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Reads holding registers, function codes 3 and 23
+    fn read_holding_registers(
+        &mut self,
+        _unit_id: u8,
+        _address: u16,
+        _registers: &mut [u16],
+    ) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Reads input registers, function code 4
+    fn read_input_registers(
+        &mut self,
+        _unit_id: u8,
+        _address: u16,
+        _registers: &mut [u16],
+    ) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Writes coils, function codes 5 and 15
+    fn write_coils(
+        &mut self,
+        _unit_id: u8,
+        _address: u16,
+        _coils: &Bits<'_>,
+    ) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Writes holding registers, function codes 6, 16 and 23. Function code 23 writes
+    /// before it reads.
+    fn write_registers(
+        &mut self,
+        _unit_id: u8,
+        _address: u16,
+        _registers: &[u16],
+    ) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+}
+
+/// A socket the server listens on, together with its request buffer
+pub struct Connection {
+    socket: TcpSocket,
+    buffer: [u8; MAX_ADU_SIZE],
+    filled: usize,
+    connected: bool,
+    last_activity_ms: u64,
+}
+
+impl Connection {
+    /// Creates a connection on the socket, it is opened by [`ModbusServer::poll`]
+    pub fn new(socket: UninitializedSocket) -> Self {
+        Connection {
+            socket: TcpSocket(socket.0),
+            buffer: [0u8; MAX_ADU_SIZE],
+            filled: 0,
+            connected: false,
+            last_activity_ms: 0,
+        }
+    }
+
+    /// Returns the socket, which may still be connected
+    pub fn release(self) -> TcpSocket {
+        self.socket
+    }
+}
+
+/// Modbus TCP server, see the [module documentation](self)
+pub struct ModbusServer<'c> {
+    port: u16,
+    connections: &'c mut [Connection],
+    idle_timeout_ms: u64,
+}
+
+impl<'c> ModbusServer<'c> {
+    /// Creates a server that listens on the port with all given connections
+    pub fn new(port: u16, connections: &'c mut [Connection]) -> Self {
+        ModbusServer {
+            port,
+            connections,
+            idle_timeout_ms: DEFAULT_IDLE_TIMEOUT_MS,
+        }
+    }
+
+    /// Sets the time without requests after which a connection is closed, defaults to one
+    /// minute
+    pub fn with_idle_timeout(mut self, idle_timeout_ms: u64) -> Self {
+        self.idle_timeout_ms = idle_timeout_ms;
+        self
+    }
+
+    /// Reopens closed connections, receives requests and answers complete ones from the
+    /// register map. Connections that send malformed frames or time out are closed, only
+    /// failing to communicate with the chip is returned as error.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>, M: RegisterMap>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        map: &mut M,
+        now_ms: u64,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        for connection in self.connections.iter_mut() {
+            if !poll_connection(
+                w5500,
+                self.port,
+                self.idle_timeout_ms,
+                map,
+                connection,
+                now_ms,
+            )? {
+                debug!("Modbus connection on {:?} closed", connection.socket.0);
+                connection.filled = 0;
+                connection.connected = false;
+                (&mut *w5500, &connection.socket).disconnect()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves the connection and returns whether it is kept open
+fn poll_connection<ChipSelect: OutputPin, Spi: FullDuplex<u8>, M: RegisterMap>(
+    w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    port: u16,
+    idle_timeout_ms: u64,
+    map: &mut M,
+    connection: &mut Connection,
+    now_ms: u64,
+) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+    let Connection {
+        socket,
+        buffer,
+        filled,
+        connected,
+        last_activity_ms,
+    } = connection;
+
+    let status = (&mut *w5500, &*socket).status()?;
+    match status {
+        SocketStatus::Closed => {
+            w5500.open_tcp(socket.0, port)?;
+            (&mut *w5500, &*socket).listen()?;
+            *filled = 0;
+            *connected = false;
+            return Ok(true);
+        }
+        SocketStatus::Init => {
+            (&mut *w5500, &*socket).listen()?;
+            return Ok(true);
+        }
+        SocketStatus::Established | SocketStatus::CloseWait if *connected => {}
+        SocketStatus::Established | SocketStatus::CloseWait => {
+            *connected = true;
+            *last_activity_ms = now_ms;
+        }
+        _ => return Ok(true),
+    }
+
+    if *filled < MAX_ADU_SIZE {
+        match (&mut *w5500, &*socket).receive(&mut buffer[*filled..]) {
+            Ok(length) => {
+                *filled += length;
+                *last_activity_ms = now_ms;
+            }
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(error)) => return Err(error),
+        }
+    }
+
+    match w5500.poll_send_complete(socket.0) {
+        Ok(()) => {}
+        Err(nb::Error::WouldBlock) => return Ok(true),
+        Err(nb::Error::Other(error)) => return Err(error),
+    }
+    let free_size = w5500.read_u16_stable(socket.0.at(SocketRegister::TxFreeSize))?;
+    let mut queued = 0u16;
+    let mut valid = true;
+    let mut response = [0u8; MAX_ADU_SIZE];
+    while *filled >= MBAP_HEADER_SIZE {
+        let length = usize::from(BigEndian::read_u16(&buffer[4..6]));
+        if BigEndian::read_u16(&buffer[2..4]) != PROTOCOL_ID || !(2..=MAX_LENGTH).contains(&length)
+        {
+            debug!("Modbus invalid MBAP header on {:?}", socket.0);
+            valid = false;
+            break;
+        }
+        let frame_length = 6 + length;
+        if *filled < frame_length {
+            break;
+        }
+        if usize::from(free_size - queued) < MAX_ADU_SIZE {
+            // answer the rest once the pending responses have been sent
+            break;
+        }
+
+        let unit_id = buffer[6];
+        let request = &buffer[MBAP_HEADER_SIZE..frame_length];
+        let pdu_length = match process(map, unit_id, request, &mut response[MBAP_HEADER_SIZE..]) {
+            Ok(pdu_length) => pdu_length,
+            Err(exception) => {
+                debug!("Modbus function {} failed, {:?}", request[0], exception);
+                response[MBAP_HEADER_SIZE] = request[0] | EXCEPTION_FLAG;
+                response[MBAP_HEADER_SIZE + 1] = exception as u8;
+                2
+            }
+        };
+        response[..4].copy_from_slice(&buffer[..4]);
+        BigEndian::write_u16(&mut response[4..6], 1 + pdu_length as u16);
+        response[6] = unit_id;
+        let response_length = MBAP_HEADER_SIZE + pdu_length;
+        w5500.queue_tx_buffer(socket.0, queued, &response[..response_length])?;
+        queued += response_length as u16;
+
+        buffer.copy_within(frame_length..*filled, 0);
+        *filled -= frame_length;
+        *last_activity_ms = now_ms;
+    }
+    if queued > 0 {
+        w5500.commit_tx_buffer(socket.0, queued)?;
+    }
+
+    if !valid || (*filled == 0 && status == SocketStatus::CloseWait) {
+        return Ok(false);
+    }
+    Ok(now_ms.saturating_sub(*last_activity_ms) < idle_timeout_ms)
+}
+
+/// Performs the request PDU, writes the response PDU from the function code on and returns
+/// its length
+fn process<M: RegisterMap>(
+    map: &mut M,
+    unit_id: u8,
+    request: &[u8],
+    response: &mut [u8],
+) -> Result<usize, Exception> {
+    let function = request[0];
+    let data = &request[1..];
+    response[0] = function;
+    trace!("Modbus unit {} function {}", unit_id, function);
+    match function {
+        READ_COILS | READ_DISCRETE_INPUTS => {
+            let (address, quantity) = address_quantity(data, 4)?;
+            check_range(address, quantity, MAX_READ_BITS)?;
+            let byte_count = usize::from(quantity).div_ceil(8);
+            response[1] = byte_count as u8;
+            let bytes = &mut response[2..2 + byte_count];
+            bytes.fill(0);
+            let mut bits = BitsMut {
+                bytes,
+                len: usize::from(quantity),
+            };
+            if function == READ_COILS {
+                map.read_coils(unit_id, address, &mut bits)?;
+            } else {
+                map.read_discrete_inputs(unit_id, address, &mut bits)?;
+            }
+            Ok(2 + byte_count)
+        }
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let (address, quantity) = address_quantity(data, 4)?;
+            check_range(address, quantity, MAX_READ_REGISTERS)?;
+            let mut registers = [0u16; MAX_READ_REGISTERS as usize];
+            let registers = &mut registers[..usize::from(quantity)];
+            if function == READ_HOLDING_REGISTERS {
+                map.read_holding_registers(unit_id, address, registers)?;
+            } else {
+                map.read_input_registers(unit_id, address, registers)?;
+            }
+            Ok(1 + encode_registers(registers, &mut response[1..]))
+        }
+        WRITE_SINGLE_COIL => {
+            let (address, value) = address_quantity(data, 4)?;
+            let byte = match value {
+                COIL_ON => 1,
+                COIL_OFF => 0,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+            let coils = Bits {
+                bytes: &[byte],
+                len: 1,
+            };
+            map.write_coils(unit_id, address, &coils)?;
+            response[1..5].copy_from_slice(data);
+            Ok(5)
+        }
+        WRITE_SINGLE_REGISTER => {
+            let (address, value) = address_quantity(data, 4)?;
+            map.write_registers(unit_id, address, &[value])?;
+            response[1..5].copy_from_slice(data);
+            Ok(5)
+        }
+        WRITE_MULTIPLE_COILS => {
+            let (address, quantity) = address_quantity(data, 5 + byte_count(data, 4))?;
+            check_range(address, quantity, MAX_WRITE_BITS)?;
+            if usize::from(data[4]) != usize::from(quantity).div_ceil(8) {
+                return Err(Exception::IllegalDataValue);
+            }
+            let coils = Bits {
+                bytes: &data[5..],
+                len: usize::from(quantity),
+            };
+            map.write_coils(unit_id, address, &coils)?;
+            response[1..5].copy_from_slice(&data[..4]);
+            Ok(5)
+        }
+        WRITE_MULTIPLE_REGISTERS => {
+            let (address, quantity) = address_quantity(data, 5 + byte_count(data, 4))?;
+            check_range(address, quantity, MAX_WRITE_REGISTERS)?;
+            if usize::from(data[4]) != 2 * usize::from(quantity) {
+                return Err(Exception::IllegalDataValue);
+            }
+            let mut registers = [0u16; MAX_WRITE_REGISTERS as usize];
+            let registers = decode_registers(&data[5..], &mut registers);
+            map.write_registers(unit_id, address, registers)?;
+            response[1..5].copy_from_slice(&data[..4]);
+            Ok(5)
+        }
+        READ_WRITE_MULTIPLE_REGISTERS => {
+            let (read_address, read_quantity) = address_quantity(data, 9 + byte_count(data, 8))?;
+            let (write_address, write_quantity) =
+                address_quantity(&data[4..], 5 + byte_count(data, 8))?;
+            check_range(read_address, read_quantity, MAX_READ_REGISTERS)?;
+            check_range(write_address, write_quantity, MAX_READ_WRITE_REGISTERS)?;
+            if usize::from(data[8]) != 2 * usize::from(write_quantity) {
+                return Err(Exception::IllegalDataValue);
+            }
+            let mut registers = [0u16; MAX_READ_REGISTERS as usize];
+            let written = decode_registers(&data[9..], &mut registers);
+            map.write_registers(unit_id, write_address, written)?;
+            let registers = &mut registers[..usize::from(read_quantity)];
+            registers.fill(0);
+            map.read_holding_registers(unit_id, read_address, registers)?;
+            Ok(1 + encode_registers(registers, &mut response[1..]))
+        }
+        _ => Err(Exception::IllegalFunction),
+    }
+}
+
+/// Reads the address and quantity or value at the start of the request data, which has to
+/// be exactly `length` bytes long
+fn address_quantity(data: &[u8], length: usize) -> Result<(u16, u16), Exception> {
+    if data.len() != length || length < 4 {
+        return Err(Exception::IllegalDataValue);
+    }
+    Ok((
+        BigEndian::read_u16(&data[0..2]),
+        BigEndian::read_u16(&data[2..4]),
+    ))
+}
+
+/// The byte count field at the given index, zero if the request is too short for it
+fn byte_count(data: &[u8], index: usize) -> usize {
+    data.get(index).copied().map_or(0, usize::from)
+}
+
+fn check_range(address: u16, quantity: u16, max_quantity: u16) -> Result<(), Exception> {
+    if quantity == 0 || quantity > max_quantity {
+        return Err(Exception::IllegalDataValue);
+    }
+    if u32::from(address) + u32::from(quantity) > 0x1_0000 {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+/// Writes the byte count and the registers, returns the number of bytes written
+fn encode_registers(registers: &[u16], response: &mut [u8]) -> usize {
+    response[0] = (2 * registers.len()) as u8;
+    for (register, bytes) in registers.iter().zip(response[1..].chunks_exact_mut(2)) {
+        BigEndian::write_u16(bytes, *register);
+    }
+    1 + 2 * registers.len()
+}
+
+fn decode_registers<'r>(data: &[u8], registers: &'r mut [u16]) -> &'r [u16] {
+    let count = data.len() / 2;
+    for (register, bytes) in registers.iter_mut().zip(data.chunks_exact(2)) {
+        *register = BigEndian::read_u16(bytes);
+    }
+    &registers[..count]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::Replay;
+    use crate::{ArpResponses, ConnectionType, OnPingRequest, OnWakeOnLan, Socket, W5500};
+
+    /// Sixteen coils and holding registers, discrete inputs at odd addresses are set and
+    /// input registers hold their address
+    #[derive(Default)]
+    struct Map {
+        coils: [bool; 16],
+        registers: [u16; 16],
+    }
+
+    fn check(address: u16, length: usize) -> Result<usize, Exception> {
+        let address = usize::from(address);
+        if address + length > 16 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        Ok(address)
+    }
+
+    impl RegisterMap for Map {
+        fn read_coils(
+            &mut self,
+            _unit_id: u8,
+            address: u16,
+            coils: &mut BitsMut<'_>,
+        ) -> Result<(), Exception> {
+            let start = check(address, coils.len())?;
+            for index in 0..coils.len() {
+                coils.set(index, self.coils[start + index]);
+            }
+            Ok(())
+        }
+
+        fn read_discrete_inputs(
+            &mut self,
+            _unit_id: u8,
+            address: u16,
+            inputs: &mut BitsMut<'_>,
+        ) -> Result<(), Exception> {
+            for index in 0..inputs.len() {
+                inputs.set(index, (usize::from(address) + index) % 2 == 1);
+            }
+            Ok(())
+        }
+
+        fn read_holding_registers(
+            &mut self,
+            _unit_id: u8,
+            address: u16,
+            registers: &mut [u16],
+        ) -> Result<(), Exception> {
+            let start = check(address, registers.len())?;
+            registers.copy_from_slice(&self.registers[start..start + registers.len()]);
+            Ok(())
+        }
+
+        fn read_input_registers(
+            &mut self,
+            _unit_id: u8,
+            address: u16,
+            registers: &mut [u16],
+        ) -> Result<(), Exception> {
+            for (offset, register) in registers.iter_mut().enumerate() {
+                *register = address + offset as u16;
+            }
+            Ok(())
+        }
+
+        fn write_coils(
+            &mut self,
+            _unit_id: u8,
+            address: u16,
+            coils: &Bits<'_>,
+        ) -> Result<(), Exception> {
+            let start = check(address, coils.len())?;
+            for (index, coil) in coils.iter().enumerate() {
+                self.coils[start + index] = coil;
+            }
+            Ok(())
+        }
+
+        fn write_registers(
+            &mut self,
+            _unit_id: u8,
+            address: u16,
+            registers: &[u16],
+        ) -> Result<(), Exception> {
+            let start = check(address, registers.len())?;
+            self.registers[start..start + registers.len()].copy_from_slice(registers);
+            Ok(())
+        }
+    }
+
+    /// Processes the request PDU and checks the response PDU
+    fn assert_response(map: &mut Map, request: &[u8], expected: &[u8]) {
+        let mut response = [0u8; MAX_ADU_SIZE - MBAP_HEADER_SIZE];
+        let length = process(map, 1, request, &mut response).unwrap();
+        assert_eq!(&response[..length], expected);
+    }
+
+    fn exception(map: &mut Map, request: &[u8]) -> Exception {
+        let mut response = [0u8; MAX_ADU_SIZE - MBAP_HEADER_SIZE];
+        process(map, 1, request, &mut response).unwrap_err()
+    }
+
+    #[test]
+    fn reads_bits() {
+        let mut map = Map::default();
+        for address in [0, 2, 3, 8] {
+            map.coils[address] = true;
+        }
+        assert_response(&mut map, &[0x01, 0, 0, 0, 9], &[0x01, 2, 0b1101, 0b1]);
+        assert_response(&mut map, &[0x02, 0, 1, 0, 3], &[0x02, 1, 0b101]);
+        assert_eq!(
+            exception(&mut map, &[0x01, 0, 15, 0, 2]),
+            Exception::IllegalDataAddress
+        );
+    }
+
+    #[test]
+    fn reads_registers() {
+        let mut map = Map::default();
+        map.registers[1] = 0x1234;
+        map.registers[2] = 0xabcd;
+        assert_response(
+            &mut map,
+            &[0x03, 0, 1, 0, 2],
+            &[0x03, 4, 0x12, 0x34, 0xab, 0xcd],
+        );
+        assert_response(
+            &mut map,
+            &[0x04, 0x01, 0x00, 0, 2],
+            &[0x04, 4, 0x01, 0x00, 0x01, 0x01],
+        );
+    }
+
+    #[test]
+    fn writes_single_values() {
+        let mut map = Map::default();
+        assert_response(&mut map, &[0x05, 0, 2, 0xff, 0], &[0x05, 0, 2, 0xff, 0]);
+        assert!(map.coils[2]);
+        assert_response(&mut map, &[0x05, 0, 2, 0, 0], &[0x05, 0, 2, 0, 0]);
+        assert!(!map.coils[2]);
+        assert_eq!(
+            exception(&mut map, &[0x05, 0, 2, 0x12, 0x34]),
+            Exception::IllegalDataValue
+        );
+
+        assert_response(
+            &mut map,
+            &[0x06, 0, 3, 0xab, 0xcd],
+            &[0x06, 0, 3, 0xab, 0xcd],
+        );
+        assert_eq!(map.registers[3], 0xabcd);
+    }
+
+    #[test]
+    fn writes_multiple_values() {
+        let mut map = Map::default();
+        assert_response(
+            &mut map,
+            &[0x0f, 0, 1, 0, 10, 2, 0b1111_0000, 0b10],
+            &[0x0f, 0, 1, 0, 10],
+        );
+        let expected = [
+            false, false, false, false, false, true, true, true, true, false, true,
+        ];
+        assert_eq!(map.coils[..11], expected);
+
+        assert_response(
+            &mut map,
+            &[0x10, 0, 4, 0, 2, 4, 0, 1, 0, 2],
+            &[0x10, 0, 4, 0, 2],
+        );
+        assert_eq!(map.registers[4..6], [1, 2]);
+    }
+
+    #[test]
+    fn writes_before_reading() {
+        let mut map = Map::default();
+        map.registers[0] = 7;
+        assert_response(
+            &mut map,
+            &[0x17, 0, 0, 0, 2, 0, 1, 0, 1, 2, 0, 0x99],
+            &[0x17, 4, 0, 7, 0, 0x99],
+        );
+    }
+
+    #[test]
+    fn rejects_quantity_and_byte_count_mismatch() {
+        let mut map = Map::default();
+        for request in [
+            // request too short or too long
+            &[0x03, 0, 0, 0][..],
+            &[0x03, 0, 0, 0, 1, 0],
+            // quantity zero or too large
+            &[0x01, 0, 0, 0, 0],
+            &[0x03, 0, 0, 0, 126],
+            &[0x10, 0, 0, 0, 0, 0],
+            // byte count does not match the quantity
+            &[0x0f, 0, 0, 0, 10, 1, 0xff],
+            &[0x10, 0, 0, 0, 2, 2, 0, 1],
+            &[0x17, 0, 0, 0, 1, 0, 0, 0, 2, 2, 0, 1],
+            // byte count does not match the data
+            &[0x10, 0, 0, 0, 1, 2, 0, 1, 0],
+        ] {
+            assert_eq!(exception(&mut map, request), Exception::IllegalDataValue);
+        }
+    }
+
+    #[test]
+    fn rejects_address_overflow() {
+        let mut map = Map::default();
+        for request in [
+            &[0x02, 0xff, 0xff, 0, 2][..],
+            &[0x04, 0xff, 0xf0, 0, 17],
+            &[0x0f, 0xff, 0xff, 0, 9, 2, 0, 0],
+        ] {
+            assert_eq!(exception(&mut map, request), Exception::IllegalDataAddress);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_function() {
+        let mut map = Map::default();
+        assert_eq!(
+            exception(&mut map, &[0x2b, 0x0e, 1, 0]),
+            Exception::IllegalFunction
+        );
+    }
+
+    #[test]
+    fn closes_connection_on_invalid_header() {
+        for header in [
+            // protocol ID 1
+            [0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x01],
+            // no function code
+            [0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01],
+            // longer than MAX_LENGTH
+            [0x00, 0x01, 0x00, 0x00, 0x00, 0xff, 0x01],
+        ] {
+            let log = "R 00 0039 04\nW 04 0000 80\nW 04 0000 00\n\
+                       # SOCK_ESTABLISHED, nothing received and the TX buffer is empty\n\
+                       R 28 0003 17\nR 28 0026 0000\nR 28 0026 0000\n\
+                       R 28 0020 0800\nR 28 0020 0800\n\
+                       # DISCON\n\
+                       W 2c 0001 08\n";
+            let replay = Replay::new(log);
+            let mut spi = replay.spi();
+            let mut w5500 = W5500::with_initialisation(
+                replay.chip_select(),
+                &mut spi,
+                OnWakeOnLan::Ignore,
+                OnPingRequest::Respond,
+                ConnectionType::Ethernet,
+                ArpResponses::Cache,
+            )
+            .unwrap();
+            let mut w5500 = w5500.activate(&mut spi).unwrap();
+
+            let mut connection = Connection {
+                socket: TcpSocket(Socket::Socket1),
+                buffer: [0u8; MAX_ADU_SIZE],
+                filled: header.len(),
+                connected: true,
+                last_activity_ms: 0,
+            };
+            connection.buffer[..header.len()].copy_from_slice(&header);
+            let mut connections = [connection];
+            ModbusServer::new(PORT, &mut connections)
+                .poll(&mut w5500, &mut Map::default(), 0)
+                .unwrap();
+            assert_eq!(connections[0].filled, 0);
+            assert!(!connections[0].connected);
+            replay.finish().unwrap();
+        }
+    }
+}
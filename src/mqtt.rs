@@ -0,0 +1,1215 @@
+//! MQTT 3.1.1 client.
+//!
+//! The [`MqttClient`] connects its TCP socket to the broker on the first call of
+//! [`MqttClient::poll`] and sends CONNECT with the configured credentials and [`Will`]. Poll
+//! it regularly with a local millisecond clock: it receives PUBLISH and acknowledgement
+//! packets, sends PINGREQ to keep the connection alive and reconnects after the socket
+//! reports [`Interrupt::Disconnected`] or
+//! [`Interrupt::Timeout`], or after the broker stopped answering.
+//!
+//! QoS 1 publications as well as SUBSCRIBE and UNSUBSCRIBE packets are kept in an outbox of
+//! [`OUTBOX_SIZE`] bytes until the broker acknowledges them. They are retransmitted after
+//! each reconnect and, if the broker does not answer in time, after the retry interval. They
+//! may also be queued while the client is disconnected.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::mqtt::{Event, MqttClient, QoS, Will, PORT};
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! let socket = w5500.take_socket(Socket::Socket2).unwrap();
+//! let mut mqtt = MqttClient::new(socket, Ipv4Addr::new(192, 168, 0, 2), PORT, "sensor-17")
+//!     .with_keep_alive(30)
+//!     .with_will(Will {
+//!         topic: "sensors/17/online",
+//!         payload: b"0",
+//!         qos: QoS::AtLeastOnce,
+//!         retain: true,
+//!     });
+//!
+//! loop {
+//!     let subscribe = match mqtt.poll(&mut w5500, now_ms()) {
+//!         Ok(Some(Event::Connected { .. })) => true,
+//!         Ok(Some(Event::Message(message))) => {
+//!             // message.topic, message.payload, ...
+//!             false
+//!         }
+//!         _ => false,
+//!     };
+//!     if subscribe {
+//!         let _ = mqtt.subscribe(&mut w5500, &[("sensors/17/set", QoS::AtLeastOnce)], now_ms());
+//!         let _ = mqtt.publish(&mut w5500, "sensors/17/online", b"1", QoS::AtLeastOnce, true, now_ms());
+//!     }
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use core::convert::TryFrom;
+use core::ops::Range;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{
+    ActiveW5500, Interrupt, Ipv4Addr, SocketCommand, SocketRegister, SocketStatus, Tcp, TcpSocket,
+    UninitializedSocket,
+};
+
+/// TCP port brokers listen on for unencrypted connections
+pub const PORT: u16 = 1883;
+/// Size of the buffer for received packets, larger packets are dropped
+pub const RX_BUFFER_SIZE: usize = 1024;
+/// Size of the buffer for packets waiting for an acknowledgement
+pub const OUTBOX_SIZE: usize = 1024;
+/// Number of packets that can wait for an acknowledgement at the same time
+pub const MAX_IN_FLIGHT: usize = 8;
+/// Number of topic filters in a single SUBSCRIBE or UNSUBSCRIBE
+pub const MAX_TOPIC_FILTERS: usize = 8;
+
+const DEFAULT_KEEP_ALIVE_S: u16 = 60;
+const DEFAULT_RECONNECT_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_RETRY_INTERVAL_MS: u64 = 10_000;
+/// Time to wait for the TCP connection and the CONNACK
+const CONNECT_TIMEOUT_MS: u64 = 10_000;
+
+/// Largest fixed header: packet type and flags followed by up to four length bytes
+const MAX_HEADER_SIZE: usize = 5;
+const PROTOCOL_LEVEL: u8 = 4;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Flags of SUBSCRIBE and UNSUBSCRIBE, required by the specification
+const FLAGS_REQUIRED: u8 = 0b0010;
+const FLAG_DUP: u8 = 1 << 3;
+const FLAG_RETAIN: u8 = 1;
+
+const CONNECT_USERNAME: u8 = 1 << 7;
+const CONNECT_PASSWORD: u8 = 1 << 6;
+const CONNECT_WILL_RETAIN: u8 = 1 << 5;
+const CONNECT_WILL: u8 = 1 << 2;
+const CONNECT_CLEAN_SESSION: u8 = 1 << 1;
+
+/// Error returned when polling the client or queuing packets
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// QoS 0 messages can only be published while connected
+    NotConnected,
+    /// The packet does not fit into the outbox, even if it was empty, or CONNECT does not fit
+    /// into the receive buffer
+    TooLarge,
+    /// The topic filter list is empty or longer than [`MAX_TOPIC_FILTERS`]
+    InvalidRequest,
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// Quality of service of a publication or subscription
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    /// Delivered at most once, without acknowledgement
+    AtMostOnce = 0,
+    /// Delivered at least once, acknowledged with PUBACK
+    AtLeastOnce = 1,
+}
+
+/// Message the broker publishes when the client disconnects ungracefully
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// Message published by the broker
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Message<'m> {
+    pub topic: &'m str,
+    pub payload: &'m [u8],
+    pub qos: QoS,
+    pub retain: bool,
+    /// The broker may have delivered the message before
+    pub duplicate: bool,
+}
+
+/// Return codes of a SUBACK
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Granted {
+    codes: [u8; MAX_TOPIC_FILTERS],
+    count: u8,
+}
+
+impl Granted {
+    /// The granted QoS for each topic filter in the order of the SUBSCRIBE, `None` if the
+    /// broker refused the subscription
+    pub fn iter(&self) -> impl Iterator<Item = Option<QoS>> + '_ {
+        self.codes[..usize::from(self.count)]
+            .iter()
+            .map(|code| match code {
+                0 => Some(QoS::AtMostOnce),
+                1 => Some(QoS::AtLeastOnce),
+                _ => None,
+            })
+    }
+}
+
+/// Event returned by [`MqttClient::poll`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event<'e> {
+    /// The broker accepted the connection. Without a present session, subscriptions have to
+    /// be made again.
+    Connected { session_present: bool },
+    /// The broker refused the connection with the CONNACK return code, the client retries
+    /// after the reconnect interval
+    Refused { code: u8 },
+    /// The connection has been lost, the client reconnects after the reconnect interval
+    Disconnected,
+    /// The broker acknowledged the QoS 1 publication
+    Published { packet_id: u16 },
+    /// The broker acknowledged the subscription
+    Subscribed { packet_id: u16, granted: Granted },
+    /// The broker acknowledged the unsubscription
+    Unsubscribed { packet_id: u16 },
+    /// The broker published a message to a subscribed topic
+    Message(Message<'e>),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum State {
+    Disconnected { retry_ms: u64 },
+    Connecting { since_ms: u64 },
+    WaitConnAck { since_ms: u64 },
+    Connected,
+}
+
+/// Packet in the outbox, packets are stored back to back in the order they were queued
+#[derive(Copy, Clone, Default)]
+struct InFlight {
+    packet_id: u16,
+    length: usize,
+    /// `None` if not sent since the last connect
+    sent_ms: Option<u64>,
+}
+
+/// Result of a poll step, [`Event::Message`] is resolved against the receive buffer
+enum Output {
+    Event(Event<'static>),
+    Message {
+        topic: Range<usize>,
+        payload: Range<usize>,
+        qos: QoS,
+        retain: bool,
+        duplicate: bool,
+    },
+}
+
+/// MQTT client, see the [module documentation](self)
+pub struct MqttClient<'a> {
+    socket: TcpSocket,
+    broker: Ipv4Addr,
+    port: u16,
+    client_id: &'a str,
+    keep_alive_s: u16,
+    clean_session: bool,
+    will: Option<Will<'a>>,
+    username: Option<&'a str>,
+    password: Option<&'a [u8]>,
+    reconnect_interval_ms: u64,
+    retry_interval_ms: u64,
+    state: State,
+    next_packet_id: u16,
+    last_sent_ms: u64,
+    ping_sent_ms: Option<u64>,
+    in_flight: [InFlight; MAX_IN_FLIGHT],
+    in_flight_count: usize,
+    outbox: [u8; OUTBOX_SIZE],
+    rx: [u8; RX_BUFFER_SIZE],
+    rx_length: usize,
+    /// length of the packet at the start of `rx` that has been returned by the last poll
+    consumed: usize,
+    /// bytes of an oversized packet that still have to be dropped
+    discard: usize,
+}
+
+impl<'a> MqttClient<'a> {
+    /// Creates a client that connects to the broker at the given address and port with the
+    /// client identifier. The connection is established by [`MqttClient::poll`].
+    pub fn new(
+        socket: UninitializedSocket,
+        broker: Ipv4Addr,
+        port: u16,
+        client_id: &'a str,
+    ) -> Self {
+        MqttClient {
+            socket: TcpSocket(socket.0),
+            broker,
+            port,
+            client_id,
+            keep_alive_s: DEFAULT_KEEP_ALIVE_S,
+            clean_session: true,
+            will: None,
+            username: None,
+            password: None,
+            reconnect_interval_ms: DEFAULT_RECONNECT_INTERVAL_MS,
+            retry_interval_ms: DEFAULT_RETRY_INTERVAL_MS,
+            state: State::Disconnected { retry_ms: 0 },
+            next_packet_id: 1,
+            last_sent_ms: 0,
+            ping_sent_ms: None,
+            in_flight: [InFlight::default(); MAX_IN_FLIGHT],
+            in_flight_count: 0,
+            outbox: [0u8; OUTBOX_SIZE],
+            rx: [0u8; RX_BUFFER_SIZE],
+            rx_length: 0,
+            consumed: 0,
+            discard: 0,
+        }
+    }
+
+    /// Sets the keep-alive interval in seconds, `0` disables keep-alive
+    pub fn with_keep_alive(mut self, keep_alive_s: u16) -> Self {
+        self.keep_alive_s = keep_alive_s;
+        self
+    }
+
+    /// Sets whether the broker discards the session on connect, defaults to `true`
+    pub fn with_clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// Sets the message the broker publishes when the connection is lost
+    pub fn with_will(mut self, will: Will<'a>) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// Sets the user name and password to connect with
+    pub fn with_credentials(mut self, username: &'a str, password: Option<&'a [u8]>) -> Self {
+        self.username = Some(username);
+        self.password = password;
+        self
+    }
+
+    /// Sets the time to wait before reconnecting
+    pub fn with_reconnect_interval(mut self, reconnect_interval_ms: u64) -> Self {
+        self.reconnect_interval_ms = reconnect_interval_ms;
+        self
+    }
+
+    /// Sets the time to wait for an acknowledgement before a packet is sent again, `0` only
+    /// retransmits after reconnecting
+    pub fn with_retry_interval(mut self, retry_interval_ms: u64) -> Self {
+        self.retry_interval_ms = retry_interval_ms;
+        self
+    }
+
+    /// Whether the broker has accepted the connection
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// Number of packets waiting for an acknowledgement
+    pub fn in_flight(&self) -> usize {
+        self.in_flight_count
+    }
+
+    /// Publishes the payload to the topic. QoS 1 messages are queued in the outbox, also while
+    /// disconnected, and their packet identifier is returned. Returns
+    /// [`nb::Error::WouldBlock`] while the outbox or the TX buffer of the socket is full.
+    pub fn publish<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        now_ms: u64,
+    ) -> nb::Result<Option<u16>, Error<Spi::Error, ChipSelect::Error>> {
+        let header = PUBLISH << 4 | (qos as u8) << 1 | if retain { FLAG_RETAIN } else { 0 };
+        if qos == QoS::AtLeastOnce {
+            return self
+                .enqueue(w5500, now_ms, header, |encoder, packet_id| {
+                    encoder.string(topic.as_bytes())?;
+                    encoder.u16(packet_id)?;
+                    encoder.bytes(payload)
+                })
+                .map(Some);
+        }
+
+        if self.state != State::Connected {
+            return Err(nb::Error::Other(Error::NotConnected));
+        }
+        // the free end of the outbox serves as scratch buffer
+        let start = self.outbox_length();
+        let length = match encode(&mut self.outbox[start..], header, |encoder| {
+            encoder.string(topic.as_bytes())?;
+            encoder.bytes(payload)
+        }) {
+            Some(length) => length,
+            None if start == 0 => return Err(nb::Error::Other(Error::TooLarge)),
+            None => return Err(nb::Error::WouldBlock),
+        };
+        if !send_packet(w5500, &self.socket, &self.outbox[start..start + length])
+            .map_err(Error::Transfer)?
+        {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.last_sent_ms = now_ms;
+        Ok(None)
+    }
+
+    /// Subscribes to the topic filters with the requested QoS and returns the packet
+    /// identifier of the SUBSCRIBE, which is queued like a QoS 1 publication
+    pub fn subscribe<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        topics: &[(&str, QoS)],
+        now_ms: u64,
+    ) -> nb::Result<u16, Error<Spi::Error, ChipSelect::Error>> {
+        if topics.is_empty() || topics.len() > MAX_TOPIC_FILTERS {
+            return Err(nb::Error::Other(Error::InvalidRequest));
+        }
+        let header = SUBSCRIBE << 4 | FLAGS_REQUIRED;
+        self.enqueue(w5500, now_ms, header, |encoder, packet_id| {
+            encoder.u16(packet_id)?;
+            for (topic, qos) in topics {
+                encoder.string(topic.as_bytes())?;
+                encoder.u8(*qos as u8)?;
+            }
+            Some(())
+        })
+    }
+
+    /// Unsubscribes from the topic filters and returns the packet identifier of the
+    /// UNSUBSCRIBE, which is queued like a QoS 1 publication
+    pub fn unsubscribe<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        topics: &[&str],
+        now_ms: u64,
+    ) -> nb::Result<u16, Error<Spi::Error, ChipSelect::Error>> {
+        if topics.is_empty() || topics.len() > MAX_TOPIC_FILTERS {
+            return Err(nb::Error::Other(Error::InvalidRequest));
+        }
+        let header = UNSUBSCRIBE << 4 | FLAGS_REQUIRED;
+        self.enqueue(w5500, now_ms, header, |encoder, packet_id| {
+            encoder.u16(packet_id)?;
+            for topic in topics {
+                encoder.string(topic.as_bytes())?;
+            }
+            Some(())
+        })
+    }
+
+    /// Sends DISCONNECT if connected, closes the connection and returns the socket. Packets
+    /// in the outbox are dropped.
+    pub fn release<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<TcpSocket, crate::Error<Spi::Error, ChipSelect::Error>> {
+        if self.state == State::Connected {
+            send_packet(w5500, &self.socket, &[DISCONNECT << 4, 0])?;
+            (&mut *w5500, &self.socket).disconnect()?;
+        } else {
+            w5500.write_u8(
+                self.socket.0.at(SocketRegister::Command),
+                SocketCommand::Close as u8,
+            )?;
+        }
+        Ok(self.socket)
+    }
+
+    /// Connects, receives and sends packets as required and returns what happened. Returns
+    /// at most one event per call, so call it again right away if it returned one. Fails with
+    /// [`Error::TooLarge`] instead of connecting if the CONNECT packet with the configured
+    /// client identifier, credentials and will does not fit into [`RX_BUFFER_SIZE`].
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event<'_>>, Error<Spi::Error, ChipSelect::Error>> {
+        Ok(match self.step(w5500, now_ms)? {
+            None => None,
+            Some(Output::Event(event)) => Some(event),
+            Some(Output::Message {
+                topic,
+                payload,
+                qos,
+                retain,
+                duplicate,
+            }) => Some(Event::Message(Message {
+                topic: core::str::from_utf8(&self.rx[topic]).unwrap_or(""),
+                payload: &self.rx[payload],
+                qos,
+                retain,
+                duplicate,
+            })),
+        })
+    }
+
+    fn step<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Output>, Error<Spi::Error, ChipSelect::Error>> {
+        match self.state {
+            State::Disconnected { retry_ms } => {
+                if now_ms >= retry_ms {
+                    // nothing has been received while disconnected
+                    if self.encode_connect().is_none() {
+                        return Err(Error::TooLarge);
+                    }
+                    self.open(w5500, now_ms)?;
+                }
+                return Ok(None);
+            }
+            State::Connecting { since_ms } => {
+                match (&mut *w5500, &self.socket).status()? {
+                    SocketStatus::Established if self.send_connect(w5500, now_ms)? => {
+                        self.state = State::WaitConnAck { since_ms };
+                    }
+                    SocketStatus::Closed => return Ok(self.connection_lost(w5500, now_ms)?),
+                    _ if now_ms.saturating_sub(since_ms) >= CONNECT_TIMEOUT_MS => {
+                        return Ok(self.connection_lost(w5500, now_ms)?);
+                    }
+                    _ => {}
+                }
+                return Ok(None);
+            }
+            State::WaitConnAck { .. } | State::Connected => {}
+        }
+
+        loop {
+            self.consume();
+            self.receive(w5500)?;
+            if self.rx_length < 2 {
+                break;
+            }
+            let (header, body) = match parse_fixed_header(&self.rx[..self.rx_length]) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(()) => return Ok(self.protocol_violation(w5500, now_ms)?),
+            };
+            if body.end > RX_BUFFER_SIZE {
+                self.drop_oversized(w5500, header, body)?;
+                continue;
+            }
+            if body.end > self.rx_length {
+                break;
+            }
+            self.consumed = body.end;
+            if let Some(output) = self.handle(w5500, header, body, now_ms)? {
+                return Ok(Some(output));
+            }
+            if let State::Disconnected { .. } = self.state {
+                return Ok(None);
+            }
+        }
+
+        let interrupts = w5500.read_u8(self.socket.0.at(SocketRegister::Interrupt))?;
+        let lost = interrupts & (Interrupt::Disconnected as u8 | Interrupt::Timeout as u8);
+        if lost != 0 || (&mut *w5500, &self.socket).status()? != SocketStatus::Established {
+            debug!("MQTT connection to {} lost", self.broker);
+            return Ok(self.connection_lost(w5500, now_ms)?);
+        }
+
+        match self.state {
+            State::WaitConnAck { since_ms } => {
+                if now_ms.saturating_sub(since_ms) >= CONNECT_TIMEOUT_MS {
+                    debug!("MQTT broker {} did not send CONNACK", self.broker);
+                    return Ok(self.connection_lost(w5500, now_ms)?);
+                }
+            }
+            State::Connected => {
+                let keep_alive_ms = u64::from(self.keep_alive_s) * 1000;
+                if let Some(ping_sent_ms) = self.ping_sent_ms {
+                    if now_ms.saturating_sub(ping_sent_ms) >= keep_alive_ms {
+                        debug!("MQTT broker {} did not answer PINGREQ", self.broker);
+                        return Ok(self.connection_lost(w5500, now_ms)?);
+                    }
+                }
+                self.send_in_flight(w5500, now_ms)?;
+                if keep_alive_ms > 0
+                    && self.ping_sent_ms.is_none()
+                    && now_ms.saturating_sub(self.last_sent_ms) >= keep_alive_ms
+                    && send_packet(w5500, &self.socket, &[PINGREQ << 4, 0])?
+                {
+                    trace!("MQTT PINGREQ");
+                    self.ping_sent_ms = Some(now_ms);
+                    self.last_sent_ms = now_ms;
+                }
+            }
+            State::Disconnected { .. } | State::Connecting { .. } => {}
+        }
+        Ok(None)
+    }
+
+    /// Handles a complete packet in `rx`
+    fn handle<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        header: u8,
+        body: Range<usize>,
+        now_ms: u64,
+    ) -> Result<Option<Output>, crate::Error<Spi::Error, ChipSelect::Error>> {
+        let packet_type = header >> 4;
+        let data = &self.rx[body.clone()];
+        if let State::WaitConnAck { .. } = self.state {
+            if packet_type != CONNACK || data.len() != 2 {
+                return self.protocol_violation(w5500, now_ms);
+            }
+            let session_present = data[0] & 1 != 0;
+            let code = data[1];
+            if code != 0 {
+                debug!(
+                    "MQTT broker {} refused the connection: {}",
+                    self.broker, code
+                );
+                self.connection_lost(w5500, now_ms)?;
+                return Ok(Some(Output::Event(Event::Refused { code })));
+            }
+            debug!("MQTT connected to {}", self.broker);
+            self.state = State::Connected;
+            return Ok(Some(Output::Event(Event::Connected { session_present })));
+        }
+
+        match packet_type {
+            PUBLISH => {
+                let qos = match (header >> 1) & 0b11 {
+                    0 => QoS::AtMostOnce,
+                    1 => QoS::AtLeastOnce,
+                    // QoS 2 is never requested
+                    _ => return self.protocol_violation(w5500, now_ms),
+                };
+                if data.len() < 2 {
+                    return self.protocol_violation(w5500, now_ms);
+                }
+                let topic_end = 2 + usize::from(BigEndian::read_u16(data));
+                let payload_start = match qos {
+                    QoS::AtMostOnce => topic_end,
+                    QoS::AtLeastOnce => topic_end + 2,
+                };
+                if payload_start > data.len() || core::str::from_utf8(&data[2..topic_end]).is_err()
+                {
+                    return self.protocol_violation(w5500, now_ms);
+                }
+                if qos == QoS::AtLeastOnce {
+                    let packet_id = BigEndian::read_u16(&data[topic_end..]);
+                    self.send_puback(w5500, packet_id)?;
+                }
+                Ok(Some(Output::Message {
+                    topic: body.start + 2..body.start + topic_end,
+                    payload: body.start + payload_start..body.end,
+                    qos,
+                    retain: header & FLAG_RETAIN != 0,
+                    duplicate: header & FLAG_DUP != 0,
+                }))
+            }
+            PUBACK | UNSUBACK if data.len() == 2 => {
+                let packet_id = BigEndian::read_u16(data);
+                let (request, event) = match packet_type {
+                    PUBACK => (PUBLISH, Event::Published { packet_id }),
+                    _ => (UNSUBSCRIBE, Event::Unsubscribed { packet_id }),
+                };
+                Ok(self
+                    .acknowledge(request, packet_id)
+                    .then_some(Output::Event(event)))
+            }
+            SUBACK if data.len() > 2 && data.len() <= 2 + MAX_TOPIC_FILTERS => {
+                let packet_id = BigEndian::read_u16(data);
+                let mut granted = Granted {
+                    codes: [0u8; MAX_TOPIC_FILTERS],
+                    count: (data.len() - 2) as u8,
+                };
+                granted.codes[..data.len() - 2].copy_from_slice(&data[2..]);
+                Ok(self
+                    .acknowledge(SUBSCRIBE, packet_id)
+                    .then_some(Output::Event(Event::Subscribed { packet_id, granted })))
+            }
+            PINGRESP => {
+                trace!("MQTT PINGRESP");
+                self.ping_sent_ms = None;
+                Ok(None)
+            }
+            _ => self.protocol_violation(w5500, now_ms),
+        }
+    }
+
+    /// Drops a packet that does not fit into `rx`. QoS 1 publications are still acknowledged,
+    /// so the broker does not deliver them again.
+    fn drop_oversized<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        header: u8,
+        body: Range<usize>,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        debug!("MQTT dropping a packet of {} bytes", body.end);
+        if header >> 4 == PUBLISH && (header >> 1) & 0b11 == QoS::AtLeastOnce as u8 {
+            let topic_end =
+                body.start + 2 + usize::from(BigEndian::read_u16(&self.rx[body.start..]));
+            if topic_end + 2 <= self.rx_length {
+                let packet_id = BigEndian::read_u16(&self.rx[topic_end..]);
+                self.send_puback(w5500, packet_id)?;
+            }
+        }
+        self.discard = body.end - self.rx_length;
+        self.rx_length = 0;
+        Ok(())
+    }
+
+    fn send_puback<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        packet_id: u16,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        let packet_id = packet_id.to_be_bytes();
+        // if it cannot be sent, the broker delivers the message again after reconnecting
+        send_packet(
+            w5500,
+            &self.socket,
+            &[PUBACK << 4, 2, packet_id[0], packet_id[1]],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the acknowledged packet from the outbox, returns `false` if it was unknown
+    fn acknowledge(&mut self, packet_type: u8, packet_id: u16) -> bool {
+        let mut offset = 0;
+        for index in 0..self.in_flight_count {
+            let entry = self.in_flight[index];
+            if entry.packet_id == packet_id && self.outbox[offset] >> 4 == packet_type {
+                let end = self.outbox_length();
+                self.outbox.copy_within(offset + entry.length..end, offset);
+                self.in_flight
+                    .copy_within(index + 1..self.in_flight_count, index);
+                self.in_flight_count -= 1;
+                return true;
+            }
+            offset += entry.length;
+        }
+        debug!("MQTT unexpected acknowledgement for {}", packet_id);
+        false
+    }
+
+    /// Encodes a packet with a new packet identifier into the outbox and sends it if
+    /// connected
+    fn enqueue<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+        header: u8,
+        write: impl FnOnce(&mut Encoder, u16) -> Option<()>,
+    ) -> nb::Result<u16, Error<Spi::Error, ChipSelect::Error>> {
+        if self.in_flight_count == MAX_IN_FLIGHT {
+            return Err(nb::Error::WouldBlock);
+        }
+        let start = self.outbox_length();
+        let packet_id = self.next_packet_id();
+        let length = match encode(&mut self.outbox[start..], header, |encoder| {
+            write(encoder, packet_id)
+        }) {
+            Some(length) => length,
+            None if start == 0 => return Err(nb::Error::Other(Error::TooLarge)),
+            None => return Err(nb::Error::WouldBlock),
+        };
+        self.in_flight[self.in_flight_count] = InFlight {
+            packet_id,
+            length,
+            sent_ms: None,
+        };
+        self.in_flight_count += 1;
+        if self.state == State::Connected {
+            self.send_in_flight(w5500, now_ms)
+                .map_err(Error::Transfer)?;
+        }
+        Ok(packet_id)
+    }
+
+    /// Sends the packets in the outbox that have not been sent since connecting or whose
+    /// retry interval has passed, in order
+    fn send_in_flight<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        let mut offset = 0;
+        for entry in self.in_flight[..self.in_flight_count].iter_mut() {
+            let due = match entry.sent_ms {
+                None => true,
+                Some(sent_ms) => {
+                    self.retry_interval_ms > 0
+                        && now_ms.saturating_sub(sent_ms) >= self.retry_interval_ms
+                }
+            };
+            if due {
+                let packet = &mut self.outbox[offset..offset + entry.length];
+                if !send_packet(w5500, &self.socket, packet)? {
+                    break;
+                }
+                trace!("MQTT sent packet {}", entry.packet_id);
+                if packet[0] >> 4 == PUBLISH {
+                    // any further transmission is a duplicate
+                    packet[0] |= FLAG_DUP;
+                }
+                entry.sent_ms = Some(now_ms);
+                self.last_sent_ms = now_ms;
+            }
+            offset += entry.length;
+        }
+        Ok(())
+    }
+
+    /// Encodes CONNECT with the configured credentials and will into `rx`, `None` if it does
+    /// not fit. Only call this while `rx` holds no received data.
+    fn encode_connect(&mut self) -> Option<usize> {
+        let mut flags = 0;
+        if self.clean_session {
+            flags |= CONNECT_CLEAN_SESSION;
+        }
+        if let Some(will) = self.will {
+            flags |= CONNECT_WILL | (will.qos as u8) << 3;
+            if will.retain {
+                flags |= CONNECT_WILL_RETAIN;
+            }
+        }
+        if self.username.is_some() {
+            flags |= CONNECT_USERNAME;
+            if self.password.is_some() {
+                flags |= CONNECT_PASSWORD;
+            }
+        }
+
+        let (client_id, keep_alive_s, will, username, password) = (
+            self.client_id,
+            self.keep_alive_s,
+            self.will,
+            self.username,
+            self.password,
+        );
+        encode(&mut self.rx, CONNECT << 4, |encoder| {
+            encoder.string(b"MQTT")?;
+            encoder.u8(PROTOCOL_LEVEL)?;
+            encoder.u8(flags)?;
+            encoder.u16(keep_alive_s)?;
+            encoder.string(client_id.as_bytes())?;
+            if let Some(will) = will {
+                encoder.string(will.topic.as_bytes())?;
+                encoder.string(will.payload)?;
+            }
+            if let Some(username) = username {
+                encoder.string(username.as_bytes())?;
+                if let Some(password) = password {
+                    encoder.string(password)?;
+                }
+            }
+            Some(())
+        })
+    }
+
+    /// Sends CONNECT, returns `false` if the TX buffer has no room for it yet
+    fn send_connect<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+        // the receive buffer is empty right after connecting
+        let length = self.encode_connect().ok_or(Error::TooLarge)?;
+        if !send_packet(w5500, &self.socket, &self.rx[..length])? {
+            return Ok(false);
+        }
+        debug!("MQTT connecting to {} as {}", self.broker, self.client_id);
+        self.last_sent_ms = now_ms;
+        Ok(true)
+    }
+
+    /// Opens the socket and starts to connect to the broker
+    fn open<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        let socket = self.socket.0;
+        if (&mut *w5500, &self.socket).status()? != SocketStatus::Closed {
+            w5500.write_u8(
+                socket.at(SocketRegister::Command),
+                SocketCommand::Close as u8,
+            )?;
+        }
+        let port = w5500.0.next_ephemeral_port();
+        w5500.open_tcp(socket, port)?;
+        (&mut *w5500, &self.socket).connect(&self.broker, self.port)?;
+        self.state = State::Connecting { since_ms: now_ms };
+        Ok(())
+    }
+
+    fn protocol_violation<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Output>, crate::Error<Spi::Error, ChipSelect::Error>> {
+        debug!("MQTT invalid packet from {}", self.broker);
+        self.connection_lost(w5500, now_ms)
+    }
+
+    /// Closes the socket and schedules the reconnect. All packets in the outbox are sent
+    /// again once connected.
+    fn connection_lost<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Output>, crate::Error<Spi::Error, ChipSelect::Error>> {
+        let connected = self.state == State::Connected;
+        w5500.write_u8(
+            self.socket.0.at(SocketRegister::Command),
+            SocketCommand::Close as u8,
+        )?;
+        self.state = State::Disconnected {
+            retry_ms: now_ms + self.reconnect_interval_ms,
+        };
+        for entry in self.in_flight[..self.in_flight_count].iter_mut() {
+            entry.sent_ms = None;
+        }
+        self.ping_sent_ms = None;
+        self.rx_length = 0;
+        self.consumed = 0;
+        self.discard = 0;
+        Ok(connected.then_some(Output::Event(Event::Disconnected)))
+    }
+
+    /// Reads received data into `rx`, dropping the rest of an oversized packet
+    fn receive<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        if self.rx_length < RX_BUFFER_SIZE {
+            match (&mut *w5500, &self.socket).receive(&mut self.rx[self.rx_length..]) {
+                Ok(length) => self.rx_length += length,
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(error)) => return Err(error),
+            }
+        }
+        if self.discard > 0 {
+            let length = self.discard.min(self.rx_length);
+            self.rx.copy_within(length..self.rx_length, 0);
+            self.rx_length -= length;
+            self.discard -= length;
+        }
+        Ok(())
+    }
+
+    /// Removes the packet returned by the last poll from `rx`
+    fn consume(&mut self) {
+        if self.consumed > 0 {
+            self.rx.copy_within(self.consumed..self.rx_length, 0);
+            self.rx_length -= self.consumed;
+            self.consumed = 0;
+        }
+    }
+
+    fn outbox_length(&self) -> usize {
+        self.in_flight[..self.in_flight_count]
+            .iter()
+            .map(|entry| entry.length)
+            .sum()
+    }
+
+    /// Returns a non-zero packet identifier that is not in use
+    fn next_packet_id(&mut self) -> u16 {
+        loop {
+            let packet_id = self.next_packet_id;
+            self.next_packet_id = packet_id.wrapping_add(1).max(1);
+            if !self.in_flight[..self.in_flight_count]
+                .iter()
+                .any(|entry| entry.packet_id == packet_id)
+            {
+                return packet_id;
+            }
+        }
+    }
+}
+
+/// Sends the packet if the previous send has completed and the TX buffer has room for it,
+/// returns whether it has been sent
+fn send_packet<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+    w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    socket: &TcpSocket,
+    packet: &[u8],
+) -> Result<bool, crate::Error<Spi::Error, ChipSelect::Error>> {
+    let socket = socket.0;
+    match w5500.poll_send_complete(socket) {
+        Ok(()) => {}
+        Err(nb::Error::WouldBlock) => return Ok(false),
+        Err(nb::Error::Other(error)) => return Err(error),
+    }
+    let free_size = w5500.read_u16_stable(socket.at(SocketRegister::TxFreeSize))?;
+    if packet.is_empty() || usize::from(free_size) < packet.len() {
+        return Ok(false);
+    }
+    w5500.send_tx_buffer(socket, packet)?;
+    Ok(true)
+}
+
+/// Parses the fixed header at the start of `buffer` and returns the first byte and the range
+/// of the body. Returns `None` while the remaining length is incomplete.
+fn parse_fixed_header(buffer: &[u8]) -> Result<Option<(u8, Range<usize>)>, ()> {
+    let mut remaining = 0usize;
+    for index in 1..MAX_HEADER_SIZE {
+        let byte = match buffer.get(index) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        remaining |= usize::from(byte & 0x7F) << (7 * (index - 1));
+        if byte & 0x80 == 0 {
+            return Ok(Some((buffer[0], index + 1..index + 1 + remaining)));
+        }
+    }
+    Err(())
+}
+
+/// Writes fields into a buffer, `None` if it is too small
+struct Encoder<'e> {
+    buffer: &'e mut [u8],
+    position: usize,
+}
+
+impl Encoder<'_> {
+    fn bytes(&mut self, data: &[u8]) -> Option<()> {
+        let end = self.position.checked_add(data.len())?;
+        self.buffer
+            .get_mut(self.position..end)?
+            .copy_from_slice(data);
+        self.position = end;
+        Some(())
+    }
+
+    fn u8(&mut self, value: u8) -> Option<()> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes length-prefixed data, such as a UTF-8 string
+    fn string(&mut self, data: &[u8]) -> Option<()> {
+        self.u16(u16::try_from(data.len()).ok()?)?;
+        self.bytes(data)
+    }
+}
+
+/// Encodes a packet with the given first header byte to the start of `buffer` and returns its
+/// length, `None` if it does not fit
+fn encode(
+    buffer: &mut [u8],
+    header: u8,
+    write: impl FnOnce(&mut Encoder) -> Option<()>,
+) -> Option<usize> {
+    if buffer.len() < MAX_HEADER_SIZE {
+        return None;
+    }
+    // the body is written behind the largest possible fixed header and moved afterwards
+    let mut encoder = Encoder {
+        buffer,
+        position: MAX_HEADER_SIZE,
+    };
+    write(&mut encoder)?;
+    let Encoder { buffer, position } = encoder;
+
+    let body_length = position - MAX_HEADER_SIZE;
+    let mut remaining = body_length;
+    let mut length = [0u8; MAX_HEADER_SIZE - 1];
+    let mut length_size = 0;
+    loop {
+        let mut byte = (remaining & 0x7F) as u8;
+        remaining >>= 7;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        length[length_size] = byte;
+        length_size += 1;
+        if remaining == 0 {
+            break;
+        }
+        if length_size == length.len() {
+            return None;
+        }
+    }
+    buffer.copy_within(MAX_HEADER_SIZE..position, 1 + length_size);
+    buffer[0] = header;
+    buffer[1..1 + length_size].copy_from_slice(&length[..length_size]);
+    Some(1 + length_size + body_length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Socket;
+
+    fn client() -> MqttClient<'static> {
+        MqttClient::new(
+            UninitializedSocket(Socket::Socket0),
+            Ipv4Addr::new(192, 168, 0, 2),
+            PORT,
+            "client",
+        )
+    }
+
+    /// Appends a packet with an identifier to the outbox like `enqueue`
+    fn queue(client: &mut MqttClient, header: u8, packet_id: u16, payload: &[u8]) {
+        let start = client.outbox_length();
+        let length = encode(&mut client.outbox[start..], header, |encoder| {
+            encoder.u16(packet_id)?;
+            encoder.bytes(payload)
+        })
+        .unwrap();
+        client.in_flight[client.in_flight_count] = InFlight {
+            packet_id,
+            length,
+            sent_ms: None,
+        };
+        client.in_flight_count += 1;
+    }
+
+    #[test]
+    fn encode_writes_the_shortest_remaining_length() {
+        let mut buffer = [0u8; 16];
+        let length = encode(&mut buffer, PUBLISH << 4, |encoder| {
+            encoder.string(b"a/b")?;
+            encoder.bytes(b"hi")
+        });
+        assert_eq!(length, Some(9));
+        assert_eq!(buffer[..9], [0x30, 7, 0, 3, b'a', b'/', b'b', b'h', b'i']);
+
+        let mut buffer = [0u8; 300];
+        let length = encode(&mut buffer, PUBLISH << 4, |encoder| {
+            encoder.bytes(&[0xAA; 200])
+        });
+        assert_eq!(length, Some(203));
+        assert_eq!(buffer[..3], [0x30, 0xC8, 0x01]);
+        assert_eq!(buffer[202], 0xAA);
+    }
+
+    #[test]
+    fn encode_fails_if_the_packet_does_not_fit() {
+        let mut buffer = [0u8; 16];
+        assert_eq!(
+            encode(&mut buffer, PUBLISH << 4, |encoder| encoder.bytes(&[0; 12])),
+            None
+        );
+        assert_eq!(encode(&mut buffer[..4], PINGREQ << 4, |_| Some(())), None);
+        assert_eq!(
+            encode(&mut buffer, PUBLISH << 4, |encoder| encoder.bytes(&[0; 11])),
+            Some(13)
+        );
+    }
+
+    #[test]
+    fn parse_fixed_header_returns_the_body_range() {
+        assert_eq!(
+            parse_fixed_header(&[PINGRESP << 4, 0]),
+            Ok(Some((PINGRESP << 4, 2..2)))
+        );
+        assert_eq!(
+            parse_fixed_header(&[0x30, 0xC8, 0x01, 0]),
+            Ok(Some((0x30, 3..203)))
+        );
+        assert_eq!(
+            parse_fixed_header(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F]),
+            Ok(Some((0x30, 5..5 + 268_435_455)))
+        );
+    }
+
+    #[test]
+    fn parse_fixed_header_waits_for_the_remaining_length() {
+        assert_eq!(parse_fixed_header(&[0x30]), Ok(None));
+        assert_eq!(parse_fixed_header(&[0x30, 0x80]), Ok(None));
+    }
+
+    #[test]
+    fn parse_fixed_header_rejects_five_length_bytes() {
+        assert_eq!(
+            parse_fixed_header(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn acknowledge_removes_the_packet_from_the_outbox() {
+        let mut client = client();
+        queue(&mut client, PUBLISH << 4 | 2, 1, b"first");
+        queue(&mut client, SUBSCRIBE << 4 | FLAGS_REQUIRED, 2, b"second");
+        queue(&mut client, PUBLISH << 4 | 2, 3, b"third");
+        let third = client.in_flight[2].length;
+        let third_start = client.outbox_length() - third;
+        let mut expected = [0u8; 16];
+        expected[..third].copy_from_slice(&client.outbox[third_start..third_start + third]);
+
+        assert!(client.acknowledge(SUBSCRIBE, 2));
+        assert_eq!(client.in_flight(), 2);
+        assert_eq!(client.in_flight[0].packet_id, 1);
+        assert_eq!(client.in_flight[1].packet_id, 3);
+        let first = client.in_flight[0].length;
+        assert_eq!(client.outbox[first..first + third], expected[..third]);
+
+        assert!(client.acknowledge(PUBLISH, 1));
+        assert_eq!(client.in_flight(), 1);
+        assert_eq!(client.outbox[..third], expected[..third]);
+    }
+
+    #[test]
+    fn acknowledge_ignores_unknown_packets() {
+        let mut client = client();
+        queue(&mut client, PUBLISH << 4 | 2, 1, b"first");
+        assert!(!client.acknowledge(PUBLISH, 2));
+        assert!(!client.acknowledge(SUBSCRIBE, 1));
+        assert_eq!(client.in_flight(), 1);
+        assert!(client.acknowledge(PUBLISH, 1));
+        assert_eq!(client.in_flight(), 0);
+        assert!(!client.acknowledge(PUBLISH, 1));
+    }
+
+    #[test]
+    fn connect_must_fit_into_the_receive_buffer() {
+        let mut client = client();
+        assert_eq!(client.encode_connect(), Some(20));
+        assert_eq!(client.rx[..2], [CONNECT << 4, 18]);
+
+        let payload = [0u8; RX_BUFFER_SIZE];
+        let mut client = client.with_will(Will {
+            topic: "will",
+            payload: &payload,
+            qos: QoS::AtMostOnce,
+            retain: false,
+        });
+        assert_eq!(client.encode_connect(), None);
+    }
+}
@@ -0,0 +1,464 @@
+//! Implementation of the [`embedded-nal`] network stack traits.
+//!
+//! The traits are implemented on [`ActiveW5500`], which allocates sockets from the pool of
+//! the [`W5500`] and returns them on close. This allows protocol crates that are written
+//! against [`embedded-nal`] to run on the hardware sockets of the chip.
+//!
+//! Only IPv4 addresses are supported, IPv6 addresses result in [`Error::UnsupportedAddress`].
+//!
+//! # Examples
+//!
+//! ```
+//! use embedded_nal::{UdpClientStack, UdpFullStack};
+//! use w5500::replay::Replay;
+//! # use w5500::{ArpResponses, ConnectionType, OnPingRequest, OnWakeOnLan, W5500};
+//!
+//! # let replay = Replay::new(
+//! #     "R 00 0039 04\nW 04 0000 80\nW 04 0000 00\n\
+//! #      W 0c 0002 18\nW 0c 0004 0044\nW 0c 0000 0201\n\
+//! #      R 08 0026 0000\nR 08 0026 0000\n",
+//! # );
+//! # let mut spi = replay.spi();
+//! # let mut w5500 = W5500::with_initialisation(
+//! #     replay.chip_select(),
+//! #     &mut spi,
+//! #     OnWakeOnLan::Ignore,
+//! #     OnPingRequest::Respond,
+//! #     ConnectionType::Ethernet,
+//! #     ArpResponses::Cache,
+//! # )
+//! # .unwrap();
+//! let mut stack = w5500.activate(&mut spi).unwrap();
+//! let mut socket = stack.socket().unwrap();
+//! stack.bind(&mut socket, 68).unwrap();
+//!
+//! let mut buffer = [0u8; 576];
+//! assert!(matches!(
+//!     stack.receive(&mut socket, &mut buffer),
+//!     Err(nb::Error::WouldBlock)
+//! ));
+//! # replay.finish().unwrap();
+//! ```
+//!
+//! [`embedded-nal`]: https://docs.rs/embedded-nal
+//! [`W5500`]: crate::W5500
+
+use core::net::{SocketAddr, SocketAddrV4};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+use embedded_nal::{
+    TcpClientStack, TcpError, TcpErrorKind, TcpFullStack, UdpClientStack, UdpFullStack,
+};
+
+use crate::{ActiveW5500, Ipv4Addr, Socket, SocketStatus, Tcp, Udp};
+
+/// Error returned by the [`embedded-nal`](https://docs.rs/embedded-nal) stack implementation
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// All eight sockets of the chip are in use
+    NoFreeSocket,
+    /// The socket has not been bound or connected yet
+    NotOpen,
+    /// The socket has no remote to send to
+    NotConnected,
+    /// The remote is not an IPv4 address
+    UnsupportedAddress,
+    /// The TCP connection could not be established
+    ConnectionFailed,
+    /// The TCP connection has been closed
+    ConnectionClosed,
+}
+
+impl<SpiError, ChipSelectError> TcpError for Error<SpiError, ChipSelectError>
+where
+    SpiError: core::fmt::Debug,
+    ChipSelectError: core::fmt::Debug,
+{
+    fn kind(&self) -> TcpErrorKind {
+        match self {
+            Error::ConnectionClosed => TcpErrorKind::PipeClosed,
+            _ => TcpErrorKind::Other,
+        }
+    }
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// UDP socket handle of the [`UdpClientStack`] implementation
+#[derive(Debug)]
+pub struct UdpSocket {
+    socket: Socket,
+    open: bool,
+    remote: Option<SocketAddrV4>,
+    sending: bool,
+}
+
+impl UdpSocket {
+    /// The hardware socket this handle uses
+    pub fn socket(&self) -> Socket {
+        self.socket
+    }
+}
+
+/// TCP socket handle of the [`TcpClientStack`] implementation
+#[derive(Debug)]
+pub struct TcpSocket {
+    socket: Socket,
+    state: TcpState,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum TcpState {
+    Unopened,
+    Bound(u16),
+    Listening(u16),
+    Connecting,
+    Connected,
+}
+
+impl TcpSocket {
+    /// The hardware socket this handle uses
+    pub fn socket(&self) -> Socket {
+        self.socket
+    }
+}
+
+fn to_ipv4<SpiError, ChipSelectError>(
+    remote: SocketAddr,
+) -> Result<SocketAddrV4, Error<SpiError, ChipSelectError>> {
+    match remote {
+        SocketAddr::V4(remote) => Ok(remote),
+        SocketAddr::V6(_) => Err(Error::UnsupportedAddress),
+    }
+}
+
+impl<ChipSelect, Spi> ActiveW5500<'_, '_, ChipSelect, Spi>
+where
+    ChipSelect: OutputPin,
+    Spi: FullDuplex<u8>,
+{
+    /// Takes the first socket that is available in the pool
+    fn take_any_socket(&mut self) -> Option<Socket> {
+        (0..8)
+            .map(Socket::from_number)
+            .find(|socket| self.take_socket(*socket).is_some())
+    }
+
+    fn tcp_status(
+        &mut self,
+        socket: Socket,
+    ) -> Result<SocketStatus, Error<Spi::Error, ChipSelect::Error>> {
+        Ok((&mut *self, &crate::TcpSocket(socket)).status()?)
+    }
+
+    /// Queues the datagram unless one is already in flight and polls until the chip has sent
+    /// it. Returns [`nb::Error::WouldBlock`] in between, so the caller retries with the same
+    /// datagram as [`UdpClientStack::send`] requires.
+    fn send_udp(
+        &mut self,
+        socket: &mut UdpSocket,
+        remote: SocketAddrV4,
+        buffer: &[u8],
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if !socket.open {
+            return Err(nb::Error::Other(Error::NotOpen));
+        }
+        let mut udp = (&mut *self, &crate::UdpSocket(socket.socket));
+        if !socket.sending {
+            let host = Ipv4Addr::from(*remote.ip());
+            udp.send(&host, remote.port(), buffer)
+                .map_err(|error| error.map(Error::Transfer))?;
+            socket.sending = true;
+        }
+        let result = udp.poll_send();
+        if !matches!(result, Err(nb::Error::WouldBlock)) {
+            socket.sending = false;
+        }
+        result.map_err(|error| error.map(Error::Transfer))
+    }
+}
+
+impl<ChipSelect, Spi> UdpClientStack for ActiveW5500<'_, '_, ChipSelect, Spi>
+where
+    ChipSelect: OutputPin,
+    ChipSelect::Error: core::fmt::Debug,
+    Spi: FullDuplex<u8>,
+    Spi::Error: core::fmt::Debug,
+{
+    type UdpSocket = UdpSocket;
+    type Error = Error<Spi::Error, ChipSelect::Error>;
+
+    fn socket(&mut self) -> Result<Self::UdpSocket, Self::Error> {
+        let socket = self.take_any_socket().ok_or(Error::NoFreeSocket)?;
+        Ok(UdpSocket {
+            socket,
+            open: false,
+            remote: None,
+            sending: false,
+        })
+    }
+
+    fn connect(
+        &mut self,
+        socket: &mut Self::UdpSocket,
+        remote: SocketAddr,
+    ) -> Result<(), Self::Error> {
+        let remote = to_ipv4(remote)?;
+        if !socket.open {
+            let port = self.0.next_ephemeral_port();
+            self.open_udp(socket.socket, port)?;
+            socket.open = true;
+        }
+        socket.remote = Some(remote);
+        Ok(())
+    }
+
+    fn send(&mut self, socket: &mut Self::UdpSocket, buffer: &[u8]) -> nb::Result<(), Self::Error> {
+        let remote = socket.remote.ok_or(Error::NotConnected)?;
+        self.send_udp(socket, remote, buffer)
+    }
+
+    fn receive(
+        &mut self,
+        socket: &mut Self::UdpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, SocketAddr), Self::Error> {
+        if !socket.open {
+            return Err(nb::Error::Other(Error::NotOpen));
+        }
+        match (&mut *self, &crate::UdpSocket(socket.socket))
+            .receive(buffer)
+            .map_err(Error::Transfer)?
+        {
+            Some((ip, port, length, _)) => Ok((
+                length.min(buffer.len()),
+                SocketAddr::V4(SocketAddrV4::new(ip.into(), port)),
+            )),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    fn close(&mut self, socket: Self::UdpSocket) -> Result<(), Self::Error> {
+        self.close_socket(socket.socket)?;
+        Ok(())
+    }
+}
+
+impl<ChipSelect, Spi> UdpFullStack for ActiveW5500<'_, '_, ChipSelect, Spi>
+where
+    ChipSelect: OutputPin,
+    ChipSelect::Error: core::fmt::Debug,
+    Spi: FullDuplex<u8>,
+    Spi::Error: core::fmt::Debug,
+{
+    fn bind(&mut self, socket: &mut Self::UdpSocket, local_port: u16) -> Result<(), Self::Error> {
+        self.open_udp(socket.socket, local_port)?;
+        socket.open = true;
+        Ok(())
+    }
+
+    fn send_to(
+        &mut self,
+        socket: &mut Self::UdpSocket,
+        remote: SocketAddr,
+        buffer: &[u8],
+    ) -> nb::Result<(), Self::Error> {
+        let remote = to_ipv4(remote)?;
+        self.send_udp(socket, remote, buffer)
+    }
+}
+
+impl<ChipSelect, Spi> TcpClientStack for ActiveW5500<'_, '_, ChipSelect, Spi>
+where
+    ChipSelect: OutputPin,
+    ChipSelect::Error: core::fmt::Debug,
+    Spi: FullDuplex<u8>,
+    Spi::Error: core::fmt::Debug,
+{
+    type TcpSocket = TcpSocket;
+    type Error = Error<Spi::Error, ChipSelect::Error>;
+
+    fn socket(&mut self) -> Result<Self::TcpSocket, Self::Error> {
+        let socket = self.take_any_socket().ok_or(Error::NoFreeSocket)?;
+        Ok(TcpSocket {
+            socket,
+            state: TcpState::Unopened,
+        })
+    }
+
+    fn connect(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        remote: SocketAddr,
+    ) -> nb::Result<(), Self::Error> {
+        if socket.state != TcpState::Connecting && socket.state != TcpState::Connected {
+            let remote = to_ipv4(remote)?;
+            let port = match socket.state {
+                TcpState::Bound(port) => port,
+                _ => self.0.next_ephemeral_port(),
+            };
+            self.open_tcp(socket.socket, port).map_err(Error::from)?;
+            (&mut *self, &crate::TcpSocket(socket.socket))
+                .connect(&Ipv4Addr::from(*remote.ip()), remote.port())
+                .map_err(Error::from)?;
+            socket.state = TcpState::Connecting;
+            return Err(nb::Error::WouldBlock);
+        }
+
+        match self.tcp_status(socket.socket)? {
+            SocketStatus::Established | SocketStatus::CloseWait => {
+                socket.state = TcpState::Connected;
+                Ok(())
+            }
+            SocketStatus::Init | SocketStatus::SynSent => Err(nb::Error::WouldBlock),
+            _ => {
+                let failed = socket.state == TcpState::Connecting;
+                socket.state = TcpState::Unopened;
+                if failed {
+                    Err(nb::Error::Other(Error::ConnectionFailed))
+                } else {
+                    Err(nb::Error::Other(Error::ConnectionClosed))
+                }
+            }
+        }
+    }
+
+    fn send(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        buffer: &[u8],
+    ) -> nb::Result<usize, Self::Error> {
+        match self.tcp_status(socket.socket)? {
+            SocketStatus::Established | SocketStatus::CloseWait => {
+                (&mut *self, &crate::TcpSocket(socket.socket))
+                    .send(buffer)
+                    .map_err(|error| error.map(Error::Transfer))
+            }
+            SocketStatus::Init | SocketStatus::SynSent | SocketStatus::SynRecv => {
+                Err(nb::Error::WouldBlock)
+            }
+            _ if socket.state == TcpState::Unopened => Err(nb::Error::Other(Error::NotOpen)),
+            _ => Err(nb::Error::Other(Error::ConnectionClosed)),
+        }
+    }
+
+    fn receive(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, Self::Error> {
+        if socket.state == TcpState::Unopened {
+            return Err(nb::Error::Other(Error::NotOpen));
+        }
+        match (&mut *self, &crate::TcpSocket(socket.socket)).receive(buffer) {
+            Err(nb::Error::WouldBlock) => match self.tcp_status(socket.socket)? {
+                SocketStatus::Established
+                | SocketStatus::Init
+                | SocketStatus::Listen
+                | SocketStatus::SynSent
+                | SocketStatus::SynRecv => Err(nb::Error::WouldBlock),
+                _ => Err(nb::Error::Other(Error::ConnectionClosed)),
+            },
+            result => result.map_err(|error| error.map(Error::Transfer)),
+        }
+    }
+
+    /// Starts to disconnect an established connection without waiting for it. The socket
+    /// returns to the pool once the remote has acknowledged the disconnect or the chip gave up
+    /// on it with its retransmission timeout. Other sockets are closed right away.
+    fn close(&mut self, socket: Self::TcpSocket) -> Result<(), Self::Error> {
+        if let SocketStatus::Established | SocketStatus::CloseWait =
+            self.tcp_status(socket.socket)?
+        {
+            (&mut *self, &crate::TcpSocket(socket.socket)).disconnect()?;
+            self.0.closing |= 0x01 << socket.socket.number();
+            return Ok(());
+        }
+        self.close_socket(socket.socket)?;
+        Ok(())
+    }
+}
+
+impl<ChipSelect, Spi> TcpFullStack for ActiveW5500<'_, '_, ChipSelect, Spi>
+where
+    ChipSelect: OutputPin,
+    ChipSelect::Error: core::fmt::Debug,
+    Spi: FullDuplex<u8>,
+    Spi::Error: core::fmt::Debug,
+{
+    fn bind(&mut self, socket: &mut Self::TcpSocket, local_port: u16) -> Result<(), Self::Error> {
+        self.open_tcp(socket.socket, local_port)?;
+        socket.state = TcpState::Bound(local_port);
+        Ok(())
+    }
+
+    fn listen(&mut self, socket: &mut Self::TcpSocket) -> Result<(), Self::Error> {
+        match socket.state {
+            TcpState::Bound(port) => {
+                (&mut *self, &crate::TcpSocket(socket.socket)).listen()?;
+                socket.state = TcpState::Listening(port);
+                Ok(())
+            }
+            TcpState::Listening(_) => Ok(()),
+            _ => Err(Error::NotOpen),
+        }
+    }
+
+    /// Accepts a connection on a listening socket. The hardware socket that accepted the
+    /// connection is handed out as the connected socket and the listening handle moves to
+    /// another free socket of the pool. If there is none, [`Error::NoFreeSocket`] is returned
+    /// and the connection stays pending until a socket is closed.
+    fn accept(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+    ) -> nb::Result<(Self::TcpSocket, SocketAddr), Self::Error> {
+        let port = match socket.state {
+            TcpState::Listening(port) => port,
+            _ => return Err(nb::Error::Other(Error::NotOpen)),
+        };
+
+        match self.tcp_status(socket.socket)? {
+            SocketStatus::Established | SocketStatus::CloseWait => {}
+            SocketStatus::Listen | SocketStatus::SynRecv => return Err(nb::Error::WouldBlock),
+            _ => {
+                // the pending connection was reset before it could be accepted
+                self.open_tcp(socket.socket, port).map_err(Error::from)?;
+                (&mut *self, &crate::TcpSocket(socket.socket))
+                    .listen()
+                    .map_err(Error::from)?;
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+
+        let (ip, remote_port) = (&mut *self, &crate::TcpSocket(socket.socket))
+            .remote()
+            .map_err(Error::from)?;
+        let listener = self.take_any_socket().ok_or(Error::NoFreeSocket)?;
+        let listening = self
+            .open_tcp(listener, port)
+            .and_then(|_| (&mut *self, &crate::TcpSocket(listener)).listen());
+        if let Err(error) = listening {
+            // return the socket to the pool, the connection stays pending on the listener
+            self.close_socket(listener).map_err(Error::from)?;
+            return Err(nb::Error::Other(Error::from(error)));
+        }
+
+        let connection = TcpSocket {
+            socket: core::mem::replace(&mut socket.socket, listener),
+            state: TcpState::Connected,
+        };
+        Ok((
+            connection,
+            SocketAddr::V4(SocketAddrV4::new(ip.into(), remote_port)),
+        ))
+    }
+}
@@ -84,6 +84,34 @@ impl ::core::fmt::Display for Ipv4Addr {
     }
 }
 
+impl From<core::net::Ipv4Addr> for Ipv4Addr {
+    fn from(ip: core::net::Ipv4Addr) -> Self {
+        Ipv4Addr {
+            octets: ip.octets(),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for core::net::Ipv4Addr {
+    fn from(ip: Ipv4Addr) -> Self {
+        ip.octets.into()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Ipv4Addr {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "{=u8}.{=u8}.{=u8}.{=u8}",
+            self.octets[0],
+            self.octets[1],
+            self.octets[2],
+            self.octets[3],
+        )
+    }
+}
+
 /// MAC address struct.  Can be instantiated with `MacAddress::new`.
 ///
 /// This is an EUI-48 MAC address (previously called MAC-48).
@@ -142,3 +170,19 @@ impl ::core::fmt::Display for MacAddress {
         )
     }
 }
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for MacAddress {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "{=u8:02X}:{=u8:02X}:{=u8:02X}:{=u8:02X}:{=u8:02X}:{=u8:02X}",
+            self.octets[0],
+            self.octets[1],
+            self.octets[2],
+            self.octets[3],
+            self.octets[4],
+            self.octets[5],
+        )
+    }
+}
@@ -0,0 +1,195 @@
+//! Power management.
+//!
+//! [`power_down`] turns the PHY off between reporting windows and [`PoweredDown::resume`]
+//! turns it back on. The chip keeps its registers and socket buffers while the PHY is powered
+//! down, so datagrams received before are still there after resuming.
+//!
+//! Powering down waits until every pending send has left the chip, then parks the UDP, IPRAW
+//! and MACRAW sockets by remembering their configuration and closes TCP connections, which
+//! cannot survive the link going away. Use [`Tcp::disconnect`](crate::Tcp::disconnect)
+//! beforehand to close them gracefully. Resuming first re-applies the network configuration
+//! and reopens parked sockets the chip has lost, for example to a brown-out reset, and only
+//! then powers the PHY up, so the chip is ready to receive as soon as the link is up.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn sleep_until_next_window() {}
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::power;
+//!
+//! let mut parked = nb::block!(power::power_down(&mut w5500)).unwrap_or_else(|_| panic!());
+//! sleep_until_next_window();
+//! nb::block!(parked.resume(&mut w5500, now_ms())).unwrap_or_else(|_| panic!("no link"));
+//! # }
+//! ```
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{
+    ActiveW5500, OperationMode, Register, Socket, SocketCommand, SocketRegister, SocketStatus,
+};
+
+/// Time to wait for the link after powering up unless set with
+/// [`PoweredDown::with_link_timeout`]
+pub const DEFAULT_LINK_TIMEOUT_MS: u64 = 10_000;
+
+/// Gateway, subnet mask, MAC address and IP address, starting at the gateway register
+const NETWORK_SIZE: usize = 18;
+
+/// Error returned by [`PoweredDown::resume`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// The link did not come up within the timeout
+    LinkTimeout,
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// Configuration of a socket that stays open while the PHY is powered down
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct ParkedSocket {
+    mode: u8,
+    port: u16,
+    protocol: u8,
+}
+
+/// Configuration to restore when powering up, returned by [`power_down`]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PoweredDown {
+    operation_mode: OperationMode,
+    network: [u8; NETWORK_SIZE],
+    sockets: [Option<ParkedSocket>; 8],
+    link_timeout_ms: u64,
+    /// timestamp the PHY has been powered up at
+    resumed_ms: Option<u64>,
+}
+
+/// Parks the sockets and powers the PHY down. Returns [`nb::Error::WouldBlock`] while a socket
+/// is still sending.
+pub fn power_down<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+    w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+) -> nb::Result<PoweredDown, crate::Error<Spi::Error, ChipSelect::Error>> {
+    for number in 0..8 {
+        w5500.poll_send_complete(Socket::from_number(number))?;
+    }
+
+    let mut network = [0u8; NETWORK_SIZE];
+    w5500.read_from(Register::CommonRegister(0x00_01_u16), &mut network)?;
+    let mut sockets = [None; 8];
+    for (number, parked) in (0..8).zip(sockets.iter_mut()) {
+        if w5500.0.sockets & (0x01 << number) != 0 {
+            continue;
+        }
+        let socket = Socket::from_number(number);
+        match SocketStatus::from(w5500.read_u8(socket.at(SocketRegister::Status))?) {
+            SocketStatus::Closed => {}
+            SocketStatus::Udp | SocketStatus::IpRaw | SocketStatus::MacRaw => {
+                *parked = Some(ParkedSocket {
+                    mode: w5500.read_u8(socket.at(SocketRegister::Mode))?,
+                    port: w5500.read_u16(socket.at(SocketRegister::LocalPort))?,
+                    protocol: w5500.read_u8(socket.at(SocketRegister::IpProtocol))?,
+                });
+            }
+            _ => {
+                w5500.write_u8(
+                    socket.at(SocketRegister::Command),
+                    SocketCommand::Close as u8,
+                )?;
+            }
+        }
+    }
+
+    let operation_mode = match w5500.phy_cfg()?.operation_mode() {
+        OperationMode::PowerDown => OperationMode::Auto,
+        operation_mode => operation_mode,
+    };
+    w5500.set_operation_mode(OperationMode::PowerDown)?;
+    Ok(PoweredDown {
+        operation_mode,
+        network,
+        sockets,
+        link_timeout_ms: DEFAULT_LINK_TIMEOUT_MS,
+        resumed_ms: None,
+    })
+}
+
+impl PoweredDown {
+    /// Sets the time to wait for the link after powering up
+    pub fn with_link_timeout(mut self, link_timeout_ms: u64) -> Self {
+        self.link_timeout_ms = link_timeout_ms;
+        self
+    }
+
+    /// Restores the network configuration and the parked sockets, and powers the PHY up in
+    /// its previous operation mode. Further calls return [`nb::Error::WouldBlock`] until the
+    /// link is up. After [`Error::LinkTimeout`], the next call starts over.
+    pub fn resume<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let resumed_ms = match self.resumed_ms {
+            Some(resumed_ms) => resumed_ms,
+            None => {
+                self.restore(w5500).map_err(Error::Transfer)?;
+                self.resumed_ms = Some(now_ms);
+                now_ms
+            }
+        };
+
+        if w5500.phy_cfg().map_err(Error::Transfer)?.link_up() {
+            self.resumed_ms = None;
+            return Ok(());
+        }
+        if now_ms.saturating_sub(resumed_ms) >= self.link_timeout_ms {
+            self.resumed_ms = None;
+            return Err(nb::Error::Other(Error::LinkTimeout));
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn restore<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        w5500.write_to(Register::CommonRegister(0x00_01_u16), &self.network)?;
+        for (number, parked) in (0..8).zip(self.sockets.iter()) {
+            let parked = match parked {
+                Some(parked) => parked,
+                None => continue,
+            };
+            let socket = Socket::from_number(number);
+            if SocketStatus::from(w5500.read_u8(socket.at(SocketRegister::Status))?)
+                != SocketStatus::Closed
+            {
+                continue;
+            }
+            debug!("reopening {:?}", socket);
+            w5500.write_u8(socket.at(SocketRegister::Interrupt), 0xFF)?;
+            w5500.write_u16(socket.at(SocketRegister::LocalPort), parked.port)?;
+            w5500.write_u8(socket.at(SocketRegister::IpProtocol), parked.protocol)?;
+            w5500.write_to(
+                socket.at(SocketRegister::Mode),
+                &[
+                    parked.mode,               // Socket Mode Register
+                    SocketCommand::Open as u8, // Socket Command Register
+                ],
+            )?;
+        }
+        w5500.set_operation_mode(self.operation_mode)
+    }
+}
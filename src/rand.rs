@@ -0,0 +1,21 @@
+//! Pseudo-random numbers for transaction IDs, address candidates and delays. Not suitable for
+//! anything that needs to be unpredictable beyond its seed.
+
+/// xorshift32 generator
+pub(crate) struct Xorshift32(u32);
+
+impl Xorshift32 {
+    pub(crate) fn new(seed: u32) -> Self {
+        // xorshift gets stuck at zero
+        Xorshift32(seed | 1)
+    }
+
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
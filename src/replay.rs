@@ -0,0 +1,767 @@
+//! Recording and replaying of SPI transactions.
+//!
+//! A [`Recorder`] wraps the SPI bus and the chip-select pin that are handed to the driver and
+//! writes every frame the driver exchanges with the chip into a text log. A [`Replay`] reads
+//! such a log back and acts as SPI bus and chip-select pin itself: it checks that the driver
+//! sends the recorded bytes and answers reads with the recorded data. A capture taken on a
+//! misbehaving board can therefore be turned into a regression test that runs in `cargo test`.
+//!
+//! The log contains one frame per line:
+//!
+//! ```text
+//! <R|W> <control byte> <address> [<data>]
+//! ```
+//!
+//! The control byte (two hex digits) and the address (four hex digits) are the frame header
+//! as sent over the wire. The data is the hex encoded payload that was written to or read
+//! from the chip. Empty lines and lines starting with `#` are ignored by the [`Replay`].
+//!
+//! ```text
+//! # check the chip version, reset and configure the mode register
+//! R 00 0039 04
+//! W 04 0000 80
+//! W 04 0000 00
+//! # read PHYCFGR
+//! R 00 002e bf
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use w5500::replay::Replay;
+//! use w5500::{ArpResponses, ConnectionType, OnPingRequest, OnWakeOnLan, W5500};
+//!
+//! let replay = Replay::new(
+//!     "R 00 0039 04\n\
+//!      W 04 0000 80\n\
+//!      W 04 0000 00\n\
+//!      R 00 002e bf\n",
+//! );
+//! let mut spi = replay.spi();
+//! let mut w5500 = W5500::with_initialisation(
+//!     replay.chip_select(),
+//!     &mut spi,
+//!     OnWakeOnLan::Ignore,
+//!     OnPingRequest::Respond,
+//!     ConnectionType::Ethernet,
+//!     ArpResponses::Cache,
+//! )
+//! .unwrap();
+//!
+//! let phy_cfg = w5500.activate(&mut spi).unwrap().phy_cfg().unwrap();
+//! assert!(phy_cfg.link_up());
+//! replay.finish().unwrap();
+//! ```
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::str::Lines;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::COMMAND_WRITE;
+
+/// Length of the address and control phase of each SPI frame.
+const HEADER_LENGTH: usize = 3;
+
+/// Records the SPI frames exchanged through a [`RecordingSpi`] and a [`RecordingChipSelect`]
+/// into a [`core::fmt::Write`] sink, see the [module documentation](self) for the format.
+///
+/// # Examples
+///
+/// ```
+/// use w5500::replay::{Recorder, Replay};
+/// use w5500::{ArpResponses, ConnectionType, OnPingRequest, OnWakeOnLan, W5500};
+///
+/// let log = "R 00 0039 04\nW 04 0000 80\nW 04 0000 00\nR 00 002e bf\n";
+/// let replay = Replay::new(log);
+///
+/// let recorder = Recorder::new(String::new());
+/// let mut spi = recorder.spi(replay.spi());
+/// let mut w5500 = W5500::with_initialisation(
+///     recorder.chip_select(replay.chip_select()),
+///     &mut spi,
+///     OnWakeOnLan::Ignore,
+///     OnPingRequest::Respond,
+///     ConnectionType::Ethernet,
+///     ArpResponses::Cache,
+/// )
+/// .unwrap();
+/// w5500.activate(&mut spi).unwrap().phy_cfg().unwrap();
+/// drop((spi, w5500));
+///
+/// assert_eq!(recorder.finish().unwrap(), log);
+/// ```
+pub struct Recorder<Sink: Write> {
+    state: RefCell<RecorderState<Sink>>,
+}
+
+struct RecorderState<Sink> {
+    sink: Sink,
+    failed: bool,
+    selected: bool,
+    position: usize,
+    header: [u8; HEADER_LENGTH],
+    last_sent: u8,
+}
+
+impl<Sink: Write> Recorder<Sink> {
+    /// Creates a new recorder that writes the log into the given sink.
+    pub fn new(sink: Sink) -> Self {
+        Recorder {
+            state: RefCell::new(RecorderState {
+                sink,
+                failed: false,
+                selected: false,
+                position: 0,
+                header: [0u8; HEADER_LENGTH],
+                last_sent: 0,
+            }),
+        }
+    }
+
+    /// Wraps the SPI bus that is handed to the driver.
+    pub fn spi<Spi: FullDuplex<u8>>(&self, spi: Spi) -> RecordingSpi<'_, Sink, Spi> {
+        RecordingSpi {
+            recorder: self,
+            spi,
+        }
+    }
+
+    /// Wraps the chip-select pin that is handed to the driver.
+    pub fn chip_select<ChipSelect: OutputPin>(
+        &self,
+        chip_select: ChipSelect,
+    ) -> RecordingChipSelect<'_, Sink, ChipSelect> {
+        RecordingChipSelect {
+            recorder: self,
+            chip_select,
+        }
+    }
+
+    /// Returns the sink, or an error if any frame could not be written into it.
+    pub fn finish(self) -> Result<Sink, core::fmt::Error> {
+        let state = self.state.into_inner();
+        if state.failed {
+            Err(core::fmt::Error)
+        } else {
+            Ok(state.sink)
+        }
+    }
+
+    fn select(&self) {
+        let mut state = self.state.borrow_mut();
+        state.selected = true;
+        state.position = 0;
+    }
+
+    fn deselect(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.selected && state.position >= HEADER_LENGTH {
+            let result = state.sink.write_char('\n');
+            state.failed |= result.is_err();
+        }
+        state.selected = false;
+    }
+
+    fn sent(&self, byte: u8) {
+        self.state.borrow_mut().last_sent = byte;
+    }
+
+    fn received(&self, byte: u8) {
+        let mut state = self.state.borrow_mut();
+        if !state.selected {
+            return;
+        }
+        let state = &mut *state;
+        let position = state.position;
+        state.position += 1;
+
+        let result = if position < HEADER_LENGTH {
+            state.header[position] = state.last_sent;
+            if position + 1 == HEADER_LENGTH {
+                let control = state.header[2];
+                write!(
+                    state.sink,
+                    "{} {:02x} {:02x}{:02x}",
+                    if control & COMMAND_WRITE != 0 {
+                        'W'
+                    } else {
+                        'R'
+                    },
+                    control,
+                    state.header[0],
+                    state.header[1],
+                )
+            } else {
+                Ok(())
+            }
+        } else {
+            let data = if state.header[2] & COMMAND_WRITE != 0 {
+                state.last_sent
+            } else {
+                byte
+            };
+            if position == HEADER_LENGTH {
+                write!(state.sink, " {:02x}", data)
+            } else {
+                write!(state.sink, "{:02x}", data)
+            }
+        };
+        state.failed |= result.is_err();
+    }
+}
+
+/// SPI bus that forwards to the wrapped bus and records every transferred byte.
+pub struct RecordingSpi<'r, Sink: Write, Spi: FullDuplex<u8>> {
+    recorder: &'r Recorder<Sink>,
+    spi: Spi,
+}
+
+impl<Sink: Write, Spi: FullDuplex<u8>> RecordingSpi<'_, Sink, Spi> {
+    /// Returns the wrapped SPI bus.
+    pub fn into_inner(self) -> Spi {
+        self.spi
+    }
+}
+
+impl<Sink: Write, Spi: FullDuplex<u8>> FullDuplex<u8> for RecordingSpi<'_, Sink, Spi> {
+    type Error = Spi::Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let byte = self.spi.read()?;
+        self.recorder.received(byte);
+        Ok(byte)
+    }
+
+    fn send(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.spi.send(byte)?;
+        self.recorder.sent(byte);
+        Ok(())
+    }
+}
+
+/// Chip-select pin that forwards to the wrapped pin and marks the frame boundaries.
+pub struct RecordingChipSelect<'r, Sink: Write, ChipSelect: OutputPin> {
+    recorder: &'r Recorder<Sink>,
+    chip_select: ChipSelect,
+}
+
+impl<Sink: Write, ChipSelect: OutputPin> RecordingChipSelect<'_, Sink, ChipSelect> {
+    /// Returns the wrapped chip-select pin.
+    pub fn into_inner(self) -> ChipSelect {
+        self.chip_select
+    }
+}
+
+impl<Sink: Write, ChipSelect: OutputPin> OutputPin for RecordingChipSelect<'_, Sink, ChipSelect> {
+    type Error = ChipSelect::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.chip_select.set_low()?;
+        self.recorder.select();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.chip_select.set_high()?;
+        self.recorder.deselect();
+        Ok(())
+    }
+}
+
+/// Error returned by [`ReplaySpi`] and [`ReplayChipSelect`] when the driver deviates from
+/// the recorded log. Line numbers start at 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The line is not a valid frame.
+    Malformed { line: usize },
+    /// The driver started a frame, but the log has no frames left.
+    Exhausted,
+    /// The driver sent a different byte than recorded.
+    Mismatch {
+        line: usize,
+        expected: u8,
+        actual: u8,
+    },
+    /// The driver transferred more bytes than recorded.
+    Overrun { line: usize },
+    /// The driver ended the frame before all recorded bytes were transferred.
+    Underrun { line: usize },
+    /// The driver transferred a byte without selecting the chip.
+    NotSelected,
+    /// The driver selected the chip while a frame was still in progress.
+    AlreadySelected { line: usize },
+    /// The replay was finished, but the log has frames left.
+    Remaining { line: usize },
+}
+
+/// Replays a log written by a [`Recorder`], see the [module documentation](self) for the format.
+pub struct Replay<'a> {
+    state: RefCell<ReplayState<'a>>,
+}
+
+struct ReplayState<'a> {
+    lines: Lines<'a>,
+    line: usize,
+    frame: Option<Frame<'a>>,
+    next_read: u8,
+}
+
+struct Frame<'a> {
+    line: usize,
+    header: [u8; HEADER_LENGTH],
+    write: bool,
+    data: &'a str,
+    position: usize,
+}
+
+impl<'a> Replay<'a> {
+    /// Creates a new replay of the given log.
+    pub fn new(log: &'a str) -> Self {
+        Replay {
+            state: RefCell::new(ReplayState {
+                lines: log.lines(),
+                line: 0,
+                frame: None,
+                next_read: 0,
+            }),
+        }
+    }
+
+    /// SPI bus to hand to the driver.
+    pub fn spi(&self) -> ReplaySpi<'_, 'a> {
+        ReplaySpi(self)
+    }
+
+    /// Chip-select pin to hand to the driver.
+    pub fn chip_select(&self) -> ReplayChipSelect<'_, 'a> {
+        ReplayChipSelect(self)
+    }
+
+    /// Verifies that the driver exchanged every recorded frame.
+    pub fn finish(&self) -> Result<(), ReplayError> {
+        let mut state = self.state.borrow_mut();
+        if let Some(frame) = &state.frame {
+            return Err(ReplayError::Underrun { line: frame.line });
+        }
+        match state.next_frame()? {
+            Some(frame) => Err(ReplayError::Remaining { line: frame.line }),
+            None => Ok(()),
+        }
+    }
+
+    fn select(&self) -> Result<(), ReplayError> {
+        let mut state = self.state.borrow_mut();
+        if let Some(frame) = &state.frame {
+            return Err(ReplayError::AlreadySelected { line: frame.line });
+        }
+        let frame = state.next_frame()?.ok_or(ReplayError::Exhausted)?;
+        state.frame = Some(frame);
+        Ok(())
+    }
+
+    fn deselect(&self) -> Result<(), ReplayError> {
+        let mut state = self.state.borrow_mut();
+        match state.frame.take() {
+            Some(frame) if frame.position < HEADER_LENGTH || !frame.data.is_empty() => {
+                Err(ReplayError::Underrun { line: frame.line })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn send(&self, byte: u8) -> Result<(), ReplayError> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let frame = state.frame.as_mut().ok_or(ReplayError::NotSelected)?;
+        let line = frame.line;
+
+        let expected = if frame.position < HEADER_LENGTH {
+            Some(frame.header[frame.position])
+        } else {
+            let recorded = take_hex_byte(&mut frame.data).ok_or(ReplayError::Overrun { line })?;
+            if frame.write {
+                Some(recorded)
+            } else {
+                state.next_read = recorded;
+                None
+            }
+        };
+        frame.position += 1;
+
+        match expected {
+            Some(expected) if expected != byte => Err(ReplayError::Mismatch {
+                line,
+                expected,
+                actual: byte,
+            }),
+            Some(_) => {
+                state.next_read = 0;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn read(&self) -> u8 {
+        self.state.borrow().next_read
+    }
+}
+
+impl<'a> ReplayState<'a> {
+    /// Parses the next frame in the log, skipping empty lines and comments
+    fn next_frame(&mut self) -> Result<Option<Frame<'a>>, ReplayError> {
+        for line in &mut self.lines {
+            self.line += 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return parse_frame(self.line, line).map(Some);
+        }
+        Ok(None)
+    }
+}
+
+fn parse_frame(line_number: usize, line: &str) -> Result<Frame<'_>, ReplayError> {
+    let malformed = ReplayError::Malformed { line: line_number };
+    let mut fields = line.split_whitespace();
+
+    let write = match fields.next() {
+        Some("W") | Some("w") => true,
+        Some("R") | Some("r") => false,
+        _ => return Err(malformed),
+    };
+    let control = fields
+        .next()
+        .filter(|field| field.len() == 2)
+        .and_then(|field| u8::from_str_radix(field, 16).ok())
+        .ok_or(malformed)?;
+    let address = fields
+        .next()
+        .filter(|field| field.len() == 4)
+        .and_then(|field| u16::from_str_radix(field, 16).ok())
+        .ok_or(malformed)?;
+    let data = fields.next().unwrap_or("");
+
+    if fields.next().is_some()
+        || data.len() & 1 != 0
+        || !data.bytes().all(|byte| byte.is_ascii_hexdigit())
+        || write != (control & COMMAND_WRITE != 0)
+    {
+        return Err(malformed);
+    }
+
+    let address = address.to_be_bytes();
+    Ok(Frame {
+        line: line_number,
+        header: [address[0], address[1], control],
+        write,
+        data,
+        position: 0,
+    })
+}
+
+/// Takes the next two hex digits from the front of `data`, which has been validated by
+/// [`parse_frame`] already. Returns `None` if `data` is empty.
+fn take_hex_byte(data: &mut &str) -> Option<u8> {
+    if data.is_empty() {
+        return None;
+    }
+    let (byte, rest) = data.split_at(2);
+    *data = rest;
+    u8::from_str_radix(byte, 16).ok()
+}
+
+/// SPI bus that answers the driver from a [`Replay`].
+pub struct ReplaySpi<'r, 'a>(&'r Replay<'a>);
+
+impl FullDuplex<u8> for ReplaySpi<'_, '_> {
+    type Error = ReplayError;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        Ok(self.0.read())
+    }
+
+    fn send(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.0.send(byte).map_err(nb::Error::Other)
+    }
+}
+
+/// Chip-select pin that marks the frame boundaries of a [`Replay`].
+pub struct ReplayChipSelect<'r, 'a>(&'r Replay<'a>);
+
+impl OutputPin for ReplayChipSelect<'_, '_> {
+    type Error = ReplayError;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.select()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.deselect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{
+        ArpResponses, ConnectionType, IntoTcpSocket, IntoUdpSocket, Ipv4Addr, OnPingRequest,
+        OnWakeOnLan, Socket, SocketStatus, Tcp, Udp, W5500,
+    };
+    use core::cell::Cell;
+    use core::convert::Infallible;
+    use core::fmt::Debug;
+    use std::string::String;
+
+    /// Checks the chip version, resets the chip and configures the mode register
+    const INITIALISATION: &str = "R 00 0039 04\nW 04 0000 80\nW 04 0000 00\n";
+    /// Opens socket 1 in UDP mode on port 1234
+    const OPEN_UDP: &str = "W 2c 0002 18\nW 2c 0004 04d2\nW 2c 0000 0201\n";
+
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    fn initialise<'r, 'a>(
+        replay: &'r Replay<'a>,
+        spi: &mut ReplaySpi<'r, 'a>,
+    ) -> W5500<ReplayChipSelect<'r, 'a>> {
+        W5500::with_initialisation(
+            replay.chip_select(),
+            spi,
+            OnWakeOnLan::Ignore,
+            OnPingRequest::Respond,
+            ConnectionType::Ethernet,
+            ArpResponses::Cache,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn udp_send() {
+        let log = [
+            INITIALISATION,
+            OPEN_UDP,
+            "# queue a datagram to 192.168.0.2:5678\n\
+             R 28 0020 0800\nR 28 0020 0800\nW 2c 000c c0a80002162e\n\
+             R 28 0024 0000\nW 34 0000 70696e67\nR 28 0024 0000\nW 2c 0024 0004\n\
+             W 2c 0001 20\n",
+            "# the chip reports SEND_OK\n\
+             R 28 0002 10\nW 2c 0002 10\n",
+        ]
+        .concat();
+        let replay = Replay::new(&log);
+        let mut spi = replay.spi();
+        let mut w5500 = initialise(&replay, &mut spi);
+        let mut w5500 = w5500.activate(&mut spi).unwrap();
+        let socket = w5500.take_socket(Socket::Socket1).unwrap();
+        let socket = (&mut w5500, socket)
+            .try_into_udp_server_socket(1234)
+            .map_err(|(_, error)| error)
+            .unwrap();
+
+        (&mut w5500, &socket).send(&REMOTE, 5678, b"ping").unwrap();
+        (&mut w5500, &socket).poll_send().unwrap();
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn udp_receive() {
+        let log = [
+            INITIALISATION,
+            OPEN_UDP,
+            "# 12 bytes received: header and payload of a 4 byte datagram\n\
+             R 28 0026 000c\nR 28 0026 000c\nR 28 0028 0000\n\
+             R 38 0000 c0a80002162e0004\nR 38 0008 706f6e67\n\
+             W 2c 0028 000c\nW 2c 0001 40\n",
+        ]
+        .concat();
+        let replay = Replay::new(&log);
+        let mut spi = replay.spi();
+        let mut w5500 = initialise(&replay, &mut spi);
+        let mut w5500 = w5500.activate(&mut spi).unwrap();
+        let socket = w5500.take_socket(Socket::Socket1).unwrap();
+        let socket = (&mut w5500, socket)
+            .try_into_udp_server_socket(1234)
+            .map_err(|(_, error)| error)
+            .unwrap();
+
+        let mut buffer = [0u8; 8];
+        assert_eq!(
+            (&mut w5500, &socket).receive(&mut buffer).unwrap(),
+            Some((REMOTE, 5678, 4, false))
+        );
+        assert_eq!(&buffer[..4], b"pong");
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn udp_receive_truncated() {
+        let log = [
+            INITIALISATION,
+            OPEN_UDP,
+            "# 17 bytes received: header and payload of a 9 byte datagram\n\
+             R 28 0026 0011\nR 28 0026 0011\nR 28 0028 0000\n\
+             R 38 0000 c0a80002162e0009\n\
+             # only the part that fits is read, the rest is skipped\n\
+             R 38 0008 706f6e67\nW 2c 0028 0011\nW 2c 0001 40\n",
+        ]
+        .concat();
+        let replay = Replay::new(&log);
+        let mut spi = replay.spi();
+        let mut w5500 = initialise(&replay, &mut spi);
+        let mut w5500 = w5500.activate(&mut spi).unwrap();
+        let socket = w5500.take_socket(Socket::Socket1).unwrap();
+        let socket = (&mut w5500, socket)
+            .try_into_udp_server_socket(1234)
+            .map_err(|(_, error)| error)
+            .unwrap();
+
+        let mut buffer = [0u8; 4];
+        assert_eq!(
+            (&mut w5500, &socket).receive(&mut buffer).unwrap(),
+            Some((REMOTE, 5678, 9, true))
+        );
+        assert_eq!(&buffer, b"pong");
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn tcp_connect() {
+        let log = [
+            INITIALISATION,
+            "# open socket 2 in TCP mode on port 49152\n\
+             W 4c 0002 ff\nW 4c 0004 c000\nW 4c 0000 0101\n",
+            "# connect to 192.168.0.2:80\n\
+             W 4c 000c c0a800020050\nW 4c 0001 04\n",
+            "# SOCK_ESTABLISHED\n\
+             R 48 0003 17\n",
+        ]
+        .concat();
+        let replay = Replay::new(&log);
+        let mut spi = replay.spi();
+        let mut w5500 = initialise(&replay, &mut spi);
+        let mut w5500 = w5500.activate(&mut spi).unwrap();
+        let socket = w5500.take_socket(Socket::Socket2).unwrap();
+        let socket = (&mut w5500, socket)
+            .try_into_tcp_socket(49152)
+            .map_err(|(_, error)| error)
+            .unwrap();
+
+        (&mut w5500, &socket).connect(&REMOTE, 80).unwrap();
+        assert_eq!(
+            (&mut w5500, &socket).status().unwrap(),
+            SocketStatus::Established
+        );
+        replay.finish().unwrap();
+    }
+
+    /// Chip that answers reads of the version register with `0x04` and all other reads with
+    /// `0xff`, so every size is large and every interrupt flag is set
+    #[derive(Default)]
+    struct Chip {
+        /// bytes transferred in the current frame
+        position: Cell<usize>,
+        address: Cell<u16>,
+    }
+
+    struct ChipSpi<'c> {
+        chip: &'c Chip,
+        response: u8,
+    }
+
+    impl FullDuplex<u8> for ChipSpi<'_> {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            Ok(self.response)
+        }
+
+        fn send(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+            let position = self.chip.position.get();
+            self.chip.position.set(position + 1);
+            let address = self.chip.address.get();
+            self.response = match position {
+                0 => {
+                    self.chip.address.set(u16::from(byte) << 8);
+                    0
+                }
+                1 => {
+                    self.chip.address.set(address | u16::from(byte));
+                    0
+                }
+                2 => 0,
+                _ if usize::from(address) + position - HEADER_LENGTH == 0x39 => 0x04,
+                _ => 0xff,
+            };
+            Ok(())
+        }
+    }
+
+    struct ChipSelect<'c>(&'c Chip);
+
+    impl OutputPin for ChipSelect<'_> {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.position.set(0);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Opens socket 1 in UDP mode and sends a datagram
+    fn send_datagram<Spi: FullDuplex<u8>, Pin: OutputPin>(spi: &mut Spi, chip_select: Pin)
+    where
+        Spi::Error: Debug,
+        Pin::Error: Debug,
+    {
+        let mut w5500 = W5500::with_initialisation(
+            chip_select,
+            spi,
+            OnWakeOnLan::Ignore,
+            OnPingRequest::Respond,
+            ConnectionType::Ethernet,
+            ArpResponses::Cache,
+        )
+        .unwrap();
+        let mut w5500 = w5500.activate(spi).unwrap();
+        let socket = w5500.take_socket(Socket::Socket1).unwrap();
+        let socket = (&mut w5500, socket)
+            .try_into_udp_server_socket(1234)
+            .ok()
+            .unwrap();
+        block!((&mut w5500, &socket).send(&REMOTE, 5678, b"ping")).unwrap();
+        block!((&mut w5500, &socket).poll_send()).unwrap();
+    }
+
+    #[test]
+    fn replays_recorded_udp_send() {
+        let chip = Chip::default();
+        let recorder = Recorder::new(String::new());
+        let spi = ChipSpi {
+            chip: &chip,
+            response: 0,
+        };
+        send_datagram(
+            &mut recorder.spi(spi),
+            recorder.chip_select(ChipSelect(&chip)),
+        );
+        let log = recorder.finish().unwrap();
+        assert!(
+            log.contains("W 2c 0001 20\n"),
+            "no SEND command in\n{}",
+            log
+        );
+
+        let replay = Replay::new(&log);
+        send_datagram(&mut replay.spi(), replay.chip_select());
+        replay.finish().unwrap();
+    }
+}
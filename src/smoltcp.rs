@@ -0,0 +1,179 @@
+//! Implementation of the [`smoltcp`] [`Device`] trait.
+//!
+//! The [`MacRawDevice`] moves raw Ethernet frames between smoltcp and the RX/TX buffers of
+//! [`Socket::Socket0`](crate::Socket::Socket0) in MACRAW mode. The IP stack then runs
+//! entirely in smoltcp, which allows IPv6 or more than the eight connections the chip
+//! supports in hardware. The chip neither computes nor verifies any checksums in this mode.
+//!
+//! # Examples
+//!
+//! ```
+//! use smoltcp::phy::Device;
+//! use smoltcp::time::Instant;
+//! use w5500::replay::Replay;
+//! use w5500::smoltcp::MacRawDevice;
+//! use w5500::{IntoMacRawSocket, MacFilter, Socket};
+//! # use w5500::{ArpResponses, ConnectionType, OnPingRequest, OnWakeOnLan, W5500};
+//!
+//! # let replay = Replay::new(
+//! #     "R 00 0039 04\nW 04 0000 80\nW 04 0000 00\nW 0c 0002 ff\nW 0c 0000 8401\n\
+//! #      R 08 0026 0000\nR 08 0026 0000\n",
+//! # );
+//! # let mut spi = replay.spi();
+//! # let mut w5500 = W5500::with_initialisation(
+//! #     replay.chip_select(),
+//! #     &mut spi,
+//! #     OnWakeOnLan::Ignore,
+//! #     OnPingRequest::Respond,
+//! #     ConnectionType::Ethernet,
+//! #     ArpResponses::Cache,
+//! # )
+//! # .unwrap();
+//! let mut active = w5500.activate(&mut spi).unwrap();
+//! let socket = active.take_socket(Socket::Socket0).unwrap();
+//! let socket = (&mut active, socket)
+//!     .try_into_macraw_socket(MacFilter::Enabled)
+//!     .unwrap_or_else(|_| panic!("failed to open socket"));
+//!
+//! let mut device = MacRawDevice::new(active, socket);
+//! assert_eq!(device.capabilities().max_transmission_unit, 1514);
+//! assert!(device.receive(Instant::from_millis(0)).is_none());
+//! # replay.finish().unwrap();
+//! ```
+//!
+//! [`smoltcp`]: https://docs.rs/smoltcp
+
+use ::smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use ::smoltcp::time::Instant;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{ActiveW5500, Error, MacFilter, MacRaw, MacRawSocket};
+
+/// Maximum size of an Ethernet frame without the frame check sequence
+pub const MTU: usize = 1514;
+
+/// smoltcp [`Device`] on top of a [`MacRawSocket`]
+pub struct MacRawDevice<'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> {
+    w5500: ActiveW5500<'a, 'b, ChipSelect, Spi>,
+    socket: MacRawSocket,
+    rx_buffer: [u8; MTU],
+    tx_buffer: [u8; MTU],
+}
+
+impl<'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> MacRawDevice<'a, 'b, ChipSelect, Spi> {
+    /// Creates a new device that exchanges frames through the given socket
+    pub fn new(w5500: ActiveW5500<'a, 'b, ChipSelect, Spi>, socket: MacRawSocket) -> Self {
+        MacRawDevice {
+            w5500,
+            socket,
+            rx_buffer: [0u8; MTU],
+            tx_buffer: [0u8; MTU],
+        }
+    }
+
+    /// Changes the MAC filter of the socket, see [`MacRaw::set_mac_filter`]
+    pub fn set_mac_filter(
+        &mut self,
+        filter: MacFilter,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        (&mut self.w5500, &self.socket).set_mac_filter(filter)
+    }
+
+    /// Gives access to the chip, for example to read the PHY state
+    pub fn w5500(&mut self) -> &mut ActiveW5500<'a, 'b, ChipSelect, Spi> {
+        &mut self.w5500
+    }
+
+    /// Returns the chip and the socket
+    pub fn release(self) -> (ActiveW5500<'a, 'b, ChipSelect, Spi>, MacRawSocket) {
+        (self.w5500, self.socket)
+    }
+}
+
+impl<'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> Device
+    for MacRawDevice<'a, 'b, ChipSelect, Spi>
+{
+    type RxToken<'t>
+        = RxToken<'t>
+    where
+        Self: 't;
+    type TxToken<'t>
+        = TxToken<'t, 'a, 'b, ChipSelect, Spi>
+    where
+        Self: 't;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let length = match (&mut self.w5500, &self.socket).receive_frame(&mut self.rx_buffer) {
+            Ok(Some(length)) if length <= MTU => length,
+            Ok(Some(length)) => {
+                debug!("dropped oversized frame of {} bytes", length);
+                return None;
+            }
+            Ok(None) => return None,
+            Err(_) => {
+                debug!("failed to receive frame");
+                return None;
+            }
+        };
+        Some((
+            RxToken(&self.rx_buffer[..length]),
+            TxToken {
+                w5500: &mut self.w5500,
+                socket: &self.socket,
+                buffer: &mut self.tx_buffer,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken {
+            w5500: &mut self.w5500,
+            socket: &self.socket,
+            buffer: &mut self.tx_buffer,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = MTU;
+        capabilities.max_burst_size = Some(1);
+        capabilities
+    }
+}
+
+/// Received frame handed to smoltcp
+pub struct RxToken<'t>(&'t [u8]);
+
+impl phy::RxToken for RxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(self.0)
+    }
+}
+
+/// Frame buffer handed to smoltcp, sent through the socket once it has been filled
+pub struct TxToken<'t, 'a, 'b, ChipSelect: OutputPin, Spi: FullDuplex<u8>> {
+    w5500: &'t mut ActiveW5500<'a, 'b, ChipSelect, Spi>,
+    socket: &'t MacRawSocket,
+    buffer: &'t mut [u8; MTU],
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> phy::TxToken
+    for TxToken<'_, '_, '_, ChipSelect, Spi>
+{
+    fn consume<R, F>(self, length: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let frame = &mut self.buffer[..length];
+        let result = f(frame);
+        if block!((&mut *self.w5500, self.socket).send_frame(frame)).is_err() {
+            debug!("failed to send frame of {} bytes", length);
+        }
+        result
+    }
+}
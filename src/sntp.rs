@@ -0,0 +1,380 @@
+//! SNTPv4 client (RFC 4330).
+//!
+//! The [`SntpClient`] asks the configured servers in turn for the time over a UDP socket on an
+//! ephemeral port. It checks the mode, stratum and kiss-o'-death codes of the responses and
+//! computes the clock offset and round-trip delay against a local millisecond clock that the
+//! caller passes to [`SntpClient::poll`]. Once synchronized, [`SntpClient::now`] translates
+//! that clock into a [`UnixTime`].
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::sntp::SntpClient;
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! let servers = [Ipv4Addr::new(192, 168, 0, 1), Ipv4Addr::new(162, 159, 200, 1)];
+//! let socket = w5500.take_socket(Socket::Socket5).unwrap();
+//! let mut sntp = SntpClient::new(&mut w5500, socket, &servers)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"))
+//!     .with_poll_interval(15 * 60 * 1000);
+//!
+//! loop {
+//!     if let Ok(Some(measurement)) = sntp.poll(&mut w5500, now_ms()) {
+//!         // measurement.offset_ns, measurement.delay_ns, ...
+//!     }
+//!     if let Some(time) = sntp.now(now_ms()) {
+//!         // time.seconds, time.nanos
+//!     }
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{ActiveW5500, Ipv4Addr, Udp, UdpSocket, UninitializedSocket};
+
+/// UDP port the servers listen on
+pub const SERVER_PORT: u16 = 123;
+
+/// Size of a message without extension fields or authenticator
+const MESSAGE_SIZE: usize = 48;
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+const NANOS_PER_MILLI: i64 = 1_000_000;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+/// Highest stratum of a synchronized server
+const MAX_STRATUM: u8 = 15;
+
+/// Default time between two measurements, the minimum poll interval of NTP
+const DEFAULT_POLL_INTERVAL_MS: u64 = 64_000;
+/// Shortest poll interval RFC 4330 section 10 allows
+const MIN_POLL_INTERVAL_MS: u64 = 15_000;
+/// Longest poll interval a `RATE` kiss-o'-death can back off to
+const MAX_POLL_INTERVAL_MS: u64 = 36 * 60 * 60 * 1000;
+/// Default time to wait for a response before asking the next server
+const DEFAULT_TIMEOUT_MS: u64 = 2_000;
+
+/// Error returned by [`SntpClient::poll`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// None of the servers answered with a valid response in time, the next poll is
+    /// scheduled after the poll interval
+    Timeout,
+    /// All servers denied access with a `DENY` or `RSTR` kiss-o'-death
+    Denied,
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// Point in time as seconds and nanoseconds since the Unix epoch
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UnixTime {
+    /// Whole seconds since 1970-01-01T00:00:00Z
+    pub seconds: u64,
+    /// Nanoseconds within the second
+    pub nanos: u32,
+}
+
+impl UnixTime {
+    fn from_nanos(nanos: i64) -> UnixTime {
+        let nanos = nanos.max(0);
+        UnixTime {
+            seconds: (nanos / NANOS_PER_SECOND) as u64,
+            nanos: (nanos % NANOS_PER_SECOND) as u32,
+        }
+    }
+}
+
+/// Result of a successful exchange with a server
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Measurement {
+    /// The time when the response has been received
+    pub time: UnixTime,
+    /// Offset of the server clock to the previous estimate of the client, in nanoseconds.
+    /// Before the first synchronization, the local clock counts from the Unix epoch.
+    pub offset_ns: i64,
+    /// Round-trip delay of the exchange, in nanoseconds
+    pub delay_ns: u64,
+    /// Stratum of the server
+    pub stratum: u8,
+    /// The server that answered
+    pub server: Ipv4Addr,
+}
+
+/// Request in flight
+struct Request {
+    server: usize,
+    sent_ms: u64,
+    /// transmit timestamp of the request, echoed as origin timestamp by the server
+    transmit: [u8; 8],
+}
+
+/// SNTP client, see the [module documentation](self)
+pub struct SntpClient<'a> {
+    socket: UdpSocket,
+    servers: &'a [Ipv4Addr],
+    /// servers that denied access, one bit per entry of `servers`
+    denied: u32,
+    /// next server to ask
+    server: usize,
+    /// number of servers asked in the current round
+    asked: usize,
+    poll_interval_ms: u64,
+    timeout_ms: u64,
+    next_poll_ms: u64,
+    request: Option<Request>,
+    /// Unix time minus local time, once synchronized
+    clock_offset_ns: Option<i64>,
+    buffer: [u8; MESSAGE_SIZE],
+}
+
+impl<'a> SntpClient<'a> {
+    /// Opens the socket on an ephemeral port and creates a client that asks the given servers
+    /// in order, falling back to the next one if a server does not answer. At most the first
+    /// 32 servers are used.
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: UninitializedSocket,
+        servers: &'a [Ipv4Addr],
+    ) -> Result<Self, crate::Error<Spi::Error, ChipSelect::Error>> {
+        let socket = socket.0;
+        let port = w5500.0.next_ephemeral_port();
+        w5500.open_udp(socket, port)?;
+        Ok(SntpClient {
+            socket: UdpSocket(socket),
+            servers: &servers[..servers.len().min(32)],
+            denied: 0,
+            server: 0,
+            asked: 0,
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            next_poll_ms: 0,
+            request: None,
+            clock_offset_ns: None,
+            buffer: [0u8; MESSAGE_SIZE],
+        })
+    }
+
+    /// Sets the time between two measurements, at least 15 seconds
+    pub fn with_poll_interval(mut self, poll_interval_ms: u64) -> Self {
+        self.poll_interval_ms = poll_interval_ms.max(MIN_POLL_INTERVAL_MS);
+        self
+    }
+
+    /// Sets the time to wait for a response before asking the next server
+    pub fn with_timeout(mut self, timeout_ms: u64) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// The current time, or `None` before the first successful measurement
+    pub fn now(&self, now_ms: u64) -> Option<UnixTime> {
+        self.clock_offset_ns
+            .map(|offset| UnixTime::from_nanos(local_nanos(now_ms) + offset))
+    }
+
+    /// Requests a measurement at the next call of [`SntpClient::poll`], regardless of the
+    /// poll interval
+    pub fn poll_now(&mut self) {
+        self.next_poll_ms = 0;
+    }
+
+    /// Returns the socket, abandoning the request in flight
+    pub fn release(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Sends requests when the poll interval has passed, processes responses and falls back
+    /// to the next server on timeouts. Returns the measurement once a valid response arrived.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Measurement>, Error<Spi::Error, ChipSelect::Error>> {
+        while let Some((ip, port, length, _)) = (&mut *w5500, &self.socket)
+            .receive(&mut self.buffer)
+            .map_err(Error::Transfer)?
+        {
+            let server = match &self.request {
+                Some(request) if port == SERVER_PORT && ip == self.servers[request.server] => {
+                    request.server
+                }
+                _ => continue,
+            };
+            // extension fields and the authenticator follow the fixed header
+            if let Some(measurement) = self.handle_response(length.min(MESSAGE_SIZE), now_ms) {
+                return Ok(Some(measurement));
+            }
+            if self.request.is_none() {
+                // kiss-o'-death, ask the next server right away
+                self.server = server + 1;
+                self.send_request(w5500, now_ms)?;
+            }
+        }
+
+        match &self.request {
+            Some(request) if now_ms.saturating_sub(request.sent_ms) >= self.timeout_ms => {
+                debug!("SNTP server {:?} timed out", self.servers[request.server]);
+                self.server = request.server + 1;
+                self.send_request(w5500, now_ms)?;
+            }
+            None if now_ms >= self.next_poll_ms => {
+                self.asked = 0;
+                self.send_request(w5500, now_ms)?;
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Sends a request to the next server that has not denied access, or ends the round
+    fn send_request<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        self.request = None;
+        let available = self.servers.len() - self.denied.count_ones() as usize;
+        if available == 0 {
+            self.next_poll_ms = now_ms + self.poll_interval_ms;
+            return Err(Error::Denied);
+        }
+        if self.asked >= available {
+            self.next_poll_ms = now_ms + self.poll_interval_ms;
+            return Err(Error::Timeout);
+        }
+        let mut server = self.server % self.servers.len();
+        while self.denied & (1 << server) != 0 {
+            server = (server + 1) % self.servers.len();
+        }
+        self.asked += 1;
+
+        let transmit = ntp_timestamp(local_nanos(now_ms) + self.clock_offset_ns.unwrap_or(0));
+        self.buffer = [0u8; MESSAGE_SIZE];
+        self.buffer[0] = VERSION << 3 | MODE_CLIENT;
+        self.buffer[40..48].copy_from_slice(&transmit);
+        // a request the chip cannot take or deliver counts as lost, the timeout asks the next
+        // server
+        match (&mut *w5500, &self.socket).send(&self.servers[server], SERVER_PORT, &self.buffer) {
+            Ok(()) | Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(crate::Error::ArpTimeout)) => {
+                debug!("SNTP request lost to an ARP timeout");
+            }
+            Err(nb::Error::Other(error)) => return Err(Error::Transfer(error)),
+        }
+        self.request = Some(Request {
+            server,
+            sent_ms: now_ms,
+            transmit,
+        });
+        Ok(())
+    }
+
+    /// Validates the response to the request in flight. Ends the request on a kiss-o'-death,
+    /// keeps it on responses that are to be ignored.
+    fn handle_response(&mut self, length: usize, now_ms: u64) -> Option<Measurement> {
+        let request = self.request.as_ref()?;
+        let message = &self.buffer[..length];
+        if length < MESSAGE_SIZE || message[24..32] != request.transmit {
+            return None;
+        }
+        let leap = message[0] >> 6;
+        let version = (message[0] >> 3) & 0x07;
+        let mode = message[0] & 0x07;
+        let stratum = message[1];
+        if mode != MODE_SERVER || !(3..=VERSION).contains(&version) {
+            return None;
+        }
+
+        if stratum == 0 {
+            let code = &message[12..16];
+            debug!("SNTP kiss-o'-death {:?}", code);
+            match code {
+                b"DENY" | b"RSTR" => self.denied |= 1 << request.server,
+                b"RATE" => {
+                    self.poll_interval_ms = (self.poll_interval_ms * 2).min(MAX_POLL_INTERVAL_MS)
+                }
+                _ => {}
+            }
+            self.request = None;
+            return None;
+        }
+        if stratum > MAX_STRATUM
+            || leap == LEAP_UNSYNCHRONIZED
+            || message[40..48].iter().all(|byte| *byte == 0)
+        {
+            return None;
+        }
+
+        let clock_offset = self.clock_offset_ns.unwrap_or(0);
+        let originate = local_nanos(request.sent_ms) + clock_offset;
+        let receive = unix_nanos(&message[32..40]);
+        let transmit = unix_nanos(&message[40..48]);
+        let destination = local_nanos(now_ms) + clock_offset;
+
+        let offset = ((receive - originate) + (transmit - destination)) / 2;
+        let delay = ((destination - originate) - (transmit - receive)).max(0);
+        let measurement = Measurement {
+            time: UnixTime::from_nanos(destination + offset),
+            offset_ns: offset,
+            delay_ns: delay as u64,
+            stratum,
+            server: self.servers[request.server],
+        };
+
+        self.clock_offset_ns = Some(clock_offset + offset);
+        self.server = request.server;
+        self.next_poll_ms = now_ms + self.poll_interval_ms;
+        self.request = None;
+        debug!(
+            "SNTP offset {} ns, delay {} ns",
+            measurement.offset_ns, measurement.delay_ns
+        );
+        Some(measurement)
+    }
+}
+
+fn local_nanos(now_ms: u64) -> i64 {
+    now_ms as i64 * NANOS_PER_MILLI
+}
+
+/// Converts an NTP timestamp to nanoseconds since the Unix epoch. Timestamps with the highest
+/// bit cleared belong to era 1, which starts in 2036.
+fn unix_nanos(timestamp: &[u8]) -> i64 {
+    let mut seconds = i64::from(BigEndian::read_u32(&timestamp[0..4]));
+    if seconds & 0x8000_0000 == 0 {
+        seconds += 1 << 32;
+    }
+    let fraction = i64::from(BigEndian::read_u32(&timestamp[4..8]));
+    (seconds - NTP_UNIX_OFFSET) * NANOS_PER_SECOND + ((fraction * NANOS_PER_SECOND) >> 32)
+}
+
+/// Converts nanoseconds since the Unix epoch to an NTP timestamp
+fn ntp_timestamp(nanos: i64) -> [u8; 8] {
+    let seconds = nanos.div_euclid(NANOS_PER_SECOND) + NTP_UNIX_OFFSET;
+    let fraction = (nanos.rem_euclid(NANOS_PER_SECOND) << 32) / NANOS_PER_SECOND;
+    let mut timestamp = [0u8; 8];
+    BigEndian::write_u32(&mut timestamp[0..4], seconds as u32);
+    BigEndian::write_u32(&mut timestamp[4..8], fraction as u32);
+    timestamp
+}
@@ -0,0 +1,457 @@
+//! Syslog sender (RFC 5424 and RFC 3164) over UDP (RFC 5426).
+//!
+//! The [`SyslogClient`] formats each [`Message`] into a fixed buffer of [`MAX_MESSAGE_SIZE`]
+//! bytes and sends it as a single datagram to the collector. Longer messages are truncated.
+//! Without a configured hostname, the IP address of the chip is sent in its place.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::syslog::{Facility, Message, SdElement, Severity, SyslogClient};
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! let socket = w5500.take_socket(Socket::Socket4).unwrap();
+//! let mut syslog = SyslogClient::new(&mut w5500, socket, Ipv4Addr::new(192, 168, 0, 1))
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"))
+//!     .with_facility(Facility::Local0)
+//!     .with_hostname("sensor-12")
+//!     .with_app_name("climate");
+//!
+//! let origin = [SdElement {
+//!     id: "origin",
+//!     params: &[("software", "climate"), ("swVersion", "1.4.2")],
+//! }];
+//! let temperature = 81;
+//! nb::block!(syslog.send(
+//!     &mut w5500,
+//!     &Message::new(Severity::Warning, format_args!("temperature at {} C", temperature))
+//!         .with_message_id("TEMP")
+//!         .with_structured_data(&origin),
+//! ))
+//! .unwrap_or_else(|_| panic!("failed to send the message"));
+//! # }
+//! ```
+//!
+//! With the `log` feature, a `logger::SyslogLogger` forwards the records of the
+//! [`log`](https://docs.rs/log) facade.
+
+use core::fmt::{self, Write};
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::sntp::UnixTime;
+use crate::{ActiveW5500, Error, Ipv4Addr, Register, Udp, UdpSocket, UninitializedSocket};
+
+#[cfg(all(feature = "log", target_has_atomic = "8"))]
+pub mod logger;
+
+/// UDP port collectors listen on
+pub const PORT: u16 = 514;
+
+/// Size of the buffer messages are formatted into, the limit of RFC 3164
+pub const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// Longest HOSTNAME, APP-NAME, PROCID and MSGID of RFC 5424
+const MAX_HOSTNAME_LENGTH: usize = 255;
+const MAX_APP_NAME_LENGTH: usize = 48;
+const MAX_PROC_ID_LENGTH: usize = 128;
+const MAX_MESSAGE_ID_LENGTH: usize = 32;
+/// Longest SD-NAME of RFC 5424 and TAG of RFC 3164
+const MAX_NAME_LENGTH: usize = 32;
+/// Written for absent header fields in RFC 5424
+const NIL: &str = "-";
+
+const SECONDS_PER_DAY: u64 = 86_400;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Message format
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Format {
+    /// The current syslog protocol with structured data and full timestamps
+    Rfc5424,
+    /// The BSD syslog protocol that older collectors expect. Message IDs and structured data
+    /// are not sent.
+    Rfc3164,
+}
+
+/// Origin of a message
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Facility {
+    Kernel = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Printer = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    AuthPriv = 10,
+    Ftp = 11,
+    Ntp = 12,
+    Audit = 13,
+    Alert = 14,
+    Clock = 15,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// Importance of a message, from the most to the least severe
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Severity {
+    Emergency = 0,
+    Alert = 1,
+    Critical = 2,
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Informational = 6,
+    Debug = 7,
+}
+
+/// Element of the structured data of an RFC 5424 message
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SdElement<'e> {
+    /// SD-ID, either registered with IANA or of the form `name@<private enterprise number>`
+    pub id: &'e str,
+    /// Names and values of the parameters, values are escaped as needed
+    pub params: &'e [(&'e str, &'e str)],
+}
+
+/// Message to send with [`SyslogClient::send`]
+#[derive(Copy, Clone, Debug)]
+pub struct Message<'m> {
+    severity: Severity,
+    text: fmt::Arguments<'m>,
+    timestamp: Option<UnixTime>,
+    message_id: Option<&'m str>,
+    structured_data: &'m [SdElement<'m>],
+}
+
+impl<'m> Message<'m> {
+    /// Creates a message with the given text, usually built with [`format_args`]
+    pub fn new(severity: Severity, text: fmt::Arguments<'m>) -> Self {
+        Message {
+            severity,
+            text,
+            timestamp: None,
+            message_id: None,
+            structured_data: &[],
+        }
+    }
+
+    /// Sets the time the message has been created at, for example from
+    /// [`SntpClient::now`](crate::sntp::SntpClient::now). Without it, the collector stamps the
+    /// message on reception.
+    pub fn with_timestamp(mut self, timestamp: Option<UnixTime>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Sets the MSGID of RFC 5424 that identifies the type of the message
+    pub fn with_message_id(mut self, message_id: &'m str) -> Self {
+        self.message_id = Some(message_id);
+        self
+    }
+
+    /// Sets the structured data of RFC 5424
+    pub fn with_structured_data(mut self, structured_data: &'m [SdElement<'m>]) -> Self {
+        self.structured_data = structured_data;
+        self
+    }
+}
+
+/// Syslog sender, see the [module documentation](self)
+pub struct SyslogClient<'a> {
+    socket: UdpSocket,
+    server: Ipv4Addr,
+    port: u16,
+    format: Format,
+    facility: Facility,
+    hostname: Option<&'a str>,
+    app_name: Option<&'a str>,
+    proc_id: Option<&'a str>,
+    buffer: [u8; MAX_MESSAGE_SIZE],
+}
+
+impl<'a> SyslogClient<'a> {
+    /// Opens the socket on an ephemeral port and creates a client that sends RFC 5424
+    /// messages of the [`Facility::User`] to the collector at the given address
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: UninitializedSocket,
+        server: Ipv4Addr,
+    ) -> Result<Self, Error<Spi::Error, ChipSelect::Error>> {
+        let socket = socket.0;
+        let port = w5500.0.next_ephemeral_port();
+        w5500.open_udp(socket, port)?;
+        Ok(SyslogClient {
+            socket: UdpSocket(socket),
+            server,
+            port: PORT,
+            format: Format::Rfc5424,
+            facility: Facility::User,
+            hostname: None,
+            app_name: None,
+            proc_id: None,
+            buffer: [0u8; MAX_MESSAGE_SIZE],
+        })
+    }
+
+    /// Sets the port the collector listens on, defaults to [`PORT`]
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets the message format, defaults to [`Format::Rfc5424`]
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the facility of all messages, defaults to [`Facility::User`]
+    pub fn with_facility(mut self, facility: Facility) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    /// Sets the name of the device, preferably its fully qualified domain name
+    pub fn with_hostname(mut self, hostname: &'a str) -> Self {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    /// Sets the name of the application, sent as TAG in RFC 3164
+    pub fn with_app_name(mut self, app_name: &'a str) -> Self {
+        self.app_name = Some(app_name);
+        self
+    }
+
+    /// Sets the process or instance ID of the application
+    pub fn with_proc_id(mut self, proc_id: &'a str) -> Self {
+        self.proc_id = Some(proc_id);
+        self
+    }
+
+    /// Returns the socket
+    pub fn release(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Formats the message and queues it for the collector. Returns [`nb::Error::WouldBlock`]
+    /// while the previous message is still being sent, and [`Error::ArpTimeout`] instead of
+    /// queuing if the chip could not deliver the previous message, see [`Udp::send`].
+    pub fn send<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        message: &Message<'_>,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let ip = match self.hostname {
+            Some(_) => None,
+            None => Some(w5500.read_ip(Register::CommonRegister(0x00_0F_u16))?),
+        };
+        let length = self.format(message, ip);
+        (&mut *w5500, &self.socket).send(&self.server, self.port, &self.buffer[..length])
+    }
+
+    /// Formats the message into the buffer and returns its length
+    fn format(&mut self, message: &Message<'_>, ip: Option<Ipv4Addr>) -> usize {
+        let format = self.format;
+        let priority = (self.facility as u8) << 3 | message.severity as u8;
+        let hostname = self.hostname;
+        let app_name = self.app_name;
+        let proc_id = self.proc_id;
+        let mut writer = Writer {
+            buffer: &mut self.buffer,
+            length: 0,
+        };
+        let _ = write!(writer, "<{}>", priority);
+        match format {
+            Format::Rfc5424 => {
+                writer.str("1 ");
+                match message.timestamp {
+                    Some(time) => {
+                        let (year, month, day, hour, minute, second) = civil(time.seconds);
+                        let _ = write!(
+                            writer,
+                            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                            year,
+                            month,
+                            day,
+                            hour,
+                            minute,
+                            second,
+                            time.nanos / 1_000_000
+                        );
+                    }
+                    None => writer.str(NIL),
+                }
+                writer.byte(b' ');
+                match ip {
+                    Some(ip) => {
+                        let _ = write!(writer, "{}", ip);
+                    }
+                    None => writer.name(hostname, MAX_HOSTNAME_LENGTH, b""),
+                }
+                writer.byte(b' ');
+                writer.name(app_name, MAX_APP_NAME_LENGTH, b"");
+                writer.byte(b' ');
+                writer.name(proc_id, MAX_PROC_ID_LENGTH, b"");
+                writer.byte(b' ');
+                writer.name(message.message_id, MAX_MESSAGE_ID_LENGTH, b"");
+                writer.byte(b' ');
+                if message.structured_data.is_empty() {
+                    writer.str(NIL);
+                }
+                for element in message.structured_data {
+                    writer.byte(b'[');
+                    writer.name(Some(element.id), MAX_NAME_LENGTH, b"= ]\"");
+                    for (name, value) in element.params {
+                        writer.byte(b' ');
+                        writer.name(Some(name), MAX_NAME_LENGTH, b"= ]\"");
+                        writer.str("=\"");
+                        writer.param_value(value);
+                        writer.byte(b'"');
+                    }
+                    writer.byte(b']');
+                }
+                writer.byte(b' ');
+            }
+            Format::Rfc3164 => {
+                // without a timestamp, the header is left to the collector (RFC 3164 4.3.3)
+                if let Some(time) = message.timestamp {
+                    let (_, month, day, hour, minute, second) = civil(time.seconds);
+                    let _ = write!(
+                        writer,
+                        "{} {:2} {:02}:{:02}:{:02} ",
+                        MONTHS[usize::from(month - 1)],
+                        day,
+                        hour,
+                        minute,
+                        second
+                    );
+                    match ip {
+                        Some(ip) => {
+                            let _ = write!(writer, "{}", ip);
+                        }
+                        None => writer.name(hostname, MAX_HOSTNAME_LENGTH, b""),
+                    }
+                    writer.byte(b' ');
+                }
+                if app_name.is_some() {
+                    writer.name(app_name, MAX_NAME_LENGTH, b":[");
+                    if proc_id.is_some() {
+                        writer.byte(b'[');
+                        writer.name(proc_id, MAX_PROC_ID_LENGTH, b"]");
+                        writer.byte(b']');
+                    }
+                    writer.str(": ");
+                }
+            }
+        }
+        let _ = writer.write_fmt(message.text);
+        trace!("Syslog message of {} bytes", writer.length);
+        writer.length
+    }
+}
+
+/// Writes into a fixed buffer and silently drops what does not fit
+struct Writer<'w> {
+    buffer: &'w mut [u8],
+    length: usize,
+}
+
+impl Writer<'_> {
+    fn byte(&mut self, byte: u8) {
+        if let Some(slot) = self.buffer.get_mut(self.length) {
+            *slot = byte;
+            self.length += 1;
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        let _ = self.write_str(s);
+    }
+
+    /// Writes a header field or name of at most `max_length` printable ASCII characters,
+    /// replacing others and the excluded ones with `_`, or NIL if it is empty
+    fn name(&mut self, name: Option<&str>, max_length: usize, excluded: &[u8]) {
+        match name {
+            Some(name) if !name.is_empty() => {
+                for byte in name.bytes().take(max_length) {
+                    if byte.is_ascii_graphic() && !excluded.contains(&byte) {
+                        self.byte(byte);
+                    } else {
+                        self.byte(b'_');
+                    }
+                }
+            }
+            _ => self.str(NIL),
+        }
+    }
+
+    /// Writes a PARAM-VALUE, escaping `"`, `\` and `]`
+    fn param_value(&mut self, value: &str) {
+        for c in value.chars() {
+            if matches!(c, '"' | '\\' | ']') {
+                self.byte(b'\\');
+            }
+            let mut encoded = [0u8; 4];
+            self.str(c.encode_utf8(&mut encoded));
+        }
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut length = s.len().min(self.buffer.len() - self.length);
+        while !s.is_char_boundary(length) {
+            length -= 1;
+        }
+        self.buffer[self.length..self.length + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+/// Splits seconds since the Unix epoch into year, month, day, hour, minute and second
+fn civil(seconds: u64) -> (u64, u8, u8, u8, u8, u8) {
+    let time = seconds % SECONDS_PER_DAY;
+    // days since 0000-03-01, in eras of 400 years (Howard Hinnant's `civil_from_days`)
+    let days = seconds / SECONDS_PER_DAY + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (
+        year,
+        month as u8,
+        day as u8,
+        (time / 3600) as u8,
+        (time / 60 % 60) as u8,
+        (time % 60) as u8,
+    )
+}
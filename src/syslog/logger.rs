@@ -0,0 +1,201 @@
+//! [`log::Log`] implementation that sends the records as syslog messages.
+//!
+//! The [`SyslogLogger`] takes ownership of the chip and the SPI bus, so it can send from any
+//! context the `log` macros are called in. [`SyslogLogger::with_chip`] lends them back for
+//! everything else. Records logged while the chip is in use, including the traces of this
+//! driver, are dropped instead of waiting for it. Only available on targets with atomic
+//! compare-and-swap.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # struct Cs;
+//! # impl OutputPin for Cs {
+//! #     type Error = ();
+//! #     fn set_low(&mut self) -> Result<(), ()> { Ok(()) }
+//! #     fn set_high(&mut self) -> Result<(), ()> { Ok(()) }
+//! # }
+//! # struct Spi;
+//! # impl FullDuplex<u8> for Spi {
+//! #     type Error = ();
+//! #     fn read(&mut self) -> nb::Result<u8, ()> { Ok(0) }
+//! #     fn send(&mut self, _: u8) -> nb::Result<(), ()> { Ok(()) }
+//! # }
+//! # fn example(mut w5500: w5500::W5500<Cs>, mut spi: Spi) {
+//! use w5500::syslog::logger::SyslogLogger;
+//! use w5500::syslog::SyslogClient;
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! static LOGGER: SyslogLogger<Cs, Spi> = SyslogLogger::new();
+//!
+//! let mut active = w5500.activate(&mut spi).unwrap();
+//! let socket = active.take_socket(Socket::Socket4).unwrap();
+//! let syslog = SyslogClient::new(&mut active, socket, Ipv4Addr::new(192, 168, 0, 1))
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"))
+//!     .with_app_name("climate");
+//! drop(active);
+//!
+//! LOGGER
+//!     .install(w5500, spi, syslog, || None)
+//!     .unwrap_or_else(|_| panic!("the logger is in use"));
+//! log::set_logger(&LOGGER).unwrap();
+//! log::set_max_level(log::LevelFilter::Info);
+//!
+//! log::info!("booted");
+//! LOGGER.with_chip(|_w5500| {
+//!     // use the other sockets
+//! });
+//! # }
+//! ```
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use super::{Message, Severity, SyslogClient};
+use crate::sntp::UnixTime;
+use crate::{ActiveW5500, W5500};
+
+/// Chip, bus and client owned by an installed [`SyslogLogger`]
+struct Installation<ChipSelect: OutputPin, Spi> {
+    w5500: W5500<ChipSelect>,
+    spi: Spi,
+    client: SyslogClient<'static>,
+    clock: fn() -> Option<UnixTime>,
+}
+
+/// Logger that sends records to a syslog collector, see the [module documentation](self)
+pub struct SyslogLogger<ChipSelect: OutputPin, Spi> {
+    locked: AtomicBool,
+    installation: UnsafeCell<Option<Installation<ChipSelect, Spi>>>,
+}
+
+// The installation is only accessed while `locked` is held
+unsafe impl<ChipSelect: OutputPin + Send, Spi: Send> Sync for SyslogLogger<ChipSelect, Spi> {}
+
+impl<ChipSelect: OutputPin, Spi> SyslogLogger<ChipSelect, Spi> {
+    /// Creates a logger that drops all records until [`SyslogLogger::install`] is called
+    pub const fn new() -> Self {
+        SyslogLogger {
+            locked: AtomicBool::new(false),
+            installation: UnsafeCell::new(None),
+        }
+    }
+
+    /// Runs `f` on the installation if no one else is using it
+    fn locked<R>(
+        &self,
+        f: impl FnOnce(&mut Option<Installation<ChipSelect, Spi>>) -> R,
+    ) -> Option<R> {
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        // SAFETY: the lock is held, so this is the only reference to the installation
+        let result = f(unsafe { &mut *self.installation.get() });
+        self.locked.store(false, Ordering::Release);
+        Some(result)
+    }
+}
+
+impl<ChipSelect: OutputPin, Spi> Default for SyslogLogger<ChipSelect, Spi> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<ChipSelect: OutputPin, Spi: FullDuplex<u8>> SyslogLogger<ChipSelect, Spi> {
+    /// Hands the chip, the SPI bus and the client over to the logger. `clock` provides the
+    /// timestamps of the messages, for example from
+    /// [`SntpClient::now`](crate::sntp::SntpClient::now). Returns them back if the logger is
+    /// in use or already installed, [`SyslogLogger::uninstall`] the previous installation
+    /// first to replace it.
+    #[allow(clippy::type_complexity, clippy::result_large_err)]
+    pub fn install(
+        &self,
+        w5500: W5500<ChipSelect>,
+        spi: Spi,
+        client: SyslogClient<'static>,
+        clock: fn() -> Option<UnixTime>,
+    ) -> Result<(), (W5500<ChipSelect>, Spi, SyslogClient<'static>)> {
+        let mut parts = Some((w5500, spi, client));
+        self.locked(|installation| {
+            if installation.is_some() {
+                return;
+            }
+            let (w5500, spi, client) = parts.take().unwrap();
+            *installation = Some(Installation {
+                w5500,
+                spi,
+                client,
+                clock,
+            });
+        });
+        match parts {
+            Some(parts) => Err(parts),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the chip, the SPI bus and the client back. Returns `None` if nothing is installed
+    /// or the logger is in use.
+    #[allow(clippy::type_complexity)]
+    pub fn uninstall(&self) -> Option<(W5500<ChipSelect>, Spi, SyslogClient<'static>)> {
+        let installation = self.locked(Option::take)??;
+        Some((installation.w5500, installation.spi, installation.client))
+    }
+
+    /// Activates the chip for `f`. Returns `None` if nothing is installed or the logger is in
+    /// use, for example when called from an interrupt that preempted a record being sent.
+    pub fn with_chip<R>(
+        &self,
+        f: impl FnOnce(&mut ActiveW5500<'_, '_, ChipSelect, Spi>) -> R,
+    ) -> Option<R> {
+        self.locked(|installation| {
+            let installation = installation.as_mut()?;
+            let mut w5500 = installation.w5500.activate(&mut installation.spi).ok()?;
+            Some(f(&mut w5500))
+        })?
+    }
+}
+
+impl<ChipSelect, Spi> log::Log for SyslogLogger<ChipSelect, Spi>
+where
+    ChipSelect: OutputPin + Send,
+    Spi: FullDuplex<u8> + Send,
+{
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.locked(|installation| {
+            if let Some(installation) = installation {
+                let message = Message::new(record.level().into(), *record.args())
+                    .with_timestamp((installation.clock)());
+                if let Ok(mut w5500) = installation.w5500.activate(&mut installation.spi) {
+                    // waits for the previous record, the chip gives up on it at its ARP timeout
+                    let _ = nb::block!(installation.client.send(&mut w5500, &message));
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+impl From<log::Level> for Severity {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Severity::Error,
+            log::Level::Warn => Severity::Warning,
+            log::Level::Info => Severity::Informational,
+            log::Level::Debug | log::Level::Trace => Severity::Debug,
+        }
+    }
+}
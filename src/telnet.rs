@@ -0,0 +1,472 @@
+//! Line-oriented remote console over Telnet (RFC 854).
+//!
+//! The [`TelnetConsole`] serves one client at a time on a hardware TCP socket. It asks the
+//! client to leave echoing to the server and to suppress go-ahead, refuses all other options
+//! and edits the input line itself: backspace and delete erase a character, Ctrl-U erases the
+//! line. Complete lines are returned by [`TelnetConsole::read_line`]. Output is written into
+//! the console with [`core::fmt::Write`], buffered and sent by [`TelnetConsole::poll`] or
+//! [`TelnetConsole::flush`]. Sessions without input are closed after an idle timeout.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use core::fmt::Write;
+//! use w5500::telnet::{Event, TelnetConsole, LINE_BUFFER_SIZE, PORT};
+//! use w5500::Socket;
+//!
+//! let socket = w5500.take_socket(Socket::Socket1).unwrap();
+//! let mut console = TelnetConsole::new(socket, PORT).with_prompt("sensor> ");
+//! let mut line = [0u8; LINE_BUFFER_SIZE];
+//!
+//! loop {
+//!     if let Ok(Some(Event::Connected { remote, .. })) = console.poll(&mut w5500, now_ms()) {
+//!         let _ = writeln!(console, "Hello {}", remote);
+//!     }
+//!     match console.read_line(&mut line) {
+//!         Some("uptime") => {
+//!             let _ = writeln!(console, "{} s", now_ms() / 1000);
+//!         }
+//!         Some("exit") => {
+//!             let _ = console.disconnect(&mut w5500);
+//!         }
+//!         Some(line) if !line.is_empty() => {
+//!             let _ = writeln!(console, "unknown command: {}", line);
+//!         }
+//!         _ => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use core::fmt;
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{
+    ActiveW5500, Error, ErrorSource, Ipv4Addr, SocketStatus, Tcp, TcpSocket, UninitializedSocket,
+};
+
+/// TCP port Telnet clients connect to
+pub const PORT: u16 = 23;
+
+/// Longest input line, further characters are ignored
+pub const LINE_BUFFER_SIZE: usize = 128;
+
+/// Size of the buffer output is collected in until it is sent
+pub const OUTPUT_BUFFER_SIZE: usize = 512;
+
+/// Default time without input after which a session is closed
+const DEFAULT_IDLE_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+
+/// Number of bytes received from the chip at once
+const RX_CHUNK_SIZE: usize = 64;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const EL: u8 = 248;
+const EC: u8 = 247;
+const SE: u8 = 240;
+
+const OPTION_ECHO: u8 = 1;
+const OPTION_SUPPRESS_GO_AHEAD: u8 = 3;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_U: u8 = 0x15;
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0;
+
+/// Option flags of a session
+const LOCAL_ECHO: u8 = 0x01;
+const LOCAL_SUPPRESS_GO_AHEAD: u8 = 0x02;
+const REMOTE_SUPPRESS_GO_AHEAD: u8 = 0x04;
+
+/// Change of the session
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A client connected, the prompt is written at the next poll
+    Connected { remote: Ipv4Addr, port: u16 },
+    /// The client disconnected or the session timed out
+    Disconnected,
+}
+
+/// Position in the Telnet command syntax
+#[derive(Copy, Clone, PartialEq)]
+enum Parser {
+    Data,
+    /// After a CR, which may be followed by LF or NUL
+    Return,
+    Command,
+    /// After WILL, WONT, DO or DONT, waiting for the option
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Line {
+    Editing,
+    /// Complete and not yet read
+    Ready,
+    /// Returned by `read_line`, the prompt is written at the next poll
+    Read,
+}
+
+/// Telnet console, see the [module documentation](self)
+pub struct TelnetConsole<'a> {
+    socket: TcpSocket,
+    port: u16,
+    prompt: &'a str,
+    idle_timeout_ms: u64,
+    connected: bool,
+    last_activity_ms: u64,
+    options: u8,
+    parser: Parser,
+    line: [u8; LINE_BUFFER_SIZE],
+    line_length: usize,
+    line_state: Line,
+    rx: [u8; RX_CHUNK_SIZE],
+    rx_start: usize,
+    rx_end: usize,
+    tx: [u8; OUTPUT_BUFFER_SIZE],
+    tx_length: usize,
+}
+
+impl<'a> TelnetConsole<'a> {
+    /// Creates a console that listens on the port, the socket is opened by
+    /// [`TelnetConsole::poll`]
+    pub fn new(socket: UninitializedSocket, port: u16) -> Self {
+        TelnetConsole {
+            socket: TcpSocket(socket.0),
+            port,
+            prompt: "> ",
+            idle_timeout_ms: DEFAULT_IDLE_TIMEOUT_MS,
+            connected: false,
+            last_activity_ms: 0,
+            options: 0,
+            parser: Parser::Data,
+            line: [0u8; LINE_BUFFER_SIZE],
+            line_length: 0,
+            line_state: Line::Editing,
+            rx: [0u8; RX_CHUNK_SIZE],
+            rx_start: 0,
+            rx_end: 0,
+            tx: [0u8; OUTPUT_BUFFER_SIZE],
+            tx_length: 0,
+        }
+    }
+
+    /// Sets the prompt written before every input line, defaults to `"> "`
+    pub fn with_prompt(mut self, prompt: &'a str) -> Self {
+        self.prompt = prompt;
+        self
+    }
+
+    /// Sets the time without input after which the session is closed, defaults to five
+    /// minutes
+    pub fn with_idle_timeout(mut self, idle_timeout_ms: u64) -> Self {
+        self.idle_timeout_ms = idle_timeout_ms;
+        self
+    }
+
+    /// Whether a client is connected
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Copies the line the client completed with Enter into the buffer, without the line
+    /// ending, and returns it. Lines longer than the buffer are truncated. Every line is
+    /// returned once, the next one is read after the following [`TelnetConsole::poll`].
+    pub fn read_line<'b>(&mut self, buffer: &'b mut [u8]) -> Option<&'b str> {
+        if self.line_state != Line::Ready {
+            return None;
+        }
+        self.line_state = Line::Read;
+        let length = self.line_length.min(buffer.len());
+        buffer[..length].copy_from_slice(&self.line[..length]);
+        // only printable ASCII is added to the line
+        core::str::from_utf8(&buffer[..length]).ok()
+    }
+
+    /// Sends the buffered output. Returns [`nb::Error::WouldBlock`] while the TX buffer of the
+    /// socket has no room for all of it. Output is discarded while no client is connected.
+    pub fn flush<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if !self.connected {
+            self.tx_length = 0;
+        }
+        while self.tx_length > 0 {
+            let sent = (&mut *w5500, &self.socket).send(&self.tx[..self.tx_length])?;
+            self.tx.copy_within(sent..self.tx_length, 0);
+            self.tx_length -= sent;
+        }
+        Ok(())
+    }
+
+    /// Ends the session after sending the buffered output as far as possible
+    pub fn disconnect<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        match self.flush(w5500) {
+            // the output is lost if the connection is gone already
+            Err(nb::Error::Other(error)) if error.source() == ErrorSource::Bus => {
+                return Err(error)
+            }
+            _ => {}
+        }
+        (&mut *w5500, &self.socket).disconnect()
+    }
+
+    /// Returns the socket, which may still be connected
+    pub fn release(self) -> TcpSocket {
+        self.socket
+    }
+
+    /// Accepts clients, processes their input and sends the buffered output. Reports when a
+    /// session starts or ends.
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, Error<Spi::Error, ChipSelect::Error>> {
+        let status = (&mut *w5500, &self.socket).status()?;
+        match status {
+            SocketStatus::Closed => {
+                w5500.open_tcp(self.socket.0, self.port)?;
+                (&mut *w5500, &self.socket).listen()?;
+                return Ok(self.end_session());
+            }
+            SocketStatus::Init => {
+                (&mut *w5500, &self.socket).listen()?;
+                return Ok(None);
+            }
+            SocketStatus::Established if !self.connected => {
+                let (remote, port) = (&mut *w5500, &self.socket).remote()?;
+                debug!("Telnet session with {}:{}", remote, port);
+                self.start_session(now_ms);
+                self.send_output(w5500)?;
+                return Ok(Some(Event::Connected { remote, port }));
+            }
+            SocketStatus::Established => {}
+            SocketStatus::CloseWait => {
+                (&mut *w5500, &self.socket).disconnect()?;
+                return Ok(self.end_session());
+            }
+            _ => return Ok(None),
+        }
+
+        if self.line_state == Line::Read {
+            self.line_length = 0;
+            self.line_state = Line::Editing;
+            self.output(self.prompt.as_bytes());
+        }
+        while self.line_state == Line::Editing {
+            if self.rx_start == self.rx_end {
+                match (&mut *w5500, &self.socket).receive(&mut self.rx) {
+                    Ok(length) => {
+                        self.rx_start = 0;
+                        self.rx_end = length;
+                        self.last_activity_ms = now_ms;
+                    }
+                    Err(nb::Error::WouldBlock) => break,
+                    Err(nb::Error::Other(error)) => return Err(error),
+                }
+            }
+            while self.rx_start < self.rx_end && self.line_state == Line::Editing {
+                let byte = self.rx[self.rx_start];
+                self.rx_start += 1;
+                self.input(byte);
+            }
+        }
+        self.send_output(w5500)?;
+
+        if now_ms.saturating_sub(self.last_activity_ms) >= self.idle_timeout_ms {
+            debug!("Telnet session timed out");
+            (&mut *w5500, &self.socket).disconnect()?;
+            return Ok(self.end_session());
+        }
+        Ok(None)
+    }
+
+    /// Resets the session state and asks the client for character mode
+    fn start_session(&mut self, now_ms: u64) {
+        self.connected = true;
+        self.last_activity_ms = now_ms;
+        self.options = LOCAL_ECHO | LOCAL_SUPPRESS_GO_AHEAD;
+        self.parser = Parser::Data;
+        self.line_length = 0;
+        // writes the prompt at the next poll, after a greeting of the application
+        self.line_state = Line::Read;
+        self.rx_start = 0;
+        self.rx_end = 0;
+        self.tx_length = 0;
+        self.output(&[IAC, WILL, OPTION_ECHO, IAC, WILL, OPTION_SUPPRESS_GO_AHEAD]);
+    }
+
+    fn end_session(&mut self) -> Option<Event> {
+        let connected = self.connected;
+        self.connected = false;
+        self.tx_length = 0;
+        connected.then_some(Event::Disconnected)
+    }
+
+    /// Sends as much buffered output as fits into the TX buffer
+    fn send_output<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        match self.flush(w5500) {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(error)) => Err(error),
+        }
+    }
+
+    /// Appends to the output buffer, returns whether everything fit
+    fn output(&mut self, data: &[u8]) -> bool {
+        let length = data.len().min(OUTPUT_BUFFER_SIZE - self.tx_length);
+        self.tx[self.tx_length..self.tx_length + length].copy_from_slice(&data[..length]);
+        self.tx_length += length;
+        length == data.len()
+    }
+
+    /// Processes a received byte
+    fn input(&mut self, byte: u8) {
+        match self.parser {
+            Parser::Data | Parser::Return if byte == IAC => self.parser = Parser::Command,
+            Parser::Return if byte == LF || byte == NUL => self.parser = Parser::Data,
+            Parser::Data | Parser::Return => {
+                self.parser = Parser::Data;
+                self.edit(byte);
+            }
+            Parser::Command => {
+                self.parser = Parser::Data;
+                match byte {
+                    IAC => self.edit(IAC),
+                    WILL | WONT | DO | DONT => self.parser = Parser::Negotiation(byte),
+                    SB => self.parser = Parser::Subnegotiation,
+                    EC => self.edit(BACKSPACE),
+                    EL => self.edit(CTRL_U),
+                    _ => {}
+                }
+            }
+            Parser::Negotiation(command) => {
+                self.parser = Parser::Data;
+                self.negotiate(command, byte);
+            }
+            Parser::Subnegotiation if byte == IAC => self.parser = Parser::SubnegotiationCommand,
+            Parser::Subnegotiation => {}
+            Parser::SubnegotiationCommand if byte == SE => self.parser = Parser::Data,
+            Parser::SubnegotiationCommand => self.parser = Parser::Subnegotiation,
+        }
+    }
+
+    /// Answers a request of the client to change an option, but not its acknowledgements
+    fn negotiate(&mut self, command: u8, option: u8) {
+        trace!("Telnet negotiation {} {}", command, option);
+        let local = match option {
+            OPTION_ECHO => LOCAL_ECHO,
+            OPTION_SUPPRESS_GO_AHEAD => LOCAL_SUPPRESS_GO_AHEAD,
+            _ => 0,
+        };
+        let remote = match option {
+            OPTION_SUPPRESS_GO_AHEAD => REMOTE_SUPPRESS_GO_AHEAD,
+            _ => 0,
+        };
+        let answer = match command {
+            DO if local == 0 => Some(WONT),
+            DO if self.options & local == 0 => {
+                self.options |= local;
+                Some(WILL)
+            }
+            DONT if self.options & local != 0 => {
+                self.options &= !local;
+                Some(WONT)
+            }
+            WILL if remote == 0 => Some(DONT),
+            WILL if self.options & remote == 0 => {
+                self.options |= remote;
+                Some(DO)
+            }
+            WONT if self.options & remote != 0 => {
+                self.options &= !remote;
+                Some(DONT)
+            }
+            _ => None,
+        };
+        if let Some(answer) = answer {
+            self.output(&[IAC, answer, option]);
+        }
+    }
+
+    /// Applies a character to the input line and echoes it if the client asked for it
+    fn edit(&mut self, byte: u8) {
+        let echo = self.options & LOCAL_ECHO != 0;
+        match byte {
+            CR | LF => {
+                if byte == CR {
+                    self.parser = Parser::Return;
+                }
+                self.line_state = Line::Ready;
+                if echo {
+                    self.output(b"\r\n");
+                }
+            }
+            BACKSPACE | DELETE if self.line_length > 0 => {
+                self.line_length -= 1;
+                if echo {
+                    self.output(b"\x08 \x08");
+                }
+            }
+            CTRL_U => {
+                while self.line_length > 0 {
+                    self.line_length -= 1;
+                    if echo {
+                        self.output(b"\x08 \x08");
+                    }
+                }
+            }
+            b' '..=b'~' if self.line_length < LINE_BUFFER_SIZE => {
+                self.line[self.line_length] = byte;
+                self.line_length += 1;
+                if echo {
+                    self.output(&[byte]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Write for TelnetConsole<'_> {
+    /// Buffers the text for the client, translating line feeds to CR LF. Fails if the output
+    /// buffer is full, text is discarded while no client is connected.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.connected {
+            return Ok(());
+        }
+        let mut lines = s.split('\n');
+        if let Some(first) = lines.next() {
+            if !self.output(first.as_bytes()) {
+                return Err(fmt::Error);
+            }
+        }
+        for line in lines {
+            if !self.output(b"\r\n") || !self.output(line.as_bytes()) {
+                return Err(fmt::Error);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,695 @@
+//! TFTP (RFC 1350) with the blksize (RFC 2348) and tsize (RFC 2349) options.
+//!
+//! The [`client`] module reads and writes files on a server, the [`server`] module answers
+//! requests on port 69. Files are streamed block by block into a [`Sink`] or out of a
+//! [`Source`] supplied by the caller, only the last packet is kept for retransmission. Every
+//! transfer runs on a UDP socket bound to a fresh ephemeral port, which serves as its
+//! transfer identifier. Only the `octet` mode is supported.
+//!
+//! The receiving side completes a transfer one timeout after it acknowledged the last block,
+//! or earlier if the peer sends that block again because the final ACK got lost.
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{ActiveW5500, Ipv4Addr, Udp, UdpSocket};
+
+pub mod client;
+pub mod server;
+
+/// UDP port servers listen on for requests
+pub const PORT: u16 = 69;
+/// Block size without the blksize option
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Largest block size that is negotiated, a DATA packet of this size still fits into a single
+/// Ethernet frame
+pub const MAX_BLOCK_SIZE: usize = 1468;
+
+/// Smallest block size RFC 2348 allows
+const MIN_BLOCK_SIZE: usize = 8;
+/// Opcode and block number of DATA and ACK
+const HEADER_SIZE: usize = 4;
+const PACKET_SIZE: usize = HEADER_SIZE + MAX_BLOCK_SIZE;
+const DEFAULT_TIMEOUT_MS: u64 = 1_000;
+const DEFAULT_RETRIES: u8 = 5;
+
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_WRQ: u16 = 2;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const OPCODE_OACK: u16 = 6;
+
+const MODE_OCTET: &str = "octet";
+const OPTION_BLKSIZE: &str = "blksize";
+const OPTION_TSIZE: &str = "tsize";
+
+/// Error code of an ERROR packet
+#[repr(u16)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ErrorCode {
+    NotDefined = 0,
+    FileNotFound = 1,
+    AccessViolation = 2,
+    DiskFull = 3,
+    IllegalOperation = 4,
+    UnknownTransferId = 5,
+    FileExists = 6,
+    NoSuchUser = 7,
+    /// The options could not be negotiated (RFC 2347)
+    OptionRejected = 8,
+}
+
+impl ErrorCode {
+    fn from_u16(code: u16) -> ErrorCode {
+        match code {
+            1 => ErrorCode::FileNotFound,
+            2 => ErrorCode::AccessViolation,
+            3 => ErrorCode::DiskFull,
+            4 => ErrorCode::IllegalOperation,
+            5 => ErrorCode::UnknownTransferId,
+            6 => ErrorCode::FileExists,
+            7 => ErrorCode::NoSuchUser,
+            8 => ErrorCode::OptionRejected,
+            _ => ErrorCode::NotDefined,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            ErrorCode::NotDefined => "Error",
+            ErrorCode::FileNotFound => "File not found",
+            ErrorCode::AccessViolation => "Access violation",
+            ErrorCode::DiskFull => "Disk full",
+            ErrorCode::IllegalOperation => "Illegal operation",
+            ErrorCode::UnknownTransferId => "Unknown transfer ID",
+            ErrorCode::FileExists => "File already exists",
+            ErrorCode::NoSuchUser => "No such user",
+            ErrorCode::OptionRejected => "Option rejected",
+        }
+    }
+}
+
+/// Why a transfer failed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    /// The peer sent an ERROR packet
+    Peer(ErrorCode),
+    /// The transfer has been aborted locally, the peer has been sent an ERROR packet with the
+    /// code. Either the [`Sink`] or [`Source`] failed or the peer violated the protocol.
+    Aborted(ErrorCode),
+    /// The peer did not answer after all retransmissions
+    Timeout,
+}
+
+/// Error returned by transfers of the [`client`](client::TftpClient)
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<SpiError, ChipSelectError> {
+    /// The chip or the socket reported an error, see [`crate::Error::source`]
+    Transfer(crate::Error<SpiError, ChipSelectError>),
+    /// The transfer failed
+    Failed(Failure),
+    /// The filename is empty, contains a NUL character or is too long for a request
+    InvalidFilename,
+}
+
+impl<SpiError, ChipSelectError> From<crate::Error<SpiError, ChipSelectError>>
+    for Error<SpiError, ChipSelectError>
+{
+    fn from(error: crate::Error<SpiError, ChipSelectError>) -> Self {
+        Error::Transfer(error)
+    }
+}
+
+/// Destination of a received file
+pub trait Sink {
+    /// Called with the size the peer announced with the tsize option, before the first block.
+    /// Return [`ErrorCode::DiskFull`] to refuse files that are too large.
+    fn set_size(&mut self, _size: u64) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    /// Writes the next block of the file
+    fn write(&mut self, data: &[u8]) -> Result<(), ErrorCode>;
+}
+
+/// Origin of a sent file
+pub trait Source {
+    /// The size of the file, announced with the tsize option if known
+    fn size(&mut self) -> Option<u64> {
+        None
+    }
+
+    /// Reads the next block of the file into `buffer` and returns the number of bytes read.
+    /// The buffer has to be filled completely unless the end of the file has been reached.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ErrorCode>;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Phase {
+    Idle,
+    /// RRQ or WRQ sent, waiting for the first answer of the server
+    Request,
+    /// DATA of `block` sent, or OACK as block 0, waiting for its ACK
+    Send {
+        block: u16,
+        last: bool,
+    },
+    /// ACK of `block` sent, or OACK as block 0, waiting for the next DATA
+    Receive {
+        block: u16,
+    },
+    /// ACK of the last `block` sent, waiting whether the peer sends the block again because
+    /// the ACK got lost
+    Dally {
+        block: u16,
+    },
+}
+
+/// Lockstep state of a transfer, shared by client and server
+struct Transfer {
+    phase: Phase,
+    peer: Ipv4Addr,
+    /// the transfer identifier of the peer once `locked`, the server port before
+    peer_port: u16,
+    locked: bool,
+    /// block size offered in the request
+    requested_block_size: usize,
+    block_size: usize,
+    /// bytes transferred so far
+    size: u64,
+    timeout_ms: u64,
+    retries: u8,
+    sent_ms: u64,
+    attempts: u8,
+    /// the last packet sent, for retransmission
+    tx: [u8; PACKET_SIZE],
+    tx_length: usize,
+}
+
+impl Transfer {
+    const fn new() -> Self {
+        Transfer {
+            phase: Phase::Idle,
+            peer: Ipv4Addr::UNSPECIFIED,
+            peer_port: 0,
+            locked: false,
+            requested_block_size: DEFAULT_BLOCK_SIZE,
+            block_size: DEFAULT_BLOCK_SIZE,
+            size: 0,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            retries: DEFAULT_RETRIES,
+            sent_ms: 0,
+            attempts: 0,
+            tx: [0u8; PACKET_SIZE],
+            tx_length: 0,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+
+    /// Resets the state for a new transfer with the peer. The client does not know the
+    /// transfer identifier of the server yet and locks onto the port of its first answer.
+    fn start(&mut self, peer: Ipv4Addr, peer_port: u16, locked: bool, phase: Phase) {
+        self.phase = phase;
+        self.peer = peer;
+        self.peer_port = peer_port;
+        self.locked = locked;
+        self.block_size = DEFAULT_BLOCK_SIZE;
+        self.size = 0;
+        self.attempts = 0;
+    }
+
+    /// Starts to write a new packet into `tx`
+    fn writer(&mut self, opcode: u16) -> PacketWriter<'_> {
+        let mut writer = PacketWriter {
+            buffer: &mut self.tx,
+            length: 0,
+        };
+        writer.u16(opcode);
+        writer
+    }
+
+    /// Queues the packet in `tx` for sending, it is retransmitted after the timeout. If the
+    /// previous packet is still being sent, this one is treated as lost.
+    fn transmit<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        now_ms: u64,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        self.sent_ms = now_ms;
+        match (&mut *w5500, socket).send(&self.peer, self.peer_port, &self.tx[..self.tx_length]) {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(error)) => Err(error),
+        }
+    }
+
+    /// Sends an ERROR packet to the peer and ends the transfer
+    fn abort<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        code: ErrorCode,
+    ) -> nb::Error<Error<Spi::Error, ChipSelect::Error>> {
+        debug!("TFTP aborting transfer with {}: {:?}", self.peer, code);
+        self.phase = Phase::Idle;
+        match send_error(w5500, socket, &self.peer, self.peer_port, code) {
+            Ok(()) => nb::Error::Other(Error::Failed(Failure::Aborted(code))),
+            Err(error) => nb::Error::Other(Error::Transfer(error)),
+        }
+    }
+
+    /// Receives the next packet of the peer into `rx`. Packets from other transfer
+    /// identifiers are answered with an ERROR packet.
+    fn receive<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        rx: &mut [u8],
+    ) -> Result<Option<usize>, crate::Error<Spi::Error, ChipSelect::Error>> {
+        while let Some((ip, port, length, truncated)) = (&mut *w5500, socket).receive(rx)? {
+            if ip != self.peer || truncated {
+                continue;
+            }
+            if !self.locked {
+                self.peer_port = port;
+                self.locked = true;
+            } else if port != self.peer_port {
+                debug!("TFTP packet from unknown transfer {}:{}", ip, port);
+                send_error(w5500, socket, &ip, port, ErrorCode::UnknownTransferId)?;
+                continue;
+            }
+            if length < HEADER_SIZE {
+                continue;
+            }
+            return Ok(Some(length));
+        }
+        Ok(None)
+    }
+
+    /// Retransmits the last packet after the timeout, fails after all retries
+    fn check_timeout<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        now_ms: u64,
+    ) -> nb::Result<u64, Error<Spi::Error, ChipSelect::Error>> {
+        if now_ms.saturating_sub(self.sent_ms) >= self.timeout_ms {
+            if self.attempts >= self.retries {
+                debug!("TFTP transfer with {} timed out", self.peer);
+                self.phase = Phase::Idle;
+                return Err(nb::Error::Other(Error::Failed(Failure::Timeout)));
+            }
+            self.attempts += 1;
+            trace!("TFTP retransmission {} to {}", self.attempts, self.peer);
+            self.transmit(w5500, socket, now_ms)
+                .map_err(Error::Transfer)?;
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Receives DATA into the sink and acknowledges it. Returns the size of the file once the
+    /// last block has been received.
+    fn poll_receive<ChipSelect: OutputPin, Spi: FullDuplex<u8>, S: Sink>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        rx: &mut [u8],
+        sink: &mut S,
+        now_ms: u64,
+    ) -> nb::Result<u64, Error<Spi::Error, ChipSelect::Error>> {
+        while let Some(length) = self.receive(w5500, socket, rx).map_err(Error::Transfer)? {
+            let packet = &rx[..length];
+            let number = BigEndian::read_u16(&packet[2..]);
+            match (BigEndian::read_u16(packet), self.phase) {
+                (OPCODE_DATA, Phase::Dally { block }) if number == block => {
+                    // our last ACK got lost, send it once more and finish
+                    self.transmit(w5500, socket, now_ms)
+                        .map_err(Error::Transfer)?;
+                    self.phase = Phase::Idle;
+                    return Ok(self.size);
+                }
+                // the file is complete, anything else is of no interest
+                (_, Phase::Dally { .. }) => continue,
+                (OPCODE_ERROR, _) => return Err(self.peer_error(number)),
+                (OPCODE_OACK, Phase::Request) => {
+                    let size = match self.accept_options(&packet[2..]) {
+                        Ok(size) => size,
+                        Err(code) => return Err(self.abort(w5500, socket, code)),
+                    };
+                    if let Some(size) = size {
+                        if let Err(code) = sink.set_size(size) {
+                            return Err(self.abort(w5500, socket, code));
+                        }
+                    }
+                    self.acknowledge(w5500, socket, 0, now_ms)
+                        .map_err(Error::Transfer)?;
+                }
+                (OPCODE_OACK, Phase::Receive { block: 0 }) => {
+                    // our ACK of the OACK got lost
+                    self.transmit(w5500, socket, now_ms)
+                        .map_err(Error::Transfer)?;
+                }
+                (OPCODE_DATA, Phase::Request) | (OPCODE_DATA, Phase::Receive { .. }) => {
+                    let previous = match self.phase {
+                        Phase::Receive { block } => block,
+                        _ => 0,
+                    };
+                    if number == previous && self.phase != Phase::Request {
+                        // our ACK got lost, the peer sent the block again
+                        self.transmit(w5500, socket, now_ms)
+                            .map_err(Error::Transfer)?;
+                        continue;
+                    }
+                    if number != previous.wrapping_add(1) {
+                        continue;
+                    }
+                    let data = &packet[HEADER_SIZE..];
+                    if data.len() > self.block_size {
+                        return Err(self.abort(w5500, socket, ErrorCode::IllegalOperation));
+                    }
+                    if let Err(code) = sink.write(data) {
+                        return Err(self.abort(w5500, socket, code));
+                    }
+                    let last = data.len() < self.block_size;
+                    self.size += data.len() as u64;
+                    self.acknowledge(w5500, socket, number, now_ms)
+                        .map_err(Error::Transfer)?;
+                    if last {
+                        debug!("TFTP received {} bytes from {}", self.size, self.peer);
+                        self.phase = Phase::Dally { block: number };
+                    }
+                }
+                _ => return Err(self.abort(w5500, socket, ErrorCode::IllegalOperation)),
+            }
+        }
+        if let Phase::Dally { .. } = self.phase {
+            if now_ms.saturating_sub(self.sent_ms) < self.timeout_ms {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.phase = Phase::Idle;
+            return Ok(self.size);
+        }
+        self.check_timeout(w5500, socket, now_ms)
+    }
+
+    /// Sends DATA out of the source whenever the previous block has been acknowledged.
+    /// Returns the size of the file once the last block has been acknowledged.
+    fn poll_send<ChipSelect: OutputPin, Spi: FullDuplex<u8>, S: Source>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        rx: &mut [u8],
+        source: &mut S,
+        now_ms: u64,
+    ) -> nb::Result<u64, Error<Spi::Error, ChipSelect::Error>> {
+        while let Some(length) = self.receive(w5500, socket, rx).map_err(Error::Transfer)? {
+            let packet = &rx[..length];
+            let number = BigEndian::read_u16(&packet[2..]);
+            let block = match (BigEndian::read_u16(packet), self.phase) {
+                (OPCODE_ERROR, _) => return Err(self.peer_error(number)),
+                (OPCODE_OACK, Phase::Request) => {
+                    if let Err(code) = self.accept_options(&packet[2..]) {
+                        return Err(self.abort(w5500, socket, code));
+                    }
+                    0
+                }
+                (OPCODE_ACK, Phase::Request) if number == 0 => 0,
+                (OPCODE_ACK, Phase::Send { block, last }) if number == block => {
+                    if last {
+                        debug!("TFTP sent {} bytes to {}", self.size, self.peer);
+                        self.phase = Phase::Idle;
+                        return Ok(self.size);
+                    }
+                    block
+                }
+                // duplicate ACKs are ignored, answering them would double all further packets
+                (OPCODE_ACK, Phase::Send { .. }) => continue,
+                _ => return Err(self.abort(w5500, socket, ErrorCode::IllegalOperation)),
+            };
+            self.send_data(w5500, socket, source, block.wrapping_add(1), now_ms)?;
+        }
+        self.check_timeout(w5500, socket, now_ms)
+    }
+
+    fn send_data<ChipSelect: OutputPin, Spi: FullDuplex<u8>, S: Source>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        source: &mut S,
+        block: u16,
+        now_ms: u64,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        let block_size = self.block_size;
+        let length = match source.read(&mut self.tx[HEADER_SIZE..HEADER_SIZE + block_size]) {
+            Ok(length) => length.min(block_size),
+            Err(code) => return Err(self.abort(w5500, socket, code)),
+        };
+        BigEndian::write_u16(&mut self.tx, OPCODE_DATA);
+        BigEndian::write_u16(&mut self.tx[2..], block);
+        self.tx_length = HEADER_SIZE + length;
+        self.size += length as u64;
+        self.phase = Phase::Send {
+            block,
+            last: length < block_size,
+        };
+        self.attempts = 0;
+        self.transmit(w5500, socket, now_ms)
+            .map_err(Error::Transfer)?;
+        Ok(())
+    }
+
+    fn acknowledge<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: &UdpSocket,
+        block: u16,
+        now_ms: u64,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        let mut writer = self.writer(OPCODE_ACK);
+        writer.u16(block);
+        self.tx_length = writer.length;
+        self.phase = Phase::Receive { block };
+        self.attempts = 0;
+        self.transmit(w5500, socket, now_ms)
+    }
+
+    fn peer_error<SpiError, ChipSelectError>(
+        &mut self,
+        code: u16,
+    ) -> nb::Error<Error<SpiError, ChipSelectError>> {
+        let code = ErrorCode::from_u16(code);
+        debug!("TFTP peer {} sent error {:?}", self.peer, code);
+        self.phase = Phase::Idle;
+        nb::Error::Other(Error::Failed(Failure::Peer(code)))
+    }
+
+    /// Applies the options of an OACK to the request, returns the announced size
+    fn accept_options(&mut self, options: &[u8]) -> Result<Option<u64>, ErrorCode> {
+        let mut size = None;
+        for (name, value) in Options::parse(options).ok_or(ErrorCode::OptionRejected)? {
+            if name.eq_ignore_ascii_case(OPTION_BLKSIZE) {
+                match value.parse() {
+                    Ok(block_size)
+                        if (MIN_BLOCK_SIZE..=self.requested_block_size).contains(&block_size) =>
+                    {
+                        self.block_size = block_size
+                    }
+                    _ => return Err(ErrorCode::OptionRejected),
+                }
+            } else if name.eq_ignore_ascii_case(OPTION_TSIZE) {
+                size = Some(value.parse().map_err(|_| ErrorCode::OptionRejected)?);
+            } else {
+                return Err(ErrorCode::OptionRejected);
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// Name and value pairs of the options of a request or OACK
+struct Options<'o>(core::slice::Split<'o, u8, fn(&u8) -> bool>);
+
+impl<'o> Options<'o> {
+    /// Splits NUL-terminated strings, `None` if the last one is not terminated
+    fn parse(data: &'o [u8]) -> Option<Self> {
+        let data = match data.split_last() {
+            Some((0, data)) => data,
+            Some(_) => return None,
+            None => &[],
+        };
+        let is_nul: fn(&u8) -> bool = |byte| *byte == 0;
+        let mut split = data.split(is_nul);
+        if data.is_empty() {
+            // an empty slice still yields one empty string
+            split.next();
+        }
+        Some(Options(split))
+    }
+
+    fn next_str(&mut self) -> Option<&'o str> {
+        self.0
+            .next()
+            .map(|string| core::str::from_utf8(string).unwrap_or(""))
+    }
+}
+
+impl<'o> Iterator for Options<'o> {
+    type Item = (&'o str, &'o str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let name = self.next_str()?;
+        let value = self.next_str().unwrap_or("");
+        Some((name, value))
+    }
+}
+
+/// Writes the fields of a packet, silently stops at the end of the buffer. Check `length`
+/// against the buffer size to detect truncation.
+struct PacketWriter<'w> {
+    buffer: &'w mut [u8],
+    length: usize,
+}
+
+impl PacketWriter<'_> {
+    fn bytes(&mut self, data: &[u8]) {
+        let start = self.length.min(self.buffer.len());
+        let end = (start + data.len()).min(self.buffer.len());
+        self.buffer[start..end].copy_from_slice(&data[..end - start]);
+        // a truncated packet is longer than the buffer
+        self.length += data.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes a NUL-terminated string
+    fn string(&mut self, string: &str) {
+        self.bytes(string.as_bytes());
+        self.bytes(&[0]);
+    }
+
+    /// Writes a NUL-terminated decimal number
+    fn number(&mut self, mut value: u64) {
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.bytes(&digits[start..]);
+        self.bytes(&[0]);
+    }
+
+    /// Whether the packet fits into the buffer
+    fn is_complete(&self) -> bool {
+        self.length <= self.buffer.len()
+    }
+}
+
+/// Sends an ERROR packet to the host. It is never retransmitted, so it is dropped like a lost
+/// packet if the previous packet is still being sent.
+fn send_error<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+    w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    socket: &UdpSocket,
+    host: &Ipv4Addr,
+    port: u16,
+    code: ErrorCode,
+) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+    let mut buffer = [0u8; 32];
+    let length = error_packet(&mut buffer, code);
+    match (&mut *w5500, socket).send(host, port, &buffer[..length]) {
+        Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+        Err(nb::Error::Other(error)) => Err(error),
+    }
+}
+
+/// Writes an ERROR packet with the standard message of the code, returns its length
+fn error_packet(buffer: &mut [u8; 32], code: ErrorCode) -> usize {
+    let mut writer = PacketWriter { buffer, length: 0 };
+    writer.u16(OPCODE_ERROR);
+    writer.u16(code as u16);
+    writer.string(code.message());
+    writer.length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(requested_block_size: usize) -> Transfer {
+        let mut transfer = Transfer::new();
+        transfer.requested_block_size = requested_block_size;
+        transfer
+    }
+
+    #[test]
+    fn splits_options() {
+        let mut options = Options::parse(b"blksize\x001024\x00tsize\x000\x00").unwrap();
+        assert_eq!(options.next(), Some(("blksize", "1024")));
+        assert_eq!(options.next(), Some(("tsize", "0")));
+        assert_eq!(options.next(), None);
+
+        assert_eq!(Options::parse(b"").unwrap().next(), None);
+        assert!(Options::parse(b"blksize\x001024").is_none());
+    }
+
+    #[test]
+    fn accepts_block_size_up_to_the_requested_one() {
+        let mut transfer = transfer(1024);
+        assert_eq!(transfer.accept_options(b"blksize\x00512\x00"), Ok(None));
+        assert_eq!(transfer.block_size, 512);
+        assert_eq!(transfer.accept_options(b"BLKSIZE\x001024\x00"), Ok(None));
+        assert_eq!(transfer.block_size, 1024);
+
+        for options in [
+            &b"blksize\x001025\x00"[..],
+            b"blksize\x007\x00",
+            b"blksize\x00\x00",
+        ] {
+            assert_eq!(
+                transfer.accept_options(options),
+                Err(ErrorCode::OptionRejected)
+            );
+        }
+    }
+
+    #[test]
+    fn returns_announced_size() {
+        let mut transfer = transfer(DEFAULT_BLOCK_SIZE);
+        assert_eq!(
+            transfer.accept_options(b"tsize\x00123456\x00"),
+            Ok(Some(123_456))
+        );
+        assert_eq!(
+            transfer.accept_options(b"tsize\x00large\x00"),
+            Err(ErrorCode::OptionRejected)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_unterminated_options() {
+        let mut transfer = transfer(DEFAULT_BLOCK_SIZE);
+        assert_eq!(
+            transfer.accept_options(b"timeout\x005\x00"),
+            Err(ErrorCode::OptionRejected)
+        );
+        assert_eq!(
+            transfer.accept_options(b"tsize\x00100"),
+            Err(ErrorCode::OptionRejected)
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! TFTP client.
+//!
+//! [`TftpClient::get`] reads a file from the server into a [`Sink`], [`TftpClient::put`]
+//! writes a file out of a [`Source`] to the server. Both are non-blocking: the first call
+//! sends the request, further calls with the same arguments advance the transfer until it
+//! completes with the size of the file.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::tftp::client::TftpClient;
+//! use w5500::tftp::{ErrorCode, Sink};
+//! use w5500::{Ipv4Addr, Socket};
+//!
+//! struct Flash {
+//!     offset: usize,
+//! }
+//!
+//! impl Sink for Flash {
+//!     fn set_size(&mut self, size: u64) -> Result<(), ErrorCode> {
+//!         if size > 256 * 1024 {
+//!             return Err(ErrorCode::DiskFull);
+//!         }
+//!         Ok(())
+//!     }
+//!
+//!     fn write(&mut self, data: &[u8]) -> Result<(), ErrorCode> {
+//!         // program `data` at `self.offset`
+//!         self.offset += data.len();
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let socket = w5500.take_socket(Socket::Socket6).unwrap();
+//! let mut tftp = TftpClient::new(socket, Ipv4Addr::new(192, 168, 0, 1)).with_block_size(1024);
+//! let mut flash = Flash { offset: 0 };
+//! let size = nb::block!(tftp.get(&mut w5500, "firmware.bin", &mut flash, now_ms()))
+//!     .unwrap_or_else(|_| panic!("download failed"));
+//! # }
+//! ```
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use super::{
+    Error, ErrorCode, Phase, Sink, Source, Transfer, DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE,
+    MIN_BLOCK_SIZE, MODE_OCTET, OPCODE_RRQ, OPCODE_WRQ, OPTION_BLKSIZE, OPTION_TSIZE, PACKET_SIZE,
+    PORT,
+};
+use crate::{ActiveW5500, Ipv4Addr, UdpSocket, UninitializedSocket};
+
+#[derive(Copy, Clone, PartialEq)]
+enum Direction {
+    Get,
+    Put,
+}
+
+/// TFTP client, see the [module documentation](self)
+pub struct TftpClient {
+    socket: UdpSocket,
+    server: Ipv4Addr,
+    port: u16,
+    block_size: usize,
+    /// direction of the transfer in progress
+    direction: Option<Direction>,
+    transfer: Transfer,
+    rx: [u8; PACKET_SIZE],
+}
+
+impl TftpClient {
+    /// Creates a client for the server at the given address. The socket is opened on a new
+    /// ephemeral port for every transfer.
+    pub fn new(socket: UninitializedSocket, server: Ipv4Addr) -> Self {
+        TftpClient {
+            socket: UdpSocket(socket.0),
+            server,
+            port: PORT,
+            block_size: DEFAULT_BLOCK_SIZE,
+            direction: None,
+            transfer: Transfer::new(),
+            rx: [0u8; PACKET_SIZE],
+        }
+    }
+
+    /// Sets the port the server listens on for requests, defaults to [`PORT`]
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Requests a block size other than 512 bytes with the blksize option, at most
+    /// [`MAX_BLOCK_SIZE`]. The server may choose a smaller one.
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+        self
+    }
+
+    /// Sets the time to wait for an answer before the last packet is sent again
+    pub fn with_timeout(mut self, timeout_ms: u64) -> Self {
+        self.transfer.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Sets how often the last packet is sent again before the transfer fails
+    pub fn with_retries(mut self, retries: u8) -> Self {
+        self.transfer.retries = retries;
+        self
+    }
+
+    /// Whether a transfer is in progress
+    pub fn is_busy(&self) -> bool {
+        self.direction.is_some()
+    }
+
+    /// Reads the file from the server into the sink and returns its size. Returns
+    /// [`nb::Error::WouldBlock`] while the transfer is in progress. A running [`TftpClient::put`]
+    /// is aborted.
+    pub fn get<ChipSelect: OutputPin, Spi: FullDuplex<u8>, S: Sink>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        filename: &str,
+        sink: &mut S,
+        now_ms: u64,
+    ) -> nb::Result<u64, Error<Spi::Error, ChipSelect::Error>> {
+        if self.direction != Some(Direction::Get) {
+            self.abort(w5500).map_err(Error::Transfer)?;
+            // tsize 0 asks the server for the size of the file
+            self.request(w5500, OPCODE_RRQ, filename, Some(0), now_ms)?;
+            self.direction = Some(Direction::Get);
+        }
+        let result = self
+            .transfer
+            .poll_receive(w5500, &self.socket, &mut self.rx, sink, now_ms);
+        if !self.transfer.is_active() {
+            self.direction = None;
+        }
+        result
+    }
+
+    /// Writes the file out of the source to the server and returns its size. Returns
+    /// [`nb::Error::WouldBlock`] while the transfer is in progress. A running [`TftpClient::get`]
+    /// is aborted.
+    pub fn put<ChipSelect: OutputPin, Spi: FullDuplex<u8>, S: Source>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        filename: &str,
+        source: &mut S,
+        now_ms: u64,
+    ) -> nb::Result<u64, Error<Spi::Error, ChipSelect::Error>> {
+        if self.direction != Some(Direction::Put) {
+            self.abort(w5500).map_err(Error::Transfer)?;
+            let size = source.size();
+            self.request(w5500, OPCODE_WRQ, filename, size, now_ms)?;
+            self.direction = Some(Direction::Put);
+        }
+        let result = self
+            .transfer
+            .poll_send(w5500, &self.socket, &mut self.rx, source, now_ms);
+        if !self.transfer.is_active() {
+            self.direction = None;
+        }
+        result
+    }
+
+    /// Aborts the transfer in progress and notifies the server
+    pub fn abort<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        self.direction = None;
+        if self.transfer.is_active() {
+            if let nb::Error::Other(Error::Transfer(error)) =
+                self.transfer
+                    .abort(w5500, &self.socket, ErrorCode::NotDefined)
+            {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the socket
+    pub fn release(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Opens the socket on a new ephemeral port and sends the RRQ or WRQ
+    fn request<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        opcode: u16,
+        filename: &str,
+        size: Option<u64>,
+        now_ms: u64,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        if filename.is_empty() || filename.contains('\0') {
+            return Err(Error::InvalidFilename);
+        }
+        let block_size = self.block_size;
+        let mut writer = self.transfer.writer(opcode);
+        writer.string(filename);
+        writer.string(MODE_OCTET);
+        if block_size != DEFAULT_BLOCK_SIZE {
+            writer.string(OPTION_BLKSIZE);
+            writer.number(block_size as u64);
+        }
+        if let Some(size) = size {
+            writer.string(OPTION_TSIZE);
+            writer.number(size);
+        }
+        if !writer.is_complete() {
+            return Err(Error::InvalidFilename);
+        }
+        self.transfer.tx_length = writer.length;
+
+        let socket = self.socket.0;
+        let port = w5500.0.next_ephemeral_port();
+        w5500.open_udp(socket, port)?;
+        self.transfer
+            .start(self.server, self.port, false, Phase::Request);
+        self.transfer.requested_block_size = block_size;
+        debug!("TFTP requesting {} from {}", filename, self.server);
+        self.transfer.transmit(w5500, &self.socket, now_ms)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+//! TFTP server.
+//!
+//! The [`TftpServer`] listens for requests on port 69 and opens the requested files through
+//! the [`Files`] implementation of the caller. One transfer runs at a time on a second socket
+//! bound to a new ephemeral port, further requests are refused until it completes.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn now_ms() -> u64 { 0 }
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::tftp::server::{Event, Files, TftpServer};
+//! use w5500::tftp::{ErrorCode, Sink, Source};
+//! use w5500::Socket;
+//!
+//! struct Config<'c>(&'c [u8]);
+//!
+//! impl Source for Config<'_> {
+//!     fn size(&mut self) -> Option<u64> {
+//!         Some(self.0.len() as u64)
+//!     }
+//!
+//!     fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ErrorCode> {
+//!         let length = buffer.len().min(self.0.len());
+//!         buffer[..length].copy_from_slice(&self.0[..length]);
+//!         self.0 = &self.0[length..];
+//!         Ok(length)
+//!     }
+//! }
+//!
+//! struct Discard;
+//!
+//! impl Sink for Discard {
+//!     fn write(&mut self, _data: &[u8]) -> Result<(), ErrorCode> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! struct Storage;
+//!
+//! impl Files for Storage {
+//!     type Source = Config<'static>;
+//!     type Sink = Discard;
+//!
+//!     fn open_read(&mut self, filename: &str) -> Result<Self::Source, ErrorCode> {
+//!         match filename {
+//!             "config.json" => Ok(Config(br#"{"interval":60}"#)),
+//!             _ => Err(ErrorCode::FileNotFound),
+//!         }
+//!     }
+//!
+//!     fn open_write(&mut self, _filename: &str) -> Result<Self::Sink, ErrorCode> {
+//!         Err(ErrorCode::AccessViolation)
+//!     }
+//! }
+//!
+//! let listener = w5500.take_socket(Socket::Socket6).unwrap();
+//! let socket = w5500.take_socket(Socket::Socket7).unwrap();
+//! let mut tftp = TftpServer::new(&mut w5500, listener, socket, Storage)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"));
+//!
+//! loop {
+//!     if let Ok(Some(Event::Failed { client, failure })) = tftp.poll(&mut w5500, now_ms()) {
+//!         // ...
+//!     }
+//! }
+//! # }
+//! ```
+
+use byteorder::{BigEndian, ByteOrder};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use super::{
+    send_error, Error, ErrorCode, Failure, Options, Phase, Sink, Source, Transfer,
+    DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE, MODE_OCTET, OPCODE_OACK, OPCODE_RRQ,
+    OPCODE_WRQ, OPTION_BLKSIZE, OPTION_TSIZE, PACKET_SIZE, PORT,
+};
+use crate::{ActiveW5500, Ipv4Addr, Udp, UdpSocket, UninitializedSocket};
+
+/// Opens the files clients request
+pub trait Files {
+    type Source: Source;
+    type Sink: Sink;
+
+    /// Opens the file for a read request
+    fn open_read(&mut self, filename: &str) -> Result<Self::Source, ErrorCode>;
+
+    /// Opens the file for a write request
+    fn open_write(&mut self, filename: &str) -> Result<Self::Sink, ErrorCode>;
+}
+
+/// Event returned by [`TftpServer::poll`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// The client read a file of the given size
+    Sent { client: Ipv4Addr, size: u64 },
+    /// The client wrote a file of the given size
+    Received { client: Ipv4Addr, size: u64 },
+    /// The transfer failed
+    Failed { client: Ipv4Addr, failure: Failure },
+}
+
+enum Endpoint<R, W> {
+    Read(R),
+    Write(W),
+}
+
+/// TFTP server, see the [module documentation](self)
+pub struct TftpServer<F: Files> {
+    listener: UdpSocket,
+    socket: UdpSocket,
+    files: F,
+    endpoint: Option<Endpoint<F::Source, F::Sink>>,
+    transfer: Transfer,
+    rx: [u8; PACKET_SIZE],
+}
+
+impl<F: Files> TftpServer<F> {
+    /// Opens the listening socket on [`PORT`]. The second socket is opened for each transfer.
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        listener: UninitializedSocket,
+        socket: UninitializedSocket,
+        files: F,
+    ) -> Result<Self, crate::Error<Spi::Error, ChipSelect::Error>> {
+        let listener = listener.0;
+        w5500.open_udp(listener, PORT)?;
+        Ok(TftpServer {
+            listener: UdpSocket(listener),
+            socket: UdpSocket(socket.0),
+            files,
+            endpoint: None,
+            transfer: Transfer::new(),
+            rx: [0u8; PACKET_SIZE],
+        })
+    }
+
+    /// Sets the time to wait for an answer before the last packet is sent again
+    pub fn with_timeout(mut self, timeout_ms: u64) -> Self {
+        self.transfer.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Sets how often the last packet is sent again before the transfer fails
+    pub fn with_retries(mut self, retries: u8) -> Self {
+        self.transfer.retries = retries;
+        self
+    }
+
+    /// The files served
+    pub fn files(&mut self) -> &mut F {
+        &mut self.files
+    }
+
+    /// Whether a transfer is in progress
+    pub fn is_busy(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    /// Aborts the transfer in progress and notifies the client
+    pub fn abort<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        if self.endpoint.take().is_some() {
+            if let nb::Error::Other(Error::Transfer(error)) =
+                self.transfer
+                    .abort(w5500, &self.socket, ErrorCode::NotDefined)
+            {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the listening socket, the transfer socket and the files
+    pub fn release(self) -> (UdpSocket, UdpSocket, F) {
+        (self.listener, self.socket, self.files)
+    }
+
+    /// Advances the transfer in progress and accepts new requests
+    pub fn poll<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        now_ms: u64,
+    ) -> Result<Option<Event>, crate::Error<Spi::Error, ChipSelect::Error>> {
+        if let Some(endpoint) = &mut self.endpoint {
+            let client = self.transfer.peer;
+            let result = match endpoint {
+                Endpoint::Read(source) => self
+                    .transfer
+                    .poll_send(w5500, &self.socket, &mut self.rx, source, now_ms)
+                    .map(|size| Event::Sent { client, size }),
+                Endpoint::Write(sink) => self
+                    .transfer
+                    .poll_receive(w5500, &self.socket, &mut self.rx, sink, now_ms)
+                    .map(|size| Event::Received { client, size }),
+            };
+            match result {
+                Ok(event) => {
+                    self.endpoint = None;
+                    return Ok(Some(event));
+                }
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(Error::Transfer(error))) => return Err(error),
+                Err(nb::Error::Other(Error::Failed(failure))) => {
+                    self.endpoint = None;
+                    return Ok(Some(Event::Failed { client, failure }));
+                }
+                Err(nb::Error::Other(Error::InvalidFilename)) => {}
+            }
+        }
+
+        while let Some((client, port, length, truncated)) =
+            (&mut *w5500, &self.listener).receive(&mut self.rx)?
+        {
+            if truncated {
+                debug!("TFTP ignoring oversized request from {}", client);
+                continue;
+            }
+            if self.endpoint.is_some() {
+                debug!("TFTP refusing request from {}, busy", client);
+                self.refuse(w5500, client, port, ErrorCode::NotDefined)?;
+                continue;
+            }
+            if let Some(event) = self.accept(w5500, client, port, length, now_ms)? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Opens the requested file, negotiates the options and starts the transfer
+    fn accept<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        client: Ipv4Addr,
+        port: u16,
+        length: usize,
+        now_ms: u64,
+    ) -> Result<Option<Event>, crate::Error<Spi::Error, ChipSelect::Error>> {
+        let request = match parse_request(&self.rx[..length]) {
+            Some(request) => request,
+            None => {
+                self.refuse(w5500, client, port, ErrorCode::IllegalOperation)?;
+                return Ok(None);
+            }
+        };
+        debug!(
+            "TFTP {} request for {} from {}",
+            if request.write { "write" } else { "read" },
+            request.filename,
+            client
+        );
+        let opened = if request.write {
+            self.files.open_write(request.filename).map(Endpoint::Write)
+        } else {
+            self.files.open_read(request.filename).map(Endpoint::Read)
+        };
+
+        let (block_size, size) = requested_options(request.options);
+
+        let mut endpoint = match opened {
+            Ok(endpoint) => endpoint,
+            Err(code) => {
+                self.refuse(w5500, client, port, code)?;
+                return Ok(None);
+            }
+        };
+        // tsize is answered with the size of a read file and echoed for a written one
+        let size = match &mut endpoint {
+            Endpoint::Read(source) => size.and(source.size()),
+            Endpoint::Write(sink) => match size.map(|size| sink.set_size(size)) {
+                Some(Err(code)) => {
+                    self.refuse(w5500, client, port, code)?;
+                    return Ok(None);
+                }
+                _ => size,
+            },
+        };
+
+        let socket = self.socket.0;
+        let local_port = w5500.0.next_ephemeral_port();
+        w5500.open_udp(socket, local_port)?;
+        self.transfer.start(client, port, true, Phase::Idle);
+
+        if block_size.is_some() || size.is_some() {
+            write_oack(&mut self.transfer, block_size, size);
+            self.transfer.phase = match endpoint {
+                Endpoint::Read(_) => Phase::Send {
+                    block: 0,
+                    last: false,
+                },
+                Endpoint::Write(_) => Phase::Receive { block: 0 },
+            };
+            self.transfer.transmit(w5500, &self.socket, now_ms)?;
+        } else {
+            let result = match &mut endpoint {
+                Endpoint::Read(source) => {
+                    self.transfer
+                        .send_data(w5500, &self.socket, source, 1, now_ms)
+                }
+                Endpoint::Write(_) => self
+                    .transfer
+                    .acknowledge(w5500, &self.socket, 0, now_ms)
+                    .map_err(|error| nb::Error::Other(Error::Transfer(error))),
+            };
+            match result {
+                Ok(()) => {}
+                Err(nb::Error::Other(Error::Transfer(error))) => return Err(error),
+                Err(nb::Error::Other(Error::Failed(failure))) => {
+                    return Ok(Some(Event::Failed { client, failure }));
+                }
+                Err(_) => return Ok(None),
+            }
+        }
+        self.endpoint = Some(endpoint);
+        Ok(None)
+    }
+
+    /// Answers a request with an ERROR packet from the listening socket
+    fn refuse<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        client: Ipv4Addr,
+        port: u16,
+        code: ErrorCode,
+    ) -> Result<(), crate::Error<Spi::Error, ChipSelect::Error>> {
+        send_error(w5500, &self.listener, &client, port, code)
+    }
+}
+
+/// A read or write request in octet mode
+struct Request<'r> {
+    write: bool,
+    filename: &'r str,
+    options: Options<'r>,
+}
+
+/// Parses a request, `None` if it is malformed or not in octet mode
+fn parse_request(packet: &[u8]) -> Option<Request<'_>> {
+    if packet.len() < 2 {
+        return None;
+    }
+    let write = match BigEndian::read_u16(packet) {
+        OPCODE_RRQ => false,
+        OPCODE_WRQ => true,
+        _ => return None,
+    };
+    let mut strings = Options::parse(&packet[2..])?;
+    let filename = strings.next_str().filter(|filename| !filename.is_empty())?;
+    strings
+        .next_str()
+        .filter(|mode| mode.eq_ignore_ascii_case(MODE_OCTET))?;
+    Some(Request {
+        write,
+        filename,
+        options: strings,
+    })
+}
+
+/// The block size and transfer size options of a request that are answered, unknown and
+/// malformed options are ignored
+fn requested_options(options: Options<'_>) -> (Option<usize>, Option<u64>) {
+    let mut block_size = None;
+    let mut size = None;
+    for (name, value) in options {
+        if name.eq_ignore_ascii_case(OPTION_BLKSIZE) {
+            block_size = value
+                .parse::<usize>()
+                .ok()
+                .filter(|block_size| *block_size >= MIN_BLOCK_SIZE)
+                .map(|block_size| block_size.min(MAX_BLOCK_SIZE));
+        } else if name.eq_ignore_ascii_case(OPTION_TSIZE) {
+            size = value.parse::<u64>().ok();
+        }
+    }
+    (block_size, size)
+}
+
+/// Writes the OACK for the negotiated options into the transfer and applies the block size
+fn write_oack(transfer: &mut Transfer, block_size: Option<usize>, size: Option<u64>) {
+    transfer.block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let mut writer = transfer.writer(OPCODE_OACK);
+    if let Some(block_size) = block_size {
+        writer.string(OPTION_BLKSIZE);
+        writer.number(block_size as u64);
+    }
+    if let Some(size) = size {
+        writer.string(OPTION_TSIZE);
+        writer.number(size);
+    }
+    transfer.tx_length = writer.length;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_octet_requests() {
+        let request = parse_request(b"\x00\x01firmware.bin\x00octet\x00").unwrap();
+        assert!(!request.write);
+        assert_eq!(request.filename, "firmware.bin");
+        assert_eq!(requested_options(request.options), (None, None));
+
+        let request = parse_request(b"\x00\x02log.txt\x00OCTET\x00").unwrap();
+        assert!(request.write);
+        assert_eq!(request.filename, "log.txt");
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        for packet in [
+            &b"\x00\x01firmware.bin\x00netascii\x00"[..],
+            b"\x00\x01firmware.bin\x00mail\x00",
+            b"\x00\x01firmware.bin\x00octet",
+            b"\x00\x01firmware.bin",
+            b"\x00\x01\x00octet\x00",
+            b"\x00\x03firmware.bin\x00octet\x00",
+            b"\x00",
+        ] {
+            assert!(parse_request(packet).is_none());
+        }
+    }
+
+    #[test]
+    fn clamps_requested_block_size() {
+        let options = |packet| requested_options(parse_request(packet).unwrap().options);
+        assert_eq!(
+            options(b"\x00\x01a\x00octet\x00blksize\x001024\x00"),
+            (Some(1024), None)
+        );
+        assert_eq!(
+            options(b"\x00\x01a\x00octet\x00blksize\x009000\x00"),
+            (Some(MAX_BLOCK_SIZE), None)
+        );
+        assert_eq!(
+            options(b"\x00\x01a\x00octet\x00blksize\x007\x00timeout\x001\x00"),
+            (None, None)
+        );
+        assert_eq!(
+            options(b"\x00\x02a\x00octet\x00tsize\x004096\x00"),
+            (None, Some(4096))
+        );
+    }
+
+    #[test]
+    fn echoes_options() {
+        let mut transfer = Transfer::new();
+        write_oack(&mut transfer, Some(1024), Some(4096));
+        assert_eq!(
+            &transfer.tx[..transfer.tx_length],
+            b"\x00\x06blksize\x001024\x00tsize\x004096\x00"
+        );
+        assert_eq!(transfer.block_size, 1024);
+
+        write_oack(&mut transfer, None, Some(0));
+        assert_eq!(
+            &transfer.tx[..transfer.tx_length],
+            b"\x00\x06tsize\x000\x00"
+        );
+        assert_eq!(transfer.block_size, DEFAULT_BLOCK_SIZE);
+    }
+}
@@ -0,0 +1,174 @@
+//! Wake-on-LAN.
+//!
+//! [`sleep`] prepares the chip to wait for a magic packet addressed to its own MAC address:
+//! it enables the WOL mode, clears and unmasks the [`CommonInterrupt::MagicPacket`] interrupt
+//! so it asserts the INTn pin, and optionally switches the PHY to a mode that draws less power.
+//! The chip only recognises magic packets sent over UDP. [`Sleep::wake`] restores the previous
+//! configuration.
+//!
+//! [`WolSender`] wakes other machines by broadcasting magic packets, optionally with a
+//! SecureOn password.
+//!
+//! ```no_run
+//! # use embedded_hal::spi::FullDuplex;
+//! # use embedded_hal::digital::v2::OutputPin;
+//! # fn wait_for_interrupt() {}
+//! # fn example<Cs: OutputPin, Spi: FullDuplex<u8>>(mut w5500: w5500::ActiveW5500<Cs, Spi>) {
+//! use w5500::wol::{self, WolSender};
+//! use w5500::{MacAddress, OperationMode, Socket};
+//!
+//! let sleep = wol::sleep(&mut w5500, OperationMode::HalfDuplex10bt).unwrap_or_else(|_| panic!());
+//! while !sleep.is_woken(&mut w5500).unwrap_or(true) {
+//!     wait_for_interrupt();
+//! }
+//! sleep.wake(&mut w5500).unwrap_or_else(|_| panic!());
+//!
+//! let socket = w5500.take_socket(Socket::Socket3).unwrap();
+//! let mut sender = WolSender::new(&mut w5500, socket)
+//!     .unwrap_or_else(|_| panic!("failed to open the socket"));
+//! let nas = MacAddress::new(0x00, 0x11, 0x32, 0x0A, 0x0B, 0x0C);
+//! nb::block!(sender.send(&mut w5500, nas, None)).unwrap_or_else(|_| panic!());
+//! # }
+//! ```
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::FullDuplex;
+
+use crate::{
+    ActiveW5500, CommonInterrupt, Error, Ipv4Addr, MacAddress, OperationMode, Register, Udp,
+    UdpSocket, UninitializedSocket,
+};
+
+/// UDP port magic packets are sent to unless set with [`WolSender::with_port`]
+pub const PORT: u16 = 9;
+/// Largest SecureOn password
+pub const MAX_PASSWORD_SIZE: usize = 6;
+
+/// Six bytes of 0xFF followed by 16 repetitions of the MAC address
+const MAGIC_PACKET_SIZE: usize = 6 + 16 * 6;
+/// WOL bit of the mode register
+const MODE_WOL: u8 = 1 << 5;
+
+/// Configuration to restore when waking up, returned by [`sleep`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sleep {
+    mode: u8,
+    operation_mode: OperationMode,
+    interrupt_mask: u8,
+}
+
+/// Enables the WOL mode and the magic packet interrupt, and switches the PHY to
+/// `operation_mode` while waiting, for example [`OperationMode::HalfDuplex10bt`]. The PHY is
+/// left alone if it is in that mode already. [`OperationMode::PowerDown`] would prevent any
+/// packet from arriving.
+pub fn sleep<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+    w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    operation_mode: OperationMode,
+) -> Result<Sleep, Error<Spi::Error, ChipSelect::Error>> {
+    let sleep = Sleep {
+        mode: w5500.read_u8(Register::CommonRegister(0x00_00_u16))?,
+        operation_mode: w5500.phy_cfg()?.operation_mode(),
+        interrupt_mask: w5500.read_u8(Register::CommonRegister(0x00_16_u16))?,
+    };
+    if operation_mode != sleep.operation_mode {
+        w5500.set_operation_mode(operation_mode)?;
+    }
+    w5500.reset_common_interrupt(CommonInterrupt::MagicPacket)?;
+    w5500.set_common_interrupt_enabled(CommonInterrupt::MagicPacket, true)?;
+    w5500.write_u8(Register::CommonRegister(0x00_00_u16), sleep.mode | MODE_WOL)?;
+    debug!("waiting for a magic packet");
+    Ok(sleep)
+}
+
+impl Sleep {
+    /// Whether a magic packet has been received since [`sleep`]
+    pub fn is_woken<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<bool, Error<Spi::Error, ChipSelect::Error>> {
+        w5500.is_common_interrupt_set(CommonInterrupt::MagicPacket)
+    }
+
+    /// Clears the magic packet interrupt and restores the WOL mode, the interrupt mask and the
+    /// PHY operation mode from before [`sleep`]
+    pub fn wake<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+    ) -> Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        w5500.write_u8(Register::CommonRegister(0x00_00_u16), self.mode)?;
+        w5500.write_u8(Register::CommonRegister(0x00_16_u16), self.interrupt_mask)?;
+        w5500.reset_common_interrupt(CommonInterrupt::MagicPacket)?;
+        if w5500.phy_cfg()?.operation_mode() != self.operation_mode {
+            w5500.set_operation_mode(self.operation_mode)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends magic packets, see the [module documentation](self)
+pub struct WolSender {
+    socket: UdpSocket,
+    destination: Ipv4Addr,
+    port: u16,
+    buffer: [u8; MAGIC_PACKET_SIZE + MAX_PASSWORD_SIZE],
+}
+
+impl WolSender {
+    /// Opens the socket on a new ephemeral port
+    pub fn new<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        socket: UninitializedSocket,
+    ) -> Result<Self, Error<Spi::Error, ChipSelect::Error>> {
+        let socket = socket.0;
+        let port = w5500.0.next_ephemeral_port();
+        w5500.open_udp(socket, port)?;
+        Ok(WolSender {
+            socket: UdpSocket(socket),
+            destination: Ipv4Addr::BROADCAST,
+            port: PORT,
+            buffer: [0u8; MAGIC_PACKET_SIZE + MAX_PASSWORD_SIZE],
+        })
+    }
+
+    /// Sends the packets to a directed broadcast address like 192.168.0.255 instead of
+    /// 255.255.255.255, so routers can forward them into that subnet
+    pub fn with_destination(mut self, destination: Ipv4Addr) -> Self {
+        self.destination = destination;
+        self
+    }
+
+    /// Sets the UDP port the packets are sent to, defaults to [`PORT`]
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Returns the socket
+    pub fn release(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Sends a magic packet that wakes the machine with the given MAC address. The SecureOn
+    /// password is usually 4 or 6 bytes long, longer ones are truncated to
+    /// [`MAX_PASSWORD_SIZE`]. Returns [`nb::Error::WouldBlock`] while the previous packet is
+    /// still being sent, see [`Udp::send`].
+    pub fn send<ChipSelect: OutputPin, Spi: FullDuplex<u8>>(
+        &mut self,
+        w5500: &mut ActiveW5500<'_, '_, ChipSelect, Spi>,
+        target: MacAddress,
+        password: Option<&[u8]>,
+    ) -> nb::Result<(), Error<Spi::Error, ChipSelect::Error>> {
+        self.buffer[..6].copy_from_slice(&[0xFF; 6]);
+        for repetition in self.buffer[6..MAGIC_PACKET_SIZE].chunks_exact_mut(6) {
+            repetition.copy_from_slice(&target.octets);
+        }
+        let password = password.unwrap_or(&[]);
+        let password = &password[..password.len().min(MAX_PASSWORD_SIZE)];
+        let length = MAGIC_PACKET_SIZE + password.len();
+        self.buffer[MAGIC_PACKET_SIZE..length].copy_from_slice(password);
+
+        debug!("magic packet for {:?}", target);
+        (&mut *w5500, &self.socket).send(&self.destination, self.port, &self.buffer[..length])
+    }
+}